@@ -1,6 +1,6 @@
 extern crate easer;
 
-use std::ops::Add;
+use std::ops::{Add, Range};
 use std::sync::{Arc, RwLock};
 use chrono::prelude::*;
 use chrono::Duration;
@@ -8,35 +8,30 @@ use easer::functions::*;
 use rand::Rng;
 use rand::prelude::SliceRandom;
 
-use crate::clock_objects::{ClockType, DisplayMessage};
-use crate::clock_objects::LingerDurations;
-
-pub struct DisplayMessageStringUtils {}
+use chrono_tz::Tz;
+use serde::Deserialize;
 
-impl DisplayMessageStringUtils {
-    pub fn for_local(local: DateTime<Local>, clock_type: ClockType) -> String {
-        match clock_type {
-            ClockType::NCS3148C => {
-                let mut s = local.format("%I:%M:%S%.3f").to_string();
-                s.push_str(" ");
-                s
-            }
-            ClockType::NCS3186 => {
-                local.format("%I:%M:%S").to_string()
-            }
-        }
-    }
-}
+use crate::clock_objects::{ClockType, DisplayMessage, SlotKind};
+use crate::clock_objects::LingerDurations;
+use crate::display_template::DisplayTemplate;
+use crate::rgb_driver::LedColor;
+use crate::time_sync::SyncStatus;
+use crate::sensors::{Reading, SensorBus, Unit};
+use crate::value_format::NumberFormat;
 
 pub trait Overlayable {
     fn has_ended(&self, current_time: DateTime<Local>) -> bool;
     fn is_visible(&self, current_time: DateTime<Local>) -> bool;
+    fn reschedule(&mut self, offset: Duration);
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Overlay {
     AntiPoison(AntiPoisonAnimation),
     TempOverlay(TempOverlayAnimation),
+    Scene(SceneOverlay),
+    SyncOffset(SyncOffsetOverlay),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -65,12 +60,12 @@ impl AntiPoisonAnimation {
         fade_pct: f32,
     ) -> AntiPoisonAnimation {
         let mut retval = AntiPoisonAnimation {
-            tube_idx: tube_idx,
-            style: style,
-            start_time: start_time,
-            duration: duration,
-            cycle: cycle,
-            fade_pct: fade_pct,
+            tube_idx,
+            style,
+            start_time,
+            duration,
+            cycle,
+            fade_pct,
         };
         if retval.style == AntiPoisonAnimationStyle::Random {
             retval.cycle.shuffle(&mut rand::thread_rng());
@@ -81,7 +76,7 @@ impl AntiPoisonAnimation {
     pub fn matrix_style_set() -> Vec<Overlay> {
         //numeric tube anti-poisions, skipping sub-second tubes which aren't at risk of poisioning
         let mut rng = rand::thread_rng();
-        let mut numeric_tube_positions = vec![0, 1, 3, 4, 6, 7];
+        let mut numeric_tube_positions = [0, 1, 3, 4, 6, 7];
         numeric_tube_positions.shuffle(&mut rng);
         let num_antipoisons = rng.gen_range(0..9);
         let mut set_for_minute: Vec<Overlay> = vec![];
@@ -99,11 +94,33 @@ impl AntiPoisonAnimation {
         set_for_minute
     }
 
+    /// Runs every numeric tube through all its cathodes, one tube after another, for when
+    /// anti-poisoning is asked for rather than scheduled
+    pub fn full_cycle_set(clock_type: ClockType, start_time: DateTime<Local>) -> Vec<Overlay> {
+        clock_type
+            .slot_layout()
+            .iter()
+            .enumerate()
+            .filter(|(_, kind)| **kind == SlotKind::Numeric)
+            .enumerate()
+            .map(|(n, (tube_idx, _))| {
+                Overlay::AntiPoison(AntiPoisonAnimation::new(
+                    tube_idx,
+                    AntiPoisonAnimationStyle::Sequential,
+                    start_time + Duration::milliseconds(250 * n as i64),
+                    Duration::seconds(5),
+                    vec!['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'],
+                    0.1f32,
+                ))
+            })
+            .collect()
+    }
+
     pub fn apply_to_message(
         &self,
         current_time: DateTime<Local>,
         cur_message: &mut impl DisplayMessage,
-    ) -> () {
+    ) {
         if self.is_visible(current_time) {
             if let Some(v) = self.get_current_value(current_time) {
                 cur_message.set_tube(self.tube_idx, v).unwrap()
@@ -116,7 +133,7 @@ impl AntiPoisonAnimation {
             .map(|t| t as f32)
         {
             if let Some(d) = self.duration.num_microseconds().map(|d| d as f32) {
-                let p = Linear::ease_in(t, 0f32, 70f32, d as f32) as usize;
+                let p = Linear::ease_in(t, 0f32, 70f32, d) as usize;
                 let cur_char = Some(self.cycle[p % self.cycle.len()]);
                 //use PFM to fade between this char and the underlying time value
                 if t < (d * self.fade_pct) {
@@ -154,38 +171,137 @@ impl Overlayable for AntiPoisonAnimation {
     fn is_visible(&self, current_time: DateTime<Local>) -> bool {
         self.start_time < current_time && self.start_time + self.duration > current_time
     }
+    fn reschedule(&mut self, offset: Duration) {
+        self.start_time = self.start_time + offset;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+    //side by side, Fahrenheit on the left, which needs the NCS3148C's 8 digits
+    FahrenheitCelsius,
+}
+
+impl TemperatureUnit {
+    pub fn convert_celsius(&self, celsius: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit | TemperatureUnit::FahrenheitCelsius => celsius * (9f32 / 5f32) + 32f32,
+            TemperatureUnit::Kelvin => celsius + 273.15,
+        }
+    }
+
+    //the IN-19A has ℃ and κ but nothing for Fahrenheit
+    fn symbol(&self) -> char {
+        match self {
+            TemperatureUnit::Celsius | TemperatureUnit::FahrenheitCelsius => '℃',
+            TemperatureUnit::Kelvin => 'κ',
+            TemperatureUnit::Fahrenheit => ' ',
+        }
+    }
+}
+
+/// How temperature scenes lay a reading out on the board
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TemperatureFormat {
+    pub unit: TemperatureUnit,
+    pub number: NumberFormat,
+}
+
+impl TemperatureFormat {
+    /// The slot string for a reading, right aligned with the unit on the IN-19A if there is
+    /// one, or None if it can't be shown
+    pub fn render(&self, celsius: f32, clock_type: ClockType) -> Option<String> {
+        let layout = clock_type.slot_layout();
+        let mut slots = vec![' '; layout.len()];
+        let mut end = layout.len();
+        if layout.last() == Some(&SlotKind::IN19A) {
+            end -= 1;
+            slots[end] = self.unit.symbol();
+        }
+        if self.unit == TemperatureUnit::FahrenheitCelsius {
+            //split at the middle separator
+            let middle = end / 2;
+            if layout[middle] != SlotKind::Separator {
+                return None;
+            }
+            slots[middle] = '\'';
+            self.number.place(layout, &mut slots, 0..middle, self.unit.convert_celsius(celsius))?;
+            self.number.place(layout, &mut slots, middle + 1..end, celsius)?;
+        } else {
+            self.number.place(layout, &mut slots, 0..end, self.unit.convert_celsius(celsius))?;
+        }
+        Some(slots.into_iter().collect())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReadingFormat {
+    //a °C reading converted to the configured unit
+    Temperature(TemperatureFormat),
+    //any reading in its own unit
+    Value(NumberFormat),
 }
 
+/// What a sensor scene shows and how
+#[derive(Debug, Clone)]
+pub struct ReadingScene {
+    //the bus name of the measurement to show
+    pub sensor: String,
+    //lit while it shows, to tell the sensors apart
+    pub led: Option<LedColor>,
+    pub format: ReadingFormat,
+}
+
+/// Shows a sensor's reading, the temperature unless the scene says otherwise
 #[derive(Debug)]
 pub struct TempOverlayAnimation {
     pub start_time: DateTime<Local>,
     pub duration: Duration,
-    //a Some(None) means to stop trying to read the temp, it doesn't exist
-    temperature_celsius: Option<Option<f32>>,
-    temperature_lock: Arc<RwLock<Option<f32>>>,
+    //a Some(None) means to stop trying to read the sensor, it has no recent reading
+    reading: Option<Option<Reading>>,
+    sensors: SensorBus,
+    scene: ReadingScene,
     clock_type: ClockType,
+    transition: SceneTransition,
 }
 
 impl TempOverlayAnimation {
     pub fn new(
-        temperature_lock: Arc<RwLock<Option<f32>>>,
+        sensors: SensorBus,
+        scene: ReadingScene,
         clock_type: ClockType,
+        start_time: DateTime<Local>,
+        duration: Duration,
+        transition: SceneTransition,
     ) -> TempOverlayAnimation {
         TempOverlayAnimation {
-            start_time: Local::now().with_second(16).unwrap(),
-            duration: Duration::seconds(3),
-            temperature_celsius: None,
-            temperature_lock: temperature_lock,
-            clock_type: clock_type,
+            start_time,
+            duration,
+            reading: None,
+            sensors,
+            scene,
+            clock_type,
+            transition,
         }
     }
     pub fn apply_to_message(
         &mut self,
         current_time: DateTime<Local>,
         cur_message: &mut impl DisplayMessage,
-    ) -> () {
+    ) {
         if self.is_visible(current_time) {
             if let Some(temp_string) = self.get_temperature_string() {
+                let temp_string = self.transition.apply(
+                    temp_string,
+                    self.start_time,
+                    self.duration,
+                    current_time,
+                );
                 cur_message.set_from_string(temp_string).unwrap();
                 cur_message.set_lingers(LingerDurations {
                     off: Some(Duration::microseconds(0)),
@@ -194,24 +310,22 @@ impl TempOverlayAnimation {
             }
         }
     }
+    /// The LED colour that tells which sensor is showing, while it shows
+    pub fn led_at(&self, current_time: DateTime<Local>) -> Option<LedColor> {
+        self.scene.led.filter(|_| self.is_visible(current_time))
+    }
     fn get_temperature_string(&mut self) -> Option<String> {
-        if self.temperature_celsius.is_none() {
-            let t_lock = self.temperature_lock.read().unwrap();
-            if t_lock.is_some() {
-                let temp = t_lock.unwrap();
-                self.temperature_celsius = Some(Some(temp));
-            } else {
-                self.temperature_celsius = Some(None);//This says we've checked the temp lock but there was no reported temp
-            }
+        if self.reading.is_none() {
+            //Some(None) says we've checked the bus but there was no recent reading
+            self.reading = Some(self.sensors.latest(&self.scene.sensor));
         }
-        if let Some(cur_temp) = self.temperature_celsius.flatten() {
-            // Some("12.34'56.78℃".to_string())
-            match self.clock_type {
-                ClockType::NCS3148C => Some(format!("{:2.2}'{:2.2}℃", ((cur_temp * (9f32 / 5f32)) + 32f32) % 100f32, cur_temp % 100f32)),
-                ClockType::NCS3186 => Some(format!("{:2.2}'  ", ((cur_temp * (9f32 / 5f32)) + 32f32) % 100f32)),
+        let reading = self.reading.flatten()?;
+        match self.scene.format {
+            ReadingFormat::Temperature(format) if reading.unit == Unit::Celsius => {
+                format.render(reading.value, self.clock_type)
             }
-        } else {
-            None
+            ReadingFormat::Temperature(_) => None,
+            ReadingFormat::Value(format) => format.render(reading.value, reading.unit, self.clock_type),
         }
     }
 }
@@ -223,6 +337,215 @@ impl Overlayable for TempOverlayAnimation {
     fn is_visible(&self, current_time: DateTime<Local>) -> bool {
         self.start_time < current_time && self.start_time + self.duration > current_time
     }
+    fn reschedule(&mut self, offset: Duration) {
+        self.start_time = self.start_time + offset;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionStyle {
+    #[default]
+    Cut,
+    Fade,
+    Scramble,
+}
+
+/// How an overlay enters and leaves, applied over `duration` at each end of it
+#[derive(Debug, Copy, Clone)]
+pub struct SceneTransition {
+    pub style: TransitionStyle,
+    pub exit_style: TransitionStyle,
+    pub duration: Duration,
+}
+
+impl SceneTransition {
+    pub fn cut() -> SceneTransition {
+        SceneTransition {
+            style: TransitionStyle::Cut,
+            exit_style: TransitionStyle::Cut,
+            duration: Duration::zero(),
+        }
+    }
+
+    /// Blends an overlay's slot string with what is underneath it. Fading uses PFM between
+    /// each slot and '*' (keep the underlying value), scrambling spins the digits until they
+    /// settle.
+    pub fn apply(
+        &self,
+        slots: String,
+        start_time: DateTime<Local>,
+        overlay_duration: Duration,
+        current_time: DateTime<Local>,
+    ) -> String {
+        let d = self.duration.num_microseconds().unwrap_or(0) as f32;
+        let since_start = current_time - start_time;
+        let until_end = start_time + overlay_duration - current_time;
+        let style = if since_start < until_end { self.style } else { self.exit_style };
+        if style == TransitionStyle::Cut || d <= 0f32 {
+            return slots;
+        }
+        let edge = since_start.min(until_end).num_microseconds().unwrap_or(0) as f32;
+        if edge >= d {
+            return slots;
+        }
+        let p = Linear::ease_in(edge.max(0f32), 0f32, 255f32, d);
+        let mut rng = rand::thread_rng();
+        slots
+            .chars()
+            .map(|c| {
+                let shown = rng.gen_range(0..255) < p as isize;
+                match style {
+                    TransitionStyle::Fade if !shown => '*',
+                    TransitionStyle::Scramble if !shown && c.is_ascii_digit() => {
+                        std::char::from_digit(rng.gen_range(0..10), 10).unwrap()
+                    }
+                    _ => c,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Shows a template, such as the date or the time in another format, for part of a minute
+#[derive(Debug)]
+pub struct SceneOverlay {
+    pub start_time: DateTime<Local>,
+    pub duration: Duration,
+    template: DisplayTemplate,
+    zone: Option<Tz>,
+    transition: SceneTransition,
+}
+
+impl SceneOverlay {
+    pub fn new(
+        start_time: DateTime<Local>,
+        duration: Duration,
+        template: DisplayTemplate,
+        zone: Option<Tz>,
+        transition: SceneTransition,
+    ) -> SceneOverlay {
+        SceneOverlay {
+            start_time,
+            duration,
+            template,
+            zone,
+            transition,
+        }
+    }
+
+    pub fn apply_to_message(
+        &self,
+        current_time: DateTime<Local>,
+        cur_message: &mut impl DisplayMessage,
+    ) {
+        if self.is_visible(current_time) {
+            let micros = current_time.timestamp_subsec_micros();
+            let separators_on = TimeSeparators::time_separators_animation(micros);
+            let slots = match self.zone {
+                Some(tz) => self.template.render(&current_time.with_timezone(&tz), separators_on),
+                None => self.template.render(&current_time, separators_on),
+            };
+            let slots = self
+                .transition
+                .apply(slots, self.start_time, self.duration, current_time);
+            cur_message.set_from_string(slots).unwrap();
+        }
+    }
+}
+
+impl Overlayable for SceneOverlay {
+    fn has_ended(&self, current_time: DateTime<Local>) -> bool {
+        self.start_time + self.duration < current_time
+    }
+    fn is_visible(&self, current_time: DateTime<Local>) -> bool {
+        self.start_time < current_time && self.start_time + self.duration > current_time
+    }
+    fn reschedule(&mut self, offset: Duration) {
+        self.start_time = self.start_time + offset;
+    }
+}
+
+/// Shows how far the system clock was from the time source at the last sync, in ms.
+/// The integer part is right aligned with blanked leading zeros, the top dot of the first
+/// separator means the clock is fast, and the IN-19A shows 'ₘ' for milli where there is one.
+#[derive(Debug)]
+pub struct SyncOffsetOverlay {
+    pub start_time: DateTime<Local>,
+    pub duration: Duration,
+    offset_ms: Option<f64>,
+    clock_type: ClockType,
+}
+
+impl SyncOffsetOverlay {
+    pub fn new(
+        sync_lock: &Arc<RwLock<SyncStatus>>,
+        clock_type: ClockType,
+        start_time: DateTime<Local>,
+        duration: Duration,
+    ) -> SyncOffsetOverlay {
+        let offset_ms = sync_lock
+            .read()
+            .unwrap()
+            .offset
+            .and_then(|o| o.num_microseconds())
+            .map(|us| us as f64 / 1000f64);
+        SyncOffsetOverlay {
+            start_time,
+            duration,
+            offset_ms,
+            clock_type,
+        }
+    }
+
+    pub fn apply_to_message(
+        &self,
+        current_time: DateTime<Local>,
+        cur_message: &mut impl DisplayMessage,
+    ) {
+        if self.is_visible(current_time) {
+            if let Some(offset_slots) = self.get_offset_string() {
+                cur_message.set_from_string(offset_slots).unwrap();
+            }
+        }
+    }
+
+    fn get_offset_string(&self) -> Option<String> {
+        let offset_ms = self.offset_ms?;
+        //a positive offset means the system clock is slow
+        let sign = if offset_ms < 0f64 { '\'' } else { ' ' };
+        let hundredths = (offset_ms.abs() * 100f64).round() as u64;
+        match self.clock_type {
+            ClockType::NCS3148C => {
+                let digits: Vec<char> = format!("{:>6}", (hundredths / 100).min(999_999)).chars().collect();
+                Some(format!(
+                    "{}{}{}{}{} {}{}.{:02}ₘ",
+                    digits[0], digits[1], sign, digits[2], digits[3], digits[4], digits[5],
+                    hundredths % 100
+                ))
+            }
+            ClockType::NCS3186 => {
+                let digits: Vec<char> = format!("{:>4}", (hundredths / 100).min(9_999)).chars().collect();
+                Some(format!(
+                    "{}{}{}{}{}.{:02}",
+                    digits[0], digits[1], sign, digits[2], digits[3],
+                    hundredths % 100
+                ))
+            }
+        }
+    }
+}
+
+impl Overlayable for SyncOffsetOverlay {
+    fn has_ended(&self, current_time: DateTime<Local>) -> bool {
+        self.start_time + self.duration < current_time
+    }
+    fn is_visible(&self, current_time: DateTime<Local>) -> bool {
+        self.start_time < current_time && self.start_time + self.duration > current_time
+    }
+    fn reschedule(&mut self, offset: Duration) {
+        self.start_time = self.start_time + offset;
+    }
 }
 
 pub struct TimeSeparators {}
@@ -231,8 +554,7 @@ impl TimeSeparators {
     //a pulse frequency modulation-based animation
     pub fn time_separators_animation(micros: u32) -> bool {
         if micros < 750_000 {
-            let p: f32;
-            p = Bounce::ease_in(micros as f32, 255f32, -255f32, 750_000f32);
+            let p: f32 = Bounce::ease_in(micros as f32, 255f32, -255f32, 750_000f32);
             rand::thread_rng().gen_range(0..255) < p as isize
         } else {
             false
@@ -279,8 +601,7 @@ impl PwmAnimation {
         let fd = self.frame_interval_us as f32 + rand::thread_rng().gen_range(0..90) as f32;
         let delta:f32 = rand::thread_rng().gen_range(0..90) as f32;
         if micros < 750_000 {
-            let p: f32;
-            p = Sine::ease_in(micros as f32, fd, -delta, 750_000f32);
+            let p: f32 = Sine::ease_in(micros as f32, fd, -delta, 750_000f32);
             LingerDurations {
                 off: Some(Duration::microseconds((fd - p) as i64)),
                 on: Some(Duration::microseconds(p as i64)),
@@ -292,8 +613,7 @@ impl PwmAnimation {
                 on: Some(Duration::microseconds(fd as i64)),
             }
         } else {
-            let p: f32;
-            p = Quint::ease_in((micros - 900_000u32) as f32, fd - delta, delta, 100_000f32);
+            let p: f32 = Quint::ease_in((micros - 900_000u32) as f32, fd - delta, delta, 100_000f32);
             LingerDurations {
                 off: Some(Duration::microseconds((fd - p) as i64)),
                 on: Some(Duration::microseconds(p as i64)),
@@ -0,0 +1,302 @@
+use std::error::Error;
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+
+use crate::sensors::SensorBus;
+
+//the tubes are dimmed in small steps this often, looking the reading up every frame is wasteful
+const UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// `[display.auto_brightness]`, dimming the tubes by the ambient light. The brightness for a
+/// lux reading is interpolated between the points of the curve, and scales the schedule's.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AutoBrightnessConfig {
+    //the light measurement on the sensor bus
+    pub sensor: String,
+    //[lux, brightness] points in order of lux
+    pub curve: Vec<(f32, f32)>,
+    //how far the curve has to move from the brightness the tubes are heading for to change it
+    pub hysteresis: f32,
+    //the time a change from off to full brightness takes
+    pub ramp_seconds: f32,
+}
+
+impl Default for AutoBrightnessConfig {
+    fn default() -> Self {
+        AutoBrightnessConfig {
+            sensor: "lux".to_string(),
+            curve: vec![(0f32, 0.1), (10f32, 0.3), (100f32, 0.7), (500f32, 1f32)],
+            hysteresis: 0.05,
+            ramp_seconds: 10f32,
+        }
+    }
+}
+
+impl AutoBrightnessConfig {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.curve.is_empty() {
+            return Err("The auto brightness curve needs at least one point".into());
+        }
+        if let Some((lux, _)) = self.curve.iter().find(|(lux, _)| !lux.is_finite()) {
+            return Err(format!("Auto brightness curve lux {} isn't a number", lux).into());
+        }
+        if self.curve.windows(2).any(|w| w[1].0 <= w[0].0) {
+            return Err("The auto brightness curve's lux must go up from point to point".into());
+        }
+        if let Some((_, b)) = self.curve.iter().find(|(_, b)| !(0f32..=1f32).contains(b)) {
+            return Err(format!("Auto brightness {} is outside 0.0-1.0", b).into());
+        }
+        if !(0f32..1f32).contains(&self.hysteresis) {
+            return Err(format!("Auto brightness hysteresis {} is outside 0.0-1.0", self.hysteresis).into());
+        }
+        //a NaN ramp would make the step NaN and panic in clamp
+        if !self.ramp_seconds.is_finite() || self.ramp_seconds < 0f32 {
+            return Err(format!("Auto brightness ramp_seconds {} must be 0 or more", self.ramp_seconds).into());
+        }
+        Ok(())
+    }
+
+    pub fn brightness_for(&self, lux: f32) -> f32 {
+        let (first, last) = (self.curve[0], self.curve[self.curve.len() - 1]);
+        if lux <= first.0 {
+            return first.1;
+        }
+        if lux >= last.0 {
+            return last.1;
+        }
+        self.curve
+            .windows(2)
+            .find(|w| lux <= w[1].0)
+            .map(|w| {
+                let ((lux0, b0), (lux1, b1)) = (w[0], w[1]);
+                b0 + (b1 - b0) * (lux - lux0) / (lux1 - lux0)
+            })
+            .unwrap_or(last.1)
+    }
+}
+
+/// Follows the light sensor with the brightness, only heading for a new one once the curve
+/// has moved past the hysteresis, and ramping there slowly so the tubes don't pulse.
+#[derive(Debug, Clone, Default)]
+pub struct AutoDimmer {
+    //what the tubes are dimmed by now, None until there is a reading
+    level: Option<f32>,
+    target: f32,
+    last_update: Option<Instant>,
+}
+
+impl AutoDimmer {
+    /// The level to scale the schedule's brightness by, 1.0 without a config or a reading.
+    /// A reading that goes stale leaves the tubes where they are.
+    pub fn update(&mut self, config: Option<&AutoBrightnessConfig>, sensors: &SensorBus, now: Instant) -> f32 {
+        let config = match config {
+            Some(c) => c,
+            None => {
+                self.level = None;
+                return 1f32;
+            }
+        };
+        let elapsed = match self.last_update {
+            Some(last) if now.duration_since(last) < UPDATE_INTERVAL => return self.level.unwrap_or(1f32),
+            Some(last) => now.duration_since(last),
+            None => Duration::from_secs(0),
+        };
+        self.last_update = Some(now);
+        if let Some(reading) = sensors.latest(&config.sensor) {
+            let wanted = config.brightness_for(reading.value);
+            if self.level.is_none() {
+                //nothing to ramp from at startup
+                self.level = Some(wanted);
+                self.target = wanted;
+                println!("Ambient light {:.1}lx, brightness {:.2}", reading.value, wanted);
+            } else if (wanted - self.target).abs() > config.hysteresis {
+                println!(
+                    "Ambient light {:.1}lx, brightness going from {:.2} to {:.2}",
+                    reading.value, self.target, wanted
+                );
+                self.target = wanted;
+            }
+        }
+        let step = if config.ramp_seconds > 0f32 {
+            elapsed.as_secs_f32() / config.ramp_seconds
+        } else {
+            1f32
+        };
+        let target = self.target;
+        let level = self.level.as_mut().map(|level| {
+            *level += (target - *level).clamp(-step, step);
+            *level
+        });
+        level.unwrap_or(1f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use tokio::sync::watch;
+
+    use crate::sensors::{Reading, SensorState, Unit};
+
+    fn lux_bus() -> (SensorBus, watch::Sender<SensorState>) {
+        let bus = SensorBus::default();
+        let sender = bus.register("lux", Unit::Lux, chrono::Duration::minutes(2));
+        (bus, sender)
+    }
+
+    fn set_lux(sender: &watch::Sender<SensorState>, lux: f32, age: chrono::Duration) {
+        sender.send_modify(|s| {
+            s.reading = Some(Reading {
+                value: lux,
+                raw: lux,
+                unit: Unit::Lux,
+                taken_at: Local::now() - age,
+            })
+        });
+    }
+
+    fn assert_near(level: f32, expected: f32) {
+        assert!((level - expected).abs() < 1e-6, "{} isn't {}", level, expected);
+    }
+
+    #[test]
+    fn interpolates_the_curve() {
+        let config = AutoBrightnessConfig::default();
+        assert_near(config.brightness_for(-5f32), 0.1);
+        assert_near(config.brightness_for(0f32), 0.1);
+        assert_near(config.brightness_for(5f32), 0.2);
+        assert_near(config.brightness_for(55f32), 0.5);
+        assert_near(config.brightness_for(100f32), 0.7);
+        assert_near(config.brightness_for(20000f32), 1f32);
+    }
+
+    #[test]
+    fn validates_the_curve() {
+        assert!(AutoBrightnessConfig::default().validate().is_ok());
+        let bad = vec![
+            AutoBrightnessConfig {
+                curve: vec![],
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                curve: vec![(10f32, 0.2), (10f32, 0.5)],
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                curve: vec![(0f32, 1.5)],
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                hysteresis: 1f32,
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                ramp_seconds: -1f32,
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                ramp_seconds: f32::NAN,
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                ramp_seconds: f32::INFINITY,
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                hysteresis: f32::NAN,
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                curve: vec![(f32::NAN, 0.5)],
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                curve: vec![(0f32, 0.2), (f32::NAN, 0.5), (10f32, 0.8)],
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                curve: vec![(0f32, 0.2), (f32::INFINITY, 0.5)],
+                ..Default::default()
+            },
+            AutoBrightnessConfig {
+                curve: vec![(0f32, f32::NAN)],
+                ..Default::default()
+            },
+        ];
+        for config in bad {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn rejects_a_nan_ramp_from_toml() {
+        let config: AutoBrightnessConfig = toml::from_str("ramp_seconds = nan").unwrap();
+        assert!(config.ramp_seconds.is_nan());
+        assert!(config.validate().is_err());
+        let config: AutoBrightnessConfig = toml::from_str("ramp_seconds = 0").unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn full_brightness_without_a_config_or_reading() {
+        let (bus, _sender) = lux_bus();
+        let config = AutoBrightnessConfig::default();
+        let mut dimmer = AutoDimmer::default();
+        let now = Instant::now();
+        assert_near(dimmer.update(None, &bus, now), 1f32);
+        assert_near(dimmer.update(Some(&config), &bus, now), 1f32);
+    }
+
+    #[test]
+    fn ignores_changes_within_the_hysteresis() {
+        let (bus, sender) = lux_bus();
+        let config = AutoBrightnessConfig::default();
+        let mut dimmer = AutoDimmer::default();
+        let start = Instant::now();
+        set_lux(&sender, 100f32, chrono::Duration::zero());
+        //straight to the curve at startup
+        assert_near(dimmer.update(Some(&config), &bus, start), 0.7);
+        //0.72 on the curve
+        set_lux(&sender, 130f32, chrono::Duration::zero());
+        assert_near(dimmer.update(Some(&config), &bus, start + Duration::from_secs(5)), 0.7);
+        //0.64 is past it
+        set_lux(&sender, 86.5, chrono::Duration::zero());
+        let level = dimmer.update(Some(&config), &bus, start + Duration::from_secs(15));
+        assert_near(level, 0.64);
+    }
+
+    #[test]
+    fn ramps_to_a_new_brightness() {
+        let (bus, sender) = lux_bus();
+        let config = AutoBrightnessConfig::default();
+        let mut dimmer = AutoDimmer::default();
+        let start = Instant::now();
+        set_lux(&sender, 100f32, chrono::Duration::zero());
+        dimmer.update(Some(&config), &bus, start);
+        set_lux(&sender, 500f32, chrono::Duration::zero());
+        //a tenth of the way from off to full each second
+        let level = dimmer.update(Some(&config), &bus, start + Duration::from_secs(1));
+        assert_near(level, 0.8);
+        //too soon to move again
+        let same = dimmer.update(Some(&config), &bus, start + Duration::from_millis(1020));
+        assert_eq!(same, level);
+        let level = dimmer.update(Some(&config), &bus, start + Duration::from_secs(2));
+        assert_near(level, 0.9);
+        assert_near(dimmer.update(Some(&config), &bus, start + Duration::from_secs(10)), 1f32);
+    }
+
+    #[test]
+    fn stays_put_when_the_reading_goes_stale() {
+        let (bus, sender) = lux_bus();
+        let config = AutoBrightnessConfig::default();
+        let mut dimmer = AutoDimmer::default();
+        let start = Instant::now();
+        set_lux(&sender, 10f32, chrono::Duration::zero());
+        assert_near(dimmer.update(Some(&config), &bus, start), 0.3);
+        set_lux(&sender, 500f32, chrono::Duration::minutes(5));
+        let level = dimmer.update(Some(&config), &bus, start + Duration::from_secs(10));
+        assert_near(level, 0.3);
+    }
+}
@@ -0,0 +1,379 @@
+use std::error::Error;
+use std::fmt::Debug;
+use std::thread;
+use std::time::{Duration, Instant};
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use rppal::i2c::I2c;
+use serde::Deserialize;
+
+use crate::errors::{Bme280Error, Bme280Result, SensorError, SensorResult};
+#[cfg(test)]
+use crate::mock_i2c::MockI2c;
+use crate::sensors::{Sensor, Unit};
+
+const CHIP_ID: u8 = 0xd0;
+const BME280_ID: u8 = 0x60;
+//the BMP280's samples had other IDs
+const BMP280_IDS: [u8; 3] = [0x56, 0x57, 0x58];
+const CALIBRATION: u8 = 0x88;
+const HUMIDITY_CALIBRATION: u8 = 0xe1;
+const CTRL_HUM: u8 = 0xf2;
+const STATUS: u8 = 0xf3;
+const CTRL_MEAS: u8 = 0xf4;
+const DATA: u8 = 0xf7;
+const MEASURING: u8 = 0x08;
+//one sample of each, then back to sleep
+const OVERSAMPLING_X1: u8 = 0b001;
+const FORCED_MODE: u8 = 0b01;
+//a forced measurement at 1x oversampling takes under 10ms
+const MEASUREMENT_TIMEOUT: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PressureUnit {
+    //with the IN-19A's P
+    #[default]
+    Hpa,
+    //shown in kPa with the κ prefix
+    Pa,
+}
+
+/// The `[bme280]` section of the config, which also covers the BMP280 without humidity
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Bme280Config {
+    pub bus: u8,
+    //0x76, or 0x77 with SDO pulled high
+    pub address: u8,
+    //bus names of the measurements
+    pub temperature: String,
+    pub humidity: String,
+    pub pressure: String,
+    pub pressure_unit: PressureUnit,
+    pub interval_seconds: u32,
+}
+
+impl Default for Bme280Config {
+    fn default() -> Self {
+        Bme280Config {
+            bus: 1,
+            address: 0x76,
+            temperature: "bme280_temperature".to_string(),
+            humidity: "humidity".to_string(),
+            pressure: "pressure".to_string(),
+            pressure_unit: PressureUnit::Hpa,
+            interval_seconds: 10,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Bme280Chip {
+    Bme280,
+    //no humidity
+    Bmp280,
+}
+
+/// The trimming values each chip is programmed with at the factory, see section 4.2.2 of
+/// the BME280 datasheet
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p: [f64; 9],
+    h1: f64,
+    h2: f64,
+    h3: f64,
+    h4: f64,
+    h5: f64,
+    h6: f64,
+}
+
+impl Calibration {
+    fn parse(regs: &[u8; 26], humidity: Option<&[u8; 7]>) -> Calibration {
+        let unsigned = |i: usize| u16::from_le_bytes([regs[i], regs[i + 1]]) as f64;
+        let signed = |i: usize| i16::from_le_bytes([regs[i], regs[i + 1]]) as f64;
+        let mut p = [unsigned(6); 9];
+        for (n, value) in p.iter_mut().enumerate().skip(1) {
+            *value = signed(6 + 2 * n);
+        }
+        let mut calibration = Calibration {
+            t1: unsigned(0),
+            t2: signed(2),
+            t3: signed(4),
+            p,
+            ..Default::default()
+        };
+        if let Some(h) = humidity {
+            calibration.h1 = regs[25] as f64;
+            calibration.h2 = i16::from_le_bytes([h[0], h[1]]) as f64;
+            calibration.h3 = h[2] as f64;
+            //12 bit values sharing the middle byte
+            calibration.h4 = (((h[3] as i8 as i16) << 4) | (h[4] & 0x0f) as i16) as f64;
+            calibration.h5 = (((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16) as f64;
+            calibration.h6 = h[6] as i8 as f64;
+        }
+        calibration
+    }
+
+    //°C and the fine temperature the other two are compensated with
+    fn temperature(&self, adc: f64) -> (f64, f64) {
+        let var1 = (adc / 16384.0 - self.t1 / 1024.0) * self.t2;
+        let var2 = (adc / 131072.0 - self.t1 / 8192.0).powi(2) * self.t3;
+        let t_fine = var1 + var2;
+        (t_fine / 5120.0, t_fine)
+    }
+
+    //Pa
+    fn pressure(&self, adc: f64, t_fine: f64) -> Option<f64> {
+        let p = &self.p;
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * p[5] / 32768.0;
+        var2 += var1 * p[4] * 2.0;
+        var2 = var2 / 4.0 + p[3] * 65536.0;
+        var1 = (p[2] * var1 * var1 / 524288.0 + p[1] * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * p[0];
+        if var1 == 0.0 {
+            return None;
+        }
+        let mut pressure = 1048576.0 - adc;
+        pressure = (pressure - var2 / 4096.0) * 6250.0 / var1;
+        var1 = p[8] * pressure * pressure / 2147483648.0;
+        var2 = pressure * p[7] / 32768.0;
+        Some(pressure + (var1 + var2 + p[6]) / 16.0)
+    }
+
+    //%RH
+    fn humidity(&self, adc: f64, t_fine: f64) -> f64 {
+        let mut h = t_fine - 76800.0;
+        h = (adc - (self.h4 * 64.0 + self.h5 / 16384.0 * h))
+            * (self.h2 / 65536.0 * (1.0 + self.h6 / 67108864.0 * h * (1.0 + self.h3 / 67108864.0 * h)));
+        h *= 1.0 - self.h1 * h / 524288.0;
+        h.clamp(0.0, 100.0)
+    }
+}
+
+/// A Bosch BME280 (temperature, humidity and pressure) or BMP280 (no humidity) on I2C,
+/// woken for one forced measurement each read
+pub struct Bme280<I> {
+    i2c: I,
+    config: Bme280Config,
+    chip: Bme280Chip,
+    calibration: Calibration,
+}
+
+impl Bme280<I2c> {
+    pub fn open(config: &Bme280Config) -> Result<Bme280<I2c>, Box<dyn Error>> {
+        let i2c = I2c::with_bus(config.bus)?;
+        Ok(Bme280::new(i2c, config.clone())?)
+    }
+}
+
+impl<I, E> Bme280<I>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    /// Checks the chip ID and reads its calibration
+    pub fn new(mut i2c: I, config: Bme280Config) -> Bme280Result<Bme280<I>> {
+        let mut id = [0u8];
+        read_registers(&mut i2c, config.address, CHIP_ID, &mut id)?;
+        let chip = match id[0] {
+            BME280_ID => Bme280Chip::Bme280,
+            id if BMP280_IDS.contains(&id) => Bme280Chip::Bmp280,
+            id => return Err(Bme280Error::UnknownChip(id)),
+        };
+        let mut regs = [0u8; 26];
+        read_registers(&mut i2c, config.address, CALIBRATION, &mut regs)?;
+        let mut humidity = [0u8; 7];
+        if chip == Bme280Chip::Bme280 {
+            read_registers(&mut i2c, config.address, HUMIDITY_CALIBRATION, &mut humidity)?;
+        }
+        let calibration = Calibration::parse(&regs, Some(&humidity).filter(|_| chip == Bme280Chip::Bme280));
+        println!("Found a {:?} at {:#04x} on I2C bus {}", chip, config.address, config.bus);
+        Ok(Bme280 {
+            i2c,
+            config,
+            chip,
+            calibration,
+        })
+    }
+
+    pub fn chip(&self) -> Bme280Chip {
+        self.chip
+    }
+
+    /// °C, %RH (None on a BMP280) and Pa
+    pub fn measure(&mut self) -> Bme280Result<(f64, Option<f64>, Option<f64>)> {
+        let address = self.config.address;
+        //humidity oversampling only takes effect once ctrl_meas is written
+        self.write_register(CTRL_HUM, OVERSAMPLING_X1)?;
+        self.write_register(CTRL_MEAS, OVERSAMPLING_X1 << 5 | OVERSAMPLING_X1 << 2 | FORCED_MODE)?;
+        let started = Instant::now();
+        loop {
+            let mut status = [0u8];
+            read_registers(&mut self.i2c, address, STATUS, &mut status)?;
+            if status[0] & MEASURING == 0 {
+                break;
+            }
+            if started.elapsed() > MEASUREMENT_TIMEOUT {
+                return Err(Bme280Error::Timeout);
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+        let mut data = [0u8; 8];
+        read_registers(&mut self.i2c, address, DATA, &mut data)?;
+        let adc20 = |i: usize| ((data[i] as u32) << 12 | (data[i + 1] as u32) << 4 | (data[i + 2] as u32) >> 4) as f64;
+        let (celsius, t_fine) = self.calibration.temperature(adc20(3));
+        let pressure = self.calibration.pressure(adc20(0), t_fine);
+        let humidity = match self.chip {
+            Bme280Chip::Bme280 => Some(self.calibration.humidity(u16::from_be_bytes([data[6], data[7]]) as f64, t_fine)),
+            Bme280Chip::Bmp280 => None,
+        };
+        Ok((celsius, humidity, pressure))
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Bme280Result<()> {
+        self.i2c
+            .write(self.config.address, &[register, value])
+            .map_err(|e| Bme280Error::Bus(format!("{:?}", e)))
+    }
+}
+
+fn read_registers<I, E>(i2c: &mut I, address: u8, start: u8, buffer: &mut [u8]) -> Bme280Result<()>
+where
+    I: WriteRead<Error = E>,
+    E: Debug,
+{
+    i2c.write_read(address, &[start], buffer)
+        .map_err(|e| Bme280Error::Bus(format!("{:?}", e)))
+}
+
+impl<I, E> Sensor for Bme280<I>
+where
+    I: Write<Error = E> + WriteRead<Error = E> + Send,
+    E: Debug,
+{
+    fn name(&self) -> String {
+        format!("{:?}", self.chip)
+    }
+
+    fn measurements(&self) -> Vec<(String, Unit)> {
+        let pressure_unit = match self.config.pressure_unit {
+            PressureUnit::Hpa => Unit::Hectopascal,
+            PressureUnit::Pa => Unit::Pascal,
+        };
+        let mut measurements = vec![(self.config.temperature.clone(), Unit::Celsius)];
+        if self.chip == Bme280Chip::Bme280 {
+            measurements.push((self.config.humidity.clone(), Unit::Percent));
+        }
+        measurements.push((self.config.pressure.clone(), pressure_unit));
+        measurements
+    }
+
+    fn read(&mut self) -> Result<Vec<SensorResult<f32>>, Box<dyn Error>> {
+        let (celsius, humidity, pressure) = self.measure()?;
+        let mut results = vec![Ok(celsius as f32)];
+        if let Some(humidity) = humidity {
+            results.push(Ok(humidity as f32));
+        }
+        let scale = match self.config.pressure_unit {
+            PressureUnit::Hpa => 100.0,
+            PressureUnit::Pa => 1.0,
+        };
+        results.push(pressure.map(|p| (p / scale) as f32).ok_or_else(|| SensorError::Failed("No pressure calibration".to_string())));
+        Ok(results)
+    }
+
+    fn interval(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.config.interval_seconds as i64)
+    }
+}
+
+/// Adds a BME280 (or BMP280) to a mock bus, with the datasheet's example calibration and a
+/// reading of 25.08°C and 1006.53hPa, plus humidity from a real chip's calibration
+#[cfg(test)]
+pub fn add_mock_bme280(bus: &MockI2c, address: u8, chip: Bme280Chip) {
+    bus.add_device(address);
+    let id = match chip {
+        Bme280Chip::Bme280 => BME280_ID,
+        Bme280Chip::Bmp280 => BMP280_IDS[2],
+    };
+    bus.set_registers(address, CHIP_ID, &[id]);
+    let words: [u16; 12] = [
+        27504, 26435, -1000i16 as u16, 36477, -10685i16 as u16, 3024, 2855, 140, -7i16 as u16, 15500,
+        -14600i16 as u16, 6000,
+    ];
+    let regs: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    bus.set_registers(address, CALIBRATION, &regs);
+    //dig_H1 at 0xa1, then dig_H2 to dig_H6 from 0xe1
+    bus.set_registers(address, 0xa1, &[75]);
+    bus.set_registers(address, HUMIDITY_CALIBRATION, &[0x6a, 0x01, 0x00, 0x13, 0x26, 0x03, 0x1e]);
+    //adc_P 415148, adc_T 519888, adc_H 30000
+    let (p, t, h): (u32, u32, u16) = (415148, 519888, 30000);
+    let mut data = vec![(p >> 12) as u8, (p >> 4) as u8, (p << 4) as u8, (t >> 12) as u8, (t >> 4) as u8, (t << 4) as u8];
+    data.extend_from_slice(&h.to_be_bytes());
+    bus.set_registers(address, DATA, &data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_mock(chip: Bme280Chip) -> Bme280<MockI2c> {
+        let bus = MockI2c::new();
+        add_mock_bme280(&bus, 0x76, chip);
+        Bme280::new(bus, Bme280Config::default()).unwrap()
+    }
+
+    #[test]
+    fn compensates_the_datasheet_example() {
+        let mut bme280 = open_mock(Bme280Chip::Bme280);
+        assert_eq!(bme280.chip(), Bme280Chip::Bme280);
+        let (celsius, humidity, pressure) = bme280.measure().unwrap();
+        assert!((celsius - 25.08).abs() < 0.01, "{}", celsius);
+        assert!((pressure.unwrap() / 100.0 - 1006.53).abs() < 0.01, "{:?}", pressure);
+        assert!((humidity.unwrap() - 56.07).abs() < 0.01, "{:?}", humidity);
+    }
+
+    #[test]
+    fn reads_pressure_in_the_configured_unit() {
+        let mut bme280 = open_mock(Bme280Chip::Bme280);
+        let units: Vec<Unit> = bme280.measurements().into_iter().map(|(_, unit)| unit).collect();
+        assert_eq!(units, vec![Unit::Celsius, Unit::Percent, Unit::Hectopascal]);
+        let results = bme280.read().unwrap();
+        assert!((results[2].clone().unwrap() - 1006.53).abs() < 0.01);
+
+        bme280.config.pressure_unit = PressureUnit::Pa;
+        let results = bme280.read().unwrap();
+        assert!((results[2].clone().unwrap() - 100653.27).abs() < 1.0);
+    }
+
+    #[test]
+    fn bmp280_has_no_humidity() {
+        let mut bmp280 = open_mock(Bme280Chip::Bmp280);
+        assert_eq!(bmp280.chip(), Bme280Chip::Bmp280);
+        let (celsius, humidity, _) = bmp280.measure().unwrap();
+        assert!((celsius - 25.08).abs() < 0.01);
+        assert_eq!(humidity, None);
+        assert_eq!(bmp280.measurements().len(), 2);
+        assert_eq!(bmp280.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_other_chips() {
+        let bus = MockI2c::new();
+        add_mock_bme280(&bus, 0x76, Bme280Chip::Bme280);
+        bus.set_registers(0x76, CHIP_ID, &[0x55]);
+        assert_eq!(
+            Bme280::new(bus.clone(), Bme280Config::default()).err(),
+            Some(Bme280Error::UnknownChip(0x55))
+        );
+        let config = Bme280Config {
+            address: 0x77,
+            ..Default::default()
+        };
+        assert!(matches!(Bme280::new(bus, config), Err(Bme280Error::Bus(_))));
+    }
+}
@@ -10,14 +10,24 @@ use rppal::system::DeviceInfo;
 use std::error::Error;
 use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Instant;
 use typenum::{U64, U96};
 
 use crate::animation_utils::*;
-use crate::animation_utils::Overlay::TempOverlay;
+use crate::auto_brightness::AutoDimmer;
+use crate::led_warning::LedWarnings;
 use crate::clock_objects::{
     ClockType, DisplayMessage, LingerDurations, NCS3148CMessage, NCS3186Message,
 };
-use crate::temperature_sensor::TemperatureSensor;
+use crate::config::DisplaySettings;
+use crate::control::{ClockCommand, ClockState, CommandReceiver};
+use crate::display_stream::{DisplayFrame, DisplaySampler};
+use crate::metrics::{ClockMetrics, FrameMetrics};
+use crate::rgb_driver::{LedColor, LedDisplay};
+use crate::scheduler::{DisplayMode, ScheduledState};
+use crate::sensors::SensorBus;
+use crate::time_keeper::{TimeJump, TimeKeeper};
+use crate::time_sync::{SyncStatus, TimeConfirmation};
 
 //The latch enable pin GPIO number. Should be low during writes. Also tied to strobe on chips.
 const LE_PIN: u8 = 22;
@@ -32,6 +42,9 @@ pub trait ClockDriver {
         on_linger: Option<Duration>,
     ) -> Result<(), Box<dyn Error>>;
     fn setup_overlays_for_minute(&mut self) -> ();
+    fn reschedule_overlays(&mut self, jump: TimeJump);
+    fn apply_schedule(&mut self, local: DateTime<Local>);
+    fn handle_command(&mut self, command: ClockCommand);
 }
 
 
@@ -42,31 +55,77 @@ pub struct NCS3148CDriver {
     frame_interval_us: i64,
     raw_message: BitArray<u8, U96>,
     last_frame_time: DateTime<Local>,
-    temperature_lock: Arc<RwLock<Option<f32>>>,
+    sensors: SensorBus,
     overlays: Vec<Overlay>,
+    time_keeper: TimeKeeper,
+    settings: DisplaySettings,
+    scheduled: ScheduledState,
+    leds: LedDisplay,
+    led_color: LedColor,
+    sync_lock: Arc<RwLock<SyncStatus>>,
+    time_confirmed: TimeConfirmation,
+    commands: CommandReceiver,
+    state_lock: Arc<RwLock<ClockState>>,
+    //set through the control APIs, winning over the schedule until cleared
+    mode_override: Option<DisplayMode>,
+    brightness_override: Option<f32>,
+    auto_dimmer: AutoDimmer,
+    led_warnings: LedWarnings,
+    metrics: FrameMetrics,
+    metrics_lock: Arc<RwLock<ClockMetrics>>,
+    sampler: DisplaySampler,
+    display_lock: Arc<RwLock<DisplayFrame>>,
 }
 impl NCS3148CDriver {
     const CLOCK_TYPE: ClockType = ClockType::NCS3148C;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        temperature_lk: Arc<RwLock<Option<f32>>>,
+        sensors: SensorBus,
         frame_interval_us: i64,
+        time_keeper: TimeKeeper,
+        settings: DisplaySettings,
+        sync_lock: Arc<RwLock<SyncStatus>>,
+        commands: CommandReceiver,
+        state_lock: Arc<RwLock<ClockState>>,
+        metrics_lock: Arc<RwLock<ClockMetrics>>,
+        display_lock: Arc<RwLock<DisplayFrame>>,
     ) -> Result<NCS3148CDriver, Box<dyn Error>> {
         println!(
             "Running a {:?} clock from a {}.",
             NCS3148CDriver::CLOCK_TYPE,
             DeviceInfo::new()?.model()
         );
+        let time_confirmed = TimeConfirmation::new(&sync_lock.read().unwrap());
         let mut cd = NCS3148CDriver {
             le_pin: Gpio::new()?.get(LE_PIN)?.into_output(),
             spi: Spi::new(Bus::Spi0, SlaveSelect::Ss0, 8_000_000, Mode::Mode2)?,
-            frame_interval_us: frame_interval_us,
+            frame_interval_us,
             raw_message: BitArray::<u8, U96>::from_elem(false),
             last_frame_time: Local::now(),
-            temperature_lock: temperature_lk,
+            sensors,
             overlays: vec![],
+            time_keeper,
+            settings,
+            scheduled: ScheduledState::default(),
+            leds: LedDisplay::new()?,
+            led_color: LedColor::Off,
+            time_confirmed,
+            sync_lock,
+            commands,
+            state_lock,
+            mode_override: None,
+            brightness_override: None,
+            auto_dimmer: AutoDimmer::default(),
+            led_warnings: LedWarnings::default(),
+            metrics: FrameMetrics::default(),
+            metrics_lock,
+            sampler: DisplaySampler::default(),
+            display_lock,
         };
         cd.le_pin.set_high();
+        cd.leds.set_color(LedColor::Off);
+        cd.apply_schedule(cd.last_frame_time);
 
         Ok(cd)
     }
@@ -82,33 +141,89 @@ impl ClockDriver for NCS3148CDriver {
         let seconds_pulse = PwmAnimation {
             frame_interval_us: self.frame_interval_us,
         };
-        let local: DateTime<Local> = Local::now();
-        let micros = local.timestamp_subsec_micros();
+        while let Ok(command) = self.commands.try_recv() {
+            self.handle_command(command);
+        }
+        let reading = self.time_keeper.now();
+        if let Some(jump) = reading.jump {
+            self.reschedule_overlays(jump);
+        }
+        let local: DateTime<Local> = reading.actual;
+        let (synced, pps_correction) = {
+            let sync_status = self.sync_lock.read().unwrap();
+            (sync_status.is_synced(), sync_status.pps_correction())
+        };
+        let confirmed_by = self.time_confirmed.update(&self.sync_lock.read().unwrap());
+        if let Some(source) = confirmed_by {
+            println!("Time confirmed by {}", source);
+            self.apply_schedule(local);
+        }
+        //differs from local while rolling over to a corrected time, and by the PPS phase
+        let displayed: DateTime<Local> = reading.displayed + pps_correction;
+        let micros = displayed.timestamp_subsec_micros();
         // let secs = local.second();
         let minute = local.minute();
-        let mut msg_string: String;
-        let frame_lingers: LingerDurations;
-        if self.last_frame_time.minute() < minute {
+        if self.last_frame_time.minute() != minute {
             self.setup_overlays_for_minute();
         }
 
-        msg_string = DisplayMessageStringUtils::for_local(local, ClockType::NCS3148C);
-        if !TimeSeparators::time_separators_animation(micros) {
-            msg_string = msg_string.replace(":", " ");
-            msg_string = msg_string.replace(".", " ");
-            // msg_string = "            ".to_string();
+        //separators only stop blinking once the time is known to be right
+        let separators_on = synced || TimeSeparators::time_separators_animation(micros);
+        let msg_string = self.scheduled.mode.render(
+            &displayed,
+            separators_on,
+            self.settings.time_template_for(&self.scheduled),
+            ClockType::NCS3148C,
+        );
+        //a temperature scene's colour tells which sensor it shows, then warnings win over the theme
+        let warning_led = self
+            .led_warnings
+            .update(&self.settings.led_warnings, &self.sensors, Instant::now());
+        let led_color = self
+            .overlays
+            .iter()
+            .find_map(|o| match o {
+                Overlay::TempOverlay(t) => t.led_at(local),
+                _ => None,
+            })
+            .or(warning_led)
+            .unwrap_or_else(|| self.settings.led_for(&self.scheduled, &displayed));
+        if led_color != self.led_color {
+            self.leds.set_color(led_color);
+            self.led_color = led_color;
         }
-        frame_lingers = seconds_pulse.pwm_seconds_animation(local.timestamp_subsec_micros());
+        let frame_lingers: LingerDurations = seconds_pulse.pwm_seconds_animation(micros);
         let mut cur_message = NCS3148CMessage::from_string(msg_string, frame_lingers);
 
-        for cur_overlay in &mut self.overlays {
-            match cur_overlay {
-                Overlay::TempOverlay(t) => t.apply_to_message(local, &mut cur_message),
-                Overlay::AntiPoison(o) => o.apply_to_message(local, &mut cur_message),
+        //overlays would hide the digits rolling to a corrected time
+        if !reading.slewing {
+            for cur_overlay in &mut self.overlays {
+                match cur_overlay {
+                    Overlay::TempOverlay(t) => t.apply_to_message(local, &mut cur_message),
+                    Overlay::AntiPoison(o) => o.apply_to_message(local, &mut cur_message),
+                    Overlay::Scene(s) => s.apply_to_message(local, &mut cur_message),
+                    Overlay::SyncOffset(s) => s.apply_to_message(local, &mut cur_message),
+                }
             }
         }
+        //the light sensor scales the schedule's brightness, an override is taken as it is
+        let ambient = self
+            .auto_dimmer
+            .update(self.settings.auto_brightness.as_ref(), &self.sensors, Instant::now());
+        let brightness = match self.brightness_override {
+            Some(_) => self.scheduled.brightness,
+            None => self.scheduled.brightness * ambient,
+        };
+        self.metrics.brightness = brightness;
+        cur_message
+            .dim(brightness)
+            .expect("Attempt to set bad message linger");
 
         let res = self.show(cur_message);
+        self.metrics.publish(&self.metrics_lock, &self.overlays);
+        if self.sampler.is_due() {
+            self.sampler.publish(NCS3148CDriver::CLOCK_TYPE, &self.display_lock);
+        }
 
         self.last_frame_time = local;
         res
@@ -122,35 +237,139 @@ impl ClockDriver for NCS3148CDriver {
             println!("Latch already set low by another process, aborting write!")
         } else {
             self.le_pin.set_low();
-            self.spi.write(&*self.raw_message.to_bytes())?;
-            off_linger.map(|off| thread::sleep(off.to_std().unwrap()));
+            //a dropped frame is better than a stopped clock, the failures show in the metrics
+            if let Err(e) = self.spi.write(&self.raw_message.to_bytes()) {
+                self.metrics.spi_write_errors += 1;
+                if self.metrics.spi_write_errors % 1000 == 1 {
+                    println!("SPI write failed ({} so far): {}", self.metrics.spi_write_errors, e);
+                }
+                self.metrics.record_frame(&self.raw_message, None);
+            } else {
+                self.metrics.record_frame(&self.raw_message, on_linger);
+                self.sampler.record_frame(&self.raw_message, off_linger, on_linger);
+            }
+            if let Some(off) = off_linger {
+                thread::sleep(off.to_std().unwrap())
+            }
             self.le_pin.set_high();
-            on_linger.map(|on| thread::sleep(on.to_std().unwrap()));
+            if let Some(on) = on_linger {
+                thread::sleep(on.to_std().unwrap())
+            }
         }
         Ok(())
     }
-    fn setup_overlays_for_minute(&mut self) -> () {
-        //clear out expired overlays
+    fn setup_overlays_for_minute(&mut self) {
         let local: DateTime<Local> = Local::now();
+        self.apply_schedule(local);
+
+        //clear out expired overlays
         self.overlays.retain(|cur_overlay| match cur_overlay {
             Overlay::AntiPoison(ap) => !ap.has_ended(local),
             Overlay::TempOverlay(t) => !t.has_ended(local),
+            Overlay::Scene(s) => !s.has_ended(local),
+            Overlay::SyncOffset(s) => !s.has_ended(local),
         });
 
+        //only the clock mode gets anti-poisons and scenes, other modes are shown as they are
+        if !self.scheduled.mode.shows_overlays() {
+            return;
+        }
+
         //adds a number of random anti-poison overlays to individual numeric tubes
         self.overlays.append(&mut AntiPoisonAnimation::matrix_style_set());
-        let temperature_overlay = TempOverlay(
-            TempOverlayAnimation::new(
-                self.temperature_lock.clone(),
-                ClockType::NCS3148C,
-            )
-        );
-        self.overlays.push(temperature_overlay);
+        //scenes are applied after the anti-poisons so they aren't interrupted
+        self.overlays.append(&mut self.settings.playlist.overlays_for_minute(
+            local,
+            &self.sensors,
+            ClockType::NCS3148C,
+        ));
 
         for o in &self.overlays {
             println!("{:?}", o)
         }
     }
+    fn reschedule_overlays(&mut self, jump: TimeJump) {
+        let offset = jump.whole_minutes();
+        for cur_overlay in &mut self.overlays {
+            match cur_overlay {
+                Overlay::AntiPoison(ap) => ap.reschedule(offset),
+                Overlay::TempOverlay(t) => t.reschedule(offset),
+                Overlay::Scene(s) => s.reschedule(offset),
+                Overlay::SyncOffset(s) => s.reschedule(offset),
+            }
+        }
+        //overlays that were moved along with the clock are still valid for the new minute
+        self.last_frame_time = self.last_frame_time + offset;
+        println!("Rescheduled {} overlays by {} minutes", self.overlays.len(), offset.num_minutes());
+    }
+    fn apply_schedule(&mut self, local: DateTime<Local>) {
+        let mut state = self.settings.scheduler.state_at(&local);
+        if let Some(brightness) = self.brightness_override {
+            state.brightness = brightness;
+        }
+        if let Some(mode) = &self.mode_override {
+            state.mode = mode.clone();
+        } else if !self.time_confirmed.is_confirmed() {
+            state.mode = DisplayMode::Unsynced(self.settings.unsynced);
+        }
+        *self.state_lock.write().unwrap() = ClockState {
+            mode: state.mode.name().to_string(),
+            brightness: state.brightness,
+            theme: state.theme.clone(),
+            time_confirmed: self.time_confirmed.is_confirmed(),
+            mode_override: self.mode_override.is_some(),
+            brightness_override: self.brightness_override.is_some(),
+        };
+        if state == self.scheduled {
+            return;
+        }
+        println!(
+            "Switching to {} mode, brightness {}, theme {:?}",
+            state.mode.name(),
+            state.brightness,
+            state.theme
+        );
+        self.scheduled = state;
+    }
+    fn handle_command(&mut self, command: ClockCommand) {
+        match command {
+            ClockCommand::ShowSyncOffset => {
+                self.overlays.push(Overlay::SyncOffset(SyncOffsetOverlay::new(
+                    &self.sync_lock,
+                    ClockType::NCS3148C,
+                    Local::now(),
+                    Duration::seconds(3),
+                )));
+            }
+            ClockCommand::SetBrightness(brightness) => {
+                self.brightness_override = brightness;
+                self.apply_schedule(Local::now());
+            }
+            ClockCommand::SetMode(mode) => {
+                self.mode_override = mode;
+                self.apply_schedule(Local::now());
+            }
+            ClockCommand::ShowMessage { template, duration } => {
+                self.overlays.push(Overlay::Scene(SceneOverlay::new(
+                    Local::now(),
+                    duration,
+                    template,
+                    None,
+                    SceneTransition::cut(),
+                )));
+            }
+            ClockCommand::AntiPoison => {
+                self.overlays
+                    .append(&mut AntiPoisonAnimation::full_cycle_set(ClockType::NCS3148C, Local::now()));
+            }
+            ClockCommand::ApplySettings(settings) => {
+                //the playlist is picked up at the start of the next minute
+                println!("Applying the edited config");
+                self.settings = *settings;
+                self.apply_schedule(Local::now());
+            }
+        }
+    }
 }
 
 
@@ -162,31 +381,77 @@ pub struct NCS3186Driver {
     frame_interval_us: i64,
     raw_message: BitArray<u8, U96>,
     last_frame_time: DateTime<Local>,
-    temperature_lock: Arc<RwLock<Option<f32>>>,
+    sensors: SensorBus,
     overlays: Vec<Overlay>,
+    time_keeper: TimeKeeper,
+    settings: DisplaySettings,
+    scheduled: ScheduledState,
+    leds: LedDisplay,
+    led_color: LedColor,
+    sync_lock: Arc<RwLock<SyncStatus>>,
+    time_confirmed: TimeConfirmation,
+    commands: CommandReceiver,
+    state_lock: Arc<RwLock<ClockState>>,
+    //set through the control APIs, winning over the schedule until cleared
+    mode_override: Option<DisplayMode>,
+    brightness_override: Option<f32>,
+    auto_dimmer: AutoDimmer,
+    led_warnings: LedWarnings,
+    metrics: FrameMetrics,
+    metrics_lock: Arc<RwLock<ClockMetrics>>,
+    sampler: DisplaySampler,
+    display_lock: Arc<RwLock<DisplayFrame>>,
 }
 impl NCS3186Driver {
     const CLOCK_TYPE: ClockType = ClockType::NCS3186;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        temperature_lk: Arc<RwLock<Option<f32>>>,
+        sensors: SensorBus,
         frame_interval_us: i64,
+        time_keeper: TimeKeeper,
+        settings: DisplaySettings,
+        sync_lock: Arc<RwLock<SyncStatus>>,
+        commands: CommandReceiver,
+        state_lock: Arc<RwLock<ClockState>>,
+        metrics_lock: Arc<RwLock<ClockMetrics>>,
+        display_lock: Arc<RwLock<DisplayFrame>>,
     ) -> Result<NCS3186Driver, Box<dyn Error>> {
         println!(
             "Running a {:?} clock from a {}.",
             NCS3186Driver::CLOCK_TYPE,
             DeviceInfo::new()?.model()
         );
+        let time_confirmed = TimeConfirmation::new(&sync_lock.read().unwrap());
         let mut cd = NCS3186Driver {
             le_pin: Gpio::new()?.get(LE_PIN)?.into_output(),
             spi: Spi::new(Bus::Spi0, SlaveSelect::Ss0, 8_000_000, Mode::Mode2)?,
-            frame_interval_us: frame_interval_us,
+            frame_interval_us,
             raw_message: BitArray::<u8, U96>::from_elem(false),
             last_frame_time: Local::now(),
-            temperature_lock: temperature_lk,
+            sensors,
             overlays: vec![],
+            time_keeper,
+            settings,
+            scheduled: ScheduledState::default(),
+            leds: LedDisplay::new()?,
+            led_color: LedColor::Off,
+            time_confirmed,
+            sync_lock,
+            commands,
+            state_lock,
+            mode_override: None,
+            brightness_override: None,
+            auto_dimmer: AutoDimmer::default(),
+            led_warnings: LedWarnings::default(),
+            metrics: FrameMetrics::default(),
+            metrics_lock,
+            sampler: DisplaySampler::default(),
+            display_lock,
         };
         cd.le_pin.set_high();
+        cd.leds.set_color(LedColor::Off);
+        cd.apply_schedule(cd.last_frame_time);
 
         Ok(cd)
     }
@@ -202,32 +467,89 @@ impl ClockDriver for NCS3186Driver {
         let seconds_pulse = PwmAnimation {
             frame_interval_us: self.frame_interval_us,
         };
-        let local: DateTime<Local> = Local::now();
-        let micros = local.timestamp_subsec_micros();
+        while let Ok(command) = self.commands.try_recv() {
+            self.handle_command(command);
+        }
+        let reading = self.time_keeper.now();
+        if let Some(jump) = reading.jump {
+            self.reschedule_overlays(jump);
+        }
+        let local: DateTime<Local> = reading.actual;
+        let (synced, pps_correction) = {
+            let sync_status = self.sync_lock.read().unwrap();
+            (sync_status.is_synced(), sync_status.pps_correction())
+        };
+        let confirmed_by = self.time_confirmed.update(&self.sync_lock.read().unwrap());
+        if let Some(source) = confirmed_by {
+            println!("Time confirmed by {}", source);
+            self.apply_schedule(local);
+        }
+        //differs from local while rolling over to a corrected time, and by the PPS phase
+        let displayed: DateTime<Local> = reading.displayed + pps_correction;
+        let micros = displayed.timestamp_subsec_micros();
         // let secs = local.second();
         let minute = local.minute();
-        let mut msg_string: String;
-        let frame_lingers: LingerDurations;
-        if self.last_frame_time.minute() < minute {
+        if self.last_frame_time.minute() != minute {
             self.setup_overlays_for_minute();
         }
 
-        msg_string = DisplayMessageStringUtils::for_local(local, ClockType::NCS3186);
-        if !TimeSeparators::time_separators_animation(micros) {
-            msg_string = msg_string.replace(":", " ");
-            msg_string = msg_string.replace(".", " ");
+        //separators only stop blinking once the time is known to be right
+        let separators_on = synced || TimeSeparators::time_separators_animation(micros);
+        let msg_string = self.scheduled.mode.render(
+            &displayed,
+            separators_on,
+            self.settings.time_template_for(&self.scheduled),
+            ClockType::NCS3186,
+        );
+        //a temperature scene's colour tells which sensor it shows, then warnings win over the theme
+        let warning_led = self
+            .led_warnings
+            .update(&self.settings.led_warnings, &self.sensors, Instant::now());
+        let led_color = self
+            .overlays
+            .iter()
+            .find_map(|o| match o {
+                Overlay::TempOverlay(t) => t.led_at(local),
+                _ => None,
+            })
+            .or(warning_led)
+            .unwrap_or_else(|| self.settings.led_for(&self.scheduled, &displayed));
+        if led_color != self.led_color {
+            self.leds.set_color(led_color);
+            self.led_color = led_color;
         }
-        frame_lingers = seconds_pulse.pwm_seconds_animation(local.timestamp_subsec_micros());
+        let frame_lingers: LingerDurations = seconds_pulse.pwm_seconds_animation(micros);
         let mut cur_message = NCS3186Message::from_string(msg_string, frame_lingers);
 
-        for cur_overlay in &mut self.overlays {
-            match cur_overlay {
-                Overlay::TempOverlay(t) => t.apply_to_message(local, &mut cur_message),
-                Overlay::AntiPoison(o) => o.apply_to_message(local, &mut cur_message),
+        //overlays would hide the digits rolling to a corrected time
+        if !reading.slewing {
+            for cur_overlay in &mut self.overlays {
+                match cur_overlay {
+                    Overlay::TempOverlay(t) => t.apply_to_message(local, &mut cur_message),
+                    Overlay::AntiPoison(o) => o.apply_to_message(local, &mut cur_message),
+                    Overlay::Scene(s) => s.apply_to_message(local, &mut cur_message),
+                    Overlay::SyncOffset(s) => s.apply_to_message(local, &mut cur_message),
+                }
             }
         }
+        //the light sensor scales the schedule's brightness, an override is taken as it is
+        let ambient = self
+            .auto_dimmer
+            .update(self.settings.auto_brightness.as_ref(), &self.sensors, Instant::now());
+        let brightness = match self.brightness_override {
+            Some(_) => self.scheduled.brightness,
+            None => self.scheduled.brightness * ambient,
+        };
+        self.metrics.brightness = brightness;
+        cur_message
+            .dim(brightness)
+            .expect("Attempt to set bad message linger");
 
         let res = self.show(cur_message);
+        self.metrics.publish(&self.metrics_lock, &self.overlays);
+        if self.sampler.is_due() {
+            self.sampler.publish(NCS3186Driver::CLOCK_TYPE, &self.display_lock);
+        }
 
         self.last_frame_time = local;
         res
@@ -241,33 +563,137 @@ impl ClockDriver for NCS3186Driver {
             println!("Latch already set low by another process, aborting write!")
         } else {
             self.le_pin.set_low();
-            self.spi.write(&*self.raw_message.to_bytes())?;
-            off_linger.map(|off| thread::sleep(off.to_std().unwrap()));
+            //a dropped frame is better than a stopped clock, the failures show in the metrics
+            if let Err(e) = self.spi.write(&self.raw_message.to_bytes()) {
+                self.metrics.spi_write_errors += 1;
+                if self.metrics.spi_write_errors % 1000 == 1 {
+                    println!("SPI write failed ({} so far): {}", self.metrics.spi_write_errors, e);
+                }
+                self.metrics.record_frame(&self.raw_message, None);
+            } else {
+                self.metrics.record_frame(&self.raw_message, on_linger);
+                self.sampler.record_frame(&self.raw_message, off_linger, on_linger);
+            }
+            if let Some(off) = off_linger {
+                thread::sleep(off.to_std().unwrap())
+            }
             self.le_pin.set_high();
-            on_linger.map(|on| thread::sleep(on.to_std().unwrap()));
+            if let Some(on) = on_linger {
+                thread::sleep(on.to_std().unwrap())
+            }
         }
         Ok(())
     }
-    fn setup_overlays_for_minute(&mut self) -> () {
-        //clear out expired overlays
+    fn setup_overlays_for_minute(&mut self) {
         let local: DateTime<Local> = Local::now();
+        self.apply_schedule(local);
+
+        //clear out expired overlays
         self.overlays.retain(|cur_overlay| match cur_overlay {
             Overlay::AntiPoison(ap) => !ap.has_ended(local),
             Overlay::TempOverlay(t) => !t.has_ended(local),
+            Overlay::Scene(s) => !s.has_ended(local),
+            Overlay::SyncOffset(s) => !s.has_ended(local),
         });
 
+        //only the clock mode gets anti-poisons and scenes, other modes are shown as they are
+        if !self.scheduled.mode.shows_overlays() {
+            return;
+        }
+
         //adds a number of random anti-poison overlays to individual numeric tubes
         self.overlays.append(&mut AntiPoisonAnimation::matrix_style_set());
-        let temperature_overlay = TempOverlay(
-            TempOverlayAnimation::new(
-                self.temperature_lock.clone(),
-                ClockType::NCS3186,
-            )
-        );
-        self.overlays.push(temperature_overlay);
+        //scenes are applied after the anti-poisons so they aren't interrupted
+        self.overlays.append(&mut self.settings.playlist.overlays_for_minute(
+            local,
+            &self.sensors,
+            ClockType::NCS3186,
+        ));
 
         for o in &self.overlays {
             println!("{:?}", o)
         }
     }
+    fn reschedule_overlays(&mut self, jump: TimeJump) {
+        let offset = jump.whole_minutes();
+        for cur_overlay in &mut self.overlays {
+            match cur_overlay {
+                Overlay::AntiPoison(ap) => ap.reschedule(offset),
+                Overlay::TempOverlay(t) => t.reschedule(offset),
+                Overlay::Scene(s) => s.reschedule(offset),
+                Overlay::SyncOffset(s) => s.reschedule(offset),
+            }
+        }
+        //overlays that were moved along with the clock are still valid for the new minute
+        self.last_frame_time = self.last_frame_time + offset;
+        println!("Rescheduled {} overlays by {} minutes", self.overlays.len(), offset.num_minutes());
+    }
+    fn apply_schedule(&mut self, local: DateTime<Local>) {
+        let mut state = self.settings.scheduler.state_at(&local);
+        if let Some(brightness) = self.brightness_override {
+            state.brightness = brightness;
+        }
+        if let Some(mode) = &self.mode_override {
+            state.mode = mode.clone();
+        } else if !self.time_confirmed.is_confirmed() {
+            state.mode = DisplayMode::Unsynced(self.settings.unsynced);
+        }
+        *self.state_lock.write().unwrap() = ClockState {
+            mode: state.mode.name().to_string(),
+            brightness: state.brightness,
+            theme: state.theme.clone(),
+            time_confirmed: self.time_confirmed.is_confirmed(),
+            mode_override: self.mode_override.is_some(),
+            brightness_override: self.brightness_override.is_some(),
+        };
+        if state == self.scheduled {
+            return;
+        }
+        println!(
+            "Switching to {} mode, brightness {}, theme {:?}",
+            state.mode.name(),
+            state.brightness,
+            state.theme
+        );
+        self.scheduled = state;
+    }
+    fn handle_command(&mut self, command: ClockCommand) {
+        match command {
+            ClockCommand::ShowSyncOffset => {
+                self.overlays.push(Overlay::SyncOffset(SyncOffsetOverlay::new(
+                    &self.sync_lock,
+                    ClockType::NCS3186,
+                    Local::now(),
+                    Duration::seconds(3),
+                )));
+            }
+            ClockCommand::SetBrightness(brightness) => {
+                self.brightness_override = brightness;
+                self.apply_schedule(Local::now());
+            }
+            ClockCommand::SetMode(mode) => {
+                self.mode_override = mode;
+                self.apply_schedule(Local::now());
+            }
+            ClockCommand::ShowMessage { template, duration } => {
+                self.overlays.push(Overlay::Scene(SceneOverlay::new(
+                    Local::now(),
+                    duration,
+                    template,
+                    None,
+                    SceneTransition::cut(),
+                )));
+            }
+            ClockCommand::AntiPoison => {
+                self.overlays
+                    .append(&mut AntiPoisonAnimation::full_cycle_set(ClockType::NCS3186, Local::now()));
+            }
+            ClockCommand::ApplySettings(settings) => {
+                //the playlist is picked up at the start of the next minute
+                println!("Applying the edited config");
+                self.settings = *settings;
+                self.apply_schedule(Local::now());
+            }
+        }
+    }
 }
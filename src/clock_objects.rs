@@ -14,12 +14,106 @@ use chrono::prelude::*;
 use chrono::Duration;
 use typenum::{U10, U2, U64, U96};
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ClockType {
     NCS3148C,
     NCS3186,
 }
 
+impl ClockType {
+    // HH:MM:SS.ccS
+    const NCS3148C_LAYOUT: [SlotKind; 12] = [
+        SlotKind::Numeric, SlotKind::Numeric, SlotKind::Separator,
+        SlotKind::Numeric, SlotKind::Numeric, SlotKind::Separator,
+        SlotKind::Numeric, SlotKind::Numeric, SlotKind::Separator,
+        SlotKind::Numeric, SlotKind::Numeric, SlotKind::IN19A,
+    ];
+    // HH:MM:SS
+    const NCS3186_LAYOUT: [SlotKind; 8] = [
+        SlotKind::Numeric, SlotKind::Numeric, SlotKind::Separator,
+        SlotKind::Numeric, SlotKind::Numeric, SlotKind::Separator,
+        SlotKind::Numeric, SlotKind::Numeric,
+    ];
+
+    //(first raw bit, slot) of each tube, in the order `to_raw` lays them out
+    const RAW_TUBES: [(usize, usize); 12] = [
+        (0, 8),
+        (2, 11),
+        (12, 10),
+        (22, 9),
+        (32, 5),
+        (34, 7),
+        (44, 6),
+        (54, 4),
+        (64, 2),
+        (66, 3),
+        (76, 1),
+        (86, 0),
+    ];
+
+    /// The kind of tube behind each character of a `DisplayMessage::from_string` string
+    pub fn slot_layout(&self) -> &'static [SlotKind] {
+        match self {
+            ClockType::NCS3148C => &ClockType::NCS3148C_LAYOUT,
+            ClockType::NCS3186 => &ClockType::NCS3186_LAYOUT,
+        }
+    }
+
+    /// Which slot and cathode a bit of the raw message lights, if any
+    pub fn cathode_for_bit(&self, bit: usize) -> Option<(usize, &'static str)> {
+        let (start, slot) = ClockType::RAW_TUBES.iter().rev().find(|(start, _)| *start <= bit)?;
+        let offset = bit - start;
+        let cathode = match self.slot_layout().get(*slot)? {
+            SlotKind::Numeric => ["9", "8", "7", "6", "5", "4", "3", "2", "1", "0"][offset],
+            SlotKind::Separator => ["top", "bottom"][offset],
+            SlotKind::IN19A => ["", "", "℃", "μ", "η", "κ", "ₘ", "P", "Μ", "%"][offset],
+        };
+        if cathode.is_empty() {
+            None
+        } else {
+            Some((*slot, cathode))
+        }
+    }
+
+    pub fn default_time_format(&self) -> &'static str {
+        match self {
+            ClockType::NCS3148C => "%I%:%M%:%S%.%C ",
+            ClockType::NCS3186 => "%I%:%M%:%S",
+        }
+    }
+
+    pub fn default_date_format(&self) -> &'static str {
+        match self {
+            ClockType::NCS3148C => "%d.%m.%y    ",
+            ClockType::NCS3186 => "%d.%m.%y",
+        }
+    }
+
+    pub fn default_countdown_format(&self) -> &'static str {
+        match self {
+            ClockType::NCS3148C => "%H%:%M%:%S%.%C ",
+            ClockType::NCS3186 => "%H%:%M%:%S",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlotKind {
+    Numeric,
+    Separator,
+    IN19A,
+}
+
+impl SlotKind {
+    pub fn accepts(&self, c: char) -> bool {
+        match self {
+            SlotKind::Numeric => NumericTube::from_char(c).is_ok(),
+            SlotKind::Separator => Separator::from_char(c).is_ok(),
+            SlotKind::IN19A => IN19ATube::from_char(c).is_ok(),
+        }
+    }
+}
+
 // pub enum RegisterSizes {
 //     U64(typenum::U64),
 //     U96(typenum::U64),
@@ -36,6 +130,21 @@ pub trait DisplayMessage {
     fn get_on_linger(&self) -> Option<Duration>;
     fn set_lingers(&mut self, lingers:LingerDurations) -> DisplayMessageResult<()>;
     fn set_from_string(&mut self, time_string: String) -> DisplayMessageResult<()>;
+    // Shortens the on linger by the brightness (0.0-1.0) and lengthens the off linger by the
+    // same amount so the frame rate doesn't change
+    fn dim(&mut self, brightness: f32) -> DisplayMessageResult<()> {
+        if brightness >= 1f32 {
+            return Ok(());
+        }
+        let on = self.get_on_linger().unwrap_or_else(Duration::zero);
+        let off = self.get_off_linger().unwrap_or_else(Duration::zero);
+        let on_us = on.num_microseconds().unwrap_or(0) as f32;
+        let dimmed_on = Duration::microseconds((on_us * brightness.max(0f32)) as i64);
+        self.set_lingers(LingerDurations {
+            off: Some(off + (on - dimmed_on)),
+            on: Some(dimmed_on),
+        })
+    }
 }
 
 pub struct LingerDurations {
@@ -180,7 +289,7 @@ impl DisplayMessage for NCS3148CMessage {
             t6: NumericTube::from_char(cs[9]).unwrap_or(None),
             t7: NumericTube::from_char(cs[10]).unwrap_or(None),
             t8: IN19ATube::from_char(cs[11]).unwrap_or(None),
-            lingers: lingers,
+            lingers,
         }
     }
     fn set_tube(&mut self, tube_idx: usize, disp: char) -> DisplayMessageResult<()> {
@@ -0,0 +1,372 @@
+use std::error::Error;
+use std::fmt::Debug;
+use std::thread;
+use std::time::{Duration, Instant};
+use embedded_hal::blocking::i2c::{Read, Write};
+use rppal::i2c::I2c;
+use serde::Deserialize;
+
+use crate::errors::{Co2Error, Co2Result, SensorError, SensorResult};
+#[cfg(test)]
+use crate::mock_i2c::MockI2c;
+use crate::sensors::{Sensor, Unit};
+
+const SCD30_FIRMWARE_VERSION: u16 = 0xd100;
+const SCD30_SET_INTERVAL: u16 = 0x4600;
+//takes the ambient pressure in hPa, 0 for none
+const SCD30_START_CONTINUOUS: u16 = 0x0010;
+const SCD30_DATA_READY: u16 = 0x0202;
+const SCD30_READ_MEASUREMENT: u16 = 0x0300;
+const SCD30_INTERVAL_SECONDS: u16 = 2;
+
+const SCD4X_STOP_PERIODIC: u16 = 0x3f86;
+const SCD4X_SERIAL_NUMBER: u16 = 0x3682;
+const SCD4X_START_PERIODIC: u16 = 0x21b1;
+const SCD4X_DATA_READY: u16 = 0xe4b8;
+const SCD4X_READ_MEASUREMENT: u16 = 0xec05;
+const SCD4X_STOP_TIME: Duration = Duration::from_millis(500);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Co2Chip {
+    Scd30,
+    //SCD40 and SCD41
+    #[default]
+    Scd4x,
+}
+
+impl Co2Chip {
+    fn default_address(&self) -> u8 {
+        match self {
+            Co2Chip::Scd30 => 0x61,
+            Co2Chip::Scd4x => 0x62,
+        }
+    }
+
+    //between a command and reading its reply
+    fn command_time(&self) -> Duration {
+        match self {
+            Co2Chip::Scd30 => Duration::from_millis(3),
+            Co2Chip::Scd4x => Duration::from_millis(1),
+        }
+    }
+
+    fn measurement_period(&self) -> Duration {
+        match self {
+            Co2Chip::Scd30 => Duration::from_secs(SCD30_INTERVAL_SECONDS as u64),
+            Co2Chip::Scd4x => Duration::from_secs(5),
+        }
+    }
+}
+
+/// The `[co2]` section of the config, a Sensirion CO2 sensor on I2C
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Co2Config {
+    pub chip: Co2Chip,
+    pub bus: u8,
+    //the chip's fixed address when unset
+    pub address: Option<u8>,
+    //bus names of the measurements, the temperature reads high from the chip's own heat
+    pub co2: String,
+    pub temperature: String,
+    pub humidity: String,
+}
+
+impl Default for Co2Config {
+    fn default() -> Self {
+        Co2Config {
+            chip: Co2Chip::Scd4x,
+            bus: 1,
+            address: None,
+            co2: "co2".to_string(),
+            temperature: "co2_temperature".to_string(),
+            humidity: "co2_humidity".to_string(),
+        }
+    }
+}
+
+impl Co2Config {
+    pub fn address(&self) -> u8 {
+        self.address.unwrap_or_else(|| self.chip.default_address())
+    }
+}
+
+//Sensirion's CRC-8 over each 16 bit word
+fn crc8(word: [u8; 2]) -> u8 {
+    let mut crc = 0xffu8;
+    for byte in word {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn with_crcs(words: &[u16]) -> Vec<u8> {
+    words
+        .iter()
+        .flat_map(|w| {
+            let bytes = w.to_be_bytes();
+            vec![bytes[0], bytes[1], crc8(bytes)]
+        })
+        .collect()
+}
+
+/// A Sensirion SCD30 or SCD4x, measuring CO2 by infrared absorption along with temperature
+/// and humidity. Both measure continuously, each read waits for the next measurement.
+pub struct Co2Sensor<I> {
+    i2c: I,
+    config: Co2Config,
+}
+
+impl Co2Sensor<I2c> {
+    pub fn open(config: &Co2Config) -> Result<Co2Sensor<I2c>, Box<dyn Error>> {
+        Ok(Co2Sensor::new(I2c::with_bus(config.bus)?, config.clone())?)
+    }
+}
+
+impl<I, E> Co2Sensor<I>
+where
+    I: Read<Error = E> + Write<Error = E>,
+    E: Debug,
+{
+    /// Checks the chip answers with good CRCs and starts it measuring
+    pub fn new(i2c: I, config: Co2Config) -> Co2Result<Co2Sensor<I>> {
+        let mut sensor = Co2Sensor { i2c, config };
+        match sensor.config.chip {
+            Co2Chip::Scd30 => {
+                let version = sensor.read_words(SCD30_FIRMWARE_VERSION, 1)?;
+                println!("SCD30 firmware {}.{}", version[0] >> 8, version[0] & 0xff);
+                sensor.command(SCD30_SET_INTERVAL, &[SCD30_INTERVAL_SECONDS])?;
+                sensor.command(SCD30_START_CONTINUOUS, &[0])?;
+            }
+            Co2Chip::Scd4x => {
+                //it ignores everything else while measuring, which it may still be from a last run
+                sensor.command(SCD4X_STOP_PERIODIC, &[])?;
+                thread::sleep(SCD4X_STOP_TIME);
+                let serial = sensor.read_words(SCD4X_SERIAL_NUMBER, 3)?;
+                println!("SCD4x serial {:04x}{:04x}{:04x}", serial[0], serial[1], serial[2]);
+                sensor.command(SCD4X_START_PERIODIC, &[])?;
+            }
+        }
+        println!(
+            "Found an {:?} at {:#04x} on I2C bus {}",
+            sensor.config.chip,
+            sensor.config.address(),
+            sensor.config.bus
+        );
+        Ok(sensor)
+    }
+
+    /// ppm of CO2, °C and %RH, once the chip has a new measurement
+    pub fn measure(&mut self) -> Co2Result<(f32, f32, f32)> {
+        let chip = self.config.chip;
+        let started = Instant::now();
+        while !self.data_ready()? {
+            if started.elapsed() > chip.measurement_period() * 2 {
+                return Err(Co2Error::NotReady);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        match chip {
+            Co2Chip::Scd30 => {
+                //three big-endian floats of two words each
+                let words = self.read_words(SCD30_READ_MEASUREMENT, 6)?;
+                let float = |i: usize| f32::from_bits((words[i] as u32) << 16 | words[i + 1] as u32);
+                Ok((float(0), float(2), float(4)))
+            }
+            Co2Chip::Scd4x => {
+                let words = self.read_words(SCD4X_READ_MEASUREMENT, 3)?;
+                Ok((
+                    words[0] as f32,
+                    -45f32 + 175f32 * words[1] as f32 / 65535f32,
+                    100f32 * words[2] as f32 / 65535f32,
+                ))
+            }
+        }
+    }
+
+    fn data_ready(&mut self) -> Co2Result<bool> {
+        Ok(match self.config.chip {
+            Co2Chip::Scd30 => self.read_words(SCD30_DATA_READY, 1)?[0] == 1,
+            Co2Chip::Scd4x => self.read_words(SCD4X_DATA_READY, 1)?[0] & 0x07ff != 0,
+        })
+    }
+
+    fn command(&mut self, command: u16, args: &[u16]) -> Co2Result<()> {
+        let mut bytes = command.to_be_bytes().to_vec();
+        bytes.extend(with_crcs(args));
+        self.i2c
+            .write(self.config.address(), &bytes)
+            .map_err(|e| Co2Error::Bus(format!("{:?}", e)))
+    }
+
+    fn read_words(&mut self, command: u16, count: usize) -> Co2Result<Vec<u16>> {
+        self.command(command, &[])?;
+        thread::sleep(self.config.chip.command_time());
+        let mut reply = vec![0u8; count * 3];
+        self.i2c
+            .read(self.config.address(), &mut reply)
+            .map_err(|e| Co2Error::Bus(format!("{:?}", e)))?;
+        reply
+            .chunks(3)
+            .map(|c| {
+                if crc8([c[0], c[1]]) == c[2] {
+                    Ok(u16::from_be_bytes([c[0], c[1]]))
+                } else {
+                    Err(Co2Error::Checksum)
+                }
+            })
+            .collect()
+    }
+}
+
+impl<I, E> Sensor for Co2Sensor<I>
+where
+    I: Read<Error = E> + Write<Error = E> + Send,
+    E: Debug,
+{
+    fn name(&self) -> String {
+        format!("{:?}", self.config.chip)
+    }
+
+    fn measurements(&self) -> Vec<(String, Unit)> {
+        vec![
+            (self.config.co2.clone(), Unit::PartsPerMillion),
+            (self.config.temperature.clone(), Unit::Celsius),
+            (self.config.humidity.clone(), Unit::Percent),
+        ]
+    }
+
+    fn read(&mut self) -> Result<Vec<SensorResult<f32>>, Box<dyn Error>> {
+        match self.measure() {
+            Ok((co2, celsius, humidity)) => Ok(vec![Ok(co2), Ok(celsius), Ok(humidity)]),
+            Err(Co2Error::Checksum) => Ok(vec![Err(SensorError::Checksum); 3]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    //paced by waiting for the chip's own measurements
+    fn interval(&self) -> chrono::Duration {
+        chrono::Duration::seconds(1)
+    }
+}
+
+/// Adds an SCD30 or SCD4x at its address to a mock bus, with a measurement ready to read
+#[cfg(test)]
+pub fn add_mock_co2_sensor(bus: &MockI2c, chip: Co2Chip, co2: f32, celsius: f32, humidity: f32) {
+    let address = chip.default_address();
+    bus.add_command_device(address);
+    match chip {
+        Co2Chip::Scd30 => {
+            bus.set_reply(address, SCD30_FIRMWARE_VERSION, &with_crcs(&[0x0342]));
+            bus.set_reply(address, SCD30_DATA_READY, &with_crcs(&[1]));
+            let words: Vec<u16> = [co2, celsius, humidity]
+                .iter()
+                .flat_map(|v| {
+                    let bits = v.to_bits();
+                    vec![(bits >> 16) as u16, bits as u16]
+                })
+                .collect();
+            bus.set_reply(address, SCD30_READ_MEASUREMENT, &with_crcs(&words));
+        }
+        Co2Chip::Scd4x => {
+            bus.set_reply(address, SCD4X_SERIAL_NUMBER, &with_crcs(&[0xf896, 0x9f07, 0x3bb3]));
+            bus.set_reply(address, SCD4X_DATA_READY, &with_crcs(&[0x8006]));
+            let words = [
+                co2.round() as u16,
+                ((celsius + 45f32) * 65535f32 / 175f32).round() as u16,
+                (humidity * 65535f32 / 100f32).round() as u16,
+            ];
+            bus.set_reply(address, SCD4X_READ_MEASUREMENT, &with_crcs(&words));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_mock(chip: Co2Chip, co2: f32, celsius: f32, humidity: f32) -> (Co2Sensor<MockI2c>, MockI2c) {
+        let bus = MockI2c::new();
+        add_mock_co2_sensor(&bus, chip, co2, celsius, humidity);
+        let config = Co2Config {
+            chip,
+            ..Default::default()
+        };
+        (Co2Sensor::new(bus.clone(), config).unwrap(), bus)
+    }
+
+    fn command_ids(bus: &MockI2c, chip: Co2Chip) -> Vec<u16> {
+        bus.commands(chip.default_address()).into_iter().map(|(c, _)| c).collect()
+    }
+
+    #[test]
+    fn crc_matches_the_datasheet() {
+        assert_eq!(crc8([0xbe, 0xef]), 0x92);
+        assert_eq!(with_crcs(&[0xbeef, 0x0002]), vec![0xbe, 0xef, 0x92, 0x00, 0x02, 0xe3]);
+    }
+
+    #[test]
+    fn scd30_measures() {
+        let (mut scd30, bus) = open_mock(Co2Chip::Scd30, 812.5, 23.25, 41.5);
+        //the interval is set with its CRC before measuring starts
+        let commands = bus.commands(0x61);
+        assert_eq!(commands[1], (SCD30_SET_INTERVAL, with_crcs(&[SCD30_INTERVAL_SECONDS])));
+        assert_eq!(commands[2], (SCD30_START_CONTINUOUS, with_crcs(&[0])));
+        assert_eq!(scd30.measure(), Ok((812.5, 23.25, 41.5)));
+        assert_eq!(
+            command_ids(&bus, Co2Chip::Scd30),
+            vec![
+                SCD30_FIRMWARE_VERSION,
+                SCD30_SET_INTERVAL,
+                SCD30_START_CONTINUOUS,
+                SCD30_DATA_READY,
+                SCD30_READ_MEASUREMENT
+            ]
+        );
+    }
+
+    #[test]
+    fn scd4x_measures() {
+        let (mut scd4x, bus) = open_mock(Co2Chip::Scd4x, 650f32, 21.5, 48f32);
+        let (co2, celsius, humidity) = scd4x.measure().unwrap();
+        assert_eq!(co2, 650f32);
+        assert!((celsius - 21.5).abs() < 0.01, "{}", celsius);
+        assert!((humidity - 48f32).abs() < 0.01, "{}", humidity);
+        //stopped first in case it was left measuring
+        assert_eq!(
+            command_ids(&bus, Co2Chip::Scd4x),
+            vec![
+                SCD4X_STOP_PERIODIC,
+                SCD4X_SERIAL_NUMBER,
+                SCD4X_START_PERIODIC,
+                SCD4X_DATA_READY,
+                SCD4X_READ_MEASUREMENT
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_crc_is_a_checksum_error() {
+        let (mut scd30, bus) = open_mock(Co2Chip::Scd30, 812.5, 23.25, 41.5);
+        let mut reply = with_crcs(&[0x4448, 0x2000, 0x41ba, 0x0000, 0x4226, 0x0000]);
+        reply[5] ^= 0x01;
+        bus.set_reply(0x61, SCD30_READ_MEASUREMENT, &reply);
+        assert_eq!(scd30.measure(), Err(Co2Error::Checksum));
+        assert_eq!(scd30.read().unwrap(), vec![Err(SensorError::Checksum); 3]);
+    }
+
+    #[test]
+    fn missing_chip_is_a_bus_error() {
+        let config = Co2Config {
+            chip: Co2Chip::Scd30,
+            ..Default::default()
+        };
+        assert!(matches!(Co2Sensor::new(MockI2c::new(), config), Err(Co2Error::Bus(_))));
+    }
+}
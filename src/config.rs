@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use chrono::prelude::*;
+use serde::Deserialize;
+
+use crate::clock_objects::ClockType;
+use crate::display_template::DisplayTemplate;
+use crate::playlist::{Playlist, SceneConfig, TemperatureDisplayConfig};
+use crate::rgb_driver::LedColor;
+use crate::scheduler::{ScheduleRuleConfig, ScheduledState, Scheduler, ThemeConfig, UnsyncedStyle};
+use crate::world_clock::WorldClockConfig;
+use crate::ntp_client::NtpConfig;
+use crate::rtc::RtcConfig;
+use crate::gps::GpsConfig;
+use crate::http_api::HttpConfig;
+use crate::control_socket::SocketConfig;
+use crate::mqtt::MqttConfig;
+use crate::temperature_sensor::TemperatureConfig;
+use crate::sensors::FilterConfig;
+use crate::bme280::Bme280Config;
+use crate::light_sensor::LightSensorConfig;
+use crate::auto_brightness::AutoBrightnessConfig;
+use crate::co2_sensor::Co2Config;
+use crate::particulate::Pms5003Config;
+use crate::led_warning::LedWarningConfig;
+use crate::pulse_counter::PulseInputConfig;
+
+/// Settings read from the optional TOML file given after the clock type on the command line.
+/// Everything has a default so an empty or partial file is fine.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ClockConfig {
+    pub display: DisplayConfig,
+    //scenes shown within each minute, the temperature at second 16 when empty
+    pub playlist: Vec<SceneConfig>,
+    pub schedule: Vec<ScheduleRuleConfig>,
+    pub themes: HashMap<String, ThemeConfig>,
+    pub world_clock: Option<WorldClockConfig>,
+    pub ntp: Option<NtpConfig>,
+    pub rtc: Option<RtcConfig>,
+    pub gps: Option<GpsConfig>,
+    pub http: Option<HttpConfig>,
+    //for `gfx_clock ctl`
+    pub socket: Option<SocketConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub temperature: TemperatureConfig,
+    //calibration and smoothing by measurement name, e.g. [sensors.outdoor]
+    pub sensors: HashMap<String, FilterConfig>,
+    pub bme280: Option<Bme280Config>,
+    pub light: Option<LightSensorConfig>,
+    pub co2: Option<Co2Config>,
+    pub pms5003: Option<Pms5003Config>,
+    //Geiger counters and S0 meters on GPIO pins
+    pub pulse_inputs: Vec<PulseInputConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    //see display_template.rs for the syntax, uses the board's default when unset
+    pub time_format: Option<String>,
+    //shown at boot until a configured time source (NTP, RTC or GPS) confirms the time
+    pub unsynced: UnsyncedStyle,
+    pub temperature: TemperatureDisplayConfig,
+    //dims the tubes by a light sensor's readings
+    pub auto_brightness: Option<AutoBrightnessConfig>,
+    //LED colours for measurements over a threshold, like CO2
+    pub led_warnings: Vec<LedWarningConfig>,
+}
+
+impl ClockConfig {
+    pub fn load(path: &str) -> Result<ClockConfig, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+/// Everything the drivers need to decide what to show, built from the config and checked
+/// against the board before the tubes are lit.
+#[derive(Debug, Clone)]
+pub struct DisplaySettings {
+    pub time_template: DisplayTemplate,
+    pub playlist: Playlist,
+    pub scheduler: Scheduler,
+    pub unsynced: UnsyncedStyle,
+    pub auto_brightness: Option<AutoBrightnessConfig>,
+    pub led_warnings: Vec<LedWarningConfig>,
+}
+
+impl DisplaySettings {
+    pub fn from_config(config: &ClockConfig, clock_type: ClockType) -> Result<DisplaySettings, Box<dyn Error>> {
+        let time_template = DisplayTemplate::parse(
+            config
+                .display
+                .time_format
+                .as_deref()
+                .unwrap_or_else(|| clock_type.default_time_format()),
+            clock_type,
+        )?;
+        let temperature_format = config.display.temperature.format_for(clock_type)?;
+        let playlist = if config.playlist.is_empty() {
+            Playlist::default_playlist(&config.display.temperature, temperature_format)?
+        } else {
+            Playlist::from_config(&config.playlist, &time_template, temperature_format, clock_type)?
+        };
+        let scheduler = Scheduler::from_config(
+            &config.schedule,
+            &config.themes,
+            &config.world_clock,
+            clock_type,
+        )?;
+        if let Some(auto_brightness) = &config.display.auto_brightness {
+            auto_brightness.validate()?;
+        }
+        Ok(DisplaySettings {
+            time_template,
+            playlist,
+            scheduler,
+            unsynced: config.display.unsynced,
+            auto_brightness: config.display.auto_brightness.clone(),
+            led_warnings: config.display.led_warnings.clone(),
+        })
+    }
+
+    //the scheduled theme's time format wins over the display one
+    pub fn time_template_for(&self, state: &ScheduledState) -> &DisplayTemplate {
+        state
+            .theme
+            .as_deref()
+            .and_then(|t| self.scheduler.theme(t))
+            .and_then(|t| t.time_template.as_ref())
+            .unwrap_or(&self.time_template)
+    }
+
+    //a mode's own colour, like the world clock's zones, wins over the theme's
+    pub fn led_for(&self, state: &ScheduledState, displayed: &DateTime<Local>) -> LedColor {
+        state
+            .mode
+            .led_at(displayed)
+            .or_else(|| state.theme.as_deref().and_then(|t| self.scheduler.theme(t)).and_then(|t| t.led))
+            .unwrap_or(LedColor::Off)
+    }
+}
@@ -0,0 +1,356 @@
+use std::fs;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::Duration;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::clock_objects::ClockType;
+use crate::config::{ClockConfig, DisplaySettings};
+use crate::control::{ClockCommand, ClockState, CommandSender};
+use crate::display_stream::{DisplayFrame, Simulator};
+use crate::errors::{ControlError, ControlResult};
+
+pub const CONFIG_HTML: &str = include_str!("../static/config.html");
+//sections the display loop takes on without a restart
+const LIVE_SECTIONS: [&str; 4] = ["display", "playlist", "schedule", "themes"];
+//a preview left open in a browser tab stops after this long
+const PREVIEW_MINUTES: u64 = 10;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigRequest {
+    //the whole config file
+    pub toml: String,
+    //for previews, the board can only change with a restart
+    pub board: Option<String>,
+    //for previews, `YYYY-MM-DDTHH:MM` to run the simulated clock from then rather than now
+    pub start: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigReport {
+    pub board: String,
+    pub path: Option<String>,
+    pub toml: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigCheckReport {
+    //sections that differ from what the clock started with and only take effect on a restart
+    pub restart_needed: Vec<String>,
+    pub saved_to: Option<String>,
+}
+
+/// Backs the web UI at `/config`: hands out the config file, checks edits against the
+/// board, previews them on a simulated clock, and saves and applies them
+#[derive(Debug, Clone)]
+pub struct ConfigEditor {
+    clock_type: ClockType,
+    path: Option<String>,
+    //the config as the clock started, to tell which edits need a restart
+    started_with: Arc<toml::Value>,
+    commands: CommandSender,
+    pub preview_lock: Arc<RwLock<DisplayFrame>>,
+    preview_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl ConfigEditor {
+    pub fn new(clock_type: ClockType, path: Option<String>, commands: CommandSender) -> ConfigEditor {
+        let started_with = path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_else(|| toml::Value::Table(Default::default()));
+        ConfigEditor {
+            clock_type,
+            path,
+            started_with: Arc::new(started_with),
+            commands,
+            preview_lock: Arc::new(RwLock::new(DisplayFrame::default())),
+            preview_task: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn current(&self) -> ControlResult<String> {
+        let toml = match &self.path {
+            Some(p) => fs::read_to_string(p).map_err(|e| ControlError::BadRequest(format!("{}: {}", p, e)))?,
+            None => String::new(),
+        };
+        to_json(&ConfigReport {
+            board: format!("{:?}", self.clock_type),
+            path: self.path.clone(),
+            toml,
+        })
+    }
+
+    pub fn check(&self, request: ConfigRequest) -> ControlResult<String> {
+        self.settings_for(&request.toml, self.board(&request)?)?;
+        to_json(&ConfigCheckReport {
+            restart_needed: self.restart_needed(&request.toml),
+            saved_to: None,
+        })
+    }
+
+    /// Runs the edited config on a simulated clock, streamed at `/config/preview/stream`,
+    /// replacing any preview already running
+    pub fn preview(&self, request: ConfigRequest) -> ControlResult<String> {
+        let clock_type = self.board(&request)?;
+        let settings = self.settings_for(&request.toml, clock_type)?;
+        //nothing sends to the preview, it just runs the schedule
+        let (_, commands) = mpsc::channel();
+        let mut simulator = Simulator::new(
+            clock_type,
+            settings,
+            commands,
+            Arc::new(RwLock::new(ClockState::default())),
+            self.preview_lock.clone(),
+        );
+        if let Some(start) = &request.start {
+            let start = NaiveDateTime::parse_from_str(start, "%Y-%m-%dT%H:%M")
+                .ok()
+                .and_then(|s| Local.from_local_datetime(&s).single())
+                .ok_or_else(|| ControlError::BadRequest(format!("Bad start time {}, expected YYYY-MM-DDTHH:MM", start)))?;
+            simulator = simulator.starting_at(start);
+        }
+        let task = tokio::spawn(async move {
+            let _ = tokio::time::timeout(Duration::from_secs(PREVIEW_MINUTES * 60), simulator.run()).await;
+        });
+        if let Some(previous) = self.preview_task.lock().unwrap().replace(task) {
+            previous.abort();
+        }
+        to_json(&ConfigCheckReport {
+            restart_needed: self.restart_needed(&request.toml),
+            saved_to: None,
+        })
+    }
+
+    /// Saves the config over the file the clock started with, and has the display loop
+    /// switch to it
+    pub fn apply(&self, request: ConfigRequest) -> ControlResult<String> {
+        let settings = self.settings_for(&request.toml, self.clock_type)?;
+        if let Some(path) = &self.path {
+            //written alongside and renamed so a failed write can't leave half a config
+            let temp_path = format!("{}.new", path);
+            fs::write(&temp_path, &request.toml)
+                .and_then(|_| fs::rename(&temp_path, path))
+                .map_err(|e| ControlError::ConfigNotSaved(format!("{}: {}", path, e)))?;
+            println!("Saved the edited config to {}", path);
+        }
+        self.commands
+            .send(ClockCommand::ApplySettings(Box::new(settings)))
+            .map_err(|_| ControlError::ClockStopped)?;
+        to_json(&ConfigCheckReport {
+            restart_needed: self.restart_needed(&request.toml),
+            saved_to: self.path.clone(),
+        })
+    }
+
+    fn board(&self, request: &ConfigRequest) -> ControlResult<ClockType> {
+        match request.board.as_deref() {
+            None => Ok(self.clock_type),
+            Some("NCS3148C") => Ok(ClockType::NCS3148C),
+            Some("NCS3186") => Ok(ClockType::NCS3186),
+            Some(b) => Err(ControlError::BadRequest(format!("Unknown board {}", b))),
+        }
+    }
+
+    //the same checks as at startup
+    fn settings_for(&self, toml: &str, clock_type: ClockType) -> ControlResult<DisplaySettings> {
+        let config: ClockConfig = toml::from_str(toml).map_err(|e| ControlError::BadRequest(e.to_string()))?;
+        DisplaySettings::from_config(&config, clock_type).map_err(|e| ControlError::BadRequest(e.to_string()))
+    }
+
+    fn restart_needed(&self, toml: &str) -> Vec<String> {
+        let edited: toml::Value = match toml::from_str(toml) {
+            Ok(v) => v,
+            Err(_) => return vec![],
+        };
+        let (edited, started) = match (edited.as_table(), self.started_with.as_table()) {
+            (Some(e), Some(s)) => (e, s),
+            _ => return vec![],
+        };
+        let mut sections: Vec<String> = edited
+            .keys()
+            .chain(started.keys())
+            .filter(|k| !LIVE_SECTIONS.contains(&k.as_str()) && edited.get(*k) != started.get(*k))
+            .cloned()
+            .collect();
+        sections.sort();
+        sections.dedup();
+        sections
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> ControlResult<String> {
+    serde_json::to_string(value).map_err(|e| ControlError::BadRequest(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::mpsc::Receiver;
+
+    const STARTED_WITH: &str = r#"
+[display]
+time_format = "%H%:%M%:%S"
+
+[ntp]
+servers = ["pool.ntp.org"]
+"#;
+
+    fn editor(dir: &Path) -> (ConfigEditor, String, Receiver<ClockCommand>) {
+        let path = dir.join("gfx_clock.toml").to_str().unwrap().to_string();
+        fs::write(&path, STARTED_WITH).unwrap();
+        let (commands, receiver) = mpsc::channel();
+        (ConfigEditor::new(ClockType::NCS3186, Some(path.clone()), commands), path, receiver)
+    }
+
+    fn request(toml: &str) -> ConfigRequest {
+        ConfigRequest {
+            toml: toml.to_string(),
+            board: None,
+            start: None,
+        }
+    }
+
+    fn restart_needed(report: &str) -> Vec<String> {
+        let report: serde_json::Value = serde_json::from_str(report).unwrap();
+        serde_json::from_value(report["restart_needed"].clone()).unwrap()
+    }
+
+    #[test]
+    fn tells_which_sections_need_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let (editor, _, _) = editor(dir.path());
+        assert_eq!(editor.restart_needed(STARTED_WITH), Vec::<String>::new());
+        //the live sections can change freely
+        let live = r#"
+[display]
+time_format = "%I%:%M%:%S"
+
+[[playlist]]
+scene = "date"
+seconds = 5
+
+[ntp]
+servers = ["pool.ntp.org"]
+"#;
+        assert_eq!(editor.restart_needed(live), Vec::<String>::new());
+        //added, changed and removed sections
+        let restart = r#"
+[http]
+listen = "0.0.0.0:8080"
+
+[ntp]
+servers = ["192.168.1.2"]
+"#;
+        assert_eq!(editor.restart_needed(restart), vec!["http", "ntp"]);
+        assert_eq!(editor.restart_needed("[display"), Vec::<String>::new());
+        assert_eq!(restart_needed(&editor.check(request(restart)).unwrap()), vec!["http", "ntp"]);
+    }
+
+    #[test]
+    fn checks_against_the_board() {
+        let dir = tempfile::tempdir().unwrap();
+        let (editor, _, _) = editor(dir.path());
+        let twelve_slots = r#"
+[display]
+time_format = "%H%:%M%:%S%.%C "
+"#;
+        assert!(editor.check(request(twelve_slots)).is_err());
+        let on = |board: &str| ConfigRequest {
+            board: Some(board.to_string()),
+            ..request(twelve_slots)
+        };
+        assert!(editor.check(on("NCS3148C")).is_ok());
+        assert_eq!(
+            editor.check(on("IN-14")),
+            Err(ControlError::BadRequest("Unknown board IN-14".to_string()))
+        );
+        assert!(editor.check(request("[display")).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_bad_preview_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let (editor, _, _) = editor(dir.path());
+        for start in ["2021-06-07 12:34", "2021-13-07T12:34", "tomorrow"] {
+            let preview = editor.preview(ConfigRequest {
+                start: Some(start.to_string()),
+                ..request(STARTED_WITH)
+            });
+            assert!(matches!(preview, Err(ControlError::BadRequest(_))), "{}", start);
+        }
+    }
+
+    #[tokio::test]
+    async fn previews_the_playlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let (editor, _, _) = editor(dir.path());
+        let playlist = r#"
+[[playlist]]
+scene = "date"
+format = "%d.%m.%y    "
+seconds = 30
+"#;
+        editor
+            .preview(ConfigRequest {
+                toml: playlist.to_string(),
+                board: Some("NCS3148C".to_string()),
+                start: Some("2021-06-07T12:34".to_string()),
+            })
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let frame = editor.preview_lock.read().unwrap().clone();
+        assert_eq!(frame.clock_type, "NCS3148C");
+        let digits: String = frame
+            .tubes
+            .iter()
+            .filter(|t| t.kind == "numeric")
+            .map(|t| {
+                t.lit
+                    .iter()
+                    .max_by(|a, b| a.brightness.total_cmp(&b.brightness))
+                    .map_or(" ", |c| c.cathode)
+            })
+            .collect();
+        assert_eq!(digits, "070621  ");
+        editor.preview_task.lock().unwrap().take().unwrap().abort();
+    }
+
+    #[test]
+    fn applies_and_saves() {
+        let dir = tempfile::tempdir().unwrap();
+        let (editor, path, receiver) = editor(dir.path());
+        let edited = r#"
+[display]
+time_format = "%I%:%M%:%S"
+"#;
+        let report: serde_json::Value = serde_json::from_str(&editor.apply(request(edited)).unwrap()).unwrap();
+        assert_eq!(report["saved_to"], path.as_str());
+        assert_eq!(report["restart_needed"], serde_json::json!(["ntp"]));
+        assert_eq!(fs::read_to_string(&path).unwrap(), edited);
+        assert!(!Path::new(&format!("{}.new", path)).exists());
+        assert!(matches!(receiver.try_recv(), Ok(ClockCommand::ApplySettings(_))));
+    }
+
+    #[test]
+    fn leaves_the_file_alone_when_the_config_is_bad() {
+        let dir = tempfile::tempdir().unwrap();
+        let (editor, path, receiver) = editor(dir.path());
+        assert!(editor.apply(request("[display]\ntime_format = \"%Q\"")).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), STARTED_WITH);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn does_not_apply_what_it_could_not_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let (commands, receiver) = mpsc::channel();
+        let missing = dir.path().join("missing").join("gfx_clock.toml");
+        let editor = ConfigEditor::new(ClockType::NCS3186, Some(missing.to_str().unwrap().to_string()), commands);
+        assert!(matches!(editor.apply(request(STARTED_WITH)), Err(ControlError::ConfigNotSaved(_))));
+        assert!(receiver.try_recv().is_err());
+    }
+}
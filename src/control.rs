@@ -0,0 +1,376 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, RwLock};
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::clock_objects::ClockType;
+use crate::config::DisplaySettings;
+use crate::config_ui::ConfigEditor;
+use crate::display_stream::DisplayFrame;
+use crate::display_template::DisplayTemplate;
+use crate::errors::{ControlError, ControlResult};
+use crate::metrics::ClockMetrics;
+use crate::scheduler::{DisplayMode, ModeKind};
+use crate::sensors::{SensorBus, SensorState, Unit};
+use crate::time_sync::SyncStatus;
+use crate::world_clock::WorldClockConfig;
+
+/// Requests from the async side of the daemon (signals and the control APIs) to the
+/// display loop, which picks them up between frames.
+#[derive(Debug, Clone)]
+pub enum ClockCommand {
+    ShowSyncOffset,
+    //None goes back to the schedule
+    SetBrightness(Option<f32>),
+    SetMode(Option<DisplayMode>),
+    ShowMessage { template: DisplayTemplate, duration: Duration },
+    AntiPoison,
+    //a config edited through the web UI
+    ApplySettings(Box<DisplaySettings>),
+}
+
+const OK_RESPONSE: &str = r#"{"ok":true}"#;
+
+pub type CommandSender = Sender<ClockCommand>;
+pub type CommandReceiver = Receiver<ClockCommand>;
+
+/// What the display loop is showing, published for the control APIs to report
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ClockState {
+    pub mode: String,
+    pub brightness: f32,
+    pub theme: Option<String>,
+    pub time_confirmed: bool,
+    //set through a control API rather than by the schedule
+    pub mode_override: bool,
+    pub brightness_override: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BrightnessRequest {
+    //0.0-1.0, or null to go back to the schedule
+    pub brightness: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModeRequest {
+    //null to go back to the schedule
+    pub mode: Option<ModeKind>,
+    pub format: Option<String>,
+    pub countdown_to: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRequest {
+    //a display template, padded with blanks to fill the board
+    pub text: String,
+    #[serde(default = "MessageRequest::default_seconds")]
+    pub seconds: u32,
+}
+
+impl MessageRequest {
+    pub fn default_seconds() -> u32 {
+        5
+    }
+}
+
+/// One request to the control APIs, as JSON tagged by `command`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    Sensors,
+    Brightness(BrightnessRequest),
+    Mode(ModeRequest),
+    Show(MessageRequest),
+    AntiPoison,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncReport {
+    pub source: Option<String>,
+    pub synced: bool,
+    pub offset_ms: Option<i64>,
+    pub delay_ms: Option<i64>,
+    pub stratum: Option<u8>,
+    pub age_seconds: Option<i64>,
+    pub rtc_drift_ms: Option<i64>,
+}
+
+impl From<&SyncStatus> for SyncReport {
+    fn from(status: &SyncStatus) -> SyncReport {
+        SyncReport {
+            source: status.source.clone(),
+            synced: status.is_synced(),
+            offset_ms: status.offset.map(|o| o.num_milliseconds()),
+            delay_ms: status.delay.map(|d| d.num_milliseconds()),
+            stratum: status.stratum,
+            age_seconds: status.age().map(|a| a.num_seconds()),
+            rtc_drift_ms: status.rtc_drift.map(|d| d.num_milliseconds()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusReport {
+    pub clock_type: String,
+    pub time: String,
+    #[serde(flatten)]
+    pub state: ClockState,
+    pub sync: SyncReport,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SensorReport {
+    //null once the last reading is older than the sensor's max age
+    pub value: Option<f32>,
+    //the last reading before calibration and smoothing, for working out a calibration
+    pub raw: Option<f32>,
+    pub unit: Unit,
+    pub taken_at: Option<String>,
+    pub age_seconds: Option<i64>,
+    pub reads: u64,
+    pub failures: u64,
+    pub checksum_errors: u64,
+    pub rejected: u64,
+    pub last_error: Option<String>,
+}
+
+impl From<&SensorState> for SensorReport {
+    fn from(state: &SensorState) -> SensorReport {
+        SensorReport {
+            value: state.fresh().map(|r| r.value),
+            raw: state.fresh().map(|r| r.raw),
+            unit: state.unit,
+            taken_at: state.reading.map(|r| r.taken_at.to_rfc3339()),
+            age_seconds: state.reading.map(|r| r.age().num_seconds()),
+            reads: state.reads,
+            failures: state.failures,
+            checksum_errors: state.checksum_errors,
+            rejected: state.rejected,
+            last_error: state.last_error.clone(),
+        }
+    }
+}
+
+/// The operations shared by the control APIs. Requests are checked against the board
+/// here and handed to the display loop as `ClockCommand`s.
+#[derive(Clone)]
+pub struct ClockControl {
+    pub clock_type: ClockType,
+    pub world_clock: Option<WorldClockConfig>,
+    pub commands: CommandSender,
+    pub state_lock: Arc<RwLock<ClockState>>,
+    pub sync_lock: Arc<RwLock<SyncStatus>>,
+    pub sensors: SensorBus,
+    pub metrics_lock: Arc<RwLock<ClockMetrics>>,
+    pub display_lock: Arc<RwLock<DisplayFrame>>,
+    pub config_editor: ConfigEditor,
+}
+
+impl ClockControl {
+    /// Carries out a request, answering with JSON
+    pub fn execute(&self, request: ControlRequest) -> ControlResult<String> {
+        let to_json = |value: serde_json::Result<String>| value.map_err(|e| ControlError::BadRequest(e.to_string()));
+        match request {
+            ControlRequest::Status => to_json(serde_json::to_string(&self.status())),
+            ControlRequest::Sensors => to_json(serde_json::to_string(&self.sensors())),
+            ControlRequest::Brightness(r) => self.set_brightness(r).map(|_| OK_RESPONSE.to_string()),
+            ControlRequest::Mode(r) => self.set_mode(r).map(|_| OK_RESPONSE.to_string()),
+            ControlRequest::Show(r) => self.show_message(r).map(|_| OK_RESPONSE.to_string()),
+            ControlRequest::AntiPoison => self.anti_poison().map(|_| OK_RESPONSE.to_string()),
+        }
+    }
+
+    fn send(&self, command: ClockCommand) -> ControlResult<()> {
+        self.commands.send(command).map_err(|_| ControlError::ClockStopped)
+    }
+
+    pub fn status(&self) -> StatusReport {
+        StatusReport {
+            clock_type: format!("{:?}", self.clock_type),
+            time: Local::now().to_rfc3339(),
+            state: self.state_lock.read().unwrap().clone(),
+            sync: SyncReport::from(&*self.sync_lock.read().unwrap()),
+        }
+    }
+
+    /// Every measurement on the sensor bus, by name
+    pub fn sensors(&self) -> BTreeMap<String, SensorReport> {
+        self.sensors
+            .states()
+            .iter()
+            .map(|(name, state)| (name.clone(), SensorReport::from(state)))
+            .collect()
+    }
+
+    /// The Prometheus text format, for `GET /metrics`
+    pub fn metrics(&self) -> String {
+        self.metrics_lock.read().unwrap().render(self.clock_type, &self.sensors.states())
+    }
+
+    pub fn set_brightness(&self, request: BrightnessRequest) -> ControlResult<()> {
+        if let Some(b) = request.brightness {
+            if !(0f32..=1f32).contains(&b) {
+                return Err(ControlError::BadRequest(format!("Brightness {} is outside 0.0-1.0", b)));
+            }
+        }
+        self.send(ClockCommand::SetBrightness(request.brightness))
+    }
+
+    pub fn set_mode(&self, request: ModeRequest) -> ControlResult<()> {
+        let mode = match request.mode {
+            Some(kind) => Some(
+                DisplayMode::from_kind(
+                    kind,
+                    request.format.as_deref(),
+                    request.countdown_to.as_deref(),
+                    &self.world_clock,
+                    self.clock_type,
+                )
+                .map_err(|e| ControlError::BadRequest(e.to_string()))?,
+            ),
+            None => None,
+        };
+        self.send(ClockCommand::SetMode(mode))
+    }
+
+    pub fn show_message(&self, request: MessageRequest) -> ControlResult<()> {
+        let slots = self.clock_type.slot_layout().len();
+        let mut text = request.text;
+        //only fits plain text to the board, templates are checked for their real length below
+        if !text.contains('%') {
+            text = text.chars().chain(std::iter::repeat(' ')).take(slots).collect();
+        }
+        let template = DisplayTemplate::parse(&text, self.clock_type)
+            .map_err(|e| ControlError::BadRequest(e.to_string()))?;
+        self.send(ClockCommand::ShowMessage {
+            template,
+            duration: Duration::seconds(request.seconds as i64),
+        })
+    }
+
+    pub fn anti_poison(&self) -> ControlResult<()> {
+        self.send(ClockCommand::AntiPoison)
+    }
+
+    /// A control for the board with no sensors or time sources, and the display loop's end
+    /// of its commands
+    #[cfg(test)]
+    pub fn for_tests(clock_type: ClockType) -> (ClockControl, CommandReceiver) {
+        let (commands, receiver) = std::sync::mpsc::channel();
+        let control = ClockControl {
+            clock_type,
+            world_clock: None,
+            commands: commands.clone(),
+            state_lock: Arc::new(RwLock::new(ClockState::default())),
+            sync_lock: Arc::new(RwLock::new(SyncStatus::default())),
+            sensors: SensorBus::default(),
+            metrics_lock: Arc::new(RwLock::new(ClockMetrics::default())),
+            display_lock: Arc::new(RwLock::new(DisplayFrame::default())),
+            config_editor: ConfigEditor::new(clock_type, None, commands),
+        };
+        (control, receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brightness(control: &ClockControl, brightness: Option<f32>) -> ControlResult<String> {
+        control.execute(ControlRequest::Brightness(BrightnessRequest { brightness }))
+    }
+
+    fn mode(kind: Option<ModeKind>, countdown_to: Option<&str>) -> ControlRequest {
+        ControlRequest::Mode(ModeRequest {
+            mode: kind,
+            format: None,
+            countdown_to: countdown_to.map(str::to_string),
+        })
+    }
+
+    fn show(control: &ClockControl, text: &str) -> ControlResult<String> {
+        control.execute(ControlRequest::Show(MessageRequest {
+            text: text.to_string(),
+            seconds: 3,
+        }))
+    }
+
+    #[test]
+    fn sets_brightness_in_range() {
+        let (control, commands) = ClockControl::for_tests(ClockType::NCS3186);
+        for b in [0f32, 0.5, 1f32] {
+            assert_eq!(brightness(&control, Some(b)), Ok(OK_RESPONSE.to_string()));
+            assert!(matches!(commands.try_recv(), Ok(ClockCommand::SetBrightness(Some(s))) if s == b));
+        }
+        assert_eq!(brightness(&control, None), Ok(OK_RESPONSE.to_string()));
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::SetBrightness(None))));
+        for b in [-0.1, 1.1, f32::NAN, f32::INFINITY] {
+            assert!(matches!(brightness(&control, Some(b)), Err(ControlError::BadRequest(_))), "{}", b);
+        }
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[test]
+    fn sets_modes() {
+        let (control, commands) = ClockControl::for_tests(ClockType::NCS3186);
+        assert!(control.execute(mode(Some(ModeKind::Date), None)).is_ok());
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::SetMode(Some(DisplayMode::Date(_))))));
+        assert!(control.execute(mode(Some(ModeKind::Countdown), Some("17:00"))).is_ok());
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::SetMode(Some(DisplayMode::Countdown { .. })))));
+        assert!(control.execute(mode(None, None)).is_ok());
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::SetMode(None))));
+        //no [world_clock] section, and no time to count down to
+        assert!(matches!(control.execute(mode(Some(ModeKind::WorldClock), None)), Err(ControlError::BadRequest(_))));
+        assert!(matches!(control.execute(mode(Some(ModeKind::Countdown), None)), Err(ControlError::BadRequest(_))));
+        assert!(matches!(control.execute(mode(Some(ModeKind::Countdown), Some("5pm"))), Err(ControlError::BadRequest(_))));
+        assert!(commands.try_recv().is_err());
+        //a mode that doesn't exist never gets as far as the control
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"command": "mode", "mode": "disco"}"#).is_err());
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"command": "mode", "mode": "date"}"#).is_ok());
+    }
+
+    #[test]
+    fn fits_messages_to_the_board() {
+        for (clock_type, text, shown) in [
+            (ClockType::NCS3186, "12 34", "12 34   "),
+            (ClockType::NCS3186, "12 34 56 78", "12 34 56"),
+            (ClockType::NCS3186, "", "        "),
+            (ClockType::NCS3148C, "12 34", "12 34       "),
+            (ClockType::NCS3148C, "12:34:56.78%%", "12:34:56.78%%"),
+        ] {
+            let (control, commands) = ClockControl::for_tests(clock_type);
+            assert_eq!(show(&control, text), Ok(OK_RESPONSE.to_string()), "{}", text);
+            match commands.try_recv() {
+                Ok(ClockCommand::ShowMessage { template, duration }) => {
+                    assert_eq!(template.source(), shown);
+                    assert_eq!(duration, Duration::seconds(3));
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+        let (control, commands) = ClockControl::for_tests(ClockType::NCS3186);
+        //templates are left as they are
+        assert!(show(&control, "%H%:%M%:%S").is_ok());
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::ShowMessage { .. })));
+        assert!(matches!(show(&control, "%H%:%M"), Err(ControlError::BadRequest(_))));
+        assert!(matches!(show(&control, "%H%:%M%:%S%:%S"), Err(ControlError::BadRequest(_))));
+        //a letter has no slot on a numeric tube
+        assert!(matches!(show(&control, "hello"), Err(ControlError::BadRequest(_))));
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[test]
+    fn reports_a_stopped_clock() {
+        let (control, commands) = ClockControl::for_tests(ClockType::NCS3186);
+        let status: serde_json::Value = serde_json::from_str(&control.execute(ControlRequest::Status).unwrap()).unwrap();
+        assert_eq!(status["clock_type"], "NCS3186");
+        assert_eq!(status["sync"]["synced"], false);
+        assert_eq!(control.execute(ControlRequest::Sensors), Ok("{}".to_string()));
+        drop(commands);
+        assert_eq!(control.execute(ControlRequest::AntiPoison), Err(ControlError::ClockStopped));
+    }
+}
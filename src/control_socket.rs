@@ -0,0 +1,238 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::UnixListener;
+
+use crate::control::{BrightnessRequest, ClockControl, ControlRequest, MessageRequest, ModeRequest};
+use crate::scheduler::ModeKind;
+
+pub const DEFAULT_SOCKET_PATH: &str = "/run/gfx_clock.sock";
+const CTL_USAGE: &str = "gfx_clock ctl [--socket <path>] status | sensors | anti_poison
+  | show <text> [seconds] | brightness <0.0-1.0|schedule> | mode <mode|schedule> [countdown_to|format]";
+
+/// The `[socket]` section of the config, the socket is only opened with one
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SocketConfig {
+    pub path: String,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            path: DEFAULT_SOCKET_PATH.to_string(),
+        }
+    }
+}
+
+/// Line oriented JSON on a Unix socket, for cron jobs and units on the same Pi. Each line
+/// is a request tagged by `command`, like `{"command": "brightness", "brightness": 0.3}`,
+/// and gets one line back, either the answer or `{"error": "..."}`.
+pub struct ControlSocket {}
+
+impl ControlSocket {
+    pub async fn run(config: SocketConfig, control: ClockControl) -> Result<(), Box<dyn Error + Send + Sync>> {
+        ControlSocket::remove_stale(&config.path)?;
+        let listener = UnixListener::bind(&config.path)?;
+        println!("Control socket listening on {}", config.path);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let control = control.clone();
+            tokio::spawn(async move {
+                if let Err(e) = ControlSocket::serve(stream, control).await {
+                    println!("Control socket client failed: {}", e);
+                }
+            });
+        }
+    }
+
+    //a socket left behind by an earlier run would stop the bind, anything else at the path is
+    //left alone in case the path was mistyped
+    fn remove_stale(path: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match std::fs::symlink_metadata(path) {
+            Ok(m) if m.file_type().is_socket() => Ok(std::fs::remove_file(path)?),
+            Ok(_) => Err(format!("{} exists and isn't a socket", path).into()),
+            Err(_) => Ok(()),
+        }
+    }
+
+    async fn serve(stream: tokio::net::UnixStream, control: ClockControl) -> std::io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = serde_json::from_str::<ControlRequest>(&line)
+                .map_err(|e| e.to_string())
+                .and_then(|r| control.execute(r).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| serde_json::json!({ "error": e }).to_string());
+            writer.write_all(response.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+        }
+        Ok(())
+    }
+
+    /// `gfx_clock ctl ...`, sends one request to the running clock and prints the answer
+    pub fn ctl(args: &[String]) -> Result<(), Box<dyn Error>> {
+        let (path, args) = match args.first().map(String::as_str) {
+            Some("--socket") => (args.get(1).ok_or(CTL_USAGE)?.as_str(), &args[2..]),
+            _ => (DEFAULT_SOCKET_PATH, args),
+        };
+        let request = ControlSocket::parse_ctl(args)?;
+        let mut stream = UnixStream::connect(path).map_err(|e| format!("Can't reach the clock on {}: {}", path, e))?;
+        stream.write_all(serde_json::to_string(&request)?.as_bytes())?;
+        stream.write_all(b"\n")?;
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        let value: serde_json::Value = serde_json::from_str(&response)?;
+        if let Some(e) = value.get("error").and_then(|e| e.as_str()) {
+            return Err(e.into());
+        }
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        Ok(())
+    }
+
+    fn parse_ctl(args: &[String]) -> Result<ControlRequest, Box<dyn Error>> {
+        let arg = |i: usize| args.get(i).map(String::as_str);
+        Ok(match (arg(0), arg(1)) {
+            (Some("status"), _) => ControlRequest::Status,
+            (Some("sensors"), _) => ControlRequest::Sensors,
+            (Some("anti_poison"), _) => ControlRequest::AntiPoison,
+            (Some("show"), Some(text)) => ControlRequest::Show(MessageRequest {
+                text: text.to_string(),
+                seconds: match arg(2) {
+                    Some(s) => s.parse()?,
+                    None => MessageRequest::default_seconds(),
+                },
+            }),
+            (Some("brightness"), Some("schedule")) => ControlRequest::Brightness(BrightnessRequest { brightness: None }),
+            (Some("brightness"), Some(b)) => ControlRequest::Brightness(BrightnessRequest {
+                brightness: Some(b.parse()?),
+            }),
+            (Some("mode"), Some("schedule")) => ControlRequest::Mode(ModeRequest {
+                mode: None,
+                format: None,
+                countdown_to: None,
+            }),
+            (Some("mode"), Some(m)) => {
+                let mode: ModeKind = serde_json::from_value(serde_json::Value::String(m.to_string()))
+                    .map_err(|_| format!("Unknown mode {}", m))?;
+                let extra = arg(2).map(str::to_string);
+                ControlRequest::Mode(ModeRequest {
+                    mode: Some(mode),
+                    countdown_to: extra.clone().filter(|_| mode == ModeKind::Countdown),
+                    format: extra.filter(|_| mode != ModeKind::Countdown),
+                })
+            }
+            _ => return Err(CTL_USAGE.into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn only_removes_stale_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("gfx_clock.sock");
+        let socket = socket.to_str().unwrap();
+        assert!(ControlSocket::remove_stale(socket).is_ok());
+        drop(UnixListener::bind(socket).unwrap());
+        ControlSocket::remove_stale(socket).unwrap();
+        assert!(UnixListener::bind(socket).is_ok());
+
+        let file = dir.path().join("clock.toml");
+        std::fs::write(&file, "[http]\n").unwrap();
+        assert!(ControlSocket::remove_stale(file.to_str().unwrap()).is_err());
+        assert!(file.exists());
+    }
+
+    #[test]
+    fn socket_is_opt_in() {
+        let socket = |source| toml::from_str::<crate::config::ClockConfig>(source).unwrap().socket;
+        assert!(socket("").is_none());
+        assert!(socket("[http]\n").is_none());
+        assert_eq!(socket("[socket]\n").unwrap().path, DEFAULT_SOCKET_PATH);
+        assert_eq!(socket("[socket]\npath = \"/tmp/clock.sock\"\n").unwrap().path, "/tmp/clock.sock");
+    }
+
+    fn ctl(line: &str) -> Result<serde_json::Value, String> {
+        let args: Vec<String> = line.split(' ').map(str::to_string).collect();
+        ControlSocket::parse_ctl(&args)
+            .map(|r| serde_json::to_value(r).unwrap())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn parses_ctl_commands() {
+        use serde_json::json;
+        assert_eq!(ctl("status"), Ok(json!({"command": "status"})));
+        assert_eq!(ctl("sensors"), Ok(json!({"command": "sensors"})));
+        assert_eq!(ctl("anti_poison"), Ok(json!({"command": "anti_poison"})));
+        assert_eq!(ctl("show 12:34"), Ok(json!({"command": "show", "text": "12:34", "seconds": 5})));
+        assert_eq!(ctl("show 12:34 10"), Ok(json!({"command": "show", "text": "12:34", "seconds": 10})));
+        assert_eq!(ctl("brightness 0.3"), Ok(json!({"command": "brightness", "brightness": 0.3f32})));
+        assert_eq!(ctl("brightness schedule"), Ok(json!({"command": "brightness", "brightness": null})));
+        assert_eq!(
+            ctl("mode schedule"),
+            Ok(json!({"command": "mode", "mode": null, "format": null, "countdown_to": null}))
+        );
+        assert_eq!(
+            ctl("mode countdown 17:00"),
+            Ok(json!({"command": "mode", "mode": "countdown", "format": null, "countdown_to": "17:00"}))
+        );
+        assert_eq!(
+            ctl("mode date %d%:%m%:%y"),
+            Ok(json!({"command": "mode", "mode": "date", "format": "%d%:%m%:%y", "countdown_to": null}))
+        );
+        assert_eq!(
+            ctl("mode world_clock"),
+            Ok(json!({"command": "mode", "mode": "world_clock", "format": null, "countdown_to": null}))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_ctl_arguments() {
+        assert_eq!(ctl("mode disco"), Err("Unknown mode disco".to_string()));
+        for line in ["", "status_please", "show", "brightness", "mode"] {
+            assert_eq!(ctl(line), Err(CTL_USAGE.to_string()), "{}", line);
+        }
+        //numbers that don't parse
+        assert!(ctl("show 12:34 ten").is_err());
+        assert!(ctl("show 12:34 -1").is_err());
+        assert!(ctl("brightness bright").is_err());
+        assert!(ControlSocket::parse_ctl(&[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_a_line_per_request() {
+        use crate::clock_objects::ClockType;
+        use tokio::io::AsyncReadExt;
+        let (control, commands) = ClockControl::for_tests(ClockType::NCS3186);
+        let (client, server) = tokio::net::UnixStream::pair().unwrap();
+        let served = tokio::spawn(ControlSocket::serve(server, control));
+        let (mut reader, mut writer) = client.into_split();
+        writer
+            .write_all(b"{\"command\": \"anti_poison\"}\n\n{\"command\": \"brightness\", \"brightness\": 7}\nnope\n")
+            .await
+            .unwrap();
+        drop(writer);
+        let mut answers = String::new();
+        reader.read_to_string(&mut answers).await.unwrap();
+        served.await.unwrap().unwrap();
+        let answers: Vec<&str> = answers.lines().collect();
+        assert_eq!(answers.len(), 3, "{:?}", answers);
+        assert_eq!(answers[0], r#"{"ok":true}"#);
+        assert_eq!(answers[1], r#"{"error":"Brightness 7 is outside 0.0-1.0"}"#);
+        assert!(answers[2].starts_with(r#"{"error":"#), "{}", answers[2]);
+        assert!(commands.try_recv().is_ok());
+        assert!(commands.try_recv().is_err());
+    }
+}
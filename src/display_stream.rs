@@ -0,0 +1,465 @@
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use bit_array::BitArray;
+use chrono::prelude::*;
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use typenum::U96;
+
+use crate::animation_utils::*;
+use crate::clock_objects::{ClockType, DisplayMessage, NCS3148CMessage, NCS3186Message, SlotKind};
+use crate::config::DisplaySettings;
+use crate::control::{ClockCommand, ClockState, CommandReceiver};
+use crate::scheduler::{DisplayMode, ScheduledState};
+use crate::sensors::SensorBus;
+
+//about 30 frames a second, plenty for a browser
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(33);
+const RAW_BITS: usize = 96;
+
+pub const VIEWER_HTML: &str = include_str!("../static/viewer.html");
+//draws a stream of frames as tubes, shared by the viewer and the config page
+pub const NIXIE_JS: &str = include_str!("../static/nixie.js");
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LitCathode {
+    pub cathode: &'static str,
+    //the share of the time it was lit, so the brightness the eye sees
+    pub brightness: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TubeFrame {
+    pub kind: &'static str,
+    pub lit: Vec<LitCathode>,
+}
+
+/// What the tubes showed over the last sample, left to right
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DisplayFrame {
+    pub clock_type: String,
+    pub tubes: Vec<TubeFrame>,
+}
+
+/// Adds up how long each cathode is lit between samples, as the display loop runs far
+/// faster than anyone could watch
+#[derive(Debug, Clone)]
+pub struct DisplaySampler {
+    on_us: [u64; RAW_BITS],
+    total_us: u64,
+    published_at: Instant,
+}
+
+impl Default for DisplaySampler {
+    fn default() -> Self {
+        DisplaySampler {
+            on_us: [0; RAW_BITS],
+            total_us: 0,
+            published_at: Instant::now(),
+        }
+    }
+}
+
+impl DisplaySampler {
+    pub fn record_frame(
+        &mut self,
+        raw_message: &BitArray<u8, U96>,
+        off_linger: Option<chrono::Duration>,
+        on_linger: Option<chrono::Duration>,
+    ) {
+        let micros = |d: Option<chrono::Duration>| d.and_then(|d| d.num_microseconds()).unwrap_or(0).max(0) as u64;
+        let on_us = micros(on_linger);
+        self.total_us += on_us + micros(off_linger);
+        for (i, lit) in raw_message.iter().enumerate() {
+            if lit {
+                self.on_us[i] += on_us;
+            }
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.published_at.elapsed() >= SAMPLE_INTERVAL
+    }
+
+    /// Decodes the sample into tubes for the stream, and starts the next one
+    pub fn publish(&mut self, clock_type: ClockType, display_lock: &Arc<RwLock<DisplayFrame>>) {
+        let mut tubes: Vec<TubeFrame> = clock_type
+            .slot_layout()
+            .iter()
+            .map(|kind| TubeFrame {
+                kind: match kind {
+                    SlotKind::Numeric => "numeric",
+                    SlotKind::Separator => "separator",
+                    SlotKind::IN19A => "in19a",
+                },
+                lit: vec![],
+            })
+            .collect();
+        for (bit, on_us) in self.on_us.iter().enumerate() {
+            if *on_us == 0 || self.total_us == 0 {
+                continue;
+            }
+            if let Some((slot, cathode)) = clock_type.cathode_for_bit(bit) {
+                //rounded so a steady display compares equal from one sample to the next
+                let brightness = (*on_us as f32 / self.total_us as f32 * 100f32).round() / 100f32;
+                tubes[slot].lit.push(LitCathode { cathode, brightness });
+            }
+        }
+        *display_lock.write().unwrap() = DisplayFrame {
+            clock_type: format!("{:?}", clock_type),
+            tubes,
+        };
+        *self = DisplaySampler::default();
+    }
+}
+
+/// Sends the display over a WebSocket as JSON `DisplayFrame`s whenever it changes, checking
+/// about 30 times a second
+pub struct DisplayStream {}
+
+impl DisplayStream {
+    /// Answers the upgrade request, and streams once hyper hands over the connection
+    pub fn upgrade(request: Request<Body>, display_lock: Arc<RwLock<DisplayFrame>>) -> Response<Body> {
+        let accept = match request.headers().get(SEC_WEBSOCKET_KEY) {
+            Some(key) => derive_accept_key(key.as_bytes()),
+            None => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("Expected a WebSocket upgrade"))
+                    .unwrap()
+            }
+        };
+        tokio::spawn(async move {
+            match hyper::upgrade::on(request).await {
+                Ok(upgraded) => {
+                    let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                    if let Err(e) = DisplayStream::stream(socket, display_lock).await {
+                        println!("Display stream failed: {}", e);
+                    }
+                }
+                Err(e) => println!("Display stream upgrade failed: {}", e),
+            }
+        });
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn stream(
+        socket: WebSocketStream<hyper::upgrade::Upgraded>,
+        display_lock: Arc<RwLock<DisplayFrame>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (mut sender, mut receiver) = socket.split();
+        let mut ticks = tokio::time::interval(SAMPLE_INTERVAL);
+        let mut last: Option<DisplayFrame> = None;
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {
+                    let frame = display_lock.read().unwrap().clone();
+                    if last.as_ref() != Some(&frame) {
+                        sender.send(Message::Text(serde_json::to_string(&frame)?)).await?;
+                        last = Some(frame);
+                    }
+                }
+                message = receiver.next() => match message {
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Err(e)) => return Err(e.into()),
+                    //pings are answered by the next send
+                    Some(Ok(_)) => (),
+                },
+            }
+        }
+    }
+}
+
+/// Runs the schedule, the control commands, messages and anti-poisoning without any tubes,
+/// for watching in the browser viewer. The playlist is laid out each minute as on the
+/// tubes, but there are no sensors or time sources, so the time counts as confirmed and
+/// temperature and reading scenes have nothing to show.
+pub struct Simulator {
+    clock_type: ClockType,
+    settings: DisplaySettings,
+    scheduled: ScheduledState,
+    overlays: Vec<Overlay>,
+    //always empty, for the playlist's reading scenes
+    sensors: SensorBus,
+    //None until the first frame sets up its minute
+    last_frame_time: Option<DateTime<Local>>,
+    commands: CommandReceiver,
+    state_lock: Arc<RwLock<ClockState>>,
+    display_lock: Arc<RwLock<DisplayFrame>>,
+    mode_override: Option<DisplayMode>,
+    brightness_override: Option<f32>,
+    sampler: DisplaySampler,
+    //from the real time to the simulated one
+    offset: chrono::Duration,
+}
+
+impl Simulator {
+    pub fn new(
+        clock_type: ClockType,
+        settings: DisplaySettings,
+        commands: CommandReceiver,
+        state_lock: Arc<RwLock<ClockState>>,
+        display_lock: Arc<RwLock<DisplayFrame>>,
+    ) -> Simulator {
+        Simulator {
+            clock_type,
+            settings,
+            scheduled: ScheduledState::default(),
+            overlays: vec![],
+            sensors: SensorBus::default(),
+            last_frame_time: None,
+            commands,
+            state_lock,
+            display_lock,
+            mode_override: None,
+            brightness_override: None,
+            sampler: DisplaySampler::default(),
+            offset: chrono::Duration::zero(),
+        }
+    }
+
+    /// Runs the simulated clock from another time, for seeing what a schedule does later on
+    pub fn starting_at(mut self, start: DateTime<Local>) -> Simulator {
+        self.offset = start - Local::now();
+        self
+    }
+
+    pub async fn run(mut self) {
+        println!("Simulating a {:?} clock", self.clock_type);
+        let mut ticks = tokio::time::interval(SAMPLE_INTERVAL);
+        loop {
+            ticks.tick().await;
+            match self.clock_type {
+                ClockType::NCS3148C => self.show_frame::<NCS3148CMessage>(),
+                ClockType::NCS3186 => self.show_frame::<NCS3186Message>(),
+            }
+        }
+    }
+
+    fn show_frame<M: DisplayMessage>(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            self.handle_command(command);
+        }
+        let local = Local::now() + self.offset;
+        self.apply_schedule(local);
+        if self.last_frame_time.map(|t| t.minute()) != Some(local.minute()) {
+            self.setup_overlays_for_minute(local);
+        }
+        self.last_frame_time = Some(local);
+        self.overlays.retain(|o| match o {
+            Overlay::AntiPoison(ap) => !ap.has_ended(local),
+            Overlay::TempOverlay(t) => !t.has_ended(local),
+            Overlay::Scene(s) => !s.has_ended(local),
+            Overlay::SyncOffset(s) => !s.has_ended(local),
+        });
+
+        let micros = local.timestamp_subsec_micros();
+        let msg_string = self.scheduled.mode.render(
+            &local,
+            TimeSeparators::time_separators_animation(micros),
+            self.settings.time_template_for(&self.scheduled),
+            self.clock_type,
+        );
+        let seconds_pulse = PwmAnimation { frame_interval_us: 200 };
+        let mut message = M::from_string(msg_string, seconds_pulse.pwm_seconds_animation(micros));
+        for overlay in &mut self.overlays {
+            match overlay {
+                Overlay::AntiPoison(o) => o.apply_to_message(local, &mut message),
+                Overlay::TempOverlay(t) => t.apply_to_message(local, &mut message),
+                Overlay::Scene(s) => s.apply_to_message(local, &mut message),
+                Overlay::SyncOffset(_) => (),
+            }
+        }
+        message
+            .dim(self.scheduled.brightness)
+            .expect("Attempt to set bad message linger");
+        self.sampler
+            .record_frame(&message.to_raw(), message.get_off_linger(), message.get_on_linger());
+        self.sampler.publish(self.clock_type, &self.display_lock);
+    }
+
+    //the playlist as the drivers lay it out, without their random anti-poisons
+    fn setup_overlays_for_minute(&mut self, local: DateTime<Local>) {
+        if !self.scheduled.mode.shows_overlays() {
+            return;
+        }
+        self.overlays
+            .append(&mut self.settings.playlist.overlays_for_minute(local, &self.sensors, self.clock_type));
+    }
+
+    fn apply_schedule(&mut self, local: DateTime<Local>) {
+        let mut state = self.settings.scheduler.state_at(&local);
+        if let Some(brightness) = self.brightness_override {
+            state.brightness = brightness;
+        }
+        if let Some(mode) = &self.mode_override {
+            state.mode = mode.clone();
+        }
+        *self.state_lock.write().unwrap() = ClockState {
+            mode: state.mode.name().to_string(),
+            brightness: state.brightness,
+            theme: state.theme.clone(),
+            time_confirmed: true,
+            mode_override: self.mode_override.is_some(),
+            brightness_override: self.brightness_override.is_some(),
+        };
+        self.scheduled = state;
+    }
+
+    fn handle_command(&mut self, command: ClockCommand) {
+        match command {
+            ClockCommand::SetBrightness(brightness) => self.brightness_override = brightness,
+            ClockCommand::SetMode(mode) => self.mode_override = mode,
+            ClockCommand::ShowMessage { template, duration } => {
+                self.overlays.push(Overlay::Scene(SceneOverlay::new(
+                    Local::now() + self.offset,
+                    duration,
+                    template,
+                    None,
+                    SceneTransition::cut(),
+                )));
+            }
+            ClockCommand::AntiPoison => {
+                self.overlays
+                    .append(&mut AntiPoisonAnimation::full_cycle_set(self.clock_type, Local::now() + self.offset));
+            }
+            //the playlist is picked up at the start of the next minute
+            ClockCommand::ApplySettings(settings) => self.settings = *settings,
+            ClockCommand::ShowSyncOffset => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    use crate::config::ClockConfig;
+
+    fn bits(lit: &[usize]) -> BitArray<u8, U96> {
+        let mut raw = BitArray::<u8, U96>::from_elem(false);
+        for bit in lit {
+            raw.set(*bit, true);
+        }
+        raw
+    }
+
+    fn micros(us: i64) -> Option<chrono::Duration> {
+        Some(chrono::Duration::microseconds(us))
+    }
+
+    #[test]
+    fn samples_the_share_each_cathode_is_lit() {
+        let mut sampler = DisplaySampler::default();
+        //the top dot, the IN-19A's ℃ and the 9 of the tube before it
+        sampler.record_frame(&bits(&[0, 4, 12]), micros(25), micros(75));
+        sampler.record_frame(&bits(&[0]), micros(75), micros(25));
+        //the IN-19A has nothing on its first two bits
+        sampler.record_frame(&bits(&[2]), micros(100), None);
+        let display_lock = Arc::new(RwLock::new(DisplayFrame::default()));
+        sampler.publish(ClockType::NCS3148C, &display_lock);
+        let frame = display_lock.read().unwrap().clone();
+        assert_eq!(frame.clock_type, "NCS3148C");
+        let kinds: Vec<&str> = frame.tubes.iter().map(|t| t.kind).collect();
+        assert_eq!(kinds[..3], ["numeric", "numeric", "separator"]);
+        assert_eq!(kinds[11], "in19a");
+        let lit = |cathode, brightness| vec![LitCathode { cathode, brightness }];
+        assert_eq!(frame.tubes[8].lit, lit("top", 0.33));
+        assert_eq!(frame.tubes[10].lit, lit("9", 0.25));
+        assert_eq!(frame.tubes[11].lit, lit("℃", 0.25));
+        let dark = [0, 1, 2, 3, 4, 5, 6, 7, 9];
+        assert!(dark.iter().all(|t| frame.tubes[*t].lit.is_empty()));
+        //and starts over
+        assert_eq!(sampler.total_us, 0);
+        assert!(sampler.on_us.iter().all(|us| *us == 0));
+    }
+
+    #[test]
+    fn skips_bits_the_board_does_not_have() {
+        let mut sampler = DisplaySampler::default();
+        //the NCS3186 has no third separator or IN-19A
+        sampler.record_frame(&bits(&[0, 4, 86]), micros(0), micros(100));
+        let display_lock = Arc::new(RwLock::new(DisplayFrame::default()));
+        sampler.publish(ClockType::NCS3186, &display_lock);
+        let frame = display_lock.read().unwrap().clone();
+        assert_eq!(frame.tubes.len(), 8);
+        assert_eq!(frame.tubes[0].lit, vec![LitCathode { cathode: "9", brightness: 1f32 }]);
+        assert!(frame.tubes[1..].iter().all(|t| t.lit.is_empty()));
+    }
+
+    //the brightest cathode of each numeric tube
+    fn digits(frame: &DisplayFrame) -> String {
+        frame
+            .tubes
+            .iter()
+            .filter(|t| t.kind == "numeric")
+            .map(|t| {
+                t.lit
+                    .iter()
+                    .max_by(|a, b| a.brightness.total_cmp(&b.brightness))
+                    .map_or(" ", |c| c.cathode)
+            })
+            .collect()
+    }
+
+    fn simulated_frame(toml: &str, at: (u32, u32, u32)) -> DisplayFrame {
+        let clock_type = ClockType::NCS3186;
+        let config: ClockConfig = toml::from_str(toml).unwrap();
+        let settings = DisplaySettings::from_config(&config, clock_type).unwrap();
+        let (_commands, receiver) = mpsc::channel();
+        let display_lock = Arc::new(RwLock::new(DisplayFrame::default()));
+        let start = Local.ymd(2021, 6, 7).and_hms(at.0, at.1, at.2);
+        let mut simulator = Simulator::new(
+            clock_type,
+            settings,
+            receiver,
+            Arc::new(RwLock::new(ClockState::default())),
+            display_lock.clone(),
+        )
+        .starting_at(start);
+        simulator.show_frame::<NCS3186Message>();
+        let frame = display_lock.read().unwrap().clone();
+        frame
+    }
+
+    #[test]
+    fn simulates_the_playlist() {
+        let playlist = r#"
+            [[playlist]]
+            scene = "date"
+            seconds = 20
+            [[playlist]]
+            scene = "time"
+            seconds = 20
+        "#;
+        assert_eq!(digits(&simulated_frame(playlist, (12, 34, 10))), "070621");
+        assert_eq!(digits(&simulated_frame(playlist, (12, 34, 30))), "123430");
+        //repeated to fill the minute
+        assert_eq!(digits(&simulated_frame(playlist, (12, 34, 45))), "070621");
+    }
+
+    #[test]
+    fn simulates_reading_scenes_without_readings() {
+        let playlist = r#"
+            [[playlist]]
+            scene = "temperature"
+            seconds = 60
+        "#;
+        //nothing on the bus, so the time shows through
+        assert_eq!(digits(&simulated_frame(playlist, (12, 34, 10))), "123410");
+    }
+}
@@ -0,0 +1,298 @@
+//! A small strftime-like template language that maps onto the tube slots of a board.
+//!
+//! Every character of a rendered template is one slot of `ClockType::slot_layout`, so a
+//! template is checked against the board once, when it is parsed, rather than on each frame.
+//!
+//! Tokens (all two digits wide unless noted):
+//!   %H %I     hour in 24h / 12h
+//!   %M %S     minute, second
+//!   %d %m %y  day of month, month, two digit year
+//!   %u        weekday, Monday is 1 (one digit)
+//!   %C        centiseconds
+//!   %p        AM/PM on the IN-19A, 'Μ' for AM and 'P' for PM (one slot)
+//!   %-X       any of the numeric tokens above with the leading zero blanked
+//!   %: %. %'  a separator that blinks with the seconds animation (one slot)
+//!   %%        the IN-19A percent symbol
+//! Anything else is shown literally: digits, ' ', ':', '.', '\'', the IN-19A symbols, or '*'
+//! to leave whatever is underneath untouched.
+
+use chrono::prelude::*;
+
+use crate::clock_objects::{ClockType, SlotKind};
+use crate::errors::{TemplateError, TemplateResult};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Field {
+    Hour24,
+    Hour12,
+    Minute,
+    Second,
+    Day,
+    Month,
+    Year,
+    Centis,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(char),
+    Number { field: Field, blank_zero: bool },
+    Weekday,
+    AmPm,
+    BlinkingSeparator(char),
+}
+
+impl TemplatePart {
+    fn kinds(&self) -> Vec<SlotKind> {
+        match self {
+            TemplatePart::Literal(_) => vec![],
+            TemplatePart::Number { .. } => vec![SlotKind::Numeric, SlotKind::Numeric],
+            TemplatePart::Weekday => vec![SlotKind::Numeric],
+            TemplatePart::AmPm => vec![SlotKind::IN19A],
+            TemplatePart::BlinkingSeparator(_) => vec![SlotKind::Separator],
+        }
+    }
+
+    fn width(&self) -> usize {
+        match self {
+            TemplatePart::Literal(_) => 1,
+            _ => self.kinds().len(),
+        }
+    }
+
+    fn token(&self) -> String {
+        match self {
+            TemplatePart::Literal(c) => c.to_string(),
+            TemplatePart::Number { field, blank_zero } => {
+                let c = match field {
+                    Field::Hour24 => 'H',
+                    Field::Hour12 => 'I',
+                    Field::Minute => 'M',
+                    Field::Second => 'S',
+                    Field::Day => 'd',
+                    Field::Month => 'm',
+                    Field::Year => 'y',
+                    Field::Centis => 'C',
+                };
+                format!("%{}{}", if *blank_zero { "-" } else { "" }, c)
+            }
+            TemplatePart::Weekday => "%u".to_string(),
+            TemplatePart::AmPm => "%p".to_string(),
+            TemplatePart::BlinkingSeparator(c) => format!("%{}", c),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayTemplate {
+    source: String,
+    parts: Vec<TemplatePart>,
+}
+
+impl DisplayTemplate {
+    /// Parses a template and checks that it fills every slot of the board with something
+    /// the tube in that slot can show.
+    pub fn parse(source: &str, clock_type: ClockType) -> TemplateResult<DisplayTemplate> {
+        let mut parts = vec![];
+        let mut chars = source.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                parts.push(TemplatePart::Literal(c));
+                continue;
+            }
+            let mut t = chars.next().ok_or(TemplateError::UnterminatedToken)?;
+            let blank_zero = t == '-';
+            if blank_zero {
+                t = chars.next().ok_or(TemplateError::UnterminatedToken)?;
+            }
+            let field = match t {
+                'H' => Some(Field::Hour24),
+                'I' => Some(Field::Hour12),
+                'M' => Some(Field::Minute),
+                'S' => Some(Field::Second),
+                'd' => Some(Field::Day),
+                'm' => Some(Field::Month),
+                'y' => Some(Field::Year),
+                'C' => Some(Field::Centis),
+                _ => None,
+            };
+            let part = match (field, t) {
+                (Some(field), _) => TemplatePart::Number { field, blank_zero },
+                (None, _) if blank_zero => {
+                    return Err(TemplateError::UnknownToken(format!("%-{}", t)))
+                }
+                (None, 'u') => TemplatePart::Weekday,
+                (None, 'p') => TemplatePart::AmPm,
+                (None, '%') => TemplatePart::Literal('%'),
+                (None, ':') | (None, '.') | (None, '\'') => TemplatePart::BlinkingSeparator(t),
+                (None, _) => return Err(TemplateError::UnknownToken(format!("%{}", t))),
+            };
+            parts.push(part);
+        }
+
+        let template = DisplayTemplate {
+            source: source.to_string(),
+            parts,
+        };
+        template.validate(clock_type)?;
+        Ok(template)
+    }
+
+    fn validate(&self, clock_type: ClockType) -> TemplateResult<()> {
+        let layout = clock_type.slot_layout();
+        let width: usize = self.parts.iter().map(|p| p.width()).sum();
+        if width != layout.len() {
+            return Err(TemplateError::WrongLength {
+                expected: layout.len(),
+                found: width,
+            });
+        }
+        let mut position = 0;
+        for part in &self.parts {
+            let fits = match part {
+                //'*' keeps the underlying value, so it fits anywhere
+                TemplatePart::Literal('*') => true,
+                TemplatePart::Literal(c) => layout[position].accepts(*c),
+                _ => part
+                    .kinds()
+                    .iter()
+                    .enumerate()
+                    .all(|(i, k)| layout[position + i] == *k),
+            };
+            if !fits {
+                return Err(TemplateError::SlotMismatch {
+                    position,
+                    token: part.token(),
+                });
+            }
+            position += part.width();
+        }
+        Ok(())
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Renders one slot character per tube, ready for `DisplayMessage::from_string`.
+    /// Blinking separators are blanked when `separators_on` is false.
+    pub fn render<Tz: TimeZone>(&self, time: &DateTime<Tz>, separators_on: bool) -> String {
+        let mut s = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(c) => s.push(*c),
+                TemplatePart::Number { field, blank_zero } => {
+                    let v = match field {
+                        Field::Hour24 => time.hour(),
+                        Field::Hour12 => time.hour12().1,
+                        Field::Minute => time.minute(),
+                        Field::Second => time.second(),
+                        Field::Day => time.day(),
+                        Field::Month => time.month(),
+                        Field::Year => (time.year().rem_euclid(100)) as u32,
+                        Field::Centis => (time.nanosecond() / 10_000_000) % 100,
+                    };
+                    if *blank_zero && v < 10 {
+                        s.push_str(&format!(" {}", v));
+                    } else {
+                        s.push_str(&format!("{:02}", v));
+                    }
+                }
+                TemplatePart::Weekday => {
+                    s.push_str(&time.weekday().number_from_monday().to_string())
+                }
+                TemplatePart::AmPm => s.push(if time.hour12().0 { 'P' } else { 'Μ' }),
+                TemplatePart::BlinkingSeparator(c) => {
+                    s.push(if separators_on { *c } else { ' ' })
+                }
+            }
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(h: u32, m: u32, s: u32) -> DateTime<Utc> {
+        Utc.ymd(2021, 6, 7).and_hms_milli(h, m, s, 420)
+    }
+
+    #[test]
+    fn default_formats_fit_their_boards() {
+        for clock_type in [ClockType::NCS3148C, ClockType::NCS3186] {
+            for format in [
+                clock_type.default_time_format(),
+                clock_type.default_date_format(),
+                clock_type.default_countdown_format(),
+            ] {
+                assert!(DisplayTemplate::parse(format, clock_type).is_ok(), "{}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_tokens() {
+        let parse = |s| DisplayTemplate::parse(s, ClockType::NCS3186);
+        assert_eq!(parse("%H%:%M%:%Q"), Err(TemplateError::UnknownToken("%Q".to_string())));
+        assert_eq!(parse("%-u%:%M%:%S"), Err(TemplateError::UnknownToken("%-u".to_string())));
+        assert_eq!(parse("%H%:%M%:%S%"), Err(TemplateError::UnterminatedToken));
+        assert_eq!(parse("%H%:%M%:%-"), Err(TemplateError::UnterminatedToken));
+    }
+
+    #[test]
+    fn too_many_digits_for_the_board() {
+        //the twelve slot default of the NCS3148C does not fit the eight tubes of the NCS3186
+        assert_eq!(
+            DisplayTemplate::parse(ClockType::NCS3148C.default_time_format(), ClockType::NCS3186),
+            Err(TemplateError::WrongLength { expected: 8, found: 12 })
+        );
+        assert_eq!(
+            DisplayTemplate::parse("%H%:%M%:%S", ClockType::NCS3148C),
+            Err(TemplateError::WrongLength { expected: 12, found: 8 })
+        );
+        //the right width, but the minutes land on the first separator
+        assert_eq!(
+            DisplayTemplate::parse("%H%M%S%C", ClockType::NCS3186),
+            Err(TemplateError::SlotMismatch { position: 2, token: "%M".to_string() })
+        );
+    }
+
+    #[test]
+    fn separator_slots() {
+        let parse = |s| DisplayTemplate::parse(s, ClockType::NCS3186);
+        assert_eq!(
+            parse("%H5%M%:%S"),
+            Err(TemplateError::SlotMismatch { position: 2, token: "5".to_string() })
+        );
+        assert_eq!(
+            parse("%:1%:%M%:%S"),
+            Err(TemplateError::SlotMismatch { position: 0, token: "%:".to_string() })
+        );
+        assert!(parse("%H:%M.%S").is_ok());
+        assert!(parse("%H'%M %S").is_ok());
+        //'*' leaves the slot alone, whatever its kind
+        assert!(parse("**%:%M***").is_ok());
+    }
+
+    #[test]
+    fn only_blinking_separators_blink() {
+        let template = DisplayTemplate::parse("%H%:%M.%S", ClockType::NCS3186).unwrap();
+        assert_eq!(template.render(&at(12, 34, 56), true), "12:34.56");
+        assert_eq!(template.render(&at(12, 34, 56), false), "12 34.56");
+        assert_eq!(template.source(), "%H%:%M.%S");
+    }
+
+    #[test]
+    fn in19a_slot() {
+        let template = DisplayTemplate::parse("%-I%:%M%:%S%.%C%p", ClockType::NCS3148C).unwrap();
+        assert_eq!(template.render(&at(9, 5, 7), true), " 9:05:07.42Μ");
+        assert_eq!(template.render(&at(21, 5, 7), true), " 9:05:07.42P");
+        assert!(DisplayTemplate::parse("%H%:%M%:%S%.%C%%", ClockType::NCS3148C).is_ok());
+        assert_eq!(
+            DisplayTemplate::parse("%H%:%M%:%S%.%C5", ClockType::NCS3148C),
+            Err(TemplateError::SlotMismatch { position: 11, token: "5".to_string() })
+        );
+    }
+}
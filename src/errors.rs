@@ -1,4 +1,6 @@
 use core::fmt::Debug;
+use std::error::Error;
+use std::fmt;
 
 pub type DisplayMessageResult<T> = Result<T, DisplayMessageError>;
 
@@ -6,4 +8,255 @@ pub type DisplayMessageResult<T> = Result<T, DisplayMessageError>;
 pub enum DisplayMessageError {
     TubeIndexOutOfRange,
     UnexpectedCharForTubeType,
-}
\ No newline at end of file
+}
+pub type TemplateResult<T> = Result<T, TemplateError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    UnknownToken(String),
+    UnterminatedToken,
+    WrongLength { expected: usize, found: usize },
+    SlotMismatch { position: usize, token: String },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::UnknownToken(t) => write!(f, "Unknown template token {}", t),
+            TemplateError::UnterminatedToken => write!(f, "Template ends with a bare %"),
+            TemplateError::WrongLength { expected, found } => write!(
+                f,
+                "Template fills {} slots but the board has {}",
+                found, expected
+            ),
+            TemplateError::SlotMismatch { position, token } => write!(
+                f,
+                "Template puts {} in slot {} which that tube cannot show",
+                token, position
+            ),
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+pub type RtcResult<T> = Result<T, RtcError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RtcError {
+    Bus(String),
+    //the oscillator stopped at some point, so the time it holds is meaningless
+    OscillatorStopped,
+    InvalidTime,
+}
+
+impl fmt::Display for RtcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RtcError::Bus(e) => write!(f, "RTC I2C error: {}", e),
+            RtcError::OscillatorStopped => write!(f, "RTC oscillator was stopped, time is not set"),
+            RtcError::InvalidTime => write!(f, "RTC holds an invalid time"),
+        }
+    }
+}
+
+impl Error for RtcError {}
+
+pub type Bme280Result<T> = Result<T, Bme280Error>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bme280Error {
+    Bus(String),
+    //something else answered on the address
+    UnknownChip(u8),
+    //the forced measurement never finished
+    Timeout,
+}
+
+impl fmt::Display for Bme280Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bme280Error::Bus(e) => write!(f, "BME280 I2C error: {}", e),
+            Bme280Error::UnknownChip(id) => write!(f, "Chip ID {:#04x} isn't a BME280 or BMP280", id),
+            Bme280Error::Timeout => write!(f, "BME280 measurement timed out"),
+        }
+    }
+}
+
+impl Error for Bme280Error {}
+
+pub type LightSensorResult<T> = Result<T, LightSensorError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LightSensorError {
+    Bus(String),
+    //the TSL2561's ID register held something else
+    UnknownChip(u8),
+}
+
+impl fmt::Display for LightSensorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LightSensorError::Bus(e) => write!(f, "Light sensor I2C error: {}", e),
+            LightSensorError::UnknownChip(id) => write!(f, "Chip ID {:#04x} isn't a TSL2561", id),
+        }
+    }
+}
+
+impl Error for LightSensorError {}
+
+pub type Co2Result<T> = Result<T, Co2Error>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Co2Error {
+    Bus(String),
+    //a word's CRC didn't match
+    Checksum,
+    //no new measurement within the measurement period
+    NotReady,
+}
+
+impl fmt::Display for Co2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Co2Error::Bus(e) => write!(f, "CO2 sensor I2C error: {}", e),
+            Co2Error::Checksum => write!(f, "CO2 sensor CRC mismatch"),
+            Co2Error::NotReady => write!(f, "CO2 sensor has no new measurement"),
+        }
+    }
+}
+
+impl Error for Co2Error {}
+
+pub type ParticulateResult<T> = Result<T, ParticulateError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParticulateError {
+    Io(String),
+    Checksum,
+    //a PMS5003 frame always has 28 bytes after the length
+    BadLength(u16),
+}
+
+impl fmt::Display for ParticulateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParticulateError::Io(e) => write!(f, "PMS5003 serial error: {}", e),
+            ParticulateError::Checksum => write!(f, "PMS5003 frame checksum mismatch"),
+            ParticulateError::BadLength(len) => write!(f, "PMS5003 frame has length {}", len),
+        }
+    }
+}
+
+impl Error for ParticulateError {}
+
+pub type NmeaResult<T> = Result<T, NmeaError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NmeaError {
+    NotASentence,
+    BadChecksum,
+    BadField(&'static str),
+}
+
+impl fmt::Display for NmeaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NmeaError::NotASentence => write!(f, "Not an NMEA sentence"),
+            NmeaError::BadChecksum => write!(f, "NMEA checksum is missing or wrong"),
+            NmeaError::BadField(field) => write!(f, "NMEA sentence has a bad {} field", field),
+        }
+    }
+}
+
+impl Error for NmeaError {}
+
+pub type W1Result<T> = Result<T, W1Error>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum W1Error {
+    NoDevices(String),
+    Read(String),
+    //the kernel's CRC check of the scratchpad failed
+    CrcMismatch,
+    BadFormat,
+}
+
+impl fmt::Display for W1Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            W1Error::NoDevices(dir) => write!(f, "No DS18B20s under {}", dir),
+            W1Error::Read(e) => write!(f, "Couldn't read w1_slave: {}", e),
+            W1Error::CrcMismatch => write!(f, "DS18B20 CRC check failed"),
+            W1Error::BadFormat => write!(f, "w1_slave isn't in the expected format"),
+        }
+    }
+}
+
+impl Error for W1Error {}
+
+pub type SensorResult<T> = Result<T, SensorError>;
+
+/// Why one measurement of a sensor couldn't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SensorError {
+    //the data came back but failed its CRC or checksum, usually noise on the wires
+    Checksum,
+    //a reading that came back fine but can't be right
+    Rejected(String),
+    Failed(String),
+    //nothing to report yet, like a rate before there are pulses to time
+    Pending,
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SensorError::Checksum => write!(f, "Checksum mismatch"),
+            SensorError::Rejected(e) => write!(f, "Rejected {}", e),
+            SensorError::Failed(e) => write!(f, "{}", e),
+            SensorError::Pending => write!(f, "No reading yet"),
+        }
+    }
+}
+
+impl Error for SensorError {}
+
+impl From<W1Error> for SensorError {
+    fn from(e: W1Error) -> SensorError {
+        match e {
+            W1Error::CrcMismatch => SensorError::Checksum,
+            e => SensorError::Failed(e.to_string()),
+        }
+    }
+}
+
+pub type ControlResult<T> = Result<T, ControlError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlError {
+    BadRequest(String),
+    NotFound,
+    //a path the HTTP API has, asked for with another method
+    MethodNotAllowed,
+    //the display loop has gone away
+    ClockStopped,
+    ConfigNotSaved(String),
+    //a request body over the HTTP API's limit
+    TooLarge,
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControlError::BadRequest(e) => write!(f, "{}", e),
+            ControlError::NotFound => write!(f, "No such command"),
+            ControlError::MethodNotAllowed => write!(f, "Method not allowed"),
+            ControlError::ClockStopped => write!(f, "The clock is not running"),
+            ControlError::ConfigNotSaved(e) => write!(f, "Couldn't save the config: {}", e),
+            ControlError::TooLarge => write!(f, "Request body too large"),
+        }
+    }
+}
+
+impl Error for ControlError {}
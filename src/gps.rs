@@ -0,0 +1,443 @@
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use chrono::prelude::*;
+use chrono::Duration;
+use rppal::gpio::{Gpio, Trigger};
+use rppal::uart::{Parity, Uart};
+use serde::Deserialize;
+
+use crate::errors::{NmeaError, NmeaResult};
+use crate::time_sync::{step_system_clock, SyncStatus};
+
+/// The `[gps]` section of the config
+#[derive(Debug, Clone, Deserialize)]
+pub struct GpsConfig {
+    //a serial port, or one end of a pty pair to play back recorded sentences
+    pub device: String,
+    #[serde(default = "GpsConfig::default_baud")]
+    pub baud: u32,
+    //BCM pin wired to the receiver's pulse-per-second output
+    pub pps_pin: Option<u8>,
+    //without PPS the sentences only give the time to a few hundred ms
+    #[serde(default = "GpsConfig::default_tolerance_ms")]
+    pub tolerance_ms: i64,
+    #[serde(default = "GpsConfig::default_max_age_seconds")]
+    pub max_age_seconds: i64,
+    //step the system clock when it is out of tolerance, needs root
+    #[serde(default)]
+    pub step_system_clock: bool,
+}
+
+impl GpsConfig {
+    fn default_baud() -> u32 {
+        9600
+    }
+    fn default_tolerance_ms() -> i64 {
+        500
+    }
+    fn default_max_age_seconds() -> i64 {
+        60
+    }
+
+    pub fn new_sync_status(&self) -> SyncStatus {
+        SyncStatus::new(
+            Duration::milliseconds(self.tolerance_ms),
+            Duration::seconds(self.max_age_seconds),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NmeaSentence {
+    //recommended minimum data, only trustworthy while the status is valid
+    Rmc { time: Option<NaiveDateTime>, valid: bool },
+    Zda { time: Option<NaiveDateTime> },
+    Other,
+}
+
+impl NmeaSentence {
+    /// Parses one line such as `$GPRMC,...*hh`, from any talker, checking the checksum
+    pub fn parse(line: &str) -> NmeaResult<NmeaSentence> {
+        let body = line.trim().strip_prefix('$').ok_or(NmeaError::NotASentence)?;
+        let (data, checksum) = body.split_once('*').ok_or(NmeaError::BadChecksum)?;
+        let expected = u8::from_str_radix(checksum, 16).map_err(|_| NmeaError::BadChecksum)?;
+        if data.bytes().fold(0u8, |acc, b| acc ^ b) != expected {
+            return Err(NmeaError::BadChecksum);
+        }
+        let fields: Vec<&str> = data.split(',').collect();
+        let kind = fields[0].get(2..).ok_or(NmeaError::NotASentence)?;
+        let field = |i: usize| fields.get(i).copied().unwrap_or("");
+        match kind {
+            "RMC" => {
+                let valid = field(2) == "A";
+                let time = match (field(1), field(9)) {
+                    ("", _) | (_, "") => None,
+                    (t, d) => {
+                        let date = NaiveDate::parse_from_str(d, "%d%m%y")
+                            .map_err(|_| NmeaError::BadField("date"))?;
+                        Some(date.and_time(parse_time(t)?))
+                    }
+                };
+                Ok(NmeaSentence::Rmc { time, valid })
+            }
+            "ZDA" => {
+                let time = match (field(1), field(2), field(3), field(4)) {
+                    ("", _, _, _) | (_, "", _, _) | (_, _, "", _) | (_, _, _, "") => None,
+                    (t, d, m, y) => {
+                        let number = |s: &str| s.parse::<u32>().map_err(|_| NmeaError::BadField("date"));
+                        let date = NaiveDate::from_ymd_opt(number(y)? as i32, number(m)?, number(d)?)
+                            .ok_or(NmeaError::BadField("date"))?;
+                        Some(date.and_time(parse_time(t)?))
+                    }
+                };
+                Ok(NmeaSentence::Zda { time })
+            }
+            _ => Ok(NmeaSentence::Other),
+        }
+    }
+}
+
+//hhmmss with optional fractional seconds
+fn parse_time(t: &str) -> NmeaResult<NaiveTime> {
+    let (hms, fraction) = t.split_once('.').unwrap_or((t, ""));
+    let number = |s: &str| s.parse::<u32>().map_err(|_| NmeaError::BadField("time"));
+    //sliced by the byte below, and parse would take a sign
+    if hms.len() != 6 || !hms.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit()) {
+        return Err(NmeaError::BadField("time"));
+    }
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        let digits = &fraction[..fraction.len().min(9)];
+        number(digits)? * 10u32.pow(9 - digits.len() as u32)
+    };
+    NaiveTime::from_hms_nano_opt(number(&hms[0..2])?, number(&hms[2..4])?, number(&hms[4..6])?, nanos)
+        .ok_or(NmeaError::BadField("time"))
+}
+
+pub struct UartReader(pub Uart);
+
+impl Read for UartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(io::Error::other)
+    }
+}
+
+/// Takes the time from a serial GPS receiver. With a PPS pin the sentences only label the
+/// second that the last pulse started, so the offset is as good as the pulse timing, and
+/// the drivers line their seconds tick up with the pulses.
+pub struct GpsClock {
+    config: GpsConfig,
+    sync_lock: Arc<RwLock<SyncStatus>>,
+    fix_valid: Option<bool>,
+    last_time: Option<NaiveDateTime>,
+}
+
+impl GpsClock {
+    pub fn new(config: GpsConfig, sync_lock: Arc<RwLock<SyncStatus>>) -> GpsClock {
+        GpsClock {
+            config,
+            sync_lock,
+            fix_valid: None,
+            last_time: None,
+        }
+    }
+
+    //NB: this is blocking and should only be run in a separate thread
+    pub fn run(config: GpsConfig, sync_lock: Arc<RwLock<SyncStatus>>) {
+        let mut gps = GpsClock::new(config, sync_lock);
+        loop {
+            if let Err(e) = gps.run_receiver() {
+                println!("GPS on {} failed: {}", gps.config.device, e);
+            }
+            thread::sleep(std::time::Duration::from_secs(10));
+        }
+    }
+
+    fn run_receiver(&mut self) -> Result<(), Box<dyn Error>> {
+        //held until the receiver fails, which drops the interrupt with it
+        let _pps_pin = match self.config.pps_pin {
+            Some(pin) => {
+                let mut pps = Gpio::new()?.get(pin)?.into_input();
+                let pps_lock = self.sync_lock.clone();
+                pps.set_async_interrupt(Trigger::RisingEdge, move |_| {
+                    pps_lock.write().unwrap().record_pps(Utc::now())
+                })?;
+                Some(pps)
+            }
+            None => None,
+        };
+        let mut uart = Uart::with_path(&self.config.device, self.config.baud, Parity::None, 8, 1)?;
+        uart.set_read_mode(1, std::time::Duration::default())?;
+        self.read_sentences(BufReader::new(UartReader(uart)))?;
+        Err("GPS device closed".into())
+    }
+
+    pub fn read_sentences<R: BufRead>(&mut self, mut reader: R) -> io::Result<()> {
+        let mut bytes = vec![];
+        loop {
+            bytes.clear();
+            if reader.read_until(b'\n', &mut bytes)? == 0 {
+                return Ok(());
+            }
+            //noise on the line isn't UTF-8, the checksum throws out a sentence it hit and
+            //anything before the $ is dropped
+            let line = String::from_utf8_lossy(&bytes);
+            let line = line.find('$').map_or(&*line, |start| &line[start..]);
+            self.handle_line(line.trim_end_matches(&['\r', '\n'][..]), Utc::now());
+        }
+    }
+
+    pub fn handle_line(&mut self, line: &str, received: DateTime<Utc>) {
+        let time = match NmeaSentence::parse(line) {
+            Ok(NmeaSentence::Rmc { time, valid }) => {
+                self.fix_valid = Some(valid);
+                time.filter(|_| valid)
+            }
+            //ZDA has no status of its own, so go by the last RMC if there is one
+            Ok(NmeaSentence::Zda { time }) => time.filter(|_| self.fix_valid != Some(false)),
+            Ok(NmeaSentence::Other) => None,
+            Err(NmeaError::NotASentence) => None,
+            Err(e) => {
+                println!("Skipping GPS sentence {:?}: {}", line, e);
+                None
+            }
+        };
+        if let Some(t) = time {
+            //RMC and ZDA both arrive for the same second
+            if self.last_time != Some(t) {
+                self.last_time = Some(t);
+                self.record(t, received);
+            }
+        }
+    }
+
+    fn record(&mut self, gps_time: NaiveDateTime, received: DateTime<Utc>) {
+        let pps_edge = self.sync_lock.read().unwrap().recent_pps_edge();
+        let system_time = match pps_edge {
+            Some(edge) if edge <= received => edge,
+            _ => received,
+        };
+        let mut offset = Utc.from_utc_datetime(&gps_time) - system_time;
+        let tolerance = Duration::milliseconds(self.config.tolerance_ms);
+        if self.config.step_system_clock && (offset > tolerance || -offset > tolerance) {
+            match step_system_clock(offset) {
+                Ok(()) => {
+                    println!("Stepped system clock by {}ms to GPS time", offset.num_milliseconds());
+                    offset = Duration::zero();
+                }
+                Err(e) => println!("Stepping system clock failed: {}", e),
+            }
+        }
+        let source = match pps_edge {
+            Some(_) => format!("gps+pps {}", self.config.device),
+            None => format!("gps {}", self.config.device),
+        };
+        self.sync_lock
+            .write()
+            .unwrap()
+            .record(source, offset, Duration::zero(), Some(0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use crate::mock_serial::raw_pty;
+
+    //adds the checksum
+    fn sentence(data: &str) -> String {
+        format!("${}*{:02X}", data, data.bytes().fold(0u8, |acc, b| acc ^ b))
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32, milli: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, mo, d)
+            .unwrap()
+            .and_hms_milli_opt(h, mi, s, milli)
+            .unwrap()
+    }
+
+    fn gps_clock() -> GpsClock {
+        let config: GpsConfig = toml::from_str("device = \"/dev/pts/99\"").unwrap();
+        let sync_lock = Arc::new(RwLock::new(config.new_sync_status()));
+        GpsClock::new(config, sync_lock)
+    }
+
+    #[test]
+    fn parses_rmc() {
+        let rmc = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A";
+        assert_eq!(
+            NmeaSentence::parse(rmc),
+            Ok(NmeaSentence::Rmc {
+                time: Some(utc(1994, 3, 23, 12, 35, 19, 0)),
+                valid: true
+            })
+        );
+        //any talker, and a receiver without a fix yet
+        assert_eq!(
+            NmeaSentence::parse(&sentence("GNRMC,000001.00,V,,,,,,,010124,,,N")),
+            Ok(NmeaSentence::Rmc {
+                time: Some(utc(2024, 1, 1, 0, 0, 1, 0)),
+                valid: false
+            })
+        );
+        assert_eq!(
+            NmeaSentence::parse(&sentence("GPRMC,,V,,,,,,,,,,N")),
+            Ok(NmeaSentence::Rmc { time: None, valid: false })
+        );
+    }
+
+    #[test]
+    fn parses_zda() {
+        assert_eq!(
+            NmeaSentence::parse("$GPZDA,201530.00,04,07,2002,00,00*60"),
+            Ok(NmeaSentence::Zda {
+                time: Some(utc(2002, 7, 4, 20, 15, 30, 0))
+            })
+        );
+        assert_eq!(
+            NmeaSentence::parse(&sentence("GPZDA,201530.00,,,,00,00")),
+            Ok(NmeaSentence::Zda { time: None })
+        );
+        assert_eq!(
+            NmeaSentence::parse(&sentence("GPZDA,201530.00,31,02,2024,00,00")),
+            Err(NmeaError::BadField("date"))
+        );
+        assert_eq!(
+            NmeaSentence::parse(&sentence("GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,")),
+            Ok(NmeaSentence::Other)
+        );
+    }
+
+    #[test]
+    fn checks_the_checksum() {
+        let rmc = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W";
+        assert_eq!(NmeaSentence::parse(&format!("{}*6B", rmc)), Err(NmeaError::BadChecksum));
+        assert_eq!(NmeaSentence::parse(rmc), Err(NmeaError::BadChecksum));
+        assert_eq!(NmeaSentence::parse(&format!("{}*ZZ", rmc)), Err(NmeaError::BadChecksum));
+        assert_eq!(NmeaSentence::parse("GPRMC,123519*00"), Err(NmeaError::NotASentence));
+        assert_eq!(NmeaSentence::parse(""), Err(NmeaError::NotASentence));
+    }
+
+    #[test]
+    fn fractions_of_a_second() {
+        let times = vec![
+            ("123519.5", 500_000_000),
+            ("123519.05", 50_000_000),
+            ("123519.123", 123_000_000),
+            ("123519.", 0),
+            //finer than a nanosecond is dropped
+            ("123519.1234567891", 123_456_789),
+        ];
+        for (t, nanos) in times {
+            assert_eq!(parse_time(t), Ok(NaiveTime::from_hms_nano_opt(12, 35, 19, nanos).unwrap()), "{}", t);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_times() {
+        let times = vec!["12351", "1235190", "+12351", "12:5:1", "12351é", "1235é", "123519.5é", "123519.-5", "246060"];
+        for t in times {
+            assert_eq!(parse_time(t), Err(NmeaError::BadField("time")), "{}", t);
+            let rmc = sentence(&format!("GPRMC,{},A,,,,,,,230394,,,A", t));
+            assert_eq!(NmeaSentence::parse(&rmc), Err(NmeaError::BadField("time")), "{}", t);
+        }
+    }
+
+    #[test]
+    fn follows_the_fix() {
+        let mut gps = gps_clock();
+        let received = Utc::now();
+        gps.handle_line(&sentence("GPRMC,120000,V,,,,,,,010124,,,N"), received);
+        //ZDA after a void RMC is no better
+        gps.handle_line(&sentence("GPZDA,120000.00,01,01,2024,00,00"), received);
+        assert_eq!(gps.sync_lock.read().unwrap().source, None);
+        gps.handle_line(&sentence("GPRMC,120001,A,,,,,,,010124,,,A"), received);
+        gps.handle_line(&sentence("GPZDA,120001.00,01,01,2024,00,00"), received);
+        let status = gps.sync_lock.read().unwrap().clone();
+        assert_eq!(status.source.as_deref(), Some("gps /dev/pts/99"));
+        let expected = Utc.from_utc_datetime(&utc(2024, 1, 1, 12, 0, 1, 0)) - received;
+        assert_eq!(status.offset, Some(expected));
+        assert_eq!(gps.last_time, Some(utc(2024, 1, 1, 12, 0, 1, 0)));
+    }
+
+    #[test]
+    fn reads_sentences_from_a_serial_port() {
+        let (mut port, device) = raw_pty().unwrap();
+        let mut gps = gps_clock();
+        let sync_lock = gps.sync_lock.clone();
+        let reader = thread::spawn(move || gps.read_sentences(BufReader::new(device)));
+        let now = Utc::now();
+        let rmc = sentence(&format!(
+            "GPRMC,{},A,4807.038,N,01131.000,E,0.0,0.0,{},,,A",
+            now.format("%H%M%S%.3f"),
+            now.format("%d%m%y")
+        ));
+        //a receiver just plugged in starts part way through a sentence, and sends other kinds too
+        port.write_all(b"38,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n").unwrap();
+        port.write_all(sentence("GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1").as_bytes()).unwrap();
+        port.write_all(b"\r\n").unwrap();
+        port.write_all(rmc.as_bytes()).unwrap();
+        port.write_all(b"\r\n").unwrap();
+        let started = std::time::Instant::now();
+        while sync_lock.read().unwrap().source.is_none() && started.elapsed().as_secs() < 5 {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        //the receiver going away ends the read
+        drop(port);
+        assert!(reader.join().unwrap().is_err());
+        let status = sync_lock.read().unwrap().clone();
+        assert_eq!(status.source.as_deref(), Some("gps /dev/pts/99"));
+        assert!(status.offset.unwrap().num_milliseconds().abs() < 1000, "{:?}", status.offset);
+    }
+
+    #[test]
+    fn skips_line_noise() {
+        let mut gps = gps_clock();
+        let good = sentence("GPRMC,120001,A,,,,,,,010124,,,A");
+        //a byte hit by noise in a sentence that was otherwise good
+        let mut noisy = sentence("GPRMC,120000,A,,,,,,,010124,,,A").into_bytes();
+        noisy[8] = 0xFF;
+        let mut input = vec![0xFF, 0xFE, b'\r', b'\n'];
+        input.extend(noisy);
+        input.extend(b"\r\n");
+        assert!(gps.read_sentences(io::Cursor::new(input)).is_ok());
+        assert_eq!(gps.last_time, None);
+        //and a stray byte before the $
+        let mut input = vec![0xFF];
+        input.extend(good.as_bytes());
+        input.extend(b"\r\n");
+        assert!(gps.read_sentences(io::Cursor::new(input)).is_ok());
+        assert_eq!(gps.last_time, Some(utc(2024, 1, 1, 12, 0, 1, 0)));
+    }
+
+    #[test]
+    fn keeps_reading_a_noisy_serial_port() {
+        let (mut port, device) = raw_pty().unwrap();
+        let mut gps = gps_clock();
+        let sync_lock = gps.sync_lock.clone();
+        let reader = thread::spawn(move || gps.read_sentences(BufReader::new(device)));
+        let now = Utc::now();
+        let rmc = sentence(&format!(
+            "GPRMC,{},A,4807.038,N,01131.000,E,0.0,0.0,{},,,A",
+            now.format("%H%M%S%.3f"),
+            now.format("%d%m%y")
+        ));
+        port.write_all(&[0xFF]).unwrap();
+        port.write_all(rmc.as_bytes()).unwrap();
+        port.write_all(b"\r\n").unwrap();
+        let started = std::time::Instant::now();
+        while sync_lock.read().unwrap().source.is_none() && started.elapsed().as_secs() < 5 {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        //the noise doesn't end the read, and the sentence after it is taken
+        assert!(!reader.is_finished());
+        drop(port);
+        assert!(reader.join().unwrap().is_err());
+        assert_eq!(sync_lock.read().unwrap().source.as_deref(), Some("gps /dev/pts/99"));
+    }
+}
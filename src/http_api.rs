@@ -0,0 +1,309 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::SocketAddr;
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::control::{ClockControl, ControlRequest};
+use crate::config_ui::CONFIG_HTML;
+use crate::display_stream::{DisplayStream, NIXIE_JS, VIEWER_HTML};
+use crate::errors::{ControlError, ControlResult};
+
+//every path answered, for telling a wrong method from a wrong path
+const PATHS: &[&str] = &[
+    "/",
+    "/nixie.js",
+    "/stream",
+    "/metrics",
+    "/state",
+    "/sensors",
+    "/brightness",
+    "/mode",
+    "/message",
+    "/anti_poison",
+    "/config",
+    "/config/current",
+    "/config/check",
+    "/config/preview",
+    "/config/preview/stream",
+];
+
+//the config editor's TOML is the biggest thing sent, at a few kB
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+/// The `[http]` section of the config. Anyone who can reach the API can change the clock
+/// and its config file, so it only listens on localhost unless told otherwise.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpConfig {
+    #[serde(default = "HttpConfig::default_listen")]
+    pub listen: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            listen: HttpConfig::default_listen(),
+        }
+    }
+}
+
+impl HttpConfig {
+    fn default_listen() -> String {
+        "127.0.0.1:8080".to_string()
+    }
+}
+
+/// A small JSON REST API for scripts and dashboards
+///
+/// - `GET /state` what is showing, and the time sync
+/// - `GET /sensors` the latest sensor readings
+/// - `PUT /brightness` `{"brightness": 0.5}`, or `null` to follow the schedule again
+/// - `PUT /mode` `{"mode": "date"}`, with `format` or `countdown_to` as in the schedule
+/// - `POST /message` `{"text": "12:34:56", "seconds": 5}`
+/// - `POST /anti_poison` runs every tube through its cathodes
+/// - `GET /` a page drawing the tubes live from `GET /stream`, a WebSocket of what they show
+/// - `GET /config` a page for editing the config file, previewing it on a simulated clock
+///   and applying it, backed by `GET`/`PUT /config/current`, `POST /config/check` and
+///   `POST /config/preview`
+/// - `GET /metrics` frame timing, SPI and sensor failures and cathode wear for Prometheus
+pub struct HttpApi {}
+
+impl HttpApi {
+    pub async fn run(config: HttpConfig, control: ClockControl) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let address: SocketAddr = config.listen.parse()?;
+        let make_service = make_service_fn(move |_| {
+            let control = control.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| HttpApi::handle(control.clone(), request)))
+            }
+        });
+        println!("HTTP API listening on {}", address);
+        Server::try_bind(&address)?.serve(make_service).await?;
+        Ok(())
+    }
+
+    async fn handle(control: ClockControl, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let method = request.method().clone();
+        let path = request.uri().path().to_string();
+        if method == Method::GET {
+            let page = |content_type: &str, body: &'static str| {
+                Ok(Response::builder()
+                    .header("Content-Type", content_type)
+                    .body(Body::from(body))
+                    .unwrap())
+            };
+            match path.as_str() {
+                "/metrics" => {
+                    return Ok(Response::builder()
+                        .header("Content-Type", "text/plain; version=0.0.4")
+                        .body(Body::from(control.metrics()))
+                        .unwrap())
+                }
+                "/" => return page("text/html; charset=utf-8", VIEWER_HTML),
+                "/config" => return page("text/html; charset=utf-8", CONFIG_HTML),
+                "/nixie.js" => return page("application/javascript", NIXIE_JS),
+                "/stream" => return Ok(DisplayStream::upgrade(request, control.display_lock.clone())),
+                "/config/preview/stream" => {
+                    return Ok(DisplayStream::upgrade(
+                        request,
+                        control.config_editor.preview_lock.clone(),
+                    ))
+                }
+                _ => (),
+            }
+        }
+        let body = match read_body(request.into_body()).await {
+            Ok(body) => body,
+            Err(e) => return Ok(HttpApi::respond(Err(e))),
+        };
+        let editor = &control.config_editor;
+        let result = match (method, path.as_str()) {
+            (Method::GET, "/state") => control.execute(ControlRequest::Status),
+            (Method::GET, "/sensors") => control.execute(ControlRequest::Sensors),
+            (Method::PUT, "/brightness") => from_json(&body).and_then(|r| control.execute(ControlRequest::Brightness(r))),
+            (Method::PUT, "/mode") => from_json(&body).and_then(|r| control.execute(ControlRequest::Mode(r))),
+            (Method::POST, "/message") => from_json(&body).and_then(|r| control.execute(ControlRequest::Show(r))),
+            (Method::POST, "/anti_poison") => control.execute(ControlRequest::AntiPoison),
+            (Method::GET, "/config/current") => editor.current(),
+            (Method::POST, "/config/check") => from_json(&body).and_then(|r| editor.check(r)),
+            (Method::POST, "/config/preview") => from_json(&body).and_then(|r| editor.preview(r)),
+            (Method::PUT, "/config/current") => from_json(&body).and_then(|r| editor.apply(r)),
+            (_, path) if PATHS.contains(&path) => Err(ControlError::MethodNotAllowed),
+            _ => Err(ControlError::NotFound),
+        };
+        Ok(HttpApi::respond(result))
+    }
+
+    fn respond(result: ControlResult<String>) -> Response<Body> {
+        let (status, json) = match result {
+            Ok(json) => (StatusCode::OK, json),
+            Err(e) => {
+                let status = match e {
+                    ControlError::BadRequest(_) => StatusCode::BAD_REQUEST,
+                    ControlError::NotFound => StatusCode::NOT_FOUND,
+                    ControlError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+                    ControlError::ClockStopped => StatusCode::SERVICE_UNAVAILABLE,
+                    ControlError::ConfigNotSaved(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                    ControlError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+                };
+                (status, serde_json::json!({ "error": e.to_string() }).to_string())
+            }
+        };
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json))
+            .unwrap()
+    }
+}
+
+//stops reading as soon as the body is too big, rather than buffering whatever is sent
+async fn read_body(mut body: Body) -> ControlResult<Vec<u8>> {
+    let declared = body.size_hint().lower() as usize;
+    if declared > MAX_BODY_BYTES {
+        return Err(ControlError::TooLarge);
+    }
+    let mut bytes = Vec::with_capacity(declared);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| ControlError::BadRequest(e.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(ControlError::TooLarge);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn from_json<T: DeserializeOwned>(body: &[u8]) -> ControlResult<T> {
+    serde_json::from_slice(body).map_err(|e| ControlError::BadRequest(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock_objects::ClockType;
+    use crate::control::ClockCommand;
+
+    //straight through the service, no socket needed
+    async fn call(control: &ClockControl, method: Method, path: &str, body: &str) -> (StatusCode, String, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = HttpApi::handle(control.clone(), request).await.unwrap();
+        let status = response.status();
+        let content_type = response.headers()["Content-Type"].to_str().unwrap().to_string();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, content_type, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn listens_on_localhost_by_default() {
+        let config: HttpConfig = toml::from_str("").unwrap();
+        assert_eq!(config.listen, "127.0.0.1:8080");
+    }
+
+    #[tokio::test]
+    async fn limits_request_bodies() {
+        assert_eq!(read_body(Body::from("{}")).await, Ok(b"{}".to_vec()));
+        assert_eq!(read_body(Body::from(vec![b' '; MAX_BODY_BYTES + 1])).await, Err(ControlError::TooLarge));
+        //a chunked body has no length up front
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..=MAX_BODY_BYTES / 1024 {
+                if sender.send_data(vec![b' '; 1024].into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        assert_eq!(read_body(body).await, Err(ControlError::TooLarge));
+    }
+
+    #[tokio::test]
+    async fn routes_the_control_endpoints() {
+        let (control, commands) = ClockControl::for_tests(ClockType::NCS3186);
+        let (status, content_type, body) = call(&control, Method::GET, "/state", "").await;
+        assert_eq!((status, content_type.as_str()), (StatusCode::OK, "application/json"));
+        assert!(body.contains(r#""clock_type":"NCS3186""#), "{}", body);
+        assert_eq!(call(&control, Method::GET, "/sensors", "").await.2, "{}");
+        let ok = |(status, _, body): (StatusCode, String, String)| status == StatusCode::OK && body == r#"{"ok":true}"#;
+        assert!(ok(call(&control, Method::PUT, "/brightness", r#"{"brightness": 0.5}"#).await));
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::SetBrightness(Some(b))) if b == 0.5));
+        assert!(ok(call(&control, Method::PUT, "/mode", r#"{"mode": "date"}"#).await));
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::SetMode(Some(_)))));
+        assert!(ok(call(&control, Method::POST, "/message", r#"{"text": "12 34"}"#).await));
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::ShowMessage { .. })));
+        assert!(ok(call(&control, Method::POST, "/anti_poison", "").await));
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::AntiPoison)));
+        let (status, _, body) = call(&control, Method::GET, "/config/current", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""board":"NCS3186""#), "{}", body);
+
+        for (method, path, body) in [
+            (Method::PUT, "/brightness", r#"{"brightness": 2}"#),
+            (Method::PUT, "/brightness", "bright"),
+            (Method::PUT, "/mode", r#"{"mode": "disco"}"#),
+            (Method::POST, "/message", r#"{"seconds": 5}"#),
+            (Method::POST, "/config/check", r#"{"toml": "[display"}"#),
+        ] {
+            let (status, _, response) = call(&control, method, path, body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{} {}", path, body);
+            assert!(response.starts_with(r#"{"error":"#), "{}", response);
+        }
+        assert!(commands.try_recv().is_err());
+        drop(commands);
+        assert_eq!(call(&control, Method::POST, "/anti_poison", "").await.0, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn serves_the_pages() {
+        let (control, _commands) = ClockControl::for_tests(ClockType::NCS3186);
+        for (path, content_type) in [
+            ("/", "text/html; charset=utf-8"),
+            ("/config", "text/html; charset=utf-8"),
+            ("/nixie.js", "application/javascript"),
+            ("/metrics", "text/plain; version=0.0.4"),
+        ] {
+            let (status, served, _) = call(&control, Method::GET, path, "").await;
+            assert_eq!((status, served.as_str()), (StatusCode::OK, content_type), "{}", path);
+        }
+        //the streams only answer WebSocket upgrades
+        let request = Request::get("/stream").body(Body::empty()).unwrap();
+        let response = HttpApi::handle(control.clone(), request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn tells_wrong_paths_from_wrong_methods() {
+        let (control, commands) = ClockControl::for_tests(ClockType::NCS3186);
+        for (method, path) in [
+            (Method::GET, "/nope"),
+            (Method::POST, "/nope"),
+            (Method::GET, "/state/"),
+            (Method::PUT, "/brightness/1"),
+        ] {
+            let (status, _, body) = call(&control, method, path, "").await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+            assert_eq!(body, r#"{"error":"No such command"}"#);
+        }
+        for (method, path) in [
+            (Method::GET, "/brightness"),
+            (Method::POST, "/brightness"),
+            (Method::DELETE, "/state"),
+            (Method::PUT, "/anti_poison"),
+            (Method::POST, "/metrics"),
+            (Method::PUT, "/"),
+            (Method::POST, "/config/current"),
+            (Method::GET, "/config/preview"),
+        ] {
+            let (status, _, body) = call(&control, method, path, "").await;
+            assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED, "{}", path);
+            assert_eq!(body, r#"{"error":"Method not allowed"}"#);
+        }
+        assert!(commands.try_recv().is_err());
+    }
+}
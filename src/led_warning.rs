@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+
+use crate::rgb_driver::LedColor;
+use crate::sensors::SensorBus;
+
+//readings only come every few seconds
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `[[display.led_warnings]]`, lighting the LEDs while a measurement is over a threshold
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LedWarningConfig {
+    pub sensor: String,
+    pub above: f32,
+    pub led: LedColor,
+}
+
+/// Which warning, if any, the LEDs show. Later warnings win, so the worst goes last.
+#[derive(Debug, Clone, Default)]
+pub struct LedWarnings {
+    led: Option<LedColor>,
+    checked_at: Option<Instant>,
+}
+
+impl LedWarnings {
+    pub fn update(&mut self, warnings: &[LedWarningConfig], sensors: &SensorBus, now: Instant) -> Option<LedColor> {
+        if warnings.is_empty() {
+            return None;
+        }
+        if self.checked_at.is_some_and(|c| now.duration_since(c) < CHECK_INTERVAL) {
+            return self.led;
+        }
+        self.checked_at = Some(now);
+        //a stale reading can't warn of anything
+        let warning = warnings
+            .iter()
+            .rev()
+            .find(|w| sensors.latest(&w.sensor).is_some_and(|r| r.value > w.above));
+        let led = warning.map(|w| w.led);
+        if led != self.led {
+            match warning {
+                Some(w) => println!("{} is over {}, LEDs {:?}", w.sensor, w.above, w.led),
+                None => println!("Sensor warnings cleared"),
+            }
+            self.led = led;
+        }
+        led
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use tokio::sync::watch;
+
+    use crate::sensors::{Reading, SensorState, Unit};
+
+    fn set(sender: &watch::Sender<SensorState>, value: f32, age: chrono::Duration) {
+        sender.send_modify(|s| {
+            s.reading = Some(Reading {
+                value,
+                raw: value,
+                unit: s.unit,
+                taken_at: Local::now() - age,
+            })
+        });
+    }
+
+    fn warnings() -> Vec<LedWarningConfig> {
+        vec![
+            LedWarningConfig {
+                sensor: "co2".to_string(),
+                above: 1000f32,
+                led: LedColor::Yellow,
+            },
+            LedWarningConfig {
+                sensor: "co2".to_string(),
+                above: 1500f32,
+                led: LedColor::Red,
+            },
+            LedWarningConfig {
+                sensor: "pm2_5".to_string(),
+                above: 35f32,
+                led: LedColor::Magenta,
+            },
+        ]
+    }
+
+    #[test]
+    fn lights_over_the_threshold() {
+        let bus = SensorBus::default();
+        let co2 = bus.register("co2", Unit::PartsPerMillion, chrono::Duration::minutes(2));
+        let mut leds = LedWarnings::default();
+        let start = Instant::now();
+        assert_eq!(leds.update(&warnings(), &bus, start), None);
+        set(&co2, 1000f32, chrono::Duration::zero());
+        assert_eq!(leds.update(&warnings(), &bus, start + CHECK_INTERVAL), None);
+        set(&co2, 1200f32, chrono::Duration::zero());
+        assert_eq!(leds.update(&warnings(), &bus, start + CHECK_INTERVAL * 2), Some(LedColor::Yellow));
+        //only checked once a second
+        set(&co2, 600f32, chrono::Duration::zero());
+        let soon = start + CHECK_INTERVAL * 2 + Duration::from_millis(500);
+        assert_eq!(leds.update(&warnings(), &bus, soon), Some(LedColor::Yellow));
+        assert_eq!(leds.update(&warnings(), &bus, start + CHECK_INTERVAL * 3), None);
+        assert_eq!(leds.update(&[], &bus, start + CHECK_INTERVAL * 4), None);
+    }
+
+    #[test]
+    fn later_warnings_win() {
+        let bus = SensorBus::default();
+        let co2 = bus.register("co2", Unit::PartsPerMillion, chrono::Duration::minutes(2));
+        let pm2_5 = bus.register("pm2_5", Unit::MicrogramsPerCubicMetre, chrono::Duration::minutes(2));
+        let mut leds = LedWarnings::default();
+        let start = Instant::now();
+        set(&co2, 1800f32, chrono::Duration::zero());
+        assert_eq!(leds.update(&warnings(), &bus, start), Some(LedColor::Red));
+        set(&pm2_5, 50f32, chrono::Duration::zero());
+        assert_eq!(leds.update(&warnings(), &bus, start + CHECK_INTERVAL), Some(LedColor::Magenta));
+        set(&pm2_5, 10f32, chrono::Duration::zero());
+        assert_eq!(leds.update(&warnings(), &bus, start + CHECK_INTERVAL * 2), Some(LedColor::Red));
+    }
+
+    #[test]
+    fn stale_readings_dont_warn() {
+        let bus = SensorBus::default();
+        let co2 = bus.register("co2", Unit::PartsPerMillion, chrono::Duration::minutes(2));
+        let mut leds = LedWarnings::default();
+        let start = Instant::now();
+        set(&co2, 2000f32, chrono::Duration::zero());
+        assert_eq!(leds.update(&warnings(), &bus, start), Some(LedColor::Red));
+        set(&co2, 2000f32, chrono::Duration::minutes(3));
+        assert_eq!(leds.update(&warnings(), &bus, start + CHECK_INTERVAL), None);
+    }
+}
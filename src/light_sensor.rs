@@ -0,0 +1,398 @@
+use std::error::Error;
+use std::fmt::Debug;
+use std::thread;
+use std::time::Duration;
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use rppal::i2c::I2c;
+use serde::Deserialize;
+
+use crate::errors::{LightSensorError, LightSensorResult, SensorError, SensorResult};
+#[cfg(test)]
+use crate::mock_i2c::MockI2c;
+use crate::sensors::{Sensor, Unit};
+
+const BH1750_POWER_ON: u8 = 0x01;
+//1lx resolution, then it powers down
+const BH1750_ONE_TIME_HIGH_RES: u8 = 0x20;
+const BH1750_MEASUREMENT_TIME: Duration = Duration::from_millis(180);
+//counts per lux at the default measurement time
+const BH1750_COUNTS_PER_LUX: f32 = 1.2;
+
+const TSL2561_COMMAND: u8 = 0x80;
+const TSL2561_WORD: u8 = 0x20;
+const TSL2561_CONTROL: u8 = 0x00;
+const TSL2561_TIMING: u8 = 0x01;
+const TSL2561_ID: u8 = 0x0a;
+const TSL2561_DATA0: u8 = 0x0c;
+const TSL2561_POWER_ON: u8 = 0x03;
+//402ms integration, with bit 4 for 16x gain
+const TSL2561_INTEGRATE_402MS: u8 = 0x02;
+const TSL2561_HIGH_GAIN: u8 = 0x10;
+const TSL2561_INTEGRATION_TIME: Duration = Duration::from_millis(410);
+//high gain saturates around 2000lx, and low gain reads in steps of about 0.5lx
+const TSL2561_GAIN_DOWN_COUNTS: u16 = 50000;
+const TSL2561_GAIN_UP_COUNTS: u16 = 2000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LightChip {
+    #[default]
+    Bh1750,
+    Tsl2561,
+}
+
+impl LightChip {
+    //with the address pin left floating
+    fn default_address(&self) -> u8 {
+        match self {
+            LightChip::Bh1750 => 0x23,
+            LightChip::Tsl2561 => 0x39,
+        }
+    }
+}
+
+/// The `[light]` section of the config, an ambient light sensor on I2C
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LightSensorConfig {
+    pub chip: LightChip,
+    pub bus: u8,
+    //the chip's default when unset
+    pub address: Option<u8>,
+    //bus name of the measurement
+    pub name: String,
+    pub interval_ms: u32,
+}
+
+impl Default for LightSensorConfig {
+    fn default() -> Self {
+        LightSensorConfig {
+            chip: LightChip::Bh1750,
+            bus: 1,
+            address: None,
+            name: "lux".to_string(),
+            interval_ms: 1000,
+        }
+    }
+}
+
+impl LightSensorConfig {
+    pub fn address(&self) -> u8 {
+        self.address.unwrap_or_else(|| self.chip.default_address())
+    }
+}
+
+fn bus_error<E: Debug>(e: E) -> LightSensorError {
+    LightSensorError::Bus(format!("{:?}", e))
+}
+
+/// A ROHM BH1750, which reads lux directly
+pub struct Bh1750<I> {
+    i2c: I,
+    config: LightSensorConfig,
+}
+
+impl Bh1750<I2c> {
+    pub fn open(config: &LightSensorConfig) -> Result<Bh1750<I2c>, Box<dyn Error>> {
+        Ok(Bh1750::new(I2c::with_bus(config.bus)?, config.clone())?)
+    }
+}
+
+impl<I, E> Bh1750<I>
+where
+    I: Read<Error = E> + Write<Error = E>,
+    E: Debug,
+{
+    /// It has no ID to check, so this only sees that something answers on the address
+    pub fn new(mut i2c: I, config: LightSensorConfig) -> LightSensorResult<Bh1750<I>> {
+        i2c.write(config.address(), &[BH1750_POWER_ON]).map_err(bus_error)?;
+        println!("Found a BH1750 at {:#04x} on I2C bus {}", config.address(), config.bus);
+        Ok(Bh1750 { i2c, config })
+    }
+
+    pub fn measure(&mut self) -> LightSensorResult<f32> {
+        let address = self.config.address();
+        self.i2c.write(address, &[BH1750_ONE_TIME_HIGH_RES]).map_err(bus_error)?;
+        thread::sleep(BH1750_MEASUREMENT_TIME);
+        let mut counts = [0u8; 2];
+        self.i2c.read(address, &mut counts).map_err(bus_error)?;
+        Ok(u16::from_be_bytes(counts) as f32 / BH1750_COUNTS_PER_LUX)
+    }
+}
+
+impl<I, E> Sensor for Bh1750<I>
+where
+    I: Read<Error = E> + Write<Error = E> + Send,
+    E: Debug,
+{
+    fn name(&self) -> String {
+        "BH1750".to_string()
+    }
+
+    fn measurements(&self) -> Vec<(String, Unit)> {
+        vec![(self.config.name.clone(), Unit::Lux)]
+    }
+
+    fn read(&mut self) -> Result<Vec<SensorResult<f32>>, Box<dyn Error>> {
+        Ok(vec![Ok(self.measure()?)])
+    }
+
+    fn interval(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(self.config.interval_ms as i64)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Tsl2561Package {
+    //TSL2561T, FN and CL
+    T,
+    Cs,
+}
+
+/// A TAOS/ams TSL2561, with a broadband and an infrared photodiode. It integrates
+/// continuously and switches its gain so the dark and bright ends both read well.
+pub struct Tsl2561<I> {
+    i2c: I,
+    config: LightSensorConfig,
+    package: Tsl2561Package,
+    high_gain: bool,
+}
+
+impl Tsl2561<I2c> {
+    pub fn open(config: &LightSensorConfig) -> Result<Tsl2561<I2c>, Box<dyn Error>> {
+        Ok(Tsl2561::new(I2c::with_bus(config.bus)?, config.clone())?)
+    }
+}
+
+impl<I, E> Tsl2561<I>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    /// Checks the part number and starts it integrating at high gain
+    pub fn new(mut i2c: I, config: LightSensorConfig) -> LightSensorResult<Tsl2561<I>> {
+        let mut id = [0u8];
+        i2c.write_read(config.address(), &[TSL2561_COMMAND | TSL2561_ID], &mut id)
+            .map_err(bus_error)?;
+        //the TSL2560s are the SMBus versions of the same chip
+        let package = match id[0] >> 4 {
+            0x0 | 0x1 => Tsl2561Package::Cs,
+            0x4 | 0x5 => Tsl2561Package::T,
+            _ => return Err(LightSensorError::UnknownChip(id[0])),
+        };
+        let mut sensor = Tsl2561 {
+            i2c,
+            config,
+            package,
+            high_gain: true,
+        };
+        sensor.write_register(TSL2561_CONTROL, TSL2561_POWER_ON)?;
+        sensor.set_gain(true)?;
+        println!(
+            "Found a TSL2561 ({:?} package) at {:#04x} on I2C bus {}",
+            package,
+            sensor.config.address(),
+            sensor.config.bus
+        );
+        Ok(sensor)
+    }
+
+    pub fn measure(&mut self) -> LightSensorResult<SensorResult<f32>> {
+        let (mut broadband, mut infrared) = self.read_channels()?;
+        let too_bright = self.high_gain && broadband > TSL2561_GAIN_DOWN_COUNTS;
+        let too_dark = !self.high_gain && broadband < TSL2561_GAIN_UP_COUNTS;
+        if too_bright || too_dark {
+            self.set_gain(too_dark)?;
+            //a new gain only shows after a whole integration
+            thread::sleep(TSL2561_INTEGRATION_TIME);
+            let channels = self.read_channels()?;
+            broadband = channels.0;
+            infrared = channels.1;
+        }
+        if broadband == u16::MAX || infrared == u16::MAX {
+            return Ok(Err(SensorError::Failed("TSL2561 saturated".to_string())));
+        }
+        //the lux formulas are for high gain
+        let scale = if self.high_gain { 1f32 } else { 16f32 };
+        Ok(Ok(lux(self.package, broadband as f32 * scale, infrared as f32 * scale)))
+    }
+
+    fn read_channels(&mut self) -> LightSensorResult<(u16, u16)> {
+        let mut data = [0u8; 4];
+        self.i2c
+            .write_read(
+                self.config.address(),
+                &[TSL2561_COMMAND | TSL2561_WORD | TSL2561_DATA0],
+                &mut data,
+            )
+            .map_err(bus_error)?;
+        Ok((u16::from_le_bytes([data[0], data[1]]), u16::from_le_bytes([data[2], data[3]])))
+    }
+
+    fn set_gain(&mut self, high: bool) -> LightSensorResult<()> {
+        let gain = if high { TSL2561_HIGH_GAIN } else { 0 };
+        self.write_register(TSL2561_TIMING, TSL2561_INTEGRATE_402MS | gain)?;
+        self.high_gain = high;
+        Ok(())
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> LightSensorResult<()> {
+        self.i2c
+            .write(self.config.address(), &[TSL2561_COMMAND | register, value])
+            .map_err(bus_error)
+    }
+}
+
+//the empirical formulas from the datasheet, by the infrared to broadband ratio
+fn lux(package: Tsl2561Package, broadband: f32, infrared: f32) -> f32 {
+    if broadband == 0f32 {
+        return 0f32;
+    }
+    let ratio = infrared / broadband;
+    let lux = match package {
+        Tsl2561Package::T => match ratio {
+            r if r <= 0.50 => 0.0304 * broadband - 0.062 * broadband * r.powf(1.4),
+            r if r <= 0.61 => 0.0224 * broadband - 0.031 * infrared,
+            r if r <= 0.80 => 0.0128 * broadband - 0.0153 * infrared,
+            r if r <= 1.30 => 0.00146 * broadband - 0.00112 * infrared,
+            _ => 0f32,
+        },
+        Tsl2561Package::Cs => match ratio {
+            r if r <= 0.52 => 0.0315 * broadband - 0.0593 * broadband * r.powf(1.4),
+            r if r <= 0.65 => 0.0229 * broadband - 0.0291 * infrared,
+            r if r <= 0.80 => 0.0157 * broadband - 0.0180 * infrared,
+            r if r <= 1.30 => 0.00338 * broadband - 0.00260 * infrared,
+            _ => 0f32,
+        },
+    };
+    lux.max(0f32)
+}
+
+impl<I, E> Sensor for Tsl2561<I>
+where
+    I: Write<Error = E> + WriteRead<Error = E> + Send,
+    E: Debug,
+{
+    fn name(&self) -> String {
+        "TSL2561".to_string()
+    }
+
+    fn measurements(&self) -> Vec<(String, Unit)> {
+        vec![(self.config.name.clone(), Unit::Lux)]
+    }
+
+    fn read(&mut self) -> Result<Vec<SensorResult<f32>>, Box<dyn Error>> {
+        Ok(vec![self.measure()?])
+    }
+
+    fn interval(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(self.config.interval_ms as i64)
+    }
+}
+
+/// Adds a BH1750 reading `lux` to a mock bus. The one time measurement command doubles as
+/// the register pointer, so its result is kept there.
+#[cfg(test)]
+pub fn add_mock_bh1750(bus: &MockI2c, address: u8, lux: f32) {
+    bus.add_device(address);
+    let counts = (lux * BH1750_COUNTS_PER_LUX).round().min(u16::MAX as f32) as u16;
+    bus.set_registers(address, BH1750_ONE_TIME_HIGH_RES, &counts.to_be_bytes());
+}
+
+/// Adds a TSL2561T to a mock bus, reading the given broadband and infrared counts
+#[cfg(test)]
+pub fn add_mock_tsl2561(bus: &MockI2c, address: u8, broadband: u16, infrared: u16) {
+    bus.add_device(address);
+    bus.set_registers(address, TSL2561_COMMAND | TSL2561_ID, &[0x50]);
+    set_mock_tsl2561_counts(bus, address, broadband, infrared);
+}
+
+#[cfg(test)]
+pub fn set_mock_tsl2561_counts(bus: &MockI2c, address: u8, broadband: u16, infrared: u16) {
+    let mut data = broadband.to_le_bytes().to_vec();
+    data.extend_from_slice(&infrared.to_le_bytes());
+    bus.set_registers(address, TSL2561_COMMAND | TSL2561_WORD | TSL2561_DATA0, &data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: u8 = 0x39;
+
+    fn tsl2561_config() -> LightSensorConfig {
+        LightSensorConfig {
+            chip: LightChip::Tsl2561,
+            ..Default::default()
+        }
+    }
+
+    fn timing(bus: &MockI2c) -> u8 {
+        bus.registers(ADDRESS, TSL2561_COMMAND | TSL2561_TIMING, 1)[0]
+    }
+
+    #[test]
+    fn bh1750_reads_lux() {
+        let bus = MockI2c::new();
+        add_mock_bh1750(&bus, 0x23, 250f32);
+        let mut bh1750 = Bh1750::new(bus, LightSensorConfig::default()).unwrap();
+        assert!((bh1750.measure().unwrap() - 250f32).abs() < 0.5);
+    }
+
+    #[test]
+    fn tsl2561_lux_by_infrared_ratio() {
+        //ratio 0.2, on the first segment of each formula
+        assert!((lux(Tsl2561Package::T, 10000f32, 2000f32) - 238.86).abs() < 0.01);
+        assert!((lux(Tsl2561Package::Cs, 10000f32, 2000f32) - 252.70).abs() < 0.01);
+        //ratio 0.7
+        assert!((lux(Tsl2561Package::T, 10000f32, 7000f32) - 20.9).abs() < 0.01);
+        assert!((lux(Tsl2561Package::Cs, 10000f32, 7000f32) - 31f32).abs() < 0.01);
+        //mostly infrared, like an incandescent bulb seen through a filter
+        assert_eq!(lux(Tsl2561Package::T, 1000f32, 1400f32), 0f32);
+        assert_eq!(lux(Tsl2561Package::T, 0f32, 0f32), 0f32);
+    }
+
+    #[test]
+    fn tsl2561_checks_its_id() {
+        let bus = MockI2c::new();
+        add_mock_tsl2561(&bus, ADDRESS, 0, 0);
+        bus.set_registers(ADDRESS, TSL2561_COMMAND | TSL2561_ID, &[0x10]);
+        assert_eq!(Tsl2561::new(bus.clone(), tsl2561_config()).unwrap().package, Tsl2561Package::Cs);
+        bus.set_registers(ADDRESS, TSL2561_COMMAND | TSL2561_ID, &[0x92]);
+        assert_eq!(
+            Tsl2561::new(bus, tsl2561_config()).err(),
+            Some(LightSensorError::UnknownChip(0x92))
+        );
+    }
+
+    #[test]
+    fn tsl2561_switches_gain() {
+        let bus = MockI2c::new();
+        add_mock_tsl2561(&bus, ADDRESS, 10000, 2000);
+        let mut tsl2561 = Tsl2561::new(bus.clone(), tsl2561_config()).unwrap();
+        assert_eq!(timing(&bus), TSL2561_INTEGRATE_402MS | TSL2561_HIGH_GAIN);
+        assert!((tsl2561.measure().unwrap().unwrap() - 238.86).abs() < 0.01);
+        assert!(tsl2561.high_gain);
+
+        //bright enough to saturate soon, the low gain counts are scaled back up
+        set_mock_tsl2561_counts(&bus, ADDRESS, 60000, 12000);
+        let bright = tsl2561.measure().unwrap().unwrap();
+        assert!(!tsl2561.high_gain);
+        assert_eq!(timing(&bus), TSL2561_INTEGRATE_402MS);
+        assert!((bright - 16f32 * 1433.16).abs() < 1f32, "{}", bright);
+
+        //too few counts at low gain to read well
+        set_mock_tsl2561_counts(&bus, ADDRESS, 1000, 200);
+        assert!((tsl2561.measure().unwrap().unwrap() - 23.89).abs() < 0.01);
+        assert!(tsl2561.high_gain);
+        assert_eq!(timing(&bus), TSL2561_INTEGRATE_402MS | TSL2561_HIGH_GAIN);
+    }
+
+    #[test]
+    fn tsl2561_saturated() {
+        let bus = MockI2c::new();
+        add_mock_tsl2561(&bus, ADDRESS, 40000, u16::MAX);
+        let mut tsl2561 = Tsl2561::new(bus, tsl2561_config()).unwrap();
+        assert!(matches!(tsl2561.measure(), Ok(Err(SensorError::Failed(_)))));
+    }
+}
@@ -9,20 +9,84 @@ mod temperature_sensor;
 mod tube_objects;
 mod animation_utils;
 mod errors;
+mod time_keeper;
+mod display_template;
+mod config;
+mod playlist;
+mod scheduler;
+mod rgb_driver;
+mod world_clock;
+mod time_sync;
+mod ntp_client;
+mod control;
+mod rtc;
+#[cfg(test)]
+mod mock_i2c;
+#[cfg(test)]
+mod mock_serial;
+#[cfg(test)]
+mod mock_one_wire;
+mod gps;
+mod http_api;
+mod control_socket;
+mod mqtt;
+mod metrics;
+mod display_stream;
+mod config_ui;
+mod sensors;
+mod w1_sysfs;
+mod value_format;
+mod bme280;
+mod light_sensor;
+mod auto_brightness;
+mod co2_sensor;
+mod particulate;
+mod led_warning;
+mod pulse_counter;
 
 use crate::clock_objects::{DisplayMessage, NCS3148CMessage};
-use crate::temperature_sensor::TemperatureSensor;
+use crate::config::{ClockConfig, DisplaySettings};
+use crate::control::{ClockCommand, ClockControl, ClockState, CommandSender};
+use crate::config_ui::ConfigEditor;
+use crate::display_stream::{DisplayFrame, Simulator};
+use crate::http_api::{HttpApi, HttpConfig};
+use crate::metrics::ClockMetrics;
+use crate::control_socket::ControlSocket;
+use crate::mqtt::MqttClient;
+use crate::ntp_client::NtpClient;
+use crate::rtc::RtcClock;
+use crate::gps::GpsClock;
+use crate::time_sync::SyncStatus;
+use crate::scheduler::{Scheduler, SimulatedClock};
+use crate::temperature_sensor::{TemperatureBackend, TemperatureSensor};
+use crate::w1_sysfs::W1TemperatureSensor;
+use crate::sensors::SensorBus;
+use crate::bme280::Bme280;
+use crate::light_sensor::{Bh1750, LightChip, Tsl2561};
+use crate::co2_sensor::Co2Sensor;
+use crate::particulate::Pms5003;
+use crate::pulse_counter::PulseCounter;
+use crate::time_keeper::TimeKeeper;
 use std::env::temp_dir;
 use std::error::Error;
 use std::fmt::{Debug, Write};
 use std::thread::sleep;
 use std::time::Duration;
 use std::{fmt, thread};
-use std::sync::{Arc, RwLock};
+use std::sync::{mpsc, Arc, RwLock};
+use chrono::prelude::*;
 use tokio::runtime::Builder;
 use typenum::U96;
 
 const FPS_HZ: f32 = 5000f32; //Approximate Max is 5kHz
+//wall clock steps larger than this (compared to the monotonic clock) are treated as time jumps
+const TIME_JUMP_THRESHOLD_MS: i64 = 500;
+//how long the tubes take to roll over to a corrected time, None to jump straight to it
+const TIME_SLEW_MS: Option<i64> = Some(2_000);
+const USAGE: &str = "Specify clock type as the first arg, NCS3148C | NCS3186, optionally followed by a config file path,
+or `preview NCS3148C | NCS3186 <config> [YYYY-MM-DDTHH:MM] [minutes]` to try out a schedule,
+or `simulate NCS3148C | NCS3186 [config]` to watch a clock without tubes in the browser viewer,
+or `ctl ...` to control a running clock";
 
 pub type Result<T, E = Box<dyn Error>> = std::result::Result<T, E>;
 
@@ -34,19 +98,43 @@ impl Error for ArgumentError {}
 impl fmt::Display for ArgumentError {
     // This trait requires `fmt` with this exact signature.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Specify clock type as the first arg, NCS3148C | NCS3186")
+        write!(f, "{}", USAGE)
     }
 }
 
-fn main() -> Result<()> {
-    let clock_type = match std::env::args().nth(1).as_deref() {
-        Some("NCS3148C") => ClockType::NCS3148C,
-        Some("NCS3186") => ClockType::NCS3186,
+fn parse_clock_type(arg: Option<&str>) -> Result<ClockType> {
+    match arg {
+        Some("NCS3148C") => Ok(ClockType::NCS3148C),
+        Some("NCS3186") => Ok(ClockType::NCS3186),
         _ => {
-            println!("Specify clock type as the first arg, NCS3148C | NCS3186");
-            return Result::Err(Box::new(ArgumentError::ClockTypeNeeded));
+            println!("{}", USAGE);
+            Result::Err(Box::new(ArgumentError::ClockTypeNeeded))
         }
-    };
+    }
+}
+
+fn load_config(arg: Option<&String>) -> Result<ClockConfig> {
+    match arg {
+        Some(path) => ClockConfig::load(path),
+        None => Ok(ClockConfig::default()),
+    }
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("preview") {
+        return preview(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("simulate") {
+        return simulate(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("ctl") {
+        return ControlSocket::ctl(&args[2..]);
+    }
+    let clock_type = parse_clock_type(args.get(1).map(String::as_str))?;
+    let config = load_config(args.get(2))?;
+    //templates are checked against the board here so a bad config fails before the tubes light
+    let settings = DisplaySettings::from_config(&config, clock_type)?;
     let runtime = Builder::new_multi_thread()
         .enable_all()
         .worker_threads(2)
@@ -54,9 +142,108 @@ fn main() -> Result<()> {
         .thread_stack_size(2 * 1024 * 1024)
         .build()?;
 
-    let temperature_lock = Arc::new(RwLock::new(None));
-    let sensor_lock = temperature_lock.clone();
-    thread::spawn(move || temperature_sensor::TemperatureSensor::run(sensor_lock));
+    let metrics_lock = Arc::new(RwLock::new(ClockMetrics::default()));
+    let sensors = SensorBus::new(config.sensors.clone())?;
+    for pulse_config in &config.pulse_inputs {
+        pulse_config.validate()?;
+    }
+    match config.temperature.backend {
+        TemperatureBackend::OneWire => sensors.spawn(TemperatureSensor::new(&config.temperature)?),
+        TemperatureBackend::W1Sysfs => sensors.spawn(W1TemperatureSensor::new(&config.temperature)?),
+    }
+    if let Some(bme280_config) = &config.bme280 {
+        match Bme280::open(bme280_config) {
+            Ok(bme280) => sensors.spawn(bme280),
+            Err(e) => println!("BME280 unavailable on I2C bus {}: {}", bme280_config.bus, e),
+        }
+    }
+    //without it auto brightness leaves the schedule's brightness alone
+    if let Some(light_config) = &config.light {
+        let opened = match light_config.chip {
+            LightChip::Bh1750 => Bh1750::open(light_config).map(|s| sensors.spawn(s)),
+            LightChip::Tsl2561 => Tsl2561::open(light_config).map(|s| sensors.spawn(s)),
+        };
+        if let Err(e) = opened {
+            println!("{:?} unavailable on I2C bus {}: {}", light_config.chip, light_config.bus, e);
+        }
+    }
+    if let Some(co2_config) = &config.co2 {
+        match Co2Sensor::open(co2_config) {
+            Ok(co2) => sensors.spawn(co2),
+            Err(e) => println!("{:?} unavailable on I2C bus {}: {}", co2_config.chip, co2_config.bus, e),
+        }
+    }
+    if let Some(pms_config) = &config.pms5003 {
+        match Pms5003::open(pms_config) {
+            Ok(pms) => sensors.spawn(pms),
+            Err(e) => println!("PMS5003 unavailable on {}: {}", pms_config.device, e),
+        }
+    }
+    for pulse_config in &config.pulse_inputs {
+        match PulseCounter::open(pulse_config) {
+            Ok(counter) => sensors.spawn(counter),
+            Err(e) => println!("Pulse input on GPIO {} unavailable: {}", pulse_config.pin().pin, e),
+        }
+    }
+
+    //with several time sources the tolerances of the most precise one apply
+    let sync_status = match (&config.gps, &config.ntp, &config.rtc) {
+        (Some(gps), _, _) => gps.new_sync_status(),
+        (None, Some(ntp), _) => ntp.new_sync_status(),
+        (None, None, Some(rtc)) => rtc.new_sync_status(),
+        (None, None, None) => SyncStatus::default(),
+    };
+    let sync_lock = Arc::new(RwLock::new(sync_status));
+    if let Some(rtc_config) = config.rtc.clone() {
+        //read before NTP gets going so the clock starts out close after a power cut
+        let rtc_lock = sync_lock.clone();
+        thread::spawn(move || RtcClock::run(rtc_config, rtc_lock));
+    }
+    if let Some(gps_config) = config.gps.clone() {
+        let gps_lock = sync_lock.clone();
+        thread::spawn(move || GpsClock::run(gps_config, gps_lock));
+    }
+    if let Some(ntp_config) = config.ntp.clone() {
+        runtime.spawn(NtpClient::run(ntp_config, sync_lock.clone()));
+    }
+    let (command_sender, command_receiver) = mpsc::channel();
+    let state_lock = Arc::new(RwLock::new(ClockState::default()));
+    let display_lock = Arc::new(RwLock::new(DisplayFrame::default()));
+    let control = ClockControl {
+        clock_type,
+        world_clock: config.world_clock.clone(),
+        commands: command_sender.clone(),
+        state_lock: state_lock.clone(),
+        sync_lock: sync_lock.clone(),
+        sensors: sensors.clone(),
+        metrics_lock: metrics_lock.clone(),
+        display_lock: display_lock.clone(),
+        config_editor: ConfigEditor::new(clock_type, args.get(2).cloned(), command_sender.clone()),
+    };
+    if let Some(http_config) = config.http.clone() {
+        let http_control = control.clone();
+        runtime.spawn(async move {
+            if let Err(e) = HttpApi::run(http_config, http_control).await {
+                println!("HTTP API failed: {}", e);
+            }
+        });
+    }
+    if let Some(socket_config) = config.socket.clone() {
+        let socket_control = control.clone();
+        runtime.spawn(async move {
+            if let Err(e) = ControlSocket::run(socket_config, socket_control).await {
+                println!("Control socket failed: {}", e);
+            }
+        });
+    }
+    if let Some(mqtt_config) = config.mqtt.clone() {
+        let mqtt_control = control.clone();
+        runtime.spawn(async move {
+            if let Err(e) = MqttClient::run(mqtt_config, mqtt_control).await {
+                println!("MQTT failed: {}", e);
+            }
+        });
+    }
 
     const FRAME_INTERVAL_US:i64 = 200;
     // const FRAME_INTERVAL_US:i64 = (1f32 / FPS_HZ * 1000f32 * 1000f32) as i64;
@@ -64,6 +251,10 @@ fn main() -> Result<()> {
     //     FRAME_INTERVAL_US = FRAME_INTERVAL_US - 100;
     // }
     println!("Clock Interval {:?}us", FRAME_INTERVAL_US);
+    let time_keeper = TimeKeeper::new(
+        chrono::Duration::milliseconds(TIME_JUMP_THRESHOLD_MS),
+        TIME_SLEW_MS.map(chrono::Duration::milliseconds),
+    );
 
     // let clock_driver:ClockDriver = match clock_type {
     //     ClockType::NCS3148C => NCS3148CDriver::new(temperature_lock, FRAME_INTERVAL_US),
@@ -74,14 +265,14 @@ fn main() -> Result<()> {
     match clock_type {
         ClockType::NCS3148C =>  runtime.block_on(async {
                              // runtime.spawn_blocking(|| timeloop(clock_driver));
-                             runtime.spawn_blocking( | | timeloop(NCS3148CDriver::new(temperature_lock, FRAME_INTERVAL_US).expect("Clock Init Failed")));
-                             wait_for_signal().await;
+                             runtime.spawn_blocking( | | timeloop(NCS3148CDriver::new(sensors, FRAME_INTERVAL_US, time_keeper, settings, sync_lock, command_receiver, state_lock, metrics_lock, display_lock).expect("Clock Init Failed")));
+                             wait_for_signal(command_sender).await;
                              println!("Exiting clock");
                          }),
         ClockType::NCS3186 =>  runtime.block_on(async {
                              // runtime.spawn_blocking(|| timeloop(clock_driver));
-                             runtime.spawn_blocking( | | timeloop(NCS3186Driver::new(temperature_lock, FRAME_INTERVAL_US).expect("Clock Init Failed")));
-                             wait_for_signal().await;
+                             runtime.spawn_blocking( | | timeloop(NCS3186Driver::new(sensors, FRAME_INTERVAL_US, time_keeper, settings, sync_lock, command_receiver, state_lock, metrics_lock, display_lock).expect("Clock Init Failed")));
+                             wait_for_signal(command_sender).await;
                              println!("Exiting clock");
                          }),
     };
@@ -90,14 +281,83 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-async fn wait_for_signal() {
+/// Prints every mode, theme and brightness switch of the schedule from a start time (now by
+/// default) for a number of minutes (a week by default), using a simulated clock
+fn preview(args: &[String]) -> Result<()> {
+    let clock_type = parse_clock_type(args.first().map(String::as_str))?;
+    let config = load_config(args.get(1))?;
+    let settings = DisplaySettings::from_config(&config, clock_type)?;
+    let start = match args.get(2) {
+        Some(s) => Local
+            .from_local_datetime(&NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M")?)
+            .single()
+            .ok_or("Preview start time is ambiguous or skipped in the local time zone")?,
+        None => Local::now().with_second(0).unwrap().with_nanosecond(0).unwrap(),
+    };
+    let minutes = match args.get(3) {
+        Some(m) => m.parse::<i64>()?,
+        None => 7 * 24 * 60,
+    };
+    let clock = SimulatedClock::new(
+        start,
+        start + chrono::Duration::minutes(minutes),
+        chrono::Duration::minutes(1),
+    );
+    Scheduler::preview(&settings, clock, clock_type);
+    Ok(())
+}
+
+/// Runs the schedule with no tubes, serving the HTTP API and its viewer at `[http]`'s
+/// address, or the default one
+fn simulate(args: &[String]) -> Result<()> {
+    let clock_type = parse_clock_type(args.first().map(String::as_str))?;
+    let config = load_config(args.get(1))?;
+    let settings = DisplaySettings::from_config(&config, clock_type)?;
+    let runtime = Builder::new_multi_thread().enable_all().build()?;
+    let (command_sender, command_receiver) = mpsc::channel();
+    let state_lock = Arc::new(RwLock::new(ClockState::default()));
+    let display_lock = Arc::new(RwLock::new(DisplayFrame::default()));
+    let control = ClockControl {
+        clock_type,
+        world_clock: config.world_clock.clone(),
+        commands: command_sender.clone(),
+        state_lock: state_lock.clone(),
+        sync_lock: Arc::new(RwLock::new(SyncStatus::default())),
+        sensors: SensorBus::default(),
+        metrics_lock: Arc::new(RwLock::new(ClockMetrics::default())),
+        display_lock: display_lock.clone(),
+        config_editor: ConfigEditor::new(clock_type, args.get(1).cloned(), command_sender.clone()),
+    };
+    let http_config = config.http.clone().unwrap_or_default();
+    runtime.block_on(async {
+        tokio::spawn(async move {
+            if let Err(e) = HttpApi::run(http_config, control).await {
+                println!("HTTP API failed: {}", e);
+            }
+        });
+        tokio::spawn(Simulator::new(clock_type, settings, command_receiver, state_lock, display_lock).run());
+        wait_for_signal(command_sender).await;
+    });
+    runtime.shutdown_background();
+    Ok(())
+}
+
+async fn wait_for_signal(commands: CommandSender) {
     use tokio::signal::unix::{signal, SignalKind};
     let mut term = signal(SignalKind::terminate()).expect("failed to register signal handler");
     let mut int = signal(SignalKind::interrupt()).expect("failed to register signal handler");
+    //SIGUSR1 shows the time sync offset on demand
+    let mut usr1 = signal(SignalKind::user_defined1()).expect("failed to register signal handler");
     println!("Watching for signals");
-    tokio::select! {
-        _ = term.recv() => println!("Received SIGTERM"),
-        _ = int.recv() => println!("Received SIGINT"),
+    loop {
+        tokio::select! {
+            _ = term.recv() => { println!("Received SIGTERM"); break }
+            _ = int.recv() => { println!("Received SIGINT"); break }
+            _ = usr1.recv() => {
+                println!("Received SIGUSR1");
+                commands.send(ClockCommand::ShowSyncOffset).ok();
+            }
+        }
     }
 }
 
@@ -0,0 +1,370 @@
+use std::fmt::Write;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use bit_array::BitArray;
+use typenum::U96;
+
+use crate::animation_utils::Overlay;
+use crate::clock_objects::ClockType;
+use crate::sensors::SensorState;
+
+//upper bounds of the frame interval histogram, the frames are meant to be 200μs apart
+const FRAME_INTERVAL_BUCKETS_US: [u64; 10] = [150, 200, 250, 300, 400, 500, 1_000, 2_000, 5_000, 20_000];
+const RAW_BITS: usize = 96;
+
+/// Counted by the display loop every frame, and copied out to the shared `ClockMetrics`
+/// about once a second so the loop never waits on a scrape.
+#[derive(Debug, Clone)]
+pub struct FrameMetrics {
+    pub frames: u64,
+    pub spi_write_errors: u64,
+    //as the tubes were last dimmed, after the light sensor
+    pub brightness: f32,
+    //one count per bucket, with the last one for everything slower
+    interval_counts: [u64; FRAME_INTERVAL_BUCKETS_US.len() + 1],
+    interval_sum_us: u64,
+    //time lit per bit of the raw message, so per cathode
+    cathode_on_us: [u64; RAW_BITS],
+    last_frame: Option<Instant>,
+    published_at: Instant,
+    published_frames: u64,
+}
+
+impl Default for FrameMetrics {
+    fn default() -> Self {
+        FrameMetrics {
+            frames: 0,
+            spi_write_errors: 0,
+            brightness: 1f32,
+            interval_counts: Default::default(),
+            interval_sum_us: 0,
+            cathode_on_us: [0; RAW_BITS],
+            last_frame: None,
+            published_at: Instant::now(),
+            published_frames: 0,
+        }
+    }
+}
+
+impl FrameMetrics {
+    pub fn record_frame(&mut self, raw_message: &BitArray<u8, U96>, on_linger: Option<chrono::Duration>) {
+        self.record_frame_at(Instant::now(), raw_message, on_linger)
+    }
+
+    /// Counts a frame that started at `now`
+    pub fn record_frame_at(&mut self, now: Instant, raw_message: &BitArray<u8, U96>, on_linger: Option<chrono::Duration>) {
+        if let Some(last) = self.last_frame {
+            let interval_us = (now - last).as_micros() as u64;
+            let bucket = FRAME_INTERVAL_BUCKETS_US
+                .iter()
+                .position(|b| interval_us <= *b)
+                .unwrap_or(FRAME_INTERVAL_BUCKETS_US.len());
+            self.interval_counts[bucket] += 1;
+            self.interval_sum_us += interval_us;
+        }
+        self.last_frame = Some(now);
+        self.frames += 1;
+        let on_us = on_linger.and_then(|d| d.num_microseconds()).unwrap_or(0).max(0) as u64;
+        for (i, lit) in raw_message.iter().enumerate() {
+            if lit {
+                self.cathode_on_us[i] += on_us;
+            }
+        }
+    }
+
+    /// Copies the counts out along with the frame rate since the last time, once a second
+    pub fn publish(&mut self, metrics_lock: &Arc<RwLock<ClockMetrics>>, overlays: &[Overlay]) {
+        let elapsed = self.published_at.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+        let fps = (self.frames - self.published_frames) as f64 / elapsed.as_secs_f64();
+        self.published_at = Instant::now();
+        self.published_frames = self.frames;
+        let mut overlay_counts = [0usize; 4];
+        for o in overlays {
+            match o {
+                Overlay::AntiPoison(_) => overlay_counts[0] += 1,
+                Overlay::TempOverlay(_) => overlay_counts[1] += 1,
+                Overlay::Scene(_) => overlay_counts[2] += 1,
+                Overlay::SyncOffset(_) => overlay_counts[3] += 1,
+            }
+        }
+        let mut metrics = metrics_lock.write().unwrap();
+        metrics.frames = self.clone();
+        metrics.fps = fps;
+        metrics.overlays = overlay_counts;
+    }
+}
+
+/// Everything `/metrics` reports, shared through an `Arc<RwLock<ClockMetrics>>`
+#[derive(Debug, Clone, Default)]
+pub struct ClockMetrics {
+    pub frames: FrameMetrics,
+    pub fps: f64,
+    pub overlays: [usize; 4],
+}
+
+impl ClockMetrics {
+    /// The Prometheus text exposition format
+    pub fn render(&self, clock_type: ClockType, sensors: &[(String, SensorState)]) -> String {
+        let mut out = String::new();
+        let f = &self.frames;
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            writeln!(out, "# HELP gfx_clock_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE gfx_clock_{} {}", name, kind).unwrap();
+            for (labels, value) in samples {
+                writeln!(out, "gfx_clock_{}{} {}", name, labels, value).unwrap();
+            }
+        };
+        metric("frames_total", "counter", "Frames sent to the tubes.", vec![(String::new(), f.frames.to_string())]);
+        metric("frames_per_second", "gauge", "Frames sent over the last second or so.", vec![(String::new(), format!("{:.1}", self.fps))]);
+        metric("brightness", "gauge", "Brightness the tubes are dimmed to, 0-1.", vec![(String::new(), format!("{:.3}", f.brightness))]);
+
+        let mut buckets = vec![];
+        let mut cumulative = 0;
+        for (i, count) in f.interval_counts.iter().enumerate() {
+            cumulative += count;
+            let le = match FRAME_INTERVAL_BUCKETS_US.get(i) {
+                Some(us) => format!("{}", *us as f64 / 1_000_000f64),
+                None => "+Inf".to_string(),
+            };
+            buckets.push((format!("_bucket{{le=\"{}\"}}", le), cumulative.to_string()));
+        }
+        buckets.push(("_sum".to_string(), format!("{}", f.interval_sum_us as f64 / 1_000_000f64)));
+        buckets.push(("_count".to_string(), cumulative.to_string()));
+        metric("frame_interval_seconds", "histogram", "Time between the starts of consecutive frames.", buckets);
+
+        metric("spi_write_errors_total", "counter", "Frames that failed to be written over SPI.", vec![(String::new(), f.spi_write_errors.to_string())]);
+        let overlay_kinds = ["anti_poison", "temperature", "scene", "sync_offset"];
+        metric(
+            "overlays",
+            "gauge",
+            "Overlays scheduled or showing, by kind.",
+            overlay_kinds
+                .iter()
+                .zip(self.overlays.iter())
+                .map(|(kind, count)| (format!("{{kind=\"{}\"}}", kind), count.to_string()))
+                .collect(),
+        );
+
+        let per_sensor = |value: &dyn Fn(&SensorState) -> Option<String>| {
+            sensors
+                .iter()
+                .filter_map(|(name, state)| value(state).map(|v| (format!("{{sensor=\"{}\"}}", label_value(name)), v)))
+                .collect::<Vec<_>>()
+        };
+        metric("sensor_reads_total", "counter", "Attempts to read each sensor.", per_sensor(&|s| Some(s.reads.to_string())));
+        metric("sensor_read_failures_total", "counter", "Failed sensor reads.", per_sensor(&|s| Some(s.failures.to_string())));
+        metric(
+            "sensor_checksum_errors_total",
+            "counter",
+            "Sensor reads that failed their CRC or checksum.",
+            per_sensor(&|s| Some(s.checksum_errors.to_string())),
+        );
+        metric(
+            "sensor_rejected_readings_total",
+            "counter",
+            "Sensor readings thrown out as spikes or known bad values.",
+            per_sensor(&|s| Some(s.rejected.to_string())),
+        );
+        metric(
+            "sensor_reading_age_seconds",
+            "gauge",
+            "Time since each sensor's last good reading.",
+            per_sensor(&|s| s.reading.map(|r| format!("{:.1}", r.age().num_milliseconds() as f64 / 1000f64))),
+        );
+        metric(
+            "sensor_value",
+            "gauge",
+            "The latest reading of each sensor, while it isn't stale.",
+            sensors
+                .iter()
+                .filter_map(|(name, state)| {
+                    state.fresh().map(|r| {
+                        let labels = format!("{{sensor=\"{}\",unit=\"{}\"}}", label_value(name), label_value(r.unit.symbol()));
+                        (labels, r.value.to_string())
+                    })
+                })
+                .collect(),
+        );
+
+        let mut cathodes = vec![];
+        for (bit, on_us) in f.cathode_on_us.iter().enumerate() {
+            if let Some((slot, cathode)) = clock_type.cathode_for_bit(bit) {
+                cathodes.push((
+                    format!("{{tube=\"{}\",cathode=\"{}\"}}", slot, cathode),
+                    format!("{}", *on_us as f64 / 1_000_000f64),
+                ));
+            }
+        }
+        metric("cathode_on_seconds_total", "counter", "Time each cathode has been lit, by tube slot.", cathodes);
+        out
+    }
+}
+
+//label values are quoted, so quotes, backslashes and newlines in them are escaped
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+
+    use crate::animation_utils::AntiPoisonAnimation;
+    use crate::sensors::{Reading, Unit};
+
+    fn bits(lit: &[usize]) -> BitArray<u8, U96> {
+        let mut raw = BitArray::<u8, U96>::from_elem(false);
+        for bit in lit {
+            raw.set(*bit, true);
+        }
+        raw
+    }
+
+    fn lines_of<'a>(out: &'a str, name: &str) -> Vec<&'a str> {
+        out.lines().filter(|l| l.starts_with(&format!("gfx_clock_{}", name))).collect()
+    }
+
+    fn state(reads: u64, failures: u64, reading: Option<Reading>) -> SensorState {
+        SensorState {
+            unit: Unit::Celsius,
+            reading,
+            max_age: chrono::Duration::minutes(2),
+            reads,
+            failures,
+            checksum_errors: 1,
+            rejected: 2,
+            last_error: None,
+        }
+    }
+
+    fn recorded() -> FrameMetrics {
+        let mut frames = FrameMetrics::default();
+        let start = Instant::now();
+        let on = Some(chrono::Duration::microseconds(100));
+        //the top dot of the third separator and the 9 of the tube left of the IN-19A
+        frames.record_frame_at(start, &bits(&[0, 12]), on);
+        frames.record_frame_at(start + Duration::from_micros(180), &bits(&[12]), on);
+        frames.record_frame_at(start + Duration::from_micros(630), &bits(&[12]), on);
+        //a stall, and a frame that was never lit
+        frames.record_frame_at(start + Duration::from_micros(30_630), &bits(&[12]), on);
+        frames.record_frame_at(start + Duration::from_micros(30_830), &bits(&[2, 12]), None);
+        frames
+    }
+
+    #[test]
+    fn renders_the_frame_interval_histogram() {
+        let metrics = ClockMetrics {
+            frames: recorded(),
+            ..Default::default()
+        };
+        let out = metrics.render(ClockType::NCS3148C, &[]);
+        assert!(out.contains("# TYPE gfx_clock_frame_interval_seconds histogram\n"));
+        assert_eq!(
+            lines_of(&out, "frame_interval_seconds"),
+            vec![
+                "gfx_clock_frame_interval_seconds_bucket{le=\"0.00015\"} 0",
+                "gfx_clock_frame_interval_seconds_bucket{le=\"0.0002\"} 2",
+                "gfx_clock_frame_interval_seconds_bucket{le=\"0.00025\"} 2",
+                "gfx_clock_frame_interval_seconds_bucket{le=\"0.0003\"} 2",
+                "gfx_clock_frame_interval_seconds_bucket{le=\"0.0004\"} 2",
+                "gfx_clock_frame_interval_seconds_bucket{le=\"0.0005\"} 3",
+                "gfx_clock_frame_interval_seconds_bucket{le=\"0.001\"} 3",
+                "gfx_clock_frame_interval_seconds_bucket{le=\"0.002\"} 3",
+                "gfx_clock_frame_interval_seconds_bucket{le=\"0.005\"} 3",
+                "gfx_clock_frame_interval_seconds_bucket{le=\"0.02\"} 3",
+                "gfx_clock_frame_interval_seconds_bucket{le=\"+Inf\"} 4",
+                "gfx_clock_frame_interval_seconds_sum 0.03083",
+                "gfx_clock_frame_interval_seconds_count 4",
+            ]
+        );
+        assert_eq!(lines_of(&out, "frames_total"), vec!["gfx_clock_frames_total 5"]);
+    }
+
+    #[test]
+    fn renders_cathode_time_by_tube() {
+        let metrics = ClockMetrics {
+            frames: recorded(),
+            ..Default::default()
+        };
+        let out = metrics.render(ClockType::NCS3148C, &[]);
+        let cathodes = lines_of(&out, "cathode_on_seconds_total");
+        assert!(cathodes.contains(&"gfx_clock_cathode_on_seconds_total{tube=\"10\",cathode=\"9\"} 0.0004"));
+        assert!(cathodes.contains(&"gfx_clock_cathode_on_seconds_total{tube=\"8\",cathode=\"top\"} 0.0001"));
+        assert!(cathodes.contains(&"gfx_clock_cathode_on_seconds_total{tube=\"10\",cathode=\"0\"} 0"));
+        //every cathode but the IN-19A's two unused bits
+        assert_eq!(cathodes.len(), 94);
+        //the NCS3186 has neither tube
+        let out = metrics.render(ClockType::NCS3186, &[]);
+        let cathodes = lines_of(&out, "cathode_on_seconds_total");
+        assert!(cathodes.iter().all(|l| !l.contains("tube=\"10\"") && !l.contains("tube=\"8\"")));
+        assert_eq!(cathodes.len(), 6 * 10 + 2 * 2);
+    }
+
+    #[test]
+    fn renders_sensors() {
+        let reading = |age_ms| Reading {
+            value: 21.5,
+            raw: 21.4,
+            unit: Unit::Celsius,
+            taken_at: Local::now() - chrono::Duration::milliseconds(age_ms),
+        };
+        let sensors = vec![
+            ("outdoor".to_string(), state(10, 3, Some(reading(2500)))),
+            ("stale".to_string(), state(4, 4, Some(reading(600_000)))),
+            ("back \"porch\"\\".to_string(), state(1, 1, None)),
+        ];
+        let out = ClockMetrics::default().render(ClockType::NCS3186, &sensors);
+        assert_eq!(
+            lines_of(&out, "sensor_reads_total"),
+            vec![
+                "gfx_clock_sensor_reads_total{sensor=\"outdoor\"} 10",
+                "gfx_clock_sensor_reads_total{sensor=\"stale\"} 4",
+                "gfx_clock_sensor_reads_total{sensor=\"back \\\"porch\\\"\\\\\"} 1",
+            ]
+        );
+        assert_eq!(
+            lines_of(&out, "sensor_read_failures_total"),
+            vec![
+                "gfx_clock_sensor_read_failures_total{sensor=\"outdoor\"} 3",
+                "gfx_clock_sensor_read_failures_total{sensor=\"stale\"} 4",
+                "gfx_clock_sensor_read_failures_total{sensor=\"back \\\"porch\\\"\\\\\"} 1",
+            ]
+        );
+        assert!(out.contains("gfx_clock_sensor_checksum_errors_total{sensor=\"outdoor\"} 1\n"));
+        assert!(out.contains("gfx_clock_sensor_rejected_readings_total{sensor=\"outdoor\"} 2\n"));
+        //no age without a reading
+        assert_eq!(
+            lines_of(&out, "sensor_reading_age_seconds"),
+            vec![
+                "gfx_clock_sensor_reading_age_seconds{sensor=\"outdoor\"} 2.5",
+                "gfx_clock_sensor_reading_age_seconds{sensor=\"stale\"} 600.0",
+            ]
+        );
+        //and no value once it is stale
+        assert_eq!(
+            lines_of(&out, "sensor_value"),
+            vec!["gfx_clock_sensor_value{sensor=\"outdoor\",unit=\"°C\"} 21.5"]
+        );
+    }
+
+    #[test]
+    fn publishes_once_a_second() {
+        let metrics_lock = Arc::new(RwLock::new(ClockMetrics::default()));
+        let mut frames = recorded();
+        let overlays = AntiPoisonAnimation::full_cycle_set(ClockType::NCS3186, Local::now());
+        frames.publish(&metrics_lock, &overlays);
+        assert_eq!(metrics_lock.read().unwrap().frames.frames, 0);
+        frames.published_at = Instant::now() - Duration::from_secs(2);
+        frames.publish(&metrics_lock, &overlays);
+        let metrics = metrics_lock.read().unwrap().clone();
+        assert_eq!(metrics.frames.frames, 5);
+        assert!((metrics.fps - 2.5).abs() < 0.1, "{}", metrics.fps);
+        assert_eq!(metrics.overlays, [6, 0, 0, 0]);
+        let out = metrics.render(ClockType::NCS3186, &[]);
+        assert!(out.contains("gfx_clock_overlays{kind=\"anti_poison\"} 6\n"));
+    }
+}
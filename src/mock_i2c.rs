@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MockI2cError {
+    pub address: u8,
+}
+
+impl fmt::Display for MockI2cError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no device at address {:#04x}", self.address)
+    }
+}
+
+impl std::error::Error for MockI2cError {}
+
+#[derive(Debug)]
+struct MockDevice {
+    registers: [u8; 256],
+    pointer: u8,
+    //Some for devices taking 16 bit commands, with the reply to each
+    replies: Option<HashMap<u16, Vec<u8>>>,
+    //every command written, with its arguments
+    commands: Vec<(u16, Vec<u8>)>,
+}
+
+/// An I2C bus of register-file devices, for trying out drivers without the hardware.
+/// A write sets the register pointer with its first byte and stores the rest from there,
+/// and reads carry on from the pointer, the way most sensor and RTC chips behave.
+/// Clones share the same devices so a copy can be kept to look at or change registers.
+/// Command devices instead take a 16 bit command at the start of each write, the way
+/// Sensirion's chips do, and reads return the reply set for the last one.
+#[derive(Debug, Clone, Default)]
+pub struct MockI2c {
+    devices: Arc<Mutex<HashMap<u8, MockDevice>>>,
+}
+
+impl MockI2c {
+    pub fn new() -> MockI2c {
+        MockI2c::default()
+    }
+
+    pub fn add_device(&self, address: u8) {
+        self.devices.lock().unwrap().insert(
+            address,
+            MockDevice {
+                registers: [0; 256],
+                pointer: 0,
+                replies: None,
+                commands: vec![],
+            },
+        );
+    }
+
+    pub fn add_command_device(&self, address: u8) {
+        self.add_device(address);
+        self.devices.lock().unwrap().get_mut(&address).unwrap().replies = Some(HashMap::new());
+    }
+
+    pub fn set_reply(&self, address: u8, command: u16, reply: &[u8]) {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.get_mut(&address).expect("No mock device at that address");
+        device
+            .replies
+            .as_mut()
+            .expect("Not a mock command device")
+            .insert(command, reply.to_vec());
+    }
+
+    /// The commands written to a command device so far, with their arguments
+    pub fn commands(&self, address: u8) -> Vec<(u16, Vec<u8>)> {
+        let devices = self.devices.lock().unwrap();
+        devices.get(&address).expect("No mock device at that address").commands.clone()
+    }
+
+    pub fn set_registers(&self, address: u8, start: u8, values: &[u8]) {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.get_mut(&address).expect("No mock device at that address");
+        for (i, v) in values.iter().enumerate() {
+            device.registers[start.wrapping_add(i as u8) as usize] = *v;
+        }
+    }
+
+    pub fn registers(&self, address: u8, start: u8, len: usize) -> Vec<u8> {
+        let devices = self.devices.lock().unwrap();
+        let device = devices.get(&address).expect("No mock device at that address");
+        (0..len)
+            .map(|i| device.registers[start.wrapping_add(i as u8) as usize])
+            .collect()
+    }
+}
+
+impl Write for MockI2c {
+    type Error = MockI2cError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), MockI2cError> {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.get_mut(&address).ok_or(MockI2cError { address })?;
+        if device.replies.is_some() {
+            if bytes.len() >= 2 {
+                device.commands.push((u16::from_be_bytes([bytes[0], bytes[1]]), bytes[2..].to_vec()));
+            }
+            return Ok(());
+        }
+        if let Some((pointer, values)) = bytes.split_first() {
+            device.pointer = *pointer;
+            for v in values {
+                device.registers[device.pointer as usize] = *v;
+                device.pointer = device.pointer.wrapping_add(1);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for MockI2c {
+    type Error = MockI2cError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), MockI2cError> {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.get_mut(&address).ok_or(MockI2cError { address })?;
+        if let Some(replies) = &device.replies {
+            //a command without a reply reads as zeros, which fails Sensirion's CRC
+            let reply = device
+                .commands
+                .last()
+                .and_then(|(command, _)| replies.get(command))
+                .cloned()
+                .unwrap_or_default();
+            for (i, b) in buffer.iter_mut().enumerate() {
+                *b = reply.get(i).copied().unwrap_or(0);
+            }
+            return Ok(());
+        }
+        for b in buffer.iter_mut() {
+            *b = device.registers[device.pointer as usize];
+            device.pointer = device.pointer.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+impl WriteRead for MockI2c {
+    type Error = MockI2cError;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), MockI2cError> {
+        Write::write(self, address, bytes)?;
+        Read::read(self, address, buffer)
+    }
+}
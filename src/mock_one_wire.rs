@@ -0,0 +1,245 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use one_wire_bus::crc::crc8;
+
+//the ROM and function commands a DS18B20 answers
+const SEARCH_ROM: u8 = 0xf0;
+const MATCH_ROM: u8 = 0x55;
+const SKIP_ROM: u8 = 0xcc;
+const CONVERT_TEMP: u8 = 0x44;
+const WRITE_SCRATCHPAD: u8 = 0x4e;
+const READ_SCRATCHPAD: u8 = 0xbe;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Phase {
+    //waiting for the next reset
+    Idle,
+    RomCommand,
+    MatchRom,
+    //the bit of its ROM being searched
+    SearchRom(u8),
+    Function,
+    WriteScratchpad,
+    ReadScratchpad,
+}
+
+#[derive(Debug)]
+struct MockProbe {
+    rom: u64,
+    //None for a probe that never converts, which keeps its power on reading
+    celsius: Option<f32>,
+    corrupt_crc: bool,
+    scratchpad: [u8; 9],
+    phase: Phase,
+    received: u64,
+    received_bits: u8,
+    sending: VecDeque<bool>,
+}
+
+impl MockProbe {
+    fn new(rom: u64) -> MockProbe {
+        MockProbe {
+            rom,
+            celsius: None,
+            corrupt_crc: false,
+            //85°C, the alarm thresholds and 12 bits, as after power on
+            scratchpad: [0x50, 0x05, 0x4b, 0x46, 0x7f, 0xff, 0x0c, 0x10, 0],
+            phase: Phase::Idle,
+            received: 0,
+            received_bits: 0,
+            sending: VecDeque::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.phase = Phase::RomCommand;
+        self.received = 0;
+        self.received_bits = 0;
+        self.sending.clear();
+    }
+
+    fn send_search_bit(&mut self, bit: u8) {
+        let value = self.rom >> bit & 1 == 1;
+        self.sending.extend([value, !value]);
+    }
+
+    //a time slot, Some with the bit sent if it's sending, else it takes the master's bit
+    fn slot(&mut self, master: bool) -> Option<bool> {
+        if self.phase == Phase::Idle {
+            return None;
+        }
+        if let Some(bit) = self.sending.pop_front() {
+            return Some(bit);
+        }
+        if let Phase::SearchRom(bit) = self.phase {
+            let value = self.rom >> bit & 1 == 1;
+            self.phase = match (master == value, bit) {
+                (false, _) | (true, 63) => Phase::Idle,
+                (true, _) => Phase::SearchRom(bit + 1),
+            };
+            if let Phase::SearchRom(bit) = self.phase {
+                self.send_search_bit(bit);
+            }
+            return None;
+        }
+        self.received |= (master as u64) << self.received_bits;
+        self.received_bits += 1;
+        let wanted = match self.phase {
+            Phase::MatchRom => 64,
+            Phase::WriteScratchpad => 24,
+            _ => 8,
+        };
+        if self.received_bits < wanted {
+            return None;
+        }
+        let received = self.received;
+        self.received = 0;
+        self.received_bits = 0;
+        self.phase = match (self.phase, received as u8) {
+            (Phase::RomCommand, SEARCH_ROM) => {
+                self.send_search_bit(0);
+                Phase::SearchRom(0)
+            }
+            (Phase::RomCommand, MATCH_ROM) => Phase::MatchRom,
+            (Phase::MatchRom, _) if received == self.rom => Phase::Function,
+            (Phase::RomCommand, SKIP_ROM) => Phase::Function,
+            (Phase::Function, CONVERT_TEMP) => {
+                if let Some(celsius) = self.celsius {
+                    let raw = (celsius * 16f32).round() as i16;
+                    self.scratchpad[..2].copy_from_slice(&raw.to_le_bytes());
+                }
+                Phase::Idle
+            }
+            (Phase::Function, WRITE_SCRATCHPAD) => Phase::WriteScratchpad,
+            (Phase::Function, READ_SCRATCHPAD) => {
+                self.scratchpad[8] = crc8(&self.scratchpad[..8]) ^ self.corrupt_crc as u8;
+                for byte in self.scratchpad {
+                    self.sending.extend((0..8).map(|i| byte >> i & 1 == 1));
+                }
+                Phase::ReadScratchpad
+            }
+            (Phase::WriteScratchpad, _) => {
+                self.scratchpad[2..5].copy_from_slice(&received.to_le_bytes()[..3]);
+                Phase::Idle
+            }
+            _ => Phase::Idle,
+        };
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+struct Bus {
+    micros: u64,
+    //when the master pulled the bus low, if it's holding it there
+    low_since: Option<u64>,
+    //the probes hold the bus low until then
+    held_until: u64,
+    probes: Vec<MockProbe>,
+}
+
+impl Bus {
+    fn probe(&mut self, rom: u64) -> &mut MockProbe {
+        self.probes.iter_mut().find(|p| p.rom == rom).expect("No mock probe with that ROM")
+    }
+}
+
+/// A 1-Wire bus of DS18B20 probes, for trying out the bit-banged driver without the hardware.
+/// It is both the pin and the delay: the probes tell the master's slots apart by how long
+/// the bus is held low in delay time, so clones share the same bus and clock and one can be
+/// handed to `OneWire` while another does the waiting.
+/// The probes answer searches, ROM matches, conversions and scratchpad reads and writes.
+#[derive(Debug, Clone, Default)]
+pub struct MockOneWire {
+    bus: Arc<Mutex<Bus>>,
+}
+
+impl MockOneWire {
+    pub fn new() -> MockOneWire {
+        MockOneWire::default()
+    }
+
+    pub fn add_probe(&self, rom: u64) {
+        self.bus.lock().unwrap().probes.push(MockProbe::new(rom));
+    }
+
+    /// What the probe measures at the next conversion
+    pub fn set_temperature(&self, rom: u64, celsius: f32) {
+        self.bus.lock().unwrap().probe(rom).celsius = Some(celsius);
+    }
+
+    /// Flips a bit of the scratchpad's CRC, like noise on a long cable
+    pub fn corrupt_crc(&self, rom: u64) {
+        self.bus.lock().unwrap().probe(rom).corrupt_crc = true;
+    }
+
+    pub fn scratchpad(&self, rom: u64) -> [u8; 9] {
+        self.bus.lock().unwrap().probe(rom).scratchpad
+    }
+}
+
+impl OutputPin for MockOneWire {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        let mut bus = self.bus.lock().unwrap();
+        bus.low_since = Some(bus.micros);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        let mut bus = self.bus.lock().unwrap();
+        let held = match bus.low_since.take() {
+            Some(since) => bus.micros - since,
+            None => return Ok(()),
+        };
+        let now = bus.micros;
+        if held >= 480 {
+            bus.probes.iter_mut().for_each(MockProbe::reset);
+            if !bus.probes.is_empty() {
+                //the presence pulse
+                bus.held_until = now + 240;
+            }
+            return Ok(());
+        }
+        //a short slot is a 1, or a read if a probe is sending, and a long one is a 0
+        let mut level = true;
+        for probe in &mut bus.probes {
+            if let Some(bit) = probe.slot(held < 15) {
+                level &= bit;
+            }
+        }
+        if !level {
+            bus.held_until = now + 30;
+        }
+        Ok(())
+    }
+}
+
+impl InputPin for MockOneWire {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        let bus = self.bus.lock().unwrap();
+        Ok(bus.low_since.is_none() && bus.micros >= bus.held_until)
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(!self.is_high()?)
+    }
+}
+
+impl DelayUs<u16> for MockOneWire {
+    fn delay_us(&mut self, us: u16) {
+        self.bus.lock().unwrap().micros += u64::from(us);
+    }
+}
+
+impl DelayMs<u16> for MockOneWire {
+    fn delay_ms(&mut self, ms: u16) {
+        self.bus.lock().unwrap().micros += u64::from(ms) * 1000;
+    }
+}
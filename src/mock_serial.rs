@@ -0,0 +1,26 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+
+/// A pty pair standing in for a serial port, for playing recorded data back to a driver.
+/// Bytes written to the first file are read from the second, which is in raw mode so
+/// nothing is echoed or turned into line endings along the way.
+pub fn raw_pty() -> io::Result<(File, File)> {
+    let (mut master, mut slave) = (0, 0);
+    unsafe {
+        if libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null_mut(), ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (master, slave) = (File::from_raw_fd(master), File::from_raw_fd(slave));
+        let mut termios = std::mem::zeroed();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((master, slave))
+    }
+}
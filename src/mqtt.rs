@@ -0,0 +1,536 @@
+use std::error::Error;
+use std::time::{Duration, Instant};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, Publish, QoS};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::time::sleep;
+
+use crate::control::{BrightnessRequest, ClockControl, ControlRequest, MessageRequest, ModeRequest};
+use crate::scheduler::ModeKind;
+use crate::sensors::Unit;
+
+/// The `[mqtt]` section of the config
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "MqttConfig::default_port")]
+    pub port: u16,
+    //also names the topics and the Home Assistant device, so unique per clock
+    #[serde(default = "MqttConfig::default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "MqttConfig::default_discovery_prefix")]
+    pub discovery_prefix: String,
+    //the state is also published whenever it changes
+    #[serde(default = "MqttConfig::default_publish_seconds")]
+    pub publish_seconds: u64,
+}
+
+impl MqttConfig {
+    fn default_port() -> u16 {
+        1883
+    }
+    fn default_client_id() -> String {
+        "gfx_clock".to_string()
+    }
+    fn default_discovery_prefix() -> String {
+        "homeassistant".to_string()
+    }
+    fn default_publish_seconds() -> u64 {
+        60
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("gfx_clock/{}/{}", self.client_id, name)
+    }
+}
+
+/// Publishes the clock's state to `gfx_clock/<client_id>/state` as JSON, and takes commands
+/// on `.../brightness/set` (0-100), `.../mode/set` and `.../message/set`, where `schedule`
+/// hands brightness and mode back to the schedule. The entities are announced through
+/// Home Assistant's MQTT discovery.
+pub struct MqttClient {
+    config: MqttConfig,
+    control: ClockControl,
+    client: AsyncClient,
+}
+
+impl MqttClient {
+    pub async fn run(config: MqttConfig, control: ClockControl) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut options = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(config.topic("availability"), "offline", QoS::AtLeastOnce, true));
+        if let Some(username) = &config.username {
+            options.set_credentials(username.clone(), config.password.clone().unwrap_or_default());
+        }
+        let (client, mut event_loop) = AsyncClient::new(options, 16);
+        let mqtt = MqttClient {
+            config,
+            control,
+            client,
+        };
+        tokio::spawn(mqtt.clone_for_publishing().publish_state());
+        println!("MQTT connecting to {}:{}", mqtt.config.host, mqtt.config.port);
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    println!("MQTT connected");
+                    if let Err(e) = mqtt.announce().await {
+                        println!("MQTT announce failed: {}", e);
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) => mqtt.handle_command(&p),
+                Ok(_) => (),
+                Err(e) => {
+                    //the event loop reconnects on the next poll
+                    println!("MQTT connection failed: {}", e);
+                    sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    }
+
+    fn clone_for_publishing(&self) -> MqttClient {
+        MqttClient {
+            config: self.config.clone(),
+            control: self.control.clone(),
+            client: self.client.clone(),
+        }
+    }
+
+    async fn announce(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for name in ["brightness/set", "mode/set", "message/set"] {
+            self.client.subscribe(self.config.topic(name), QoS::AtLeastOnce).await?;
+        }
+        for (component, object_id, config) in self.discovery_configs() {
+            let topic = format!(
+                "{}/{}/{}/{}/config",
+                self.config.discovery_prefix, component, self.config.client_id, object_id
+            );
+            self.client
+                .publish(topic, QoS::AtLeastOnce, true, config.to_string())
+                .await?;
+        }
+        self.client
+            .publish(self.config.topic("availability"), QoS::AtLeastOnce, true, "online")
+            .await?;
+        self.client
+            .publish(self.config.topic("state"), QoS::AtLeastOnce, true, self.state_json().to_string())
+            .await?;
+        Ok(())
+    }
+
+    fn discovery_configs(&self) -> Vec<(&'static str, String, Value)> {
+        let id = &self.config.client_id;
+        let mut modes = vec!["schedule", "clock", "date", "blank"];
+        if self.control.world_clock.is_some() {
+            modes.push("world_clock");
+        }
+        //shared by every entity
+        let common = |object_id: &str, name: &str| {
+            json!({
+                "name": name,
+                "unique_id": format!("{}_{}", id, object_id),
+                "state_topic": self.config.topic("state"),
+                "availability_topic": self.config.topic("availability"),
+                "device": {
+                    "identifiers": [id],
+                    "name": format!("Nixie clock {}", id),
+                    "model": format!("{:?}", self.control.clock_type),
+                    "manufacturer": "Gra & Afch",
+                },
+            })
+        };
+        let with = |mut base: Value, extra: Value| {
+            if let (Some(b), Some(e)) = (base.as_object_mut(), extra.as_object()) {
+                for (key, value) in e {
+                    //null drops a common key
+                    if value.is_null() {
+                        b.remove(key);
+                    } else {
+                        b.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+            base
+        };
+        //one entity per measurement on the sensor bus
+        let mut configs: Vec<(&'static str, String, Value)> = self
+            .control
+            .sensors
+            .states()
+            .iter()
+            .map(|(name, state)| {
+                let mut chars = name.chars();
+                let title: String = match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => String::new(),
+                };
+                let title = title.replace('_', " ");
+                let mut extra = json!({
+                    "unit_of_measurement": state.unit.symbol(),
+                    "state_class": "measurement",
+                    "value_template": format!("{{{{ value_json.sensors{}.value }}}}", template_key(name)),
+                });
+                if let Some(class) = device_class(state.unit) {
+                    extra["device_class"] = json!(class);
+                }
+                //energy counts up through the day and starts again at midnight
+                if state.unit == Unit::KilowattHour {
+                    extra["state_class"] = json!("total_increasing");
+                }
+                let object_id = object_id(name);
+                let config = with(common(&object_id, &title), extra);
+                ("sensor", object_id, config)
+            })
+            .collect();
+        configs.extend(vec![
+            (
+                "number",
+                "brightness".to_string(),
+                with(
+                    common("brightness", "Brightness"),
+                    json!({
+                        "command_topic": self.config.topic("brightness/set"),
+                        "min": 0,
+                        "max": 100,
+                        "unit_of_measurement": "%",
+                        "value_template": "{{ (value_json.brightness * 100) | round }}",
+                    }),
+                ),
+            ),
+            (
+                "select",
+                "mode".to_string(),
+                with(
+                    common("mode", "Mode"),
+                    json!({
+                        "command_topic": self.config.topic("mode/set"),
+                        "options": modes,
+                        "value_template":
+                            "{{ value_json.mode | replace(' ', '_') if value_json.mode_override else 'schedule' }}",
+                    }),
+                ),
+            ),
+            (
+                "text",
+                "message".to_string(),
+                //write only, a message is gone a few seconds after it is shown
+                with(
+                    common("message", "Message"),
+                    json!({
+                        "command_topic": self.config.topic("message/set"),
+                        "state_topic": null,
+                    }),
+                ),
+            ),
+            (
+                "binary_sensor",
+                "time_synced".to_string(),
+                with(
+                    common("time_synced", "Time synced"),
+                    json!({
+                        "value_template": "{{ 'ON' if value_json.sync.synced else 'OFF' }}",
+                    }),
+                ),
+            ),
+        ]);
+        configs
+    }
+
+    fn state_json(&self) -> Value {
+        let mut state = serde_json::to_value(self.control.status()).unwrap_or_default();
+        if let (Some(s), Ok(sensors)) = (state.as_object_mut(), serde_json::to_value(self.control.sensors())) {
+            s.insert("sensors".to_string(), sensors);
+        }
+        state
+    }
+
+    //published on changes, and every publish_seconds regardless
+    async fn publish_state(self) {
+        let mut last: Option<(Value, Instant)> = None;
+        loop {
+            let state = self.state_json();
+            //the time, sync and each new reading's timestamps and raw value change all the time, so leave them out
+            let mut compared = state.clone();
+            if let Some(c) = compared.as_object_mut() {
+                c.remove("time");
+                c.remove("sync");
+                if let Some(Value::Object(sensors)) = c.get_mut("sensors") {
+                    for sensor in sensors.values_mut() {
+                        if let Some(s) = sensor.as_object_mut() {
+                            for key in ["taken_at", "age_seconds", "reads", "raw"] {
+                                s.remove(key);
+                            }
+                        }
+                    }
+                }
+            }
+            let due = match &last {
+                Some((previous, at)) => {
+                    *previous != compared || at.elapsed() >= Duration::from_secs(self.config.publish_seconds)
+                }
+                None => true,
+            };
+            if due {
+                match self
+                    .client
+                    .publish(self.config.topic("state"), QoS::AtLeastOnce, true, state.to_string())
+                    .await
+                {
+                    Ok(()) => last = Some((compared, Instant::now())),
+                    Err(e) => println!("MQTT publish failed: {}", e),
+                }
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    fn handle_command(&self, publish: &Publish) {
+        let payload = String::from_utf8_lossy(&publish.payload).trim().to_string();
+        let request = match self.parse_command(&publish.topic, &payload) {
+            Some(request) => request,
+            None => return,
+        };
+        match request.and_then(|r| self.control.execute(r).map_err(|e| e.to_string())) {
+            Ok(_) => println!("MQTT {} {}", publish.topic, payload),
+            Err(e) => println!("MQTT {} {:?} rejected: {}", publish.topic, payload, e),
+        }
+    }
+
+    //None for a topic that isn't one of the command topics
+    fn parse_command(&self, topic: &str, payload: &str) -> Option<Result<ControlRequest, String>> {
+        let request = if topic == self.config.topic("brightness/set") {
+            match payload {
+                "schedule" | "" => Ok(BrightnessRequest { brightness: None }),
+                p => p
+                    .parse::<f32>()
+                    .map(|b| BrightnessRequest { brightness: Some(b / 100f32) })
+                    .map_err(|e| e.to_string()),
+            }
+            .map(ControlRequest::Brightness)
+        } else if topic == self.config.topic("mode/set") {
+            match payload {
+                "schedule" | "" => Ok(ModeRequest {
+                    mode: None,
+                    format: None,
+                    countdown_to: None,
+                }),
+                //JSON for modes that need more, like a countdown's target
+                p if p.starts_with('{') => serde_json::from_str(p).map_err(|e| e.to_string()),
+                p => serde_json::from_value::<ModeKind>(Value::String(p.to_string()))
+                    .map(|mode| ModeRequest {
+                        mode: Some(mode),
+                        format: None,
+                        countdown_to: None,
+                    })
+                    .map_err(|_| format!("Unknown mode {}", p)),
+            }
+            .map(ControlRequest::Mode)
+        } else if topic == self.config.topic("message/set") {
+            if payload.starts_with('{') {
+                serde_json::from_str::<MessageRequest>(payload).map_err(|e| e.to_string())
+            } else {
+                Ok(MessageRequest {
+                    text: payload.to_string(),
+                    seconds: MessageRequest::default_seconds(),
+                })
+            }
+            .map(ControlRequest::Show)
+        } else {
+            return None;
+        };
+        Some(request)
+    }
+}
+
+//discovery topics and entity ids only take letters, digits, '_' and '-'
+fn object_id(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+//a sensor's key in the state JSON as a template reads it, quoted unless it's a plain identifier
+fn template_key(name: &str) -> String {
+    let plain = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if plain {
+        format!(".{}", name)
+    } else {
+        format!("[{}]", json!(name))
+    }
+}
+
+//Home Assistant's name for what a unit measures
+fn device_class(unit: Unit) -> Option<&'static str> {
+    match unit {
+        Unit::Celsius => Some("temperature"),
+        //the only percentages so far
+        Unit::Percent => Some("humidity"),
+        Unit::Pascal | Unit::Hectopascal => Some("pressure"),
+        Unit::Ampere => Some("current"),
+        Unit::Volt => Some("voltage"),
+        Unit::Lux => Some("illuminance"),
+        //the only ppm so far, and PM2.5 and PM10 can't be told apart by their unit
+        Unit::PartsPerMillion => Some("carbon_dioxide"),
+        Unit::MicrogramsPerCubicMetre => None,
+        Unit::Watt => Some("power"),
+        Unit::KilowattHour => Some("energy"),
+        Unit::CountsPerMinute | Unit::MicrosievertsPerHour => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Receiver;
+
+    use crate::clock_objects::ClockType;
+    use crate::control::ClockCommand;
+    use crate::sensors::SensorBus;
+
+    //the client only queues requests for an event loop that is never polled, so no broker is needed
+    fn mqtt(sensors: SensorBus) -> (MqttClient, Receiver<ClockCommand>) {
+        let config: MqttConfig = toml::from_str(r#"host = "localhost""#).unwrap();
+        let (client, _) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 16);
+        let (mut control, command_receiver) = ClockControl::for_tests(ClockType::NCS3186);
+        control.sensors = sensors;
+        (MqttClient { config, control, client }, command_receiver)
+    }
+
+    #[test]
+    fn parses_brightness() {
+        let (mqtt, _) = mqtt(SensorBus::default());
+        let topic = "gfx_clock/gfx_clock/brightness/set";
+        let brightness = |payload| match mqtt.parse_command(topic, payload) {
+            Some(Ok(ControlRequest::Brightness(r))) => Ok(r.brightness),
+            Some(Err(e)) => Err(e),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(brightness("40"), Ok(Some(0.4)));
+        assert_eq!(brightness("schedule"), Ok(None));
+        assert_eq!(brightness(""), Ok(None));
+        assert!(brightness("bright").is_err());
+    }
+
+    #[test]
+    fn parses_modes() {
+        let (mqtt, _) = mqtt(SensorBus::default());
+        let topic = "gfx_clock/gfx_clock/mode/set";
+        let mode = |payload| match mqtt.parse_command(topic, payload) {
+            Some(Ok(ControlRequest::Mode(r))) => Ok((r.mode, r.countdown_to)),
+            Some(Err(e)) => Err(e),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(mode("date"), Ok((Some(ModeKind::Date), None)));
+        assert_eq!(mode("world_clock"), Ok((Some(ModeKind::WorldClock), None)));
+        assert_eq!(mode("schedule"), Ok((None, None)));
+        assert_eq!(
+            mode(r#"{"mode": "countdown", "countdown_to": "18:00"}"#),
+            Ok((Some(ModeKind::Countdown), Some("18:00".to_string())))
+        );
+        assert_eq!(mode("disco"), Err("Unknown mode disco".to_string()));
+        assert!(mode(r#"{"mode": "disco"}"#).is_err());
+    }
+
+    #[test]
+    fn parses_messages() {
+        let (mqtt, _) = mqtt(SensorBus::default());
+        let topic = "gfx_clock/gfx_clock/message/set";
+        let message = |payload| match mqtt.parse_command(topic, payload) {
+            Some(Ok(ControlRequest::Show(r))) => Ok((r.text, r.seconds)),
+            Some(Err(e)) => Err(e),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(message("12 34 56"), Ok(("12 34 56".to_string(), 5)));
+        assert_eq!(message(r#"{"text": "1", "seconds": 10}"#), Ok(("1".to_string(), 10)));
+        assert!(message(r#"{"seconds": 10}"#).is_err());
+    }
+
+    #[test]
+    fn ignores_other_topics() {
+        let (mqtt, _) = mqtt(SensorBus::default());
+        assert!(mqtt.parse_command("gfx_clock/gfx_clock/state", "40").is_none());
+        assert!(mqtt.parse_command("gfx_clock/other_clock/brightness/set", "40").is_none());
+    }
+
+    #[test]
+    fn commands_reach_the_clock() {
+        let (mqtt, commands) = mqtt(SensorBus::default());
+        let publish = |topic: &str, payload: &str| Publish::new(topic, QoS::AtLeastOnce, payload);
+        mqtt.handle_command(&publish("gfx_clock/gfx_clock/brightness/set", " 25\n"));
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::SetBrightness(Some(b))) if b == 0.25));
+        //parsed, but out of range
+        mqtt.handle_command(&publish("gfx_clock/gfx_clock/brightness/set", "150"));
+        mqtt.handle_command(&publish("gfx_clock/gfx_clock/mode/set", "blank"));
+        assert!(matches!(commands.try_recv(), Ok(ClockCommand::SetMode(Some(_)))));
+        //there is no world clock configured
+        mqtt.handle_command(&publish("gfx_clock/gfx_clock/mode/set", "world_clock"));
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[test]
+    fn discovery() {
+        let sensors = SensorBus::default();
+        let max_age = chrono::Duration::seconds(60);
+        let _outdoor = sensors.register("outdoor_temperature", Unit::Celsius, max_age);
+        let _energy = sensors.register("energy_today", Unit::KilowattHour, max_age);
+        let _pm = sensors.register("pm2_5", Unit::MicrogramsPerCubicMetre, max_age);
+        let _pool = sensors.register("über_pool", Unit::Celsius, max_age);
+        let (mqtt, _) = mqtt(sensors);
+        let configs = mqtt.discovery_configs();
+        let ids: Vec<(&str, &str)> = configs.iter().map(|(c, id, _)| (*c, id.as_str())).collect();
+        assert_eq!(
+            ids,
+            vec![
+                ("sensor", "energy_today"),
+                ("sensor", "outdoor_temperature"),
+                ("sensor", "pm2_5"),
+                ("sensor", "_ber_pool"),
+                ("number", "brightness"),
+                ("select", "mode"),
+                ("text", "message"),
+                ("binary_sensor", "time_synced"),
+            ]
+        );
+        let config = |id: &str| configs.iter().find(|(_, i, _)| i == id).unwrap().2.clone();
+
+        let outdoor = config("outdoor_temperature");
+        assert_eq!(outdoor["name"], "Outdoor temperature");
+        assert_eq!(outdoor["unique_id"], "gfx_clock_outdoor_temperature");
+        assert_eq!(outdoor["state_topic"], "gfx_clock/gfx_clock/state");
+        assert_eq!(outdoor["availability_topic"], "gfx_clock/gfx_clock/availability");
+        assert_eq!(outdoor["unit_of_measurement"], "°C");
+        assert_eq!(outdoor["device_class"], "temperature");
+        assert_eq!(outdoor["state_class"], "measurement");
+        assert_eq!(outdoor["value_template"], "{{ value_json.sensors.outdoor_temperature.value }}");
+        assert_eq!(outdoor["device"]["model"], "NCS3186");
+        let pool = config("_ber_pool");
+        assert_eq!(pool["name"], "Über pool");
+        assert_eq!(pool["unique_id"], "gfx_clock__ber_pool");
+        assert_eq!(pool["value_template"], r#"{{ value_json.sensors["über_pool"].value }}"#);
+        assert_eq!(config("energy_today")["state_class"], "total_increasing");
+        assert_eq!(config("energy_today")["device_class"], "energy");
+        assert!(config("pm2_5").get("device_class").is_none());
+        assert_eq!(config("pm2_5")["value_template"], "{{ value_json.sensors.pm2_5.value }}");
+
+        assert_eq!(config("brightness")["command_topic"], "gfx_clock/gfx_clock/brightness/set");
+        assert_eq!(config("mode")["options"], json!(["schedule", "clock", "date", "blank"]));
+        //write only
+        assert!(config("message").get("state_topic").is_none());
+        assert_eq!(config("message")["command_topic"], "gfx_clock/gfx_clock/message/set");
+    }
+
+    #[test]
+    fn sanitises_sensor_ids() {
+        assert_eq!(object_id("outdoor_temperature"), "outdoor_temperature");
+        assert_eq!(object_id("co2-ppm"), "co2-ppm");
+        assert_eq!(object_id("pool temp/°C"), "pool_temp__C");
+        assert_eq!(template_key("pm2_5"), ".pm2_5");
+        assert_eq!(template_key("co2-ppm"), r#"["co2-ppm"]"#);
+        assert_eq!(template_key("2nd_floor"), r#"["2nd_floor"]"#);
+        assert_eq!(template_key("say \"hi\""), r#"["say \"hi\""]"#);
+    }
+}
@@ -0,0 +1,258 @@
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use chrono::Duration;
+use serde::Deserialize;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::{sleep, timeout};
+
+use crate::time_sync::{step_system_clock, SyncStatus};
+
+pub type NtpResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+//seconds between the NTP epoch (1900) and the unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const NTP_PACKET_LEN: usize = 48;
+const NTP_PORT: u16 = 123;
+
+/// The `[ntp]` section of the config
+#[derive(Debug, Clone, Deserialize)]
+pub struct NtpConfig {
+    //host, IP address, host:port or [IPv6]:port, port 123 when left off
+    pub servers: Vec<String>,
+    #[serde(default = "NtpConfig::default_poll_seconds")]
+    pub poll_seconds: u64,
+    #[serde(default = "NtpConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    //the clock counts as synced while within this of the servers
+    #[serde(default = "NtpConfig::default_tolerance_ms")]
+    pub tolerance_ms: i64,
+    //and for this long after the last good answer
+    #[serde(default = "NtpConfig::default_max_age_seconds")]
+    pub max_age_seconds: i64,
+    //step the system clock when it is out of tolerance, needs root
+    #[serde(default)]
+    pub step_system_clock: bool,
+}
+
+impl NtpConfig {
+    fn default_poll_seconds() -> u64 {
+        64
+    }
+    fn default_timeout_ms() -> u64 {
+        2000
+    }
+    fn default_tolerance_ms() -> i64 {
+        100
+    }
+    fn default_max_age_seconds() -> i64 {
+        3600
+    }
+
+    pub fn new_sync_status(&self) -> SyncStatus {
+        SyncStatus::new(
+            Duration::milliseconds(self.tolerance_ms),
+            Duration::seconds(self.max_age_seconds),
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct NtpSample {
+    pub offset: Duration,
+    pub delay: Duration,
+    pub stratum: u8,
+}
+
+/// A small SNTP (RFC 4330) client. It keeps the shared `SyncStatus` up to date with the
+/// best answer from the configured servers and can step the system clock.
+pub struct NtpClient {}
+
+impl NtpClient {
+    pub async fn run(config: NtpConfig, sync_lock: Arc<RwLock<SyncStatus>>) {
+        loop {
+            let mut best: Option<(String, NtpSample)> = None;
+            for server in &config.servers {
+                match NtpClient::query(server, std::time::Duration::from_millis(config.timeout_ms)).await {
+                    Ok(sample) => {
+                        if best.as_ref().is_none_or(|(_, b)| sample.delay < b.delay) {
+                            best = Some((server.clone(), sample));
+                        }
+                    }
+                    Err(e) => println!("NTP query to {} failed: {}", server, e),
+                }
+            }
+            if let Some((server, mut sample)) = best {
+                println!(
+                    "NTP {} offset {}ms delay {}ms stratum {}",
+                    server,
+                    sample.offset.num_milliseconds(),
+                    sample.delay.num_milliseconds(),
+                    sample.stratum
+                );
+                let tolerance = Duration::milliseconds(config.tolerance_ms);
+                if config.step_system_clock && (sample.offset > tolerance || -sample.offset > tolerance) {
+                    match step_system_clock(sample.offset) {
+                        Ok(()) => {
+                            println!("Stepped system clock by {}ms", sample.offset.num_milliseconds());
+                            sample.offset = Duration::zero();
+                        }
+                        Err(e) => println!("Stepping system clock failed: {}", e),
+                    }
+                }
+                let mut status = sync_lock.write().unwrap();
+                status.record(format!("ntp {}", server), sample.offset, sample.delay, Some(sample.stratum));
+            }
+            sleep(std::time::Duration::from_secs(config.poll_seconds)).await;
+        }
+    }
+
+    pub async fn query(server: &str, wait: std::time::Duration) -> NtpResult<NtpSample> {
+        let address = NtpClient::resolve(server).await?;
+        let local = if address.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(address).await?;
+
+        let mut request = [0u8; NTP_PACKET_LEN];
+        //leap indicator 0, version 4, mode 3 (client)
+        request[0] = 0x23;
+        let t1 = SystemTime::now();
+        let sent = Instant::now();
+        let t1_ntp = NtpClient::to_ntp(t1)?;
+        request[40..48].copy_from_slice(&t1_ntp.to_be_bytes());
+        socket.send(&request).await?;
+
+        let mut response = [0u8; NTP_PACKET_LEN];
+        let len = timeout(wait, socket.recv(&mut response)).await??;
+        //measured on the monotonic clock so a step mid-query can't skew the delay
+        let t4_ntp = t1_ntp + NtpClient::duration_to_ntp(sent.elapsed());
+        if len < NTP_PACKET_LEN {
+            return Err(format!("short NTP response of {} bytes", len).into());
+        }
+        let leap = response[0] >> 6;
+        let mode = response[0] & 0x7;
+        let stratum = response[1];
+        if mode != 4 {
+            return Err(format!("unexpected NTP mode {}", mode).into());
+        }
+        if leap == 3 || stratum == 0 {
+            return Err("NTP server is not synchronized".into());
+        }
+        let read = |i: usize| {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&response[i..i + 8]);
+            u64::from_be_bytes(b)
+        };
+        if read(24) != t1_ntp {
+            return Err("NTP response does not match the request".into());
+        }
+        let (t2, t3) = (read(32), read(40));
+        let diff = |a: u64, b: u64| NtpClient::ntp_to_micros(a) - NtpClient::ntp_to_micros(b);
+        Ok(NtpSample {
+            offset: Duration::microseconds((diff(t2, t1_ntp) + diff(t3, t4_ntp)) / 2),
+            delay: Duration::microseconds(diff(t4_ntp, t1_ntp) - diff(t3, t2)),
+            stratum,
+        })
+    }
+
+    //a bare IPv6 address has colons too, so only a name or bracketed address can have a port
+    async fn resolve(server: &str) -> NtpResult<SocketAddr> {
+        let address = match server.parse::<IpAddr>() {
+            Ok(ip) => Some(SocketAddr::new(ip, NTP_PORT)),
+            Err(_) if server.contains(':') => lookup_host(server).await?.next(),
+            Err(_) => lookup_host((server, NTP_PORT)).await?.next(),
+        };
+        address.ok_or_else(|| format!("{} has no addresses", server).into())
+    }
+
+    fn to_ntp(t: SystemTime) -> NtpResult<u64> {
+        let since_unix = t.duration_since(UNIX_EPOCH)?;
+        let fraction = NtpClient::duration_to_ntp(since_unix) & 0xffff_ffff;
+        Ok(((since_unix.as_secs() + NTP_UNIX_OFFSET) << 32) | fraction)
+    }
+
+    fn duration_to_ntp(d: std::time::Duration) -> u64 {
+        (d.as_secs() << 32) + ((d.subsec_nanos() as u64) << 32) / 1_000_000_000
+    }
+
+    fn ntp_to_micros(t: u64) -> i64 {
+        ((t >> 32) as i64) * 1_000_000 + (((t & 0xffff_ffff) * 1_000_000) >> 32) as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //answers one request as a server `ahead` of the local clock, `holding` it before replying
+    async fn responder(
+        local: &str,
+        ahead: std::time::Duration,
+        holding: std::time::Duration,
+        stratum: u8,
+    ) -> SocketAddr {
+        let socket = UdpSocket::bind(local).await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut request = [0u8; NTP_PACKET_LEN];
+            let (_, client) = socket.recv_from(&mut request).await.unwrap();
+            let now = || NtpClient::to_ntp(SystemTime::now() + ahead).unwrap();
+            let received = now();
+            sleep(holding).await;
+            let mut response = [0u8; NTP_PACKET_LEN];
+            //leap indicator 0, version 4, mode 4 (server)
+            response[0] = 0x24;
+            response[1] = stratum;
+            response[24..32].copy_from_slice(&request[40..48]);
+            response[32..40].copy_from_slice(&received.to_be_bytes());
+            response[40..48].copy_from_slice(&now().to_be_bytes());
+            socket.send_to(&response, client).await.unwrap();
+        });
+        address
+    }
+
+    #[test]
+    fn converts_timestamps() {
+        let d = std::time::Duration::from_micros(1_500_000);
+        assert_eq!(NtpClient::duration_to_ntp(d), 0x1_8000_0000);
+        assert_eq!(NtpClient::ntp_to_micros(0x1_8000_0000), 1_500_000);
+        let d = std::time::Duration::from_micros(3_000_250);
+        assert_eq!(NtpClient::ntp_to_micros(NtpClient::duration_to_ntp(d)), 3_000_249);
+        assert_eq!(NtpClient::to_ntp(UNIX_EPOCH).unwrap(), NTP_UNIX_OFFSET << 32);
+    }
+
+    #[tokio::test]
+    async fn resolves_servers() {
+        let resolved = |s: &'static str| async move { NtpClient::resolve(s).await.unwrap().to_string() };
+        assert_eq!(resolved("192.0.2.1").await, "192.0.2.1:123");
+        assert_eq!(resolved("192.0.2.1:1123").await, "192.0.2.1:1123");
+        assert_eq!(resolved("2001:db8::1").await, "[2001:db8::1]:123");
+        assert_eq!(resolved("::1").await, "[::1]:123");
+        assert_eq!(resolved("[2001:db8::1]:1123").await, "[2001:db8::1]:1123");
+        assert!(resolved("localhost").await.ends_with(":123"));
+    }
+
+    #[tokio::test]
+    async fn measures_offset_and_delay() {
+        let wait = std::time::Duration::from_secs(2);
+        let ahead = std::time::Duration::from_millis(1500);
+        let holding = std::time::Duration::from_millis(100);
+        for local in ["127.0.0.1:0", "[::1]:0"] {
+            let server = responder(local, ahead, holding, 2).await;
+            let sample = NtpClient::query(&server.to_string(), wait).await.unwrap();
+            assert_eq!(sample.stratum, 2);
+            //the time the server held the request isn't counted as delay
+            assert!((sample.offset.num_milliseconds() - 1500).abs() < 20, "{:?}", sample);
+            assert!(sample.delay >= Duration::zero() && sample.delay.num_milliseconds() < 20, "{:?}", sample);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unsynchronized_servers() {
+        let zero = std::time::Duration::default();
+        let server = responder("127.0.0.1:0", zero, zero, 0).await;
+        let error = NtpClient::query(&server.to_string(), std::time::Duration::from_secs(2)).await;
+        assert_eq!(error.unwrap_err().to_string(), "NTP server is not synchronized");
+    }
+}
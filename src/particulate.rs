@@ -0,0 +1,213 @@
+use std::error::Error;
+use std::io::{self, Read};
+use std::thread;
+use std::time::Duration;
+use rppal::uart::{Parity, Uart};
+use serde::Deserialize;
+
+use crate::errors::{ParticulateError, ParticulateResult, SensorError, SensorResult};
+use crate::gps::UartReader;
+use crate::sensors::{Sensor, Unit};
+
+const START: [u8; 2] = [0x42, 0x4d];
+//13 data words and the checksum
+const FRAME_LENGTH: u16 = 28;
+//a closed or unplugged port fails straight away, which would spin the sensor's thread
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The `[pms5003]` section of the config, a Plantower particulate sensor on a serial port
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Pms5003Config {
+    //a serial port, or one end of a pty pair to play back recorded frames
+    pub device: String,
+    pub baud: u32,
+    //bus names of the measurements
+    pub pm1: String,
+    pub pm2_5: String,
+    pub pm10: String,
+}
+
+impl Default for Pms5003Config {
+    fn default() -> Self {
+        Pms5003Config {
+            device: "/dev/serial0".to_string(),
+            baud: 9600,
+            pm1: "pm1".to_string(),
+            pm2_5: "pm2_5".to_string(),
+            pm10: "pm10".to_string(),
+        }
+    }
+}
+
+/// One frame's concentrations in µg/m³, as corrected for the atmosphere rather than the
+/// factory's standard particle
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PmsFrame {
+    pub pm1: u16,
+    pub pm2_5: u16,
+    pub pm10: u16,
+}
+
+impl PmsFrame {
+    /// Checks a frame's length and checksum, `frame` starting at the 0x42 0x4d
+    pub fn parse(frame: &[u8; 32]) -> ParticulateResult<PmsFrame> {
+        let word = |i: usize| u16::from_be_bytes([frame[i], frame[i + 1]]);
+        if word(2) != FRAME_LENGTH {
+            return Err(ParticulateError::BadLength(word(2)));
+        }
+        let sum = frame[..30].iter().map(|b| *b as u16).fold(0u16, u16::wrapping_add);
+        if sum != word(30) {
+            return Err(ParticulateError::Checksum);
+        }
+        //the data words start at 4, the atmospheric ones after the three standard ones
+        Ok(PmsFrame {
+            pm1: word(10),
+            pm2_5: word(12),
+            pm10: word(14),
+        })
+    }
+
+    /// A frame with these concentrations, for playing back through a pty
+    #[cfg(test)]
+    pub fn to_bytes(self) -> [u8; 32] {
+        let mut frame = [0u8; 32];
+        frame[..2].copy_from_slice(&START);
+        frame[2..4].copy_from_slice(&FRAME_LENGTH.to_be_bytes());
+        for (i, value) in [self.pm1, self.pm2_5, self.pm10, self.pm1, self.pm2_5, self.pm10].iter().enumerate() {
+            frame[4 + i * 2..6 + i * 2].copy_from_slice(&value.to_be_bytes());
+        }
+        let sum = frame[..30].iter().map(|b| *b as u16).fold(0u16, u16::wrapping_add);
+        frame[30..].copy_from_slice(&sum.to_be_bytes());
+        frame
+    }
+}
+
+/// A Plantower PMS5003 in its default active mode, sending a frame every second or so.
+/// Each read takes the next frame.
+pub struct Pms5003<R> {
+    reader: R,
+    config: Pms5003Config,
+}
+
+impl Pms5003<UartReader> {
+    pub fn open(config: &Pms5003Config) -> Result<Pms5003<UartReader>, Box<dyn Error>> {
+        let mut uart = Uart::with_path(&config.device, config.baud, Parity::None, 8, 1)?;
+        uart.set_read_mode(1, Duration::default())?;
+        Ok(Pms5003::new(UartReader(uart), config.clone()))
+    }
+}
+
+impl<R: Read> Pms5003<R> {
+    pub fn new(reader: R, config: Pms5003Config) -> Pms5003<R> {
+        Pms5003 { reader, config }
+    }
+
+    pub fn read_frame(&mut self) -> ParticulateResult<PmsFrame> {
+        let io_error = |e: io::Error| ParticulateError::Io(e.to_string());
+        let mut frame = [0u8; 32];
+        //skips to the start bytes, the first read may begin part way through a frame
+        let mut byte = [0u8];
+        let mut previous = 0u8;
+        loop {
+            self.reader.read_exact(&mut byte).map_err(io_error)?;
+            if [previous, byte[0]] == START {
+                break;
+            }
+            previous = byte[0];
+        }
+        frame[..2].copy_from_slice(&START);
+        self.reader.read_exact(&mut frame[2..]).map_err(io_error)?;
+        PmsFrame::parse(&frame)
+    }
+}
+
+impl<R: Read + Send> Sensor for Pms5003<R> {
+    fn name(&self) -> String {
+        "PMS5003".to_string()
+    }
+
+    fn measurements(&self) -> Vec<(String, Unit)> {
+        [&self.config.pm1, &self.config.pm2_5, &self.config.pm10]
+            .iter()
+            .map(|name| (name.to_string(), Unit::MicrogramsPerCubicMetre))
+            .collect()
+    }
+
+    fn read(&mut self) -> Result<Vec<SensorResult<f32>>, Box<dyn Error>> {
+        match self.read_frame() {
+            Ok(frame) => Ok(vec![Ok(frame.pm1 as f32), Ok(frame.pm2_5 as f32), Ok(frame.pm10 as f32)]),
+            Err(ParticulateError::Checksum) => Ok(vec![Err(SensorError::Checksum); 3]),
+            Err(e @ ParticulateError::Io(_)) => {
+                thread::sleep(RETRY_DELAY);
+                Err(e.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    //paced by the frames, any slower and they would queue up in the port
+    fn interval(&self) -> chrono::Duration {
+        chrono::Duration::zero()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use crate::mock_serial::raw_pty;
+
+    const CLEAN: PmsFrame = PmsFrame {
+        pm1: 3,
+        pm2_5: 5,
+        pm10: 7,
+    };
+    const SMOKY: PmsFrame = PmsFrame {
+        pm1: 85,
+        pm2_5: 142,
+        pm10: 160,
+    };
+
+    #[test]
+    fn parses_a_frame() {
+        //a whole frame as the sensor sends it, the standard particle values ahead of the
+        //atmospheric ones and the particle counts after
+        let recorded = [
+            0x42, 0x4d, 0x00, 0x1c, 0x00, 0x11, 0x00, 0x1a, 0x00, 0x1e, 0x00, 0x10, 0x00, 0x19, 0x00, 0x1c, 0x0b,
+            0x8b, 0x03, 0x5d, 0x00, 0x8a, 0x00, 0x0e, 0x00, 0x04, 0x00, 0x00, 0x97, 0x00, 0x03, 0x62,
+        ];
+        let frame = PmsFrame::parse(&recorded).unwrap();
+        assert_eq!(
+            frame,
+            PmsFrame {
+                pm1: 16,
+                pm2_5: 25,
+                pm10: 28
+            }
+        );
+        assert_eq!(PmsFrame::parse(&SMOKY.to_bytes()), Ok(SMOKY));
+        let mut short = SMOKY.to_bytes();
+        short[3] = 20;
+        assert_eq!(PmsFrame::parse(&short), Err(ParticulateError::BadLength(20)));
+    }
+
+    #[test]
+    fn reads_frames_from_a_serial_port() {
+        let (mut port, device) = raw_pty().unwrap();
+        let mut pms5003 = Pms5003::new(device, Pms5003Config::default());
+        //started listening part way through a frame
+        port.write_all(&CLEAN.to_bytes()[17..]).unwrap();
+        port.write_all(&SMOKY.to_bytes()).unwrap();
+        let mut corrupted = CLEAN.to_bytes();
+        corrupted[12] ^= 0x40;
+        port.write_all(&corrupted).unwrap();
+        port.write_all(&CLEAN.to_bytes()).unwrap();
+
+        assert_eq!(pms5003.read_frame(), Ok(SMOKY));
+        let checksum = pms5003.read().unwrap();
+        assert_eq!(checksum, vec![Err(SensorError::Checksum); 3]);
+        assert_eq!(pms5003.read().unwrap(), vec![Ok(3f32), Ok(5f32), Ok(7f32)]);
+    }
+}
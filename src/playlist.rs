@@ -0,0 +1,461 @@
+use std::error::Error;
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use crate::animation_utils::{
+    Overlay, ReadingFormat, ReadingScene, SceneOverlay, SceneTransition, TempOverlayAnimation,
+    TemperatureFormat, TemperatureUnit, TransitionStyle,
+};
+use crate::clock_objects::ClockType;
+use crate::display_template::DisplayTemplate;
+use crate::rgb_driver::LedColor;
+use crate::sensors::SensorBus;
+use crate::temperature_sensor::TEMPERATURE;
+use crate::value_format::{NegativeStyle, NumberFormat};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SceneKind {
+    Time,
+    Date,
+    Temperature,
+    //any sensor's reading in its own unit
+    Reading,
+}
+
+/// One `[[playlist]]` entry of the config
+#[derive(Debug, Clone, Deserialize)]
+pub struct SceneConfig {
+    pub scene: SceneKind,
+    pub seconds: u32,
+    //template for time and date scenes, the board's default when unset
+    pub format: Option<String>,
+    //IANA zone for time and date scenes, local time when unset
+    pub zone: Option<Tz>,
+    #[serde(default)]
+    pub transition: TransitionStyle,
+    //how it leaves, the same as it enters when unset
+    pub transition_out: Option<TransitionStyle>,
+    #[serde(default = "SceneConfig::default_transition_ms")]
+    pub transition_ms: i64,
+    //for temperature and reading scenes, the bus name of the sensor to show
+    #[serde(default = "SceneConfig::default_sensor")]
+    pub sensor: String,
+    //for temperature and reading scenes, lit while the scene shows to tell the sensors apart
+    pub led: Option<LedColor>,
+    //for reading scenes, decimal places up to 2, temperatures use [display.temperature]
+    #[serde(default = "SceneConfig::default_precision")]
+    pub precision: usize,
+    #[serde(default)]
+    pub negative: NegativeStyle,
+}
+
+impl SceneConfig {
+    fn default_transition_ms() -> i64 {
+        400
+    }
+
+    fn default_sensor() -> String {
+        TEMPERATURE.to_string()
+    }
+
+    fn default_precision() -> usize {
+        1
+    }
+}
+
+/// `[display.temperature]`, how temperature scenes show a reading, and when without a playlist
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TemperatureDisplayConfig {
+    //Fahrenheit and Celsius side by side on the NCS3148C and Fahrenheit on the NCS3186 when unset
+    pub unit: Option<TemperatureUnit>,
+    //decimal places, up to 2
+    pub precision: usize,
+    pub negative: NegativeStyle,
+    //without a playlist, shown from this second of every minute
+    pub second: u32,
+    //for this long, 0 for never
+    pub seconds: u32,
+    pub transition: TransitionStyle,
+    pub transition_out: Option<TransitionStyle>,
+    pub transition_ms: i64,
+}
+
+impl Default for TemperatureDisplayConfig {
+    fn default() -> Self {
+        TemperatureDisplayConfig {
+            unit: None,
+            precision: 2,
+            negative: NegativeStyle::TopDot,
+            second: 16,
+            seconds: 3,
+            transition: TransitionStyle::Cut,
+            transition_out: None,
+            transition_ms: SceneConfig::default_transition_ms(),
+        }
+    }
+}
+
+impl TemperatureDisplayConfig {
+    pub fn format_for(&self, clock_type: ClockType) -> Result<TemperatureFormat, Box<dyn Error>> {
+        let unit = self.unit.unwrap_or(match clock_type {
+            ClockType::NCS3148C => TemperatureUnit::FahrenheitCelsius,
+            ClockType::NCS3186 => TemperatureUnit::Fahrenheit,
+        });
+        if self.precision > 2 {
+            return Err(format!("Temperature precision is {}, at most 2 decimals fit", self.precision).into());
+        }
+        let format = TemperatureFormat {
+            unit,
+            number: NumberFormat {
+                precision: self.precision,
+                negative: self.negative,
+            },
+        };
+        if format.render(0f32, clock_type).is_none() {
+            return Err(format!("Temperatures in {:?} don't fit the {:?}", unit, clock_type).into());
+        }
+        Ok(format)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum SceneContent {
+    //the time template the drivers already show, nothing to overlay
+    BaseTime,
+    Template(DisplayTemplate, Option<Tz>),
+    Reading(ReadingScene),
+}
+
+#[derive(Debug, Clone)]
+struct Scene {
+    content: SceneContent,
+    duration: Duration,
+    transition: SceneTransition,
+}
+
+/// Cycles through scenes within each minute, starting over at second 0. Scenes are laid
+/// end to end and repeat if they add up to less than a minute. Everything other than the
+/// base time is turned into overlays when the drivers set up each minute.
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    scenes: Vec<Scene>,
+}
+
+impl Playlist {
+    pub fn from_config(
+        scene_configs: &[SceneConfig],
+        time_template: &DisplayTemplate,
+        temperature_format: TemperatureFormat,
+        clock_type: ClockType,
+    ) -> Result<Playlist, Box<dyn Error>> {
+        let mut scenes = vec![];
+        for sc in scene_configs {
+            if sc.seconds == 0 {
+                return Err(format!("Playlist {:?} scene needs a length in seconds", sc.scene).into());
+            }
+            if !transition_fits(sc.transition_ms, sc.seconds) {
+                return Err(format!(
+                    "Playlist {:?} scene's transition_ms is {}, it has to be from 0 to its {} seconds",
+                    sc.scene, sc.transition_ms, sc.seconds
+                )
+                .into());
+            }
+            let content = match (sc.scene, &sc.format) {
+                (SceneKind::Time, None) if sc.zone.is_none() => SceneContent::BaseTime,
+                (SceneKind::Time, None) => SceneContent::Template(time_template.clone(), sc.zone),
+                (SceneKind::Time, Some(f)) => {
+                    SceneContent::Template(DisplayTemplate::parse(f, clock_type)?, sc.zone)
+                }
+                (SceneKind::Date, f) => SceneContent::Template(
+                    DisplayTemplate::parse(
+                        f.as_deref().unwrap_or_else(|| clock_type.default_date_format()),
+                        clock_type,
+                    )?,
+                    sc.zone,
+                ),
+                (SceneKind::Temperature, _) => SceneContent::Reading(ReadingScene {
+                    sensor: sc.sensor.clone(),
+                    led: sc.led,
+                    format: ReadingFormat::Temperature(temperature_format),
+                }),
+                (SceneKind::Reading, _) => {
+                    if sc.precision > 2 {
+                        return Err(format!("Reading scene precision is {}, at most 2 decimals fit", sc.precision).into());
+                    }
+                    SceneContent::Reading(ReadingScene {
+                        sensor: sc.sensor.clone(),
+                        led: sc.led,
+                        format: ReadingFormat::Value(NumberFormat {
+                            precision: sc.precision,
+                            negative: sc.negative,
+                        }),
+                    })
+                }
+            };
+            scenes.push(Scene {
+                content,
+                duration: Duration::seconds(sc.seconds as i64),
+                transition: SceneTransition {
+                    style: sc.transition,
+                    exit_style: sc.transition_out.unwrap_or(sc.transition),
+                    duration: Duration::milliseconds(sc.transition_ms),
+                },
+            });
+        }
+        Ok(Playlist { scenes })
+    }
+
+    /// The temperature for a few seconds of every minute, at second 16 for 3 seconds unless
+    /// `[display.temperature]` says otherwise
+    pub fn default_playlist(
+        temperature: &TemperatureDisplayConfig,
+        format: TemperatureFormat,
+    ) -> Result<Playlist, Box<dyn Error>> {
+        if temperature.seconds == 0 {
+            return Ok(Playlist { scenes: vec![] });
+        }
+        if temperature.second.checked_add(temperature.seconds).is_none_or(|end| end > 60) {
+            return Err(format!(
+                "The temperature at second {} for {} seconds runs past the minute",
+                temperature.second, temperature.seconds
+            )
+            .into());
+        }
+        if !transition_fits(temperature.transition_ms, temperature.seconds) {
+            return Err(format!(
+                "The temperature's transition_ms is {}, it has to be from 0 to its {} seconds",
+                temperature.transition_ms, temperature.seconds
+            )
+            .into());
+        }
+        let scene = |content, seconds: u32, transition| Scene {
+            content,
+            duration: Duration::seconds(seconds as i64),
+            transition,
+        };
+        let shown = SceneContent::Reading(ReadingScene {
+            sensor: TEMPERATURE.to_string(),
+            led: None,
+            format: ReadingFormat::Temperature(format),
+        });
+        let transition = SceneTransition {
+            style: temperature.transition,
+            exit_style: temperature.transition_out.unwrap_or(temperature.transition),
+            duration: Duration::milliseconds(temperature.transition_ms),
+        };
+        let scenes = vec![
+            scene(SceneContent::BaseTime, temperature.second, SceneTransition::cut()),
+            scene(shown, temperature.seconds, transition),
+            scene(SceneContent::BaseTime, 60 - temperature.second - temperature.seconds, SceneTransition::cut()),
+        ];
+        Ok(Playlist {
+            scenes: scenes.into_iter().filter(|s| s.duration > Duration::zero()).collect(),
+        })
+    }
+
+    pub fn overlays_for_minute(
+        &self,
+        local: DateTime<Local>,
+        sensors: &SensorBus,
+        clock_type: ClockType,
+    ) -> Vec<Overlay> {
+        let mut overlays = vec![];
+        if self.scenes.is_empty() {
+            return overlays;
+        }
+        let minute_start = local.with_second(0).unwrap().with_nanosecond(0).unwrap();
+        let minute_end = minute_start + Duration::minutes(1);
+        let mut start_time = minute_start;
+        for scene in self.scenes.iter().cycle() {
+            if start_time >= minute_end {
+                break;
+            }
+            let duration = scene.duration.min(minute_end - start_time);
+            match &scene.content {
+                SceneContent::BaseTime => (),
+                SceneContent::Template(t, zone) => overlays.push(Overlay::Scene(SceneOverlay::new(
+                    start_time,
+                    duration,
+                    t.clone(),
+                    *zone,
+                    scene.transition,
+                ))),
+                SceneContent::Reading(reading) => {
+                    overlays.push(Overlay::TempOverlay(TempOverlayAnimation::new(
+                        sensors.clone(),
+                        reading.clone(),
+                        clock_type,
+                        start_time,
+                        duration,
+                        scene.transition,
+                    )))
+                }
+            }
+            start_time = start_time + scene.duration;
+        }
+        overlays
+    }
+}
+
+//a transition can't run longer than the scene it brings in
+fn transition_fits(transition_ms: i64, seconds: u32) -> bool {
+    (0..=i64::from(seconds) * 1000).contains(&transition_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_temperature_fits_the_minute() {
+        let temperature = |second, seconds| TemperatureDisplayConfig {
+            second,
+            seconds,
+            ..Default::default()
+        };
+        let format = TemperatureDisplayConfig::default().format_for(ClockType::NCS3148C).unwrap();
+        assert!(Playlist::default_playlist(&temperature(57, 3), format).is_ok());
+        assert!(Playlist::default_playlist(&temperature(58, 3), format).is_err());
+        assert!(Playlist::default_playlist(&temperature(u32::MAX, 3), format).is_err());
+        assert!(Playlist::default_playlist(&temperature(16, u32::MAX), format).is_err());
+        assert!(Playlist::default_playlist(&temperature(u32::MAX, 0), format).unwrap().scenes.is_empty());
+    }
+
+    #[derive(Deserialize)]
+    struct Scenes {
+        playlist: Vec<SceneConfig>,
+    }
+
+    fn playlist(source: &str) -> Result<Playlist, Box<dyn Error>> {
+        let scenes: Scenes = toml::from_str(source).unwrap();
+        let clock_type = ClockType::NCS3186;
+        let time_template = DisplayTemplate::parse("%H%:%M%:%S", clock_type).unwrap();
+        let format = TemperatureDisplayConfig::default().format_for(clock_type).unwrap();
+        Playlist::from_config(&scenes.playlist, &time_template, format, clock_type)
+    }
+
+    //each overlay as its kind, the second it starts on and how many seconds it lasts
+    fn laid_out(playlist: &Playlist) -> Vec<(&'static str, i64, i64)> {
+        let now = Local.ymd(2021, 6, 7).and_hms_milli(12, 34, 27, 500);
+        let minute = Local.ymd(2021, 6, 7).and_hms(12, 34, 0);
+        playlist
+            .overlays_for_minute(now, &SensorBus::default(), ClockType::NCS3186)
+            .iter()
+            .map(|o| match o {
+                Overlay::Scene(s) => ("scene", s.start_time, s.duration),
+                Overlay::TempOverlay(t) => ("reading", t.start_time, t.duration),
+                other => panic!("{:?}", other),
+            })
+            .map(|(kind, start, duration)| (kind, (start - minute).num_seconds(), duration.num_seconds()))
+            .collect()
+    }
+
+    #[test]
+    fn lays_scenes_end_to_end() {
+        let scenes = playlist(
+            r#"
+            [[playlist]]
+            scene = "time"
+            seconds = 20
+            [[playlist]]
+            scene = "date"
+            seconds = 10
+            [[playlist]]
+            scene = "temperature"
+            seconds = 5
+            [[playlist]]
+            scene = "time"
+            seconds = 25
+            zone = "Asia/Tokyo"
+            "#,
+        )
+        .unwrap();
+        //the plain time is what's underneath, so it leaves a gap
+        assert_eq!(laid_out(&scenes), vec![("scene", 20, 10), ("reading", 30, 5), ("scene", 35, 25)]);
+    }
+
+    #[test]
+    fn repeats_short_playlists() {
+        let scenes = playlist(
+            r#"
+            [[playlist]]
+            scene = "time"
+            seconds = 10
+            [[playlist]]
+            scene = "date"
+            seconds = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            laid_out(&scenes),
+            vec![("scene", 10, 5), ("scene", 25, 5), ("scene", 40, 5), ("scene", 55, 5)]
+        );
+    }
+
+    #[test]
+    fn cuts_the_last_scene_at_the_minute() {
+        let scenes = playlist(
+            r#"
+            [[playlist]]
+            scene = "time"
+            seconds = 7
+            [[playlist]]
+            scene = "reading"
+            sensor = "pressure"
+            seconds = 15
+            "#,
+        )
+        .unwrap();
+        assert_eq!(laid_out(&scenes), vec![("reading", 7, 15), ("reading", 29, 15), ("reading", 51, 9)]);
+        let long = playlist("[[playlist]]\nscene = \"date\"\nseconds = 90\n").unwrap();
+        assert_eq!(laid_out(&long), vec![("scene", 0, 60)]);
+        let empty = Playlist { scenes: vec![] };
+        assert!(laid_out(&empty).is_empty());
+    }
+
+    #[test]
+    fn lays_out_the_default_temperature() {
+        let format = TemperatureDisplayConfig::default().format_for(ClockType::NCS3186).unwrap();
+        let default = Playlist::default_playlist(&TemperatureDisplayConfig::default(), format).unwrap();
+        assert_eq!(laid_out(&default), vec![("reading", 16, 3)]);
+        let at_the_end = TemperatureDisplayConfig {
+            second: 55,
+            seconds: 5,
+            ..Default::default()
+        };
+        let at_the_end = Playlist::default_playlist(&at_the_end, format).unwrap();
+        assert_eq!(at_the_end.scenes.len(), 2);
+        assert_eq!(laid_out(&at_the_end), vec![("reading", 55, 5)]);
+    }
+
+    #[test]
+    fn transitions_fit_their_scenes() {
+        let scene = |seconds, transition_ms| {
+            playlist(&format!(
+                "[[playlist]]\nscene = \"date\"\nseconds = {}\ntransition_ms = {}\n",
+                seconds, transition_ms
+            ))
+        };
+        assert!(scene(5, 0).is_ok());
+        assert!(scene(5, 5000).is_ok());
+        assert!(scene(5, 5001).is_err());
+        assert!(scene(5, -1).is_err());
+        assert!(scene(0, 0).is_err());
+        let default = playlist("[[playlist]]\nscene = \"date\"\nseconds = 1\n").unwrap();
+        assert_eq!(default.scenes[0].transition.duration, Duration::milliseconds(400));
+
+        let format = TemperatureDisplayConfig::default().format_for(ClockType::NCS3186).unwrap();
+        let temperature = |transition_ms| TemperatureDisplayConfig {
+            transition_ms,
+            ..Default::default()
+        };
+        assert!(Playlist::default_playlist(&temperature(3000), format).is_ok());
+        assert!(Playlist::default_playlist(&temperature(3001), format).is_err());
+        assert!(Playlist::default_playlist(&temperature(-400), format).is_err());
+    }
+}
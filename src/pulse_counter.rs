@@ -0,0 +1,429 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::prelude::*;
+use rppal::gpio::{Gpio, InputPin, Trigger};
+use serde::Deserialize;
+
+use crate::errors::{SensorError, SensorResult};
+use crate::sensors::{Sensor, Unit};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PulseEdge {
+    //open collector outputs like S0 pull the line low for each pulse
+    #[default]
+    Falling,
+    Rising,
+}
+
+/// Settings every pulse input has
+#[derive(Debug, Clone, Deserialize)]
+pub struct PulsePin {
+    //BCM numbering
+    pub pin: u8,
+    #[serde(default)]
+    pub edge: PulseEdge,
+    #[serde(default = "PulsePin::default_pull_up")]
+    pub pull_up: bool,
+    //edges this soon after the last one are bounces
+    #[serde(default)]
+    pub debounce_ms: u64,
+}
+
+impl PulsePin {
+    fn default_pull_up() -> bool {
+        true
+    }
+}
+
+/// One of the `[[pulse_inputs]]`, with the `preset` saying what is counted
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "preset", rename_all = "snake_case")]
+pub enum PulseInputConfig {
+    Geiger(GeigerConfig),
+    S0(S0Config),
+}
+
+impl PulseInputConfig {
+    pub fn pin(&self) -> &PulsePin {
+        match self {
+            PulseInputConfig::Geiger(g) => &g.pin,
+            PulseInputConfig::S0(s) => &s.pin,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let (window_seconds, scale, scale_name) = match self {
+            PulseInputConfig::Geiger(g) => (g.window_seconds, g.cpm_per_usv_h, "cpm_per_usv_h"),
+            PulseInputConfig::S0(s) => (s.window_seconds, s.pulses_per_kwh, "pulses_per_kwh"),
+        };
+        let pin = self.pin().pin;
+        if window_seconds == 0 {
+            return Err(format!("Pulse input on GPIO {} needs a window_seconds of at least 1", pin).into());
+        }
+        if scale.is_nan() || scale <= 0f32 {
+            return Err(format!("Pulse input on GPIO {} needs a {} above 0", pin, scale_name).into());
+        }
+        Ok(())
+    }
+}
+
+/// A Geiger counter board's pulse output, one pulse per particle
+#[derive(Debug, Clone, Deserialize)]
+pub struct GeigerConfig {
+    #[serde(flatten)]
+    pub pin: PulsePin,
+    //bus names of the measurements
+    #[serde(default = "GeigerConfig::default_cpm")]
+    pub cpm: String,
+    #[serde(default = "GeigerConfig::default_dose_rate")]
+    pub dose_rate: String,
+    //counts are averaged over this long, background radiation is only a few a second
+    #[serde(default = "GeigerConfig::default_window_seconds")]
+    pub window_seconds: u64,
+    //depends on the tube, 153.8 for the J305 and M4011, 175.4 for the SBM-20
+    #[serde(default = "GeigerConfig::default_cpm_per_usv_h")]
+    pub cpm_per_usv_h: f32,
+}
+
+impl GeigerConfig {
+    fn default_cpm() -> String {
+        "cpm".to_string()
+    }
+    fn default_dose_rate() -> String {
+        "dose_rate".to_string()
+    }
+    fn default_window_seconds() -> u64 {
+        60
+    }
+    fn default_cpm_per_usv_h() -> f32 {
+        153.8
+    }
+}
+
+/// An electricity meter's S0 output, a pulse for every so many Wh
+#[derive(Debug, Clone, Deserialize)]
+pub struct S0Config {
+    #[serde(flatten)]
+    pub pin: PulsePin,
+    #[serde(default = "S0Config::default_power")]
+    pub power: String,
+    #[serde(default = "S0Config::default_energy")]
+    pub energy: String,
+    //printed on the meter as imp/kWh
+    #[serde(default = "S0Config::default_pulses_per_kwh")]
+    pub pulses_per_kwh: f32,
+    //the power is averaged over the pulses this recent
+    #[serde(default = "S0Config::default_window_seconds")]
+    pub window_seconds: u64,
+}
+
+impl S0Config {
+    fn default_power() -> String {
+        "power".to_string()
+    }
+    fn default_energy() -> String {
+        "energy_today".to_string()
+    }
+    fn default_pulses_per_kwh() -> f32 {
+        1000f32
+    }
+    fn default_window_seconds() -> u64 {
+        60
+    }
+}
+
+/// The edges seen on an input since they were last taken. The pin's interrupt adds to it,
+/// and so can tests in place of the pin.
+#[derive(Debug, Clone, Default)]
+pub struct EdgeLog {
+    edges: Arc<Mutex<Vec<Instant>>>,
+}
+
+impl EdgeLog {
+    pub fn record(&self, at: Instant) {
+        self.edges.lock().unwrap().push(at);
+    }
+
+    fn take(&self) -> Vec<Instant> {
+        std::mem::take(&mut *self.edges.lock().unwrap())
+    }
+}
+
+/// Counts the pulses on a GPIO pin, keeping the ones in a rolling window to work out rates
+pub struct PulseCounter {
+    config: PulseInputConfig,
+    edges: EdgeLog,
+    //held for its interrupt
+    _pin: Option<InputPin>,
+    //oldest first
+    recent: VecDeque<Instant>,
+    last_edge: Option<Instant>,
+    started: Instant,
+    //the S0 pulses since midnight
+    today: (NaiveDate, u64),
+}
+
+impl PulseCounter {
+    pub fn open(config: &PulseInputConfig) -> Result<PulseCounter, Box<dyn Error>> {
+        let pulse_pin = config.pin();
+        let pin = Gpio::new()?.get(pulse_pin.pin)?;
+        let mut pin = if pulse_pin.pull_up {
+            pin.into_input_pullup()
+        } else {
+            pin.into_input_pulldown()
+        };
+        let trigger = match pulse_pin.edge {
+            PulseEdge::Falling => Trigger::FallingEdge,
+            PulseEdge::Rising => Trigger::RisingEdge,
+        };
+        let edges = EdgeLog::default();
+        let interrupt_edges = edges.clone();
+        pin.set_async_interrupt(trigger, move |_| interrupt_edges.record(Instant::now()))?;
+        println!("Counting pulses on GPIO {}", pulse_pin.pin);
+        let mut counter = PulseCounter::new(config.clone(), edges, Instant::now(), Local::now().naive_local().date());
+        counter._pin = Some(pin);
+        Ok(counter)
+    }
+
+    pub fn new(config: PulseInputConfig, edges: EdgeLog, started: Instant, today: NaiveDate) -> PulseCounter {
+        PulseCounter {
+            config,
+            edges,
+            _pin: None,
+            recent: VecDeque::new(),
+            last_edge: None,
+            started,
+            today: (today, 0),
+        }
+    }
+
+    /// The measurements as of `now`, on the local `date`
+    pub fn read_at(&mut self, now: Instant, date: NaiveDate) -> Vec<SensorResult<f32>> {
+        let debounce = Duration::from_millis(self.config.pin().debounce_ms);
+        let mut counted = 0;
+        for edge in self.edges.take() {
+            if self.last_edge.is_some_and(|last| edge.duration_since(last) < debounce) {
+                continue;
+            }
+            self.recent.push_back(edge);
+            self.last_edge = Some(edge);
+            counted += 1;
+        }
+        match &self.config {
+            PulseInputConfig::Geiger(geiger) => {
+                let window = Duration::from_secs(geiger.window_seconds);
+                forget_before(&mut self.recent, now, window, 0);
+                //until a whole window has passed the counts are only over the time so far
+                let counted_over = now.duration_since(self.started).min(window);
+                if counted_over < Duration::from_secs(1) {
+                    return vec![Err(SensorError::Pending), Err(SensorError::Pending)];
+                }
+                let cpm = self.recent.len() as f32 * 60f32 / counted_over.as_secs_f32();
+                vec![Ok(cpm), Ok(cpm / geiger.cpm_per_usv_h)]
+            }
+            PulseInputConfig::S0(s0) => {
+                //the last two pulses are kept however old, to time the one after
+                forget_before(&mut self.recent, now, Duration::from_secs(s0.window_seconds), 2);
+                if self.today.0 != date {
+                    self.today = (date, 0);
+                }
+                self.today.1 += counted;
+                let wh_per_pulse = 1000f32 / s0.pulses_per_kwh;
+                let power = match (self.recent.front(), self.recent.back()) {
+                    (Some(first), Some(last)) if self.recent.len() >= 2 => {
+                        let between = last.duration_since(*first).as_secs_f32() / (self.recent.len() - 1) as f32;
+                        //a long wait for the next pulse means the power has dropped since
+                        let interval = between.max(now.duration_since(*last).as_secs_f32());
+                        Ok(wh_per_pulse * 3600f32 / interval)
+                    }
+                    _ => Err(SensorError::Pending),
+                };
+                vec![power, Ok(self.today.1 as f32 / s0.pulses_per_kwh)]
+            }
+        }
+    }
+}
+
+//drops the edges older than the window, but not the last `keep` of them
+fn forget_before(recent: &mut VecDeque<Instant>, now: Instant, window: Duration, keep: usize) {
+    while recent.len() > keep && recent.front().is_some_and(|e| now.duration_since(*e) > window) {
+        recent.pop_front();
+    }
+}
+
+impl Sensor for PulseCounter {
+    fn name(&self) -> String {
+        match &self.config {
+            PulseInputConfig::Geiger(_) => "Geiger counter".to_string(),
+            PulseInputConfig::S0(_) => "S0 meter".to_string(),
+        }
+    }
+
+    fn measurements(&self) -> Vec<(String, Unit)> {
+        match &self.config {
+            PulseInputConfig::Geiger(g) => vec![
+                (g.cpm.clone(), Unit::CountsPerMinute),
+                (g.dose_rate.clone(), Unit::MicrosievertsPerHour),
+            ],
+            PulseInputConfig::S0(s) => vec![(s.power.clone(), Unit::Watt), (s.energy.clone(), Unit::KilowattHour)],
+        }
+    }
+
+    fn read(&mut self) -> Result<Vec<SensorResult<f32>>, Box<dyn Error>> {
+        Ok(self.read_at(Instant::now(), Local::now().naive_local().date()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin(debounce_ms: u64) -> PulsePin {
+        PulsePin {
+            pin: 17,
+            edge: PulseEdge::Falling,
+            pull_up: true,
+            debounce_ms,
+        }
+    }
+
+    fn geiger(debounce_ms: u64) -> PulseInputConfig {
+        PulseInputConfig::Geiger(GeigerConfig {
+            pin: pin(debounce_ms),
+            cpm: "cpm".to_string(),
+            dose_rate: "dose_rate".to_string(),
+            window_seconds: 60,
+            cpm_per_usv_h: 153.8,
+        })
+    }
+
+    fn s0() -> PulseInputConfig {
+        PulseInputConfig::S0(S0Config {
+            pin: pin(0),
+            power: "power".to_string(),
+            energy: "energy_today".to_string(),
+            pulses_per_kwh: 1000f32,
+            window_seconds: 60,
+        })
+    }
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 9).unwrap()
+    }
+
+    fn values(results: Vec<SensorResult<f32>>) -> Vec<f32> {
+        results.into_iter().map(Result::unwrap).collect()
+    }
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-3, "{} isn't {}", value, expected);
+    }
+
+    #[test]
+    fn parses_and_validates_presets() {
+        let config: PulseInputConfig = toml::from_str("preset = \"s0\"\npin = 27\npulses_per_kwh = 800.0").unwrap();
+        match &config {
+            PulseInputConfig::S0(s0) => {
+                assert_eq!(s0.pin.edge, PulseEdge::Falling);
+                assert!(s0.pin.pull_up);
+                assert_eq!(s0.energy, "energy_today");
+                assert_eq!(s0.window_seconds, 60);
+            }
+            PulseInputConfig::Geiger(_) => panic!("Parsed as a Geiger counter"),
+        }
+        assert!(config.validate().is_ok());
+        assert!(geiger(0).validate().is_ok());
+        let bad = vec![
+            "preset = \"s0\"\npin = 27\npulses_per_kwh = 0.0",
+            "preset = \"s0\"\npin = 27\nwindow_seconds = 0",
+            "preset = \"geiger\"\npin = 4\nwindow_seconds = 0",
+            "preset = \"geiger\"\npin = 4\ncpm_per_usv_h = -1.0",
+        ];
+        for toml in bad {
+            let config: PulseInputConfig = toml::from_str(toml).unwrap();
+            assert!(config.validate().is_err(), "{}", toml);
+        }
+    }
+
+    #[test]
+    fn ignores_bounces() {
+        let start = Instant::now();
+        let edges = EdgeLog::default();
+        let mut counter = PulseCounter::new(geiger(20), edges.clone(), start, today());
+        for ms in [1000, 1005, 1019, 1500, 1510] {
+            edges.record(start + Duration::from_millis(ms));
+        }
+        counter.read_at(start + Duration::from_secs(2), today());
+        //the bounce window carries over between reads
+        edges.record(start + Duration::from_millis(1515));
+        edges.record(start + Duration::from_millis(2600));
+        counter.read_at(start + Duration::from_secs(3), today());
+        assert_eq!(counter.recent.len(), 3);
+    }
+
+    #[test]
+    fn geiger_counts_per_minute() {
+        let start = Instant::now();
+        let edges = EdgeLog::default();
+        let mut counter = PulseCounter::new(geiger(0), edges.clone(), start, today());
+        assert_eq!(
+            counter.read_at(start, today()),
+            vec![Err(SensorError::Pending), Err(SensorError::Pending)]
+        );
+        //20 counts in the first 30s
+        for s in 1..=20 {
+            edges.record(start + Duration::from_secs(s));
+        }
+        let partial = values(counter.read_at(start + Duration::from_secs(30), today()));
+        assert_near(partial[0], 40f32);
+        assert_near(partial[1], 40f32 / 153.8);
+        //then one every 2s, the first 20 drop out of the window
+        for s in (32..=120).step_by(2) {
+            edges.record(start + Duration::from_secs(s));
+        }
+        let full = values(counter.read_at(start + Duration::from_secs(121), today()));
+        assert_near(full[0], 30f32);
+        assert_near(full[1], 30f32 / 153.8);
+    }
+
+    #[test]
+    fn s0_power_from_pulse_spacing() {
+        let start = Instant::now();
+        let edges = EdgeLog::default();
+        let mut counter = PulseCounter::new(s0(), edges.clone(), start, today());
+        edges.record(start);
+        let first = counter.read_at(start, today());
+        assert_eq!(first[0], Err(SensorError::Pending));
+        assert_near(first[1].clone().unwrap(), 0.001);
+        //1Wh every 3.6s is 1kW
+        edges.record(start + Duration::from_millis(3600));
+        edges.record(start + Duration::from_millis(7200));
+        let at_pulse = values(counter.read_at(start + Duration::from_millis(7200), today()));
+        assert_near(at_pulse[0], 1000f32);
+        assert_near(at_pulse[1], 0.003);
+        //no pulse for 36s means at most 100W, and the two last pulses are kept to time the next
+        let waiting = values(counter.read_at(start + Duration::from_millis(43200), today()));
+        assert_near(waiting[0], 100f32);
+        let much_later = values(counter.read_at(start + Duration::from_millis(3607200), today()));
+        assert_near(much_later[0], 1f32);
+        assert_near(much_later[1], 0.003);
+    }
+
+    #[test]
+    fn s0_energy_starts_again_at_midnight() {
+        let start = Instant::now();
+        let edges = EdgeLog::default();
+        let mut counter = PulseCounter::new(s0(), edges.clone(), start, today());
+        for s in 0..5 {
+            edges.record(start + Duration::from_secs(s));
+        }
+        assert_near(values(counter.read_at(start + Duration::from_secs(5), today()))[1], 0.005);
+        let tomorrow = today().succ_opt().unwrap();
+        assert_near(values(counter.read_at(start + Duration::from_secs(6), tomorrow))[1], 0f32);
+        edges.record(start + Duration::from_secs(7));
+        assert_near(values(counter.read_at(start + Duration::from_secs(8), tomorrow))[1], 0.001);
+    }
+}
@@ -1,9 +1,7 @@
-use alloc::boxed::Box;
-use core::result::Result;
-use core::result::Result::Ok;
 use rppal::gpio::Gpio;
-use rppal::gpio::pin::OutputPin;
+use rppal::gpio::OutputPin;
 use rppal::system::DeviceInfo;
+use serde::Deserialize;
 use std::error::Error;
 
 //RGB Pins
@@ -11,6 +9,35 @@ const R_PIN: u8 = 20;
 const G_PIN: u8 = 16;
 const B_PIN: u8 = 21;
 
+//The LED pins are plain on/off outputs, so only the primaries and their mixes are available
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedColor {
+    Off,
+    Red,
+    Green,
+    Blue,
+    Yellow,
+    Cyan,
+    Magenta,
+    White,
+}
+
+impl LedColor {
+    fn channels(&self) -> (bool, bool, bool) {
+        match self {
+            LedColor::Off => (false, false, false),
+            LedColor::Red => (true, false, false),
+            LedColor::Green => (false, true, false),
+            LedColor::Blue => (false, false, true),
+            LedColor::Yellow => (true, true, false),
+            LedColor::Cyan => (false, true, true),
+            LedColor::Magenta => (true, false, true),
+            LedColor::White => (true, true, true),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct LedDisplay {
     r_pin: OutputPin,
@@ -29,4 +56,15 @@ impl LedDisplay {
 
         Ok(cd)
     }
+
+    pub fn set_color(&mut self, color: LedColor) {
+        let (r, g, b) = color.channels();
+        for (pin, on) in [(&mut self.r_pin, r), (&mut self.g_pin, g), (&mut self.b_pin, b)] {
+            if on {
+                pin.set_high()
+            } else {
+                pin.set_low()
+            }
+        }
+    }
 }
@@ -0,0 +1,385 @@
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Instant;
+use chrono::prelude::*;
+use chrono::Duration;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use rppal::i2c::I2c;
+use serde::Deserialize;
+
+use crate::errors::{RtcError, RtcResult};
+use crate::time_sync::{step_system_clock, SyncStatus};
+
+//both chips answer on the same address and share the time registers
+const RTC_ADDRESS: u8 = 0x68;
+const DS3231_STATUS: u8 = 0x0f;
+const OSCILLATOR_FLAG: u8 = 0x80;
+const RTC_SOURCE: &str = "rtc";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RtcChip {
+    Ds1307,
+    Ds3231,
+}
+
+/// The `[rtc]` section of the config
+#[derive(Debug, Clone, Deserialize)]
+pub struct RtcConfig {
+    #[serde(default = "RtcConfig::default_chip")]
+    pub chip: RtcChip,
+    #[serde(default = "RtcConfig::default_bus")]
+    pub bus: u8,
+    //step the system clock to the RTC when they disagree, needs root
+    #[serde(default = "RtcConfig::default_set_system_clock")]
+    pub set_system_clock: bool,
+    //how often to read the RTC while nothing better (NTP, GPS) has the time, and to write it back once it has
+    #[serde(default = "RtcConfig::default_poll_seconds")]
+    pub poll_seconds: i64,
+    //used for the sync status when there is no [ntp] section
+    #[serde(default = "RtcConfig::default_tolerance_ms")]
+    pub tolerance_ms: i64,
+    #[serde(default = "RtcConfig::default_max_age_seconds")]
+    pub max_age_seconds: i64,
+}
+
+impl RtcConfig {
+    fn default_chip() -> RtcChip {
+        RtcChip::Ds3231
+    }
+    fn default_bus() -> u8 {
+        1
+    }
+    fn default_set_system_clock() -> bool {
+        true
+    }
+    fn default_poll_seconds() -> i64 {
+        3600
+    }
+    fn default_tolerance_ms() -> i64 {
+        1000
+    }
+    fn default_max_age_seconds() -> i64 {
+        7200
+    }
+
+    pub fn new_sync_status(&self) -> SyncStatus {
+        SyncStatus::new(
+            Duration::milliseconds(self.tolerance_ms),
+            Duration::seconds(self.max_age_seconds),
+        )
+    }
+}
+
+/// A DS1307 or DS3231 battery-backed clock, which holds UTC to whole seconds
+pub struct Rtc<I> {
+    i2c: I,
+    chip: RtcChip,
+}
+
+impl<I, E> Rtc<I>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn new(i2c: I, chip: RtcChip) -> Rtc<I> {
+        Rtc { i2c, chip }
+    }
+
+    fn read_registers(&mut self, start: u8, buffer: &mut [u8]) -> RtcResult<()> {
+        self.i2c
+            .write_read(RTC_ADDRESS, &[start], buffer)
+            .map_err(|e| RtcError::Bus(format!("{:?}", e)))
+    }
+
+    fn write_registers(&mut self, bytes: &[u8]) -> RtcResult<()> {
+        self.i2c
+            .write(RTC_ADDRESS, bytes)
+            .map_err(|e| RtcError::Bus(format!("{:?}", e)))
+    }
+
+    /// Whether the oscillator has stopped since the time was last set
+    pub fn lost_time(&mut self) -> RtcResult<bool> {
+        let mut reg = [0u8];
+        match self.chip {
+            //the clock halt bit on the seconds register
+            RtcChip::Ds1307 => self.read_registers(0x00, &mut reg)?,
+            RtcChip::Ds3231 => self.read_registers(DS3231_STATUS, &mut reg)?,
+        }
+        Ok(reg[0] & OSCILLATOR_FLAG != 0)
+    }
+
+    pub fn read_time(&mut self) -> RtcResult<NaiveDateTime> {
+        if self.lost_time()? {
+            return Err(RtcError::OscillatorStopped);
+        }
+        let mut regs = [0u8; 7];
+        self.read_registers(0x00, &mut regs)?;
+        let hour = if regs[2] & 0x40 != 0 {
+            //12 hour mode, bit 5 is PM
+            from_bcd(regs[2] & 0x1f) % 12 + if regs[2] & 0x20 != 0 { 12 } else { 0 }
+        } else {
+            from_bcd(regs[2] & 0x3f)
+        };
+        let century = match self.chip {
+            RtcChip::Ds3231 if regs[5] & 0x80 != 0 => 100,
+            _ => 0,
+        };
+        NaiveDate::from_ymd_opt(
+            2000 + century + from_bcd(regs[6]) as i32,
+            from_bcd(regs[5] & 0x1f),
+            from_bcd(regs[4] & 0x3f),
+        )
+        .and_then(|d| d.and_hms_opt(hour, from_bcd(regs[1] & 0x7f), from_bcd(regs[0] & 0x7f)))
+        .ok_or(RtcError::InvalidTime)
+    }
+
+    pub fn write_time(&mut self, time: &NaiveDateTime) -> RtcResult<()> {
+        let year = time.year() - 2000;
+        if !(0..200).contains(&year) || (year >= 100 && self.chip == RtcChip::Ds1307) {
+            return Err(RtcError::InvalidTime);
+        }
+        let century = if year >= 100 { 0x80 } else { 0 };
+        //writing the seconds also clears the DS1307 clock halt bit, starting it
+        self.write_registers(&[
+            0x00,
+            to_bcd(time.second()),
+            to_bcd(time.minute()),
+            to_bcd(time.hour()),
+            time.weekday().number_from_sunday() as u8,
+            to_bcd(time.day()),
+            to_bcd(time.month()) | century,
+            to_bcd(year as u32 % 100),
+        ])?;
+        if self.chip == RtcChip::Ds3231 {
+            let mut status = [0u8];
+            self.read_registers(DS3231_STATUS, &mut status)?;
+            self.write_registers(&[DS3231_STATUS, status[0] & !OSCILLATOR_FLAG])?;
+        }
+        Ok(())
+    }
+
+    /// Reads the RTC just as its seconds tick over, along with the system time at that point,
+    /// so the two can be compared to well under a second. Falls back to a plain read
+    /// if no tick is seen.
+    pub fn read_time_aligned(&mut self) -> RtcResult<(NaiveDateTime, DateTime<Utc>)> {
+        let first = self.read_time()?;
+        let started = Instant::now();
+        while started.elapsed() < std::time::Duration::from_millis(1100) {
+            let time = self.read_time()?;
+            if time != first {
+                return Ok((time, Utc::now()));
+            }
+            thread::sleep(std::time::Duration::from_millis(2));
+        }
+        Ok((first, Utc::now()))
+    }
+
+    /// Waits for the next whole second of the system clock corrected by `offset` and writes it
+    pub fn write_time_aligned(&mut self, offset: Duration) -> RtcResult<()> {
+        let now = Utc::now() + offset;
+        let next = now.with_nanosecond(0).unwrap() + Duration::seconds(1);
+        if let Ok(wait) = (next - now).to_std() {
+            thread::sleep(wait);
+        }
+        self.write_time(&next.naive_utc())
+    }
+}
+
+fn from_bcd(b: u8) -> u32 {
+    ((b >> 4) * 10 + (b & 0x0f)) as u32
+}
+
+fn to_bcd(v: u32) -> u8 {
+    (((v / 10) << 4) | (v % 10)) as u8
+}
+
+/// Keeps the RTC and the system clock in step. While nothing better has the time the RTC
+/// is the sync source (and can set the system clock at boot), and once another source
+/// such as NTP has synced, its time is written back to the RTC along with the drift found.
+pub struct RtcClock<I> {
+    rtc: Rtc<I>,
+    config: RtcConfig,
+    sync_lock: Arc<RwLock<SyncStatus>>,
+    last_read: Option<Instant>,
+    last_written: Option<Instant>,
+}
+
+impl RtcClock<I2c> {
+    //NB: this is blocking and should only be run in a separate thread
+    pub fn run(config: RtcConfig, sync_lock: Arc<RwLock<SyncStatus>>) {
+        let i2c = match I2c::with_bus(config.bus) {
+            Ok(i2c) => i2c,
+            Err(e) => {
+                println!("RTC unavailable on I2C bus {}: {}", config.bus, e);
+                return;
+            }
+        };
+        let mut rtc_clock = RtcClock::new(Rtc::new(i2c, config.chip), config, sync_lock);
+        loop {
+            if let Err(e) = rtc_clock.check() {
+                println!("RTC check failed: {}", e);
+            }
+            thread::sleep(std::time::Duration::from_secs(10));
+        }
+    }
+}
+
+impl<I, E> RtcClock<I>
+where
+    I: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn new(rtc: Rtc<I>, config: RtcConfig, sync_lock: Arc<RwLock<SyncStatus>>) -> RtcClock<I> {
+        RtcClock {
+            rtc,
+            config,
+            sync_lock,
+            last_read: None,
+            last_written: None,
+        }
+    }
+
+    pub fn check(&mut self) -> RtcResult<()> {
+        let status = self.sync_lock.read().unwrap().clone();
+        let poll = std::time::Duration::from_secs(self.config.poll_seconds.max(0) as u64);
+        let other_source = status.source.as_deref().is_some_and(|s| s != RTC_SOURCE);
+        if other_source && status.is_synced() {
+            //sources like GPS sync every second, so only write back once per poll
+            if self.last_written.is_none_or(|w| w.elapsed() >= poll) {
+                self.write_back(status.offset.unwrap_or_else(Duration::zero))?;
+                self.last_written = Some(Instant::now());
+            }
+            return Ok(());
+        }
+        if self.last_read.is_some_and(|r| r.elapsed() < poll) {
+            return Ok(());
+        }
+        self.last_read = Some(Instant::now());
+        self.read_into_status()
+    }
+
+    fn read_into_status(&mut self) -> RtcResult<()> {
+        let (rtc_time, system_time) = self.rtc.read_time_aligned()?;
+        let mut offset = Utc.from_utc_datetime(&rtc_time) - system_time;
+        println!("RTC reads {} UTC, {}ms from the system clock", rtc_time, offset.num_milliseconds());
+        let tolerance = Duration::milliseconds(self.config.tolerance_ms);
+        if self.config.set_system_clock && (offset > tolerance || -offset > tolerance) {
+            match step_system_clock(offset) {
+                Ok(()) => {
+                    println!("Set system clock from the RTC");
+                    offset = Duration::zero();
+                }
+                Err(e) => println!("Setting system clock from the RTC failed: {}", e),
+            }
+        }
+        let mut status = self.sync_lock.write().unwrap();
+        status.record(RTC_SOURCE.to_string(), offset, Duration::zero(), None);
+        Ok(())
+    }
+
+    fn write_back(&mut self, offset: Duration) -> RtcResult<()> {
+        match self.rtc.read_time_aligned() {
+            Ok((rtc_time, system_time)) => {
+                let drift = Utc.from_utc_datetime(&rtc_time) - (system_time + offset);
+                println!("RTC drifted {}ms, writing back the synced time", drift.num_milliseconds());
+                self.sync_lock.write().unwrap().rtc_drift = Some(drift);
+            }
+            Err(e) => println!("RTC time unusable ({}), writing back the synced time", e),
+        }
+        self.rtc.write_time_aligned(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_i2c::MockI2c;
+
+    fn mock_rtc(chip: RtcChip) -> (Rtc<MockI2c>, MockI2c) {
+        let bus = MockI2c::new();
+        bus.add_device(RTC_ADDRESS);
+        (Rtc::new(bus.clone(), chip), bus)
+    }
+
+    fn time(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, mo, d).unwrap().and_hms_opt(h, mi, s).unwrap()
+    }
+
+    #[test]
+    fn bcd() {
+        for v in 0..100 {
+            assert_eq!(from_bcd(to_bcd(v)), v);
+        }
+        assert_eq!(to_bcd(59), 0x59);
+        assert_eq!(from_bcd(0x31), 31);
+    }
+
+    #[test]
+    fn writes_bcd_registers() {
+        let (mut rtc, bus) = mock_rtc(RtcChip::Ds1307);
+        rtc.write_time(&time(2024, 2, 29, 23, 59, 58)).unwrap();
+        //a Thursday, day 5 counting from Sunday
+        assert_eq!(bus.registers(RTC_ADDRESS, 0, 7), vec![0x58, 0x59, 0x23, 5, 0x29, 0x02, 0x24]);
+    }
+
+    #[test]
+    fn round_trips() {
+        for chip in [RtcChip::Ds1307, RtcChip::Ds3231] {
+            let (mut rtc, _) = mock_rtc(chip);
+            for t in [time(2000, 1, 1, 0, 0, 0), time(2038, 1, 19, 3, 14, 7), time(2099, 12, 31, 23, 59, 59)] {
+                rtc.write_time(&t).unwrap();
+                assert_eq!(rtc.read_time(), Ok(t));
+            }
+        }
+    }
+
+    #[test]
+    fn century_bit() {
+        let (mut ds3231, bus) = mock_rtc(RtcChip::Ds3231);
+        let t = time(2101, 6, 15, 12, 0, 0);
+        ds3231.write_time(&t).unwrap();
+        assert_eq!(bus.registers(RTC_ADDRESS, 5, 2), vec![0x86, 0x01]);
+        assert_eq!(ds3231.read_time(), Ok(t));
+        //the DS1307 has no century bit to hold it
+        let (mut ds1307, _) = mock_rtc(RtcChip::Ds1307);
+        assert_eq!(ds1307.write_time(&t), Err(RtcError::InvalidTime));
+        assert_eq!(ds1307.write_time(&time(1999, 12, 31, 0, 0, 0)), Err(RtcError::InvalidTime));
+    }
+
+    #[test]
+    fn twelve_hour_mode() {
+        let (mut rtc, bus) = mock_rtc(RtcChip::Ds3231);
+        bus.set_registers(RTC_ADDRESS, 0, &[0x30, 0x15, 0x40 | 0x20 | 0x12, 1, 0x07, 0x08, 0x23]);
+        assert_eq!(rtc.read_time(), Ok(time(2023, 8, 7, 12, 15, 30)));
+        bus.set_registers(RTC_ADDRESS, 2, &[0x40 | 0x12]);
+        assert_eq!(rtc.read_time(), Ok(time(2023, 8, 7, 0, 15, 30)));
+        bus.set_registers(RTC_ADDRESS, 2, &[0x40 | 0x20 | 0x09]);
+        assert_eq!(rtc.read_time(), Ok(time(2023, 8, 7, 21, 15, 30)));
+    }
+
+    #[test]
+    fn stopped_oscillator() {
+        let (mut ds3231, bus) = mock_rtc(RtcChip::Ds3231);
+        bus.set_registers(RTC_ADDRESS, DS3231_STATUS, &[OSCILLATOR_FLAG | 0x08]);
+        assert_eq!(ds3231.read_time(), Err(RtcError::OscillatorStopped));
+        //setting the time clears the flag and leaves the rest of the status alone
+        ds3231.write_time(&time(2024, 1, 1, 0, 0, 0)).unwrap();
+        assert_eq!(bus.registers(RTC_ADDRESS, DS3231_STATUS, 1), vec![0x08]);
+        assert!(ds3231.read_time().is_ok());
+
+        let (mut ds1307, bus) = mock_rtc(RtcChip::Ds1307);
+        bus.set_registers(RTC_ADDRESS, 0, &[0x80]);
+        assert_eq!(ds1307.lost_time(), Ok(true));
+        ds1307.write_time(&time(2024, 1, 1, 0, 0, 0)).unwrap();
+        assert_eq!(ds1307.lost_time(), Ok(false));
+    }
+
+    #[test]
+    fn invalid_registers() {
+        let (mut rtc, bus) = mock_rtc(RtcChip::Ds3231);
+        bus.set_registers(RTC_ADDRESS, 0, &[0x00, 0x00, 0x00, 1, 0x31, 0x02, 0x24]);
+        assert_eq!(rtc.read_time(), Err(RtcError::InvalidTime));
+    }
+}
@@ -0,0 +1,652 @@
+use std::collections::HashMap;
+use std::error::Error;
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::clock_objects::{ClockType, SlotKind};
+use crate::config::DisplaySettings;
+use crate::display_template::DisplayTemplate;
+use crate::rgb_driver::LedColor;
+use crate::world_clock::{WorldClock, WorldClockConfig};
+
+/// A standard five field cron expression: minute hour day-of-month month day-of-week.
+/// Fields take `*`, lists, ranges and steps, and months and weekdays also take names.
+/// As in cron, when both day fields are restricted either one matching is enough.
+#[derive(Debug, Clone)]
+pub struct CronExpr {
+    source: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl CronExpr {
+    const MONTHS: [&'static str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    const WEEKDAYS: [&'static str; 8] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+    pub fn parse(source: &str) -> Result<CronExpr, Box<dyn Error>> {
+        let fields: Vec<&str> = source.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Cron expression {:?} needs 5 fields", source).into());
+        }
+        //0 and 7 are both Sunday
+        let mut weekdays = CronExpr::parse_field(fields[4], 0, 7, &CronExpr::WEEKDAYS)?;
+        weekdays[0] |= weekdays[7];
+        weekdays.truncate(7);
+        Ok(CronExpr {
+            source: source.to_string(),
+            minutes: CronExpr::parse_field(fields[0], 0, 59, &[])?,
+            hours: CronExpr::parse_field(fields[1], 0, 23, &[])?,
+            days: CronExpr::parse_field(fields[2], 1, 31, &[])?,
+            months: CronExpr::parse_field(fields[3], 1, 12, &CronExpr::MONTHS)?,
+            weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+
+    //returns a flag for every value from 0 to max, names[i] stands for min + i
+    fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<Vec<bool>, Box<dyn Error>> {
+        let value = |v: &str| -> Result<u32, Box<dyn Error>> {
+            let lower = v.to_lowercase();
+            match names.iter().position(|n| *n == lower) {
+                Some(i) => Ok(min + i as u32),
+                None => Ok(v.parse::<u32>().map_err(|_| format!("Bad cron value {:?}", v))?),
+            }
+        };
+        let mut set = vec![false; max as usize + 1];
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((r, s)) => (r, s.parse::<usize>().map_err(|_| format!("Bad cron step {:?}", s))?),
+                None => (part, 1),
+            };
+            let (lo, hi) = if range == "*" {
+                (min, max)
+            } else if let Some((a, b)) = range.split_once('-') {
+                (value(a)?, value(b)?)
+            } else {
+                let v = value(range)?;
+                //"5/15" means every 15 starting at 5
+                (v, if step > 1 { max } else { v })
+            };
+            if step == 0 || lo < min || hi > max || lo > hi {
+                return Err(format!("Cron field {:?} is out of range {}-{}", part, min, max).into());
+            }
+            for v in (lo..=hi).step_by(step) {
+                set[v as usize] = true;
+            }
+        }
+        Ok(set)
+    }
+
+    pub fn matches<Tz: TimeZone>(&self, t: &DateTime<Tz>) -> bool {
+        let day = self.days[t.day() as usize];
+        let weekday = self.weekdays[t.weekday().num_days_from_sunday() as usize];
+        let day_matches = if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        };
+        self.minutes[t.minute() as usize]
+            && self.hours[t.hour() as usize]
+            && self.months[t.month() as usize]
+            && day_matches
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DateMatch {
+    Yearly { month: u32, day: u32 },
+    Once(NaiveDate),
+}
+
+impl DateMatch {
+    //"2021-12-25" for a single day or "12-25" for every year
+    fn parse(s: &str) -> Result<DateMatch, Box<dyn Error>> {
+        if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(DateMatch::Once(d));
+        }
+        let bad_date = || format!("Bad schedule date {:?}, use YYYY-MM-DD or MM-DD", s);
+        let (m, d) = s.split_once('-').ok_or_else(bad_date)?;
+        let month = m.parse::<u32>().map_err(|_| bad_date())?;
+        let day = d.parse::<u32>().map_err(|_| bad_date())?;
+        //2000 is a leap year so Feb 29 is accepted
+        NaiveDate::from_ymd_opt(2000, month, day).ok_or_else(bad_date)?;
+        Ok(DateMatch::Yearly { month, day })
+    }
+
+    fn matches<Tz: TimeZone>(&self, t: &DateTime<Tz>) -> bool {
+        match self {
+            DateMatch::Yearly { month, day } => t.month() == *month && t.day() == *day,
+            DateMatch::Once(d) => t.naive_local().date() == *d,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModeKind {
+    Clock,
+    Date,
+    Countdown,
+    Blank,
+    WorldClock,
+}
+
+/// What to show until a time source has confirmed the time, set as `display.unsynced`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnsyncedStyle {
+    //blank digits between blinking separators, the nearest the tubes get to dashes
+    #[default]
+    Dashes,
+    //every digit stepping through 0-9 together, one a second
+    Cycle,
+    //the time as usual, the separators still blink until it is confirmed
+    Time,
+}
+
+impl UnsyncedStyle {
+    pub fn render(
+        &self,
+        displayed: &DateTime<Local>,
+        separators_on: bool,
+        time_template: &DisplayTemplate,
+        clock_type: ClockType,
+    ) -> String {
+        let digit = std::char::from_digit(displayed.second() % 10, 10).unwrap();
+        let numeric = match self {
+            UnsyncedStyle::Dashes => ' ',
+            UnsyncedStyle::Cycle => digit,
+            UnsyncedStyle::Time => return time_template.render(displayed, separators_on),
+        };
+        let separator = if separators_on { ':' } else { ' ' };
+        clock_type
+            .slot_layout()
+            .iter()
+            .map(|kind| match kind {
+                SlotKind::Numeric => numeric,
+                SlotKind::Separator => separator,
+                SlotKind::IN19A => ' ',
+            })
+            .collect()
+    }
+}
+
+/// One `[[schedule]]` entry of the config. A rule applies during every minute its cron
+/// expression matches, and only on its dates when it has any. Later rules win.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleRuleConfig {
+    pub cron: Option<String>,
+    #[serde(default)]
+    pub dates: Vec<String>,
+    pub mode: Option<ModeKind>,
+    //template for date and countdown modes
+    pub format: Option<String>,
+    //time of day as HH:MM[:SS] that countdown mode counts down to
+    pub countdown_to: Option<String>,
+    pub brightness: Option<f32>,
+    pub theme: Option<String>,
+}
+
+/// A `[themes.<name>]` entry of the config
+#[derive(Debug, Clone, Deserialize)]
+pub struct ThemeConfig {
+    pub led: Option<LedColor>,
+    pub time_format: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub led: Option<LedColor>,
+    pub time_template: Option<DisplayTemplate>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisplayMode {
+    //the time with the playlist and anti-poison overlays
+    Clock,
+    Date(DisplayTemplate),
+    Countdown { to: NaiveTime, template: DisplayTemplate },
+    Blank,
+    WorldClock(WorldClock),
+    //shown instead of the scheduled mode until the time is confirmed
+    Unsynced(UnsyncedStyle),
+}
+
+impl DisplayMode {
+    /// Builds a mode as named in the config, or by the control APIs
+    pub fn from_kind(
+        kind: ModeKind,
+        format: Option<&str>,
+        countdown_to: Option<&str>,
+        world_clock_config: &Option<WorldClockConfig>,
+        clock_type: ClockType,
+    ) -> Result<DisplayMode, Box<dyn Error>> {
+        Ok(match kind {
+            ModeKind::Clock => DisplayMode::Clock,
+            ModeKind::Blank => DisplayMode::Blank,
+            ModeKind::WorldClock => {
+                let wc = world_clock_config
+                    .as_ref()
+                    .ok_or("World clock mode needs a [world_clock] section")?;
+                DisplayMode::WorldClock(WorldClock::from_config(wc, clock_type)?)
+            }
+            ModeKind::Date => DisplayMode::Date(DisplayTemplate::parse(
+                format.unwrap_or_else(|| clock_type.default_date_format()),
+                clock_type,
+            )?),
+            ModeKind::Countdown => {
+                let to = countdown_to.ok_or("Countdown mode needs countdown_to")?;
+                let to = NaiveTime::parse_from_str(to, "%H:%M:%S")
+                    .or_else(|_| NaiveTime::parse_from_str(to, "%H:%M"))?;
+                DisplayMode::Countdown {
+                    to,
+                    template: DisplayTemplate::parse(
+                        format.unwrap_or_else(|| clock_type.default_countdown_format()),
+                        clock_type,
+                    )?,
+                }
+            }
+        })
+    }
+
+    /// The slots shown underneath any overlays
+    pub fn render(
+        &self,
+        displayed: &DateTime<Local>,
+        separators_on: bool,
+        time_template: &DisplayTemplate,
+        clock_type: ClockType,
+    ) -> String {
+        match self {
+            DisplayMode::Clock => time_template.render(displayed, separators_on),
+            DisplayMode::Date(t) => t.render(displayed, separators_on),
+            DisplayMode::Countdown { to, template } => {
+                //to the next `to`, tomorrow's once today's has passed
+                let mut remaining = *to - displayed.time();
+                if remaining < Duration::zero() {
+                    remaining = remaining + Duration::days(1);
+                }
+                let micros = remaining.num_microseconds().unwrap_or(0);
+                //rendered as a time of day on the epoch, which is fine for under a day
+                match Utc.timestamp_opt(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000).single() {
+                    Some(as_time) => template.render(&as_time, separators_on),
+                    None => " ".repeat(clock_type.slot_layout().len()),
+                }
+            }
+            DisplayMode::Blank => " ".repeat(clock_type.slot_layout().len()),
+            DisplayMode::WorldClock(w) => w.render(displayed, separators_on, time_template, clock_type),
+            DisplayMode::Unsynced(style) => style.render(displayed, separators_on, time_template, clock_type),
+        }
+    }
+
+    pub fn led_at(&self, displayed: &DateTime<Local>) -> Option<LedColor> {
+        match self {
+            DisplayMode::WorldClock(w) => w.led_at(displayed),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisplayMode::Clock => "clock",
+            DisplayMode::Date(_) => "date",
+            DisplayMode::Countdown { .. } => "countdown",
+            DisplayMode::Blank => "blank",
+            DisplayMode::WorldClock(_) => "world clock",
+            DisplayMode::Unsynced(_) => "unsynced",
+        }
+    }
+
+    pub fn shows_overlays(&self) -> bool {
+        *self == DisplayMode::Clock
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledState {
+    pub mode: DisplayMode,
+    pub brightness: f32,
+    pub theme: Option<String>,
+}
+
+impl Default for ScheduledState {
+    fn default() -> Self {
+        ScheduledState {
+            mode: DisplayMode::Clock,
+            brightness: 1f32,
+            theme: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ScheduleRule {
+    cron: Option<CronExpr>,
+    dates: Vec<DateMatch>,
+    mode: Option<DisplayMode>,
+    brightness: Option<f32>,
+    theme: Option<String>,
+}
+
+impl ScheduleRule {
+    fn matches(&self, local: &DateTime<Local>) -> bool {
+        self.cron.as_ref().is_none_or(|c| c.matches(local))
+            && (self.dates.is_empty() || self.dates.iter().any(|d| d.matches(local)))
+    }
+}
+
+/// Works out which mode, theme and brightness apply at a given minute. The drivers check it
+/// when they set up each minute, so switches line up with the minute's overlays.
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    rules: Vec<ScheduleRule>,
+    themes: HashMap<String, Theme>,
+}
+
+impl Scheduler {
+    pub fn from_config(
+        rule_configs: &[ScheduleRuleConfig],
+        theme_configs: &HashMap<String, ThemeConfig>,
+        world_clock_config: &Option<WorldClockConfig>,
+        clock_type: ClockType,
+    ) -> Result<Scheduler, Box<dyn Error>> {
+        let mut themes = HashMap::new();
+        for (name, tc) in theme_configs {
+            let time_template = match &tc.time_format {
+                Some(f) => Some(DisplayTemplate::parse(f, clock_type)?),
+                None => None,
+            };
+            themes.insert(name.clone(), Theme { led: tc.led, time_template });
+        }
+
+        let mut rules = vec![];
+        for rc in rule_configs {
+            if rc.cron.is_none() && rc.dates.is_empty() {
+                return Err("Schedule rules need a cron expression, dates or both".into());
+            }
+            if let Some(theme) = &rc.theme {
+                if !themes.contains_key(theme) {
+                    return Err(format!("Schedule uses undefined theme {:?}", theme).into());
+                }
+            }
+            if let Some(b) = rc.brightness {
+                if !(0f32..=1f32).contains(&b) {
+                    return Err(format!("Schedule brightness {} is outside 0.0-1.0", b).into());
+                }
+            }
+            let mode = match rc.mode {
+                None => None,
+                Some(kind) => Some(DisplayMode::from_kind(
+                    kind,
+                    rc.format.as_deref(),
+                    rc.countdown_to.as_deref(),
+                    world_clock_config,
+                    clock_type,
+                )?),
+            };
+            rules.push(ScheduleRule {
+                cron: match &rc.cron {
+                    Some(c) => Some(CronExpr::parse(c)?),
+                    None => None,
+                },
+                dates: rc.dates.iter().map(|d| DateMatch::parse(d)).collect::<Result<_, _>>()?,
+                mode,
+                brightness: rc.brightness,
+                theme: rc.theme.clone(),
+            });
+        }
+        Ok(Scheduler { rules, themes })
+    }
+
+    pub fn state_at(&self, local: &DateTime<Local>) -> ScheduledState {
+        let mut state = ScheduledState::default();
+        for rule in self.rules.iter().filter(|r| r.matches(local)) {
+            if let Some(mode) = &rule.mode {
+                state.mode = mode.clone();
+            }
+            if let Some(brightness) = rule.brightness {
+                state.brightness = brightness;
+            }
+            if rule.theme.is_some() {
+                state.theme = rule.theme.clone();
+            }
+        }
+        state
+    }
+
+    pub fn theme(&self, name: &str) -> Option<&Theme> {
+        self.themes.get(name)
+    }
+
+    /// Runs the schedule against a simulated clock and prints every switch with what the
+    /// tubes would show at that moment.
+    pub fn preview(settings: &DisplaySettings, clock: SimulatedClock, clock_type: ClockType) {
+        let mut last_state: Option<ScheduledState> = None;
+        for local in clock {
+            let state = settings.scheduler.state_at(&local);
+            if last_state.as_ref() == Some(&state) {
+                continue;
+            }
+            let template = settings.time_template_for(&state);
+            println!(
+                "{} {} brightness {} theme {:?} shows {:?}",
+                local.format("%a %Y-%m-%d %H:%M"),
+                state.mode.name(),
+                state.brightness,
+                state.theme,
+                state.mode.render(&local, true, template, clock_type)
+            );
+            last_state = Some(state);
+        }
+    }
+}
+
+/// Steps through time a fixed amount at a time, for trying out schedules without waiting
+#[derive(Debug, Clone)]
+pub struct SimulatedClock {
+    now: DateTime<Local>,
+    end: DateTime<Local>,
+    step: Duration,
+}
+
+impl SimulatedClock {
+    pub fn new(start: DateTime<Local>, end: DateTime<Local>, step: Duration) -> SimulatedClock {
+        SimulatedClock { now: start, end, step }
+    }
+}
+
+impl Iterator for SimulatedClock {
+    type Item = DateTime<Local>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.now >= self.end {
+            return None;
+        }
+        let cur = self.now;
+        self.now = self.now + self.step;
+        Some(cur)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Schedule {
+        schedule: Vec<ScheduleRuleConfig>,
+        #[serde(default)]
+        themes: HashMap<String, ThemeConfig>,
+    }
+
+    fn scheduler(toml: &str) -> Result<Scheduler, Box<dyn Error>> {
+        let config: Schedule = toml::from_str(toml)?;
+        Scheduler::from_config(&config.schedule, &config.themes, &None, ClockType::NCS3186)
+    }
+
+    fn local(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Local> {
+        let naive = NaiveDate::from_ymd_opt(y, mo, d).unwrap().and_hms_opt(h, mi, s).unwrap();
+        Local.from_local_datetime(&naive).single().unwrap()
+    }
+
+    fn set(flags: &[bool]) -> Vec<usize> {
+        flags.iter().enumerate().filter(|(_, f)| **f).map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn parses_cron_fields() {
+        let cron = CronExpr::parse("1-5,3-8 */6 1,15 jan-mar,dec sun").unwrap();
+        //overlapping ranges just cover both
+        assert_eq!(set(&cron.minutes), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(set(&cron.hours), vec![0, 6, 12, 18]);
+        assert_eq!(set(&cron.days), vec![1, 15]);
+        assert_eq!(set(&cron.months), vec![1, 2, 3, 12]);
+        assert_eq!(set(&cron.weekdays), vec![0]);
+        assert_eq!(set(&CronExpr::parse("5/15 * * * 5-7").unwrap().minutes), vec![5, 20, 35, 50]);
+        assert_eq!(set(&CronExpr::parse("* * * * 5-7").unwrap().weekdays), vec![0, 5, 6]);
+    }
+
+    #[test]
+    fn rejects_bad_cron() {
+        let bad = vec![
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "* * * smarch *",
+            "-1 * * * *",
+        ];
+        for cron in bad {
+            assert!(CronExpr::parse(cron).is_err(), "{}", cron);
+        }
+    }
+
+    #[test]
+    fn cron_hours_across_midnight() {
+        let night = CronExpr::parse("* 22-23,0-6 * * *").unwrap();
+        assert!(night.matches(&local(2024, 3, 8, 23, 30, 0)));
+        assert!(night.matches(&local(2024, 3, 9, 0, 0, 0)));
+        assert!(night.matches(&local(2024, 3, 9, 6, 59, 0)));
+        assert!(!night.matches(&local(2024, 3, 9, 7, 0, 0)));
+        assert!(!night.matches(&local(2024, 3, 9, 21, 59, 0)));
+    }
+
+    #[test]
+    fn either_day_field_matches() {
+        //the 1st, or any Monday
+        let cron = CronExpr::parse("0 9 1 * mon").unwrap();
+        assert!(cron.matches(&local(2024, 3, 1, 9, 0, 0)));
+        assert!(cron.matches(&local(2024, 3, 4, 9, 0, 0)));
+        assert!(!cron.matches(&local(2024, 3, 5, 9, 0, 0)));
+        //both when only one is restricted
+        let cron = CronExpr::parse("0 9 * * mon").unwrap();
+        assert!(!cron.matches(&local(2024, 3, 1, 9, 0, 0)));
+    }
+
+    #[test]
+    fn later_overlapping_rules_win() {
+        let scheduler = scheduler(
+            r#"
+            [[schedule]]
+            cron = "* 22-23,0-6 * * *"
+            brightness = 0.3
+            theme = "night"
+
+            [[schedule]]
+            cron = "0-29 23 * * *"
+            mode = "blank"
+
+            [[schedule]]
+            dates = ["12-25"]
+            mode = "date"
+
+            [themes.night]
+            led = "red"
+            "#,
+        )
+        .unwrap();
+        let at = |t| scheduler.state_at(&t);
+        assert_eq!(at(local(2024, 3, 8, 12, 0, 0)), ScheduledState::default());
+        let late = at(local(2024, 3, 8, 23, 10, 0));
+        assert_eq!(late.mode, DisplayMode::Blank);
+        assert_eq!(late.brightness, 0.3);
+        assert_eq!(late.theme.as_deref(), Some("night"));
+        assert_eq!(at(local(2024, 3, 8, 23, 45, 0)).mode, DisplayMode::Clock);
+        //into the next day
+        let early = at(local(2024, 3, 9, 2, 0, 0));
+        assert_eq!((early.mode, early.brightness), (DisplayMode::Clock, 0.3));
+        assert_eq!(at(local(2024, 12, 25, 23, 10, 0)).mode.name(), "date");
+        assert_eq!(at(local(2024, 12, 25, 12, 0, 0)).brightness, 1f32);
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        let bad = vec![
+            "[[schedule]]\nmode = \"blank\"",
+            "[[schedule]]\ncron = \"* * * * *\"\ntheme = \"missing\"",
+            "[[schedule]]\ncron = \"* * * * *\"\nbrightness = 1.5",
+            "[[schedule]]\ncron = \"* * * * *\"\nmode = \"countdown\"",
+            "[[schedule]]\ncron = \"* * * * *\"\nmode = \"countdown\"\ncountdown_to = \"25:00\"",
+            "[[schedule]]\ndates = [\"02-30\"]",
+            "[[schedule]]\ndates = [\"christmas\"]",
+        ];
+        for toml in bad {
+            assert!(scheduler(toml).is_err(), "{}", toml);
+        }
+    }
+
+    #[test]
+    fn counts_down_across_midnight() {
+        let clock_type = ClockType::NCS3186;
+        let countdown = DisplayMode::from_kind(ModeKind::Countdown, None, Some("01:00"), &None, clock_type).unwrap();
+        let template = DisplayTemplate::parse(clock_type.default_countdown_format(), clock_type).unwrap();
+        let shows = |seconds| template.render(&Utc.timestamp_opt(seconds, 0).unwrap(), true);
+        let render = |t| countdown.render(&t, true, &template, clock_type);
+        assert_eq!(render(local(2024, 3, 8, 23, 0, 0)), shows(2 * 3600));
+        assert_eq!(render(local(2024, 3, 9, 0, 30, 0)), shows(30 * 60));
+        assert_eq!(render(local(2024, 3, 9, 1, 0, 0)), shows(0));
+        assert_eq!(render(local(2024, 3, 9, 1, 0, 1)), shows(24 * 3600 - 1));
+    }
+
+    #[test]
+    fn renders_each_unsynced_style() {
+        let at = local(2021, 6, 7, 9, 5, 37);
+        for (clock_type, format) in [
+            (ClockType::NCS3148C, "%H%:%M%:%S%.%C "),
+            (ClockType::NCS3186, "%H%:%M%:%S"),
+        ] {
+            let template = DisplayTemplate::parse(format, clock_type).unwrap();
+            let render = |style: UnsyncedStyle, on| style.render(&at, on, &template, clock_type);
+            let (dashes, dark, cycle, time) = match clock_type {
+                ClockType::NCS3148C => ("  :  :  :   ", "            ", "77:77:77:77 ", "09:05:37.00 "),
+                ClockType::NCS3186 => ("  :  :  ", "        ", "77:77:77", "09:05:37"),
+            };
+            assert_eq!(render(UnsyncedStyle::Dashes, true), dashes);
+            assert_eq!(render(UnsyncedStyle::Dashes, false), dark);
+            assert_eq!(render(UnsyncedStyle::Cycle, true), cycle);
+            assert_eq!(render(UnsyncedStyle::Cycle, false), cycle.replace(':', " "));
+            assert_eq!(render(UnsyncedStyle::Time, true), time);
+            assert_eq!(render(UnsyncedStyle::Time, false), template.render(&at, false));
+        }
+        //the digits step with the seconds
+        let template = DisplayTemplate::parse("%H%:%M%:%S", ClockType::NCS3186).unwrap();
+        let next = local(2021, 6, 7, 9, 5, 38);
+        assert_eq!(UnsyncedStyle::Cycle.render(&next, true, &template, ClockType::NCS3186), "88:88:88");
+        assert_eq!(UnsyncedStyle::default(), UnsyncedStyle::Dashes);
+    }
+}
@@ -0,0 +1,567 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::errors::{SensorError, SensorResult};
+
+/// What a measurement is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Unit {
+    #[serde(rename = "°C")]
+    Celsius,
+    #[serde(rename = "%")]
+    Percent,
+    #[serde(rename = "Pa")]
+    Pascal,
+    #[serde(rename = "hPa")]
+    Hectopascal,
+    #[serde(rename = "A")]
+    Ampere,
+    #[serde(rename = "V")]
+    Volt,
+    #[serde(rename = "lx")]
+    Lux,
+    #[serde(rename = "ppm")]
+    PartsPerMillion,
+    #[serde(rename = "µg/m³")]
+    MicrogramsPerCubicMetre,
+    #[serde(rename = "CPM")]
+    CountsPerMinute,
+    #[serde(rename = "µSv/h")]
+    MicrosievertsPerHour,
+    #[serde(rename = "W")]
+    Watt,
+    #[serde(rename = "kWh")]
+    KilowattHour,
+}
+
+impl Unit {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Percent => "%",
+            Unit::Pascal => "Pa",
+            Unit::Hectopascal => "hPa",
+            Unit::Ampere => "A",
+            Unit::Volt => "V",
+            Unit::Lux => "lx",
+            Unit::PartsPerMillion => "ppm",
+            Unit::MicrogramsPerCubicMetre => "µg/m³",
+            Unit::CountsPerMinute => "CPM",
+            Unit::MicrosievertsPerHour => "µSv/h",
+            Unit::Watt => "W",
+            Unit::KilowattHour => "kWh",
+        }
+    }
+
+    /// The IN-19A glyph for the unit itself, the rest show an SI prefix there instead
+    pub fn glyph(&self) -> Option<char> {
+        match self {
+            Unit::Celsius => Some('℃'),
+            Unit::Percent => Some('%'),
+            Unit::Hectopascal => Some('P'),
+            //the prefixes, as the rest of the unit has no glyph
+            Unit::MicrosievertsPerHour => Some('μ'),
+            Unit::KilowattHour => Some('κ'),
+            _ => None,
+        }
+    }
+
+    /// Whether an SI prefix makes sense on it, a concentration or a count reads better as it is
+    pub fn si_prefixed(&self) -> bool {
+        !matches!(
+            self,
+            Unit::PartsPerMillion | Unit::MicrogramsPerCubicMetre | Unit::CountsPerMinute
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Reading {
+    //calibrated and smoothed
+    pub value: f32,
+    //as the sensor read it
+    pub raw: f32,
+    pub unit: Unit,
+    pub taken_at: DateTime<Local>,
+}
+
+impl Reading {
+    pub fn age(&self) -> Duration {
+        Local::now() - self.taken_at
+    }
+}
+
+/// The latest of one measurement, as sent on its channel of the bus
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorState {
+    pub unit: Unit,
+    //the last good reading, kept after it goes stale
+    pub reading: Option<Reading>,
+    pub max_age: Duration,
+    pub reads: u64,
+    pub failures: u64,
+    //the failures that were bad checksums
+    pub checksum_errors: u64,
+    //readings thrown out as spikes or known bad values, not counted as failures
+    pub rejected: u64,
+    pub last_error: Option<String>,
+}
+
+impl SensorState {
+    /// The last reading, unless it is older than the sensor's max age
+    pub fn fresh(&self) -> Option<Reading> {
+        self.reading.filter(|r| r.age() <= self.max_age)
+    }
+}
+
+/// Something that can be read now and then, like a thermometer. `read` may block, each
+/// sensor is read on its own thread.
+pub trait Sensor: Send {
+    /// For the logs
+    fn name(&self) -> String;
+    /// The bus names and units of the values `read` returns, in the same order
+    fn measurements(&self) -> Vec<(String, Unit)>;
+    /// A result for each measurement, or an error when none could be read
+    fn read(&mut self) -> Result<Vec<SensorResult<f32>>, Box<dyn Error>>;
+    fn interval(&self) -> Duration {
+        Duration::seconds(5)
+    }
+    /// Readings older than this count as missing
+    fn max_age(&self) -> Duration {
+        Duration::minutes(2)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Smoothing {
+    #[default]
+    None,
+    //exponential moving average, see `alpha`
+    Ema,
+    //the median of the last `window` readings, which ignores the odd bad one entirely
+    Median,
+}
+
+/// A `[sensors.<name>]` section of the config, cleaning up one measurement on the bus
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    //the reading is raw * scale + offset
+    pub scale: f32,
+    pub offset: f32,
+    pub smoothing: Smoothing,
+    //the weight of each new reading for ema
+    pub alpha: f32,
+    //readings for median
+    pub window: usize,
+    //a calibrated reading further than this from the last one is a spike and thrown out,
+    //unless it happens `spike_readings` times running, which is a real change
+    pub max_step: Option<f32>,
+    pub spike_readings: u32,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            scale: 1f32,
+            offset: 0f32,
+            smoothing: Smoothing::None,
+            alpha: 0.3,
+            window: 5,
+            max_step: None,
+            spike_readings: 3,
+        }
+    }
+}
+
+impl FilterConfig {
+    pub fn validate(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        if !self.scale.is_finite() || !self.offset.is_finite() {
+            return Err(format!("Sensor {} scale and offset must be numbers", name).into());
+        }
+        if !(self.alpha > 0f32 && self.alpha <= 1f32) {
+            return Err(format!("Sensor {} alpha is {}, expected more than 0 up to 1", name, self.alpha).into());
+        }
+        if self.window == 0 {
+            return Err(format!("Sensor {} needs a median window of at least 1", name).into());
+        }
+        if self.max_step.is_some_and(|s| s.is_nan() || s <= 0f32) {
+            return Err(format!("Sensor {} max_step must be more than 0", name).into());
+        }
+        Ok(())
+    }
+}
+
+/// Calibrates, rejects spikes and smooths one measurement's readings as they arrive
+#[derive(Debug, Clone)]
+pub struct MeasurementFilter {
+    config: FilterConfig,
+    //the last calibrated reading that wasn't a spike
+    last: Option<f32>,
+    spikes: u32,
+    ema: Option<f32>,
+    recent: VecDeque<f32>,
+}
+
+impl MeasurementFilter {
+    pub fn new(config: FilterConfig) -> MeasurementFilter {
+        MeasurementFilter {
+            config,
+            last: None,
+            spikes: 0,
+            ema: None,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// The value to show for a raw reading, or None if it was thrown out as a spike
+    pub fn apply(&mut self, raw: f32) -> Option<f32> {
+        let value = raw * self.config.scale + self.config.offset;
+        if let (Some(last), Some(max_step)) = (self.last, self.config.max_step) {
+            if (value - last).abs() > max_step {
+                self.spikes += 1;
+                if self.spikes < self.config.spike_readings {
+                    return None;
+                }
+                //it stuck, so start over from here rather than averaging across the step
+                self.ema = None;
+                self.recent.clear();
+            }
+        }
+        self.spikes = 0;
+        self.last = Some(value);
+        Some(match self.config.smoothing {
+            Smoothing::None => value,
+            Smoothing::Ema => {
+                let alpha = self.config.alpha;
+                let ema = self.ema.map_or(value, |e| alpha * value + (1f32 - alpha) * e);
+                self.ema = Some(ema);
+                ema
+            }
+            Smoothing::Median => {
+                self.recent.push_back(value);
+                if self.recent.len() > self.config.window {
+                    self.recent.pop_front();
+                }
+                let mut sorted: Vec<f32> = self.recent.iter().copied().collect();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                let mid = sorted.len() / 2;
+                if sorted.len().is_multiple_of(2) {
+                    (sorted[mid - 1] + sorted[mid]) / 2f32
+                } else {
+                    sorted[mid]
+                }
+            }
+        })
+    }
+}
+
+/// Named `watch` channels of sensor readings. Overlays, the control APIs, MQTT and the
+/// metrics look measurements up by name, so a new sensor only has to be spawned on the bus.
+#[derive(Debug, Clone, Default)]
+pub struct SensorBus {
+    channels: Arc<RwLock<BTreeMap<String, watch::Receiver<SensorState>>>>,
+    //by measurement name, the rest are passed through as read
+    filters: Arc<HashMap<String, FilterConfig>>,
+}
+
+impl SensorBus {
+    pub fn new(filters: HashMap<String, FilterConfig>) -> Result<SensorBus, Box<dyn Error>> {
+        for (name, filter) in &filters {
+            filter.validate(name)?;
+        }
+        Ok(SensorBus {
+            channels: Default::default(),
+            filters: Arc::new(filters),
+        })
+    }
+
+    pub fn register(&self, name: &str, unit: Unit, max_age: Duration) -> watch::Sender<SensorState> {
+        let (sender, receiver) = watch::channel(SensorState {
+            unit,
+            reading: None,
+            max_age,
+            reads: 0,
+            failures: 0,
+            checksum_errors: 0,
+            rejected: 0,
+            last_error: None,
+        });
+        self.channels.write().unwrap().insert(name.to_string(), receiver);
+        sender
+    }
+
+    pub fn subscribe(&self, name: &str) -> Option<watch::Receiver<SensorState>> {
+        self.channels.read().unwrap().get(name).cloned()
+    }
+
+    /// The named measurement, if it has a reading that isn't stale
+    pub fn latest(&self, name: &str) -> Option<Reading> {
+        self.channels.read().unwrap().get(name).and_then(|r| r.borrow().fresh())
+    }
+
+    /// Every measurement on the bus, by name
+    pub fn states(&self) -> Vec<(String, SensorState)> {
+        self.channels
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, receiver)| (name.clone(), receiver.borrow().clone()))
+            .collect()
+    }
+
+    /// Registers the sensor's measurements and reads it every interval on a new thread
+    pub fn spawn(&self, mut sensor: impl Sensor + 'static) {
+        let max_age = sensor.max_age();
+        let measurements = sensor.measurements();
+        let senders: Vec<watch::Sender<SensorState>> = measurements
+            .iter()
+            .map(|(name, unit)| self.register(name, *unit, max_age))
+            .collect();
+        let mut filters: Vec<MeasurementFilter> = measurements
+            .iter()
+            .map(|(name, _)| MeasurementFilter::new(self.filters.get(name).cloned().unwrap_or_default()))
+            .collect();
+        thread::spawn(move || loop {
+            let results = match sensor.read() {
+                Ok(results) => results,
+                Err(e) => vec![Err(SensorError::Failed(e.to_string())); senders.len()],
+            };
+            let taken_at = Local::now();
+            let channels = senders.iter().zip(&measurements).zip(filters.iter_mut());
+            for (((sender, (name, _)), filter), result) in channels.zip(results) {
+                sender.send_modify(|s| {
+                    s.reads += 1;
+                    match result {
+                        //a NaN would poison the smoothing for good
+                        Ok(raw) if !raw.is_finite() => {
+                            println!("{} {} read {}, thrown out", sensor.name(), name, raw);
+                            s.rejected += 1;
+                        }
+                        Ok(raw) => match filter.apply(raw) {
+                            Some(value) => {
+                                s.reading = Some(Reading {
+                                    value,
+                                    raw,
+                                    unit: s.unit,
+                                    taken_at,
+                                });
+                                s.last_error = None;
+                            }
+                            None => {
+                                println!("{} {} read {}, thrown out as a spike", sensor.name(), name, raw);
+                                s.rejected += 1;
+                            }
+                        },
+                        Err(SensorError::Pending) => (),
+                        Err(SensorError::Rejected(e)) => {
+                            println!("{} {} rejected {}", sensor.name(), name, e);
+                            s.rejected += 1;
+                        }
+                        Err(e) => {
+                            println!("{} {} failed: {}", sensor.name(), name, e);
+                            s.failures += 1;
+                            if e == SensorError::Checksum {
+                                s.checksum_errors += 1;
+                            }
+                            s.last_error = Some(e.to_string());
+                        }
+                    }
+                });
+            }
+            thread::sleep(sensor.interval().to_std().unwrap_or_default());
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration as StdDuration, Instant};
+
+    fn filter(config: FilterConfig) -> MeasurementFilter {
+        config.validate("test").unwrap();
+        MeasurementFilter::new(config)
+    }
+
+    fn apply_all(filter: &mut MeasurementFilter, raws: &[f32]) -> Vec<Option<f32>> {
+        raws.iter().map(|raw| filter.apply(*raw)).collect()
+    }
+
+    #[test]
+    fn validates_filters() {
+        assert!(FilterConfig::default().validate("outdoor").is_ok());
+        let bad = [
+            FilterConfig { alpha: 0f32, ..Default::default() },
+            FilterConfig { alpha: 1.5, ..Default::default() },
+            FilterConfig { alpha: f32::NAN, ..Default::default() },
+            FilterConfig { window: 0, ..Default::default() },
+            FilterConfig { max_step: Some(0f32), ..Default::default() },
+            FilterConfig { max_step: Some(-1f32), ..Default::default() },
+            FilterConfig { max_step: Some(f32::NAN), ..Default::default() },
+            FilterConfig { scale: f32::NAN, ..Default::default() },
+            FilterConfig { offset: f32::INFINITY, ..Default::default() },
+        ];
+        for config in bad {
+            assert!(config.validate("outdoor").is_err(), "{:?}", config);
+        }
+        let filters = HashMap::from([("outdoor".to_string(), FilterConfig { alpha: 2f32, ..Default::default() })]);
+        assert!(SensorBus::new(filters).is_err());
+    }
+
+    #[test]
+    fn applies_each_smoothing() {
+        let calibrated = FilterConfig {
+            scale: 2f32,
+            offset: -1f32,
+            ..Default::default()
+        };
+        let cases: Vec<(FilterConfig, Vec<f32>, Vec<Option<f32>>)> = vec![
+            (FilterConfig::default(), vec![20f32, 21f32, 19f32], vec![Some(20f32), Some(21f32), Some(19f32)]),
+            (calibrated.clone(), vec![10f32, 0f32], vec![Some(19f32), Some(-1f32)]),
+            (
+                FilterConfig { smoothing: Smoothing::Ema, alpha: 0.5, ..calibrated },
+                vec![10f32, 20f32, 20f32],
+                vec![Some(19f32), Some(29f32), Some(34f32)],
+            ),
+            (
+                FilterConfig { smoothing: Smoothing::Ema, alpha: 1f32, ..Default::default() },
+                vec![10f32, 20f32],
+                vec![Some(10f32), Some(20f32)],
+            ),
+            //the odd bad one drops out
+            (
+                FilterConfig { smoothing: Smoothing::Median, window: 3, ..Default::default() },
+                vec![20f32, 90f32, 21f32, 22f32, 23f32],
+                vec![Some(20f32), Some(55f32), Some(21f32), Some(22f32), Some(22f32)],
+            ),
+            //an even window averages the middle two
+            (
+                FilterConfig { smoothing: Smoothing::Median, window: 4, ..Default::default() },
+                vec![4f32, 1f32, 3f32, 2f32, 10f32],
+                vec![Some(4f32), Some(2.5), Some(3f32), Some(2.5), Some(2.5)],
+            ),
+        ];
+        for (config, raws, expected) in cases {
+            assert_eq!(apply_all(&mut filter(config.clone()), &raws), expected, "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn throws_out_spikes() {
+        let mut spiky = filter(FilterConfig {
+            max_step: Some(5f32),
+            spike_readings: 3,
+            ..Default::default()
+        });
+        //a lone spike, then one that isn't running long enough to count
+        assert_eq!(
+            apply_all(&mut spiky, &[20f32, 85f32, 21f32, 40f32, 40f32, 22f32]),
+            vec![Some(20f32), None, Some(21f32), None, None, Some(22f32)]
+        );
+        //the third running is a real change
+        assert_eq!(
+            apply_all(&mut spiky, &[40f32, 41f32, 39f32, 38f32]),
+            vec![None, None, Some(39f32), Some(38f32)]
+        );
+        //a single reading is enough when spike_readings is 1
+        let mut once = filter(FilterConfig {
+            max_step: Some(5f32),
+            spike_readings: 1,
+            ..Default::default()
+        });
+        assert_eq!(apply_all(&mut once, &[20f32, 40f32]), vec![Some(20f32), Some(40f32)]);
+    }
+
+    #[test]
+    fn starts_smoothing_over_after_a_step() {
+        let mut ema = filter(FilterConfig {
+            smoothing: Smoothing::Ema,
+            alpha: 0.5,
+            max_step: Some(5f32),
+            spike_readings: 2,
+            ..Default::default()
+        });
+        assert_eq!(apply_all(&mut ema, &[20f32, 22f32]), vec![Some(20f32), Some(21f32)]);
+        //no average across the step
+        assert_eq!(apply_all(&mut ema, &[40f32, 40f32, 42f32]), vec![None, Some(40f32), Some(41f32)]);
+        let mut median = filter(FilterConfig {
+            smoothing: Smoothing::Median,
+            window: 3,
+            max_step: Some(5f32),
+            spike_readings: 2,
+            ..Default::default()
+        });
+        assert_eq!(apply_all(&mut median, &[20f32, 21f32, 22f32]), vec![Some(20f32), Some(20.5), Some(21f32)]);
+        assert_eq!(apply_all(&mut median, &[40f32, 41f32, 42f32]), vec![None, Some(41f32), Some(41.5)]);
+    }
+
+    //reads its results in turn, then never returns
+    struct ScriptedSensor {
+        results: VecDeque<Result<Vec<SensorResult<f32>>, String>>,
+    }
+
+    impl Sensor for ScriptedSensor {
+        fn name(&self) -> String {
+            "Scripted".to_string()
+        }
+        fn measurements(&self) -> Vec<(String, Unit)> {
+            vec![("a".to_string(), Unit::Celsius), ("b".to_string(), Unit::Percent)]
+        }
+        fn read(&mut self) -> Result<Vec<SensorResult<f32>>, Box<dyn Error>> {
+            match self.results.pop_front() {
+                Some(results) => Ok(results?),
+                None => loop {
+                    thread::park();
+                },
+            }
+        }
+        fn interval(&self) -> Duration {
+            Duration::milliseconds(1)
+        }
+    }
+
+    #[test]
+    fn counts_what_the_sensor_read() {
+        let filters = HashMap::from([(
+            "a".to_string(),
+            FilterConfig {
+                max_step: Some(10f32),
+                spike_readings: 2,
+                ..Default::default()
+            },
+        )]);
+        let bus = SensorBus::new(filters).unwrap();
+        bus.spawn(ScriptedSensor {
+            results: VecDeque::from(vec![
+                Ok(vec![Ok(20f32), Ok(50f32)]),
+                Ok(vec![Ok(f32::NAN), Err(SensorError::Checksum)]),
+                Err("Bus gone".to_string()),
+                Ok(vec![Err(SensorError::Rejected("85°C".to_string())), Err(SensorError::Pending)]),
+                //a spike on a
+                Ok(vec![Ok(100f32), Ok(51f32)]),
+            ]),
+        });
+        let started = Instant::now();
+        while bus.states().iter().any(|(_, s)| s.reads < 5) {
+            assert!(started.elapsed() < StdDuration::from_secs(5), "{:?}", bus.states());
+            thread::sleep(StdDuration::from_millis(1));
+        }
+        let a = bus.subscribe("a").unwrap().borrow().clone();
+        assert_eq!((a.reads, a.failures, a.checksum_errors, a.rejected), (5, 1, 0, 3));
+        assert_eq!(a.last_error.as_deref(), Some("Bus gone"));
+        assert_eq!(a.reading.map(|r| (r.value, r.raw, r.unit)), Some((20f32, 20f32, Unit::Celsius)));
+        let b = bus.subscribe("b").unwrap().borrow().clone();
+        assert_eq!((b.reads, b.failures, b.checksum_errors, b.rejected), (5, 2, 1, 0));
+        //cleared by the next good reading
+        assert_eq!(b.last_error, None);
+        assert_eq!(bus.latest("b").map(|r| r.value), Some(51f32));
+    }
+}
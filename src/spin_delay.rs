@@ -11,7 +11,6 @@ use std::time::{Duration, Instant};
 
 use embedded_hal::blocking::delay::{DelayMs, DelayUs};
 use embedded_hal::timer::CountDown;
-use spin_sleep;
 
 /// Implements the `embedded-hal` `DelayMs` and `DelayUs` traits.
 #[derive(Debug, Default)]
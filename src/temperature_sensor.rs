@@ -1,70 +1,205 @@
-use std::fmt::{Debug, Write};
-use std::sync::{Arc, RwLock};
-use std::thread;
-use chrono::prelude::*;
-use chrono::Duration;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::Debug;
 use ds18b20::{Ds18b20, Resolution};
 use embedded_hal::blocking::delay::{DelayMs, DelayUs};
 use embedded_hal::digital::v2::{InputPin, OutputPin};
 use embedded_hal::prelude::_embedded_hal_blocking_delay_DelayUs;
-use one_wire_bus::{OneWire, OneWireError, OneWireResult};
+use one_wire_bus::{Address, OneWire, OneWireError, OneWireResult};
 use rppal::gpio::{Gpio, InputPin as RppalInputPin, OutputPin as RppalOutputPin};
-use spin_sleep;
 //will be unnecessary once new version of rppal is released
 // use rppal::hal::Delay;
 use crate::spin_delay::Delay;
+use crate::errors::{SensorError, SensorResult};
+use crate::sensors::{Sensor, Unit};
+use serde::Deserialize;
 
+//the bus name of the temperature shown by the temperature scenes
+pub const TEMPERATURE: &str = "temperature";
+//what a DS18B20 reads before its first conversion, like after a brownout mid-read
+const POWER_ON_CELSIUS: f32 = 85f32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemperatureBackend {
+    //bit-banged from here, see TemperatureSensor
+    #[default]
+    OneWire,
+    //the kernel's w1-gpio driver, see W1TemperatureSensor
+    W1Sysfs,
+}
+
+/// The `[temperature]` section of the config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TemperatureConfig {
+    pub backend: TemperatureBackend,
+    //where w1-gpio puts its devices
+    pub w1_devices: String,
+    //with none, the first DS18B20 found is read as `temperature`
+    pub sensors: Vec<Ds18b20Config>,
+}
+
+impl Default for TemperatureConfig {
+    fn default() -> Self {
+        TemperatureConfig {
+            backend: TemperatureBackend::OneWire,
+            w1_devices: "/sys/bus/w1/devices".to_string(),
+            sensors: vec![],
+        }
+    }
+}
+
+impl TemperatureConfig {
+    /// The bus names of the configured probes, in order
+    pub fn measurements(&self) -> Vec<(String, Unit)> {
+        if self.sensors.is_empty() {
+            return vec![(TEMPERATURE.to_string(), Unit::Celsius)];
+        }
+        self.sensors.iter().map(|s| (s.name.clone(), Unit::Celsius)).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let mut names = HashSet::new();
+        for sensor in &self.sensors {
+            sensor.address()?;
+            sensor.resolution()?;
+            if !names.insert(&sensor.name) {
+                return Err(format!("Temperature sensor name {} is used twice", sensor.name).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One `[[temperature.sensors]]` entry, a DS18B20 by its ROM ID
+#[derive(Debug, Clone, Deserialize)]
+pub struct Ds18b20Config {
+    //as the kernel names it, e.g. 28-0000075b0b1c
+    pub rom: String,
+    //the bus name, e.g. outdoor
+    pub name: String,
+    //bits, 9 converts in 94ms and steps by 0.5°C, 12 takes 750ms and steps by 0.0625°C
+    #[serde(default = "Ds18b20Config::default_resolution")]
+    pub resolution: u8,
+}
+
+impl Ds18b20Config {
+    fn default_resolution() -> u8 {
+        12
+    }
+
+    /// The bus address of the ROM ID, which leaves out the family code's CRC
+    pub fn address(&self) -> Result<Address, Box<dyn Error>> {
+        let bad_rom = || format!("Bad DS18B20 ROM ID {}, expected 28- and 12 hex digits", self.rom);
+        let serial = match self.rom.split_once('-') {
+            Some(("28", serial)) if serial.len() == 12 => u64::from_str_radix(serial, 16).map_err(|_| bad_rom())?,
+            _ => return Err(bad_rom().into()),
+        };
+        let rom = (serial << 8) | ds18b20::FAMILY_CODE as u64;
+        let crc = one_wire_bus::crc::crc8(&rom.to_le_bytes()[..7]);
+        Ok(Address(rom | (crc as u64) << 56))
+    }
+
+    pub fn resolution(&self) -> Result<Resolution, Box<dyn Error>> {
+        match self.resolution {
+            9 => Ok(Resolution::Bits9),
+            10 => Ok(Resolution::Bits10),
+            11 => Ok(Resolution::Bits11),
+            12 => Ok(Resolution::Bits12),
+            r => Err(format!("{} has resolution {}, expected 9 to 12 bits", self.name, r).into()),
+        }
+    }
+}
+
+/// The DS18B20s on the shield's 1-Wire bus. With `[[temperature.sensors]]` configured each
+/// is read by its ROM ID into its own measurement, otherwise the first one found is read
+/// as `temperature`.
 #[derive(Debug)]
 pub struct TemperatureSensor {
-    raw_degrees_c: Arc<RwLock<Option<f32>>>,
-    temperature_updated_at: Option<DateTime<Local>>,
+    probes: Vec<(Ds18b20Config, Address, Resolution)>,
+    measurements: Vec<(String, Unit)>,
+}
+
+impl Sensor for TemperatureSensor {
+    fn name(&self) -> String {
+        "Temperature".to_string()
+    }
+
+    fn measurements(&self) -> Vec<(String, Unit)> {
+        self.measurements.clone()
+    }
+
+    fn read(&mut self) -> Result<Vec<SensorResult<f32>>, Box<dyn Error>> {
+        let one_wire_pin = Gpio::new()?.get(TemperatureSensor::TMP_PIN)?.into_output();
+        let mut one_wire_bus = OneWire::new(one_wire_pin).map_err(|e| format!("{:?}", e))?;
+        if self.probes.is_empty() {
+            let temperature = self.get_temperature(&mut one_wire_bus).map_err(sensor_error).and_then(check_power_on);
+            return Ok(vec![temperature]);
+        }
+        Ok(self.read_probes(&mut one_wire_bus, &mut Delay::new()).map_err(|e| format!("{:?}", e))?)
+    }
 }
 
 impl TemperatureSensor {
     const TMP_PIN: u8 = 5;
 
-    //NB: this is blocking and should only be run in a separate thread
-    pub fn run(temperature_lock: Arc<RwLock<Option<f32>>>) -> ! {
-        let mut sensor = TemperatureSensor {
-            raw_degrees_c: temperature_lock,
-            temperature_updated_at: None,
-        };
-        sensor.run_temp_sensor();
-    }
-
-    fn run_temp_sensor(&mut self) -> ! {
-        loop {
-            let one_wire_pin = Gpio::new()
-                .unwrap()
-                .get(TemperatureSensor::TMP_PIN)
-                .unwrap()
-                .into_output();
-            let mut one_wire_bus = OneWire::new(one_wire_pin).unwrap();
-            let res = self.get_temperature(&mut one_wire_bus);
-            match res {
-                Ok(cur_reading) => {
-                    let mut temp_lock = self.raw_degrees_c.write().unwrap();
-                    *temp_lock = Some(cur_reading);
-                    drop(temp_lock);
-                    self.temperature_updated_at = Some(Local::now());
-                    // println!("Temperature succeeded");
-                }
-                Err(e) => {
-                    if let Some(last_update) = self.temperature_updated_at {
-                        if last_update + Duration::minutes(2) < Local::now() {
-                            //temperature is stale, so don't keep showing it
-                            let mut temp_lock = self.raw_degrees_c.write().unwrap();
-                            *temp_lock = None;
-                            drop(temp_lock);
-                            self.temperature_updated_at = None;
-                        }
-                    }
-                    println!("Temperature Failed: {:?}", e);
-                }
+    pub fn new(config: &TemperatureConfig) -> Result<TemperatureSensor, Box<dyn Error>> {
+        config.validate()?;
+        let mut probes = vec![];
+        for probe in &config.sensors {
+            probes.push((probe.clone(), probe.address()?, probe.resolution()?));
+        }
+        Ok(TemperatureSensor {
+            probes,
+            measurements: config.measurements(),
+        })
+    }
+
+    //a result for each probe, an Err if the bus itself failed
+    fn read_probes<P, E, D>(&mut self, one_wire_bus: &mut OneWire<P>, delay: &mut D) -> OneWireResult<Vec<SensorResult<f32>>, E>
+        where
+            P: OutputPin<Error=E> + InputPin<Error=E>,
+            E: Debug,
+            D: DelayUs<u16> + DelayMs<u16>,
+    {
+        let present: Vec<Address> = one_wire_bus
+            .devices(false, delay)
+            .collect::<OneWireResult<Vec<Address>, E>>()?;
+        let mut configured: Vec<SensorResult<()>> = vec![];
+        for (config, address, resolution) in &self.probes {
+            if !present.contains(address) {
+                configured.push(Err(SensorError::Failed(format!("{} isn't on the bus", config.rom))));
+                continue;
             }
-            thread::yield_now();
-            thread::sleep(Duration::seconds(5).to_std().unwrap());
+            //the resolution is only kept in the scratchpad, which is reset with the power, and
+            //the alarm thresholds are left wide open as nothing searches for alarms
+            configured.push(
+                Ds18b20::new(*address)
+                    .and_then(|probe| probe.set_config(i8::MIN, i8::MAX, *resolution, one_wire_bus, delay))
+                    .map_err(sensor_error),
+            );
+        }
+        ds18b20::start_simultaneous_temp_measurement(one_wire_bus, delay)?;
+        //the slowest probe sets the wait
+        let wait_ms = self
+            .probes
+            .iter()
+            .map(|(_, _, r)| r.max_measurement_time_millis())
+            .max()
+            .unwrap_or(0);
+        delay.delay_ms(wait_ms);
+        let mut results = vec![];
+        for ((_, address, resolution), configured) in self.probes.iter().zip(configured) {
+            //read_data scales by the resolution and reads the register unsigned, which is only
+            //right for 12 bits above freezing, so the scratchpad is read and scaled here instead
+            results.push(configured.and_then(|_| {
+                ds18b20::read_scratchpad(address, one_wire_bus, delay)
+                    .map_err(sensor_error)
+                    .and_then(|scratchpad| check_power_on(scratchpad_celsius(&scratchpad, *resolution)))
+            }));
         }
+        Ok(results)
     }
 
     fn get_temperature<P, E>(&mut self, one_wire_bus: &mut OneWire<P>) -> OneWireResult<f32, E>
@@ -83,31 +218,237 @@ impl TemperatureSensor {
 
         // iterate over all the devices, and report their temperature
         let mut search_state = None;
-        loop {
-            if let Some((device_address, state)) =
+        while let Some((device_address, state)) =
             one_wire_bus.device_search(search_state.as_ref(), false, &mut delay)?
-            {
-                search_state = Some(state);
-                if device_address.family_code() != ds18b20::FAMILY_CODE {
-                    // skip other devices
-                    continue;
-                }
-                // You will generally create the sensor once, and save it for later
-                let sensor = Ds18b20::new(device_address)?;
-
-                // contains the read temperature, as well as config info such as the resolution used
-                let sensor_data = sensor.read_data(one_wire_bus, &mut delay)?;
-                println!(
-                    "Device at {:?} is {}°C",
-                    device_address,
-                    sensor_data.temperature,
-                );
-                return Ok(sensor_data.temperature);
-            } else {
-                // println!("No device found");
-                break;
+        {
+            search_state = Some(state);
+            if device_address.family_code() != ds18b20::FAMILY_CODE {
+                // skip other devices
+                continue;
             }
+            // You will generally create the sensor once, and save it for later
+            let sensor = Ds18b20::new(device_address)?;
+
+            // contains the read temperature, as well as config info such as the resolution used
+            let sensor_data = sensor.read_data(one_wire_bus, &mut delay)?;
+            println!(
+                "Device at {:?} is {}°C",
+                device_address,
+                sensor_data.temperature,
+            );
+            return Ok(sensor_data.temperature);
         }
         Err(OneWireError::Timeout)
     }
 }
+
+/// Throws out the 85°C a DS18B20 powers up with, which is a conversion that never ran
+pub fn check_power_on(celsius: f32) -> SensorResult<f32> {
+    if celsius == POWER_ON_CELSIUS {
+        return Err(SensorError::Rejected(format!("{}°C, the DS18B20's power-on value", celsius)));
+    }
+    Ok(celsius)
+}
+
+//the register is signed sixteenths of a degree at every resolution, with the bits below it undefined
+fn scratchpad_celsius(scratchpad: &[u8; 9], resolution: Resolution) -> f32 {
+    let undefined = match resolution {
+        Resolution::Bits9 => 0b111,
+        Resolution::Bits10 => 0b11,
+        Resolution::Bits11 => 0b1,
+        Resolution::Bits12 => 0,
+    };
+    f32::from(i16::from_le_bytes([scratchpad[0], scratchpad[1]]) & !undefined) / 16f32
+}
+
+//checksum failures are counted apart from the rest
+fn sensor_error<E: Debug>(e: OneWireError<E>) -> SensorError {
+    match e {
+        OneWireError::CrcMismatch => SensorError::Checksum,
+        e => SensorError::Failed(format!("{:?}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use one_wire_bus::crc::check_crc8;
+    use crate::mock_one_wire::MockOneWire;
+
+    fn probe(rom: &str, name: &str) -> Ds18b20Config {
+        Ds18b20Config {
+            rom: rom.to_string(),
+            name: name.to_string(),
+            resolution: 12,
+        }
+    }
+
+    #[test]
+    fn parses_rom_ids() {
+        let address = probe("28-0000075b0b1c", "outdoor").address().unwrap();
+        assert_eq!(address.family_code(), ds18b20::FAMILY_CODE);
+        assert_eq!(address.0 & 0x00ff_ffff_ffff_ffff, 0x0000_0007_5b0b_1c28);
+        //upper case from other tools is the same probe
+        assert_eq!(probe("28-0000075B0B1C", "outdoor").address().unwrap(), address);
+        let bad = [
+            "10-0000075b0b1c",
+            "28-0000075b0b1",
+            "28-0000075b0b1c0",
+            "28-0000075b0b1g",
+            "28_0000075b0b1c",
+            "280000075b0b1c",
+            "",
+        ];
+        for rom in bad {
+            assert!(probe(rom, "outdoor").address().is_err(), "{}", rom);
+        }
+    }
+
+    #[test]
+    fn adds_the_rom_crc() {
+        //the bus checks the CRC of each ROM it finds, so a wrong one would never match
+        for rom in ["28-0000075b0b1c", "28-000000000000", "28-ffffffffffff"] {
+            let bytes = probe(rom, "outdoor").address().unwrap().0.to_le_bytes();
+            assert!(check_crc8::<()>(&bytes).is_ok(), "{}", rom);
+            let mut tampered = bytes;
+            tampered[7] ^= 1;
+            assert!(check_crc8::<()>(&tampered).is_err(), "{}", rom);
+        }
+    }
+
+    #[test]
+    fn maps_resolutions() {
+        let resolution = |bits| {
+            Ds18b20Config {
+                resolution: bits,
+                ..probe("28-0000075b0b1c", "outdoor")
+            }
+            .resolution()
+        };
+        assert!(matches!(resolution(9), Ok(Resolution::Bits9)));
+        assert!(matches!(resolution(10), Ok(Resolution::Bits10)));
+        assert!(matches!(resolution(11), Ok(Resolution::Bits11)));
+        assert!(matches!(resolution(12), Ok(Resolution::Bits12)));
+        assert!(resolution(8).is_err());
+        assert!(resolution(13).is_err());
+    }
+
+    #[test]
+    fn validates_probes() {
+        let config: TemperatureConfig = toml::from_str(
+            r#"
+            [[sensors]]
+            rom = "28-0000075b0b1c"
+            name = "outdoor"
+            [[sensors]]
+            rom = "28-0000075b0b2d"
+            name = "indoor"
+            resolution = 9
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.sensors[0].resolution, 12);
+        assert_eq!(
+            config.measurements(),
+            vec![("outdoor".to_string(), Unit::Celsius), ("indoor".to_string(), Unit::Celsius)]
+        );
+        assert_eq!(TemperatureConfig::default().measurements(), vec![(TEMPERATURE.to_string(), Unit::Celsius)]);
+
+        let twice = TemperatureConfig {
+            sensors: vec![probe("28-0000075b0b1c", "outdoor"), probe("28-0000075b0b2d", "outdoor")],
+            ..Default::default()
+        };
+        let e = twice.validate().unwrap_err();
+        assert_eq!(e.to_string(), "Temperature sensor name outdoor is used twice");
+        let bad_rom = TemperatureConfig {
+            sensors: vec![probe("10-0000075b0b1c", "outdoor")],
+            ..Default::default()
+        };
+        assert!(bad_rom.validate().is_err());
+        let bad_resolution = TemperatureConfig {
+            sensors: vec![Ds18b20Config {
+                resolution: 14,
+                ..probe("28-0000075b0b1c", "outdoor")
+            }],
+            ..Default::default()
+        };
+        assert!(bad_resolution.validate().is_err());
+    }
+
+    #[test]
+    fn counts_crc_failures_as_checksums() {
+        assert_eq!(sensor_error::<()>(OneWireError::CrcMismatch), SensorError::Checksum);
+        assert!(matches!(sensor_error::<()>(OneWireError::Timeout), SensorError::Failed(_)));
+        assert_eq!(check_power_on(21.5), Ok(21.5));
+        assert!(matches!(check_power_on(85f32), Err(SensorError::Rejected(_))));
+    }
+
+    fn rom(rom: &str) -> u64 {
+        probe(rom, "").address().unwrap().0
+    }
+
+    fn read(sensor: &mut TemperatureSensor, bus: &MockOneWire) -> Vec<SensorResult<f32>> {
+        let mut one_wire_bus = OneWire::new(bus.clone()).unwrap();
+        sensor.read_probes(&mut one_wire_bus, &mut bus.clone()).unwrap()
+    }
+
+    #[test]
+    fn reads_each_probe() {
+        let mut sensor = TemperatureSensor::new(&TemperatureConfig {
+            sensors: vec![
+                probe("28-0000075b0b1c", "outdoor"),
+                Ds18b20Config {
+                    resolution: 10,
+                    ..probe("28-0000075b0b2d", "indoor")
+                },
+                probe("28-0000075b0b3e", "garage"),
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+        let bus = MockOneWire::new();
+        //one that isn't configured is searched past
+        for r in ["28-0000075b0b2d", "28-00000abcdef0", "28-0000075b0b1c"] {
+            bus.add_probe(rom(r));
+        }
+        bus.set_temperature(rom("28-0000075b0b1c"), -10.0625);
+        bus.set_temperature(rom("28-0000075b0b2d"), 19.3);
+        let results = read(&mut sensor, &bus);
+        assert_eq!(results[0], Ok(-10.0625));
+        //19.3125 to a quarter of a degree
+        assert_eq!(results[1], Ok(19.25));
+        assert_eq!(results[2], Err(SensorError::Failed("28-0000075b0b3e isn't on the bus".to_string())));
+        //the resolution with wide open alarms
+        assert_eq!(&bus.scratchpad(rom("28-0000075b0b2d"))[2..5], &[0x7f, 0x80, Resolution::Bits10 as u8]);
+        assert_eq!(&bus.scratchpad(rom("28-0000075b0b1c"))[2..5], &[0x7f, 0x80, Resolution::Bits12 as u8]);
+        assert_eq!(&bus.scratchpad(rom("28-00000abcdef0"))[2..5], &[0x4b, 0x46, Resolution::Bits12 as u8]);
+    }
+
+    #[test]
+    fn counts_bad_scratchpads() {
+        let mut sensor = TemperatureSensor::new(&TemperatureConfig {
+            sensors: vec![probe("28-0000075b0b1c", "outdoor"), probe("28-0000075b0b2d", "indoor")],
+            ..Default::default()
+        })
+        .unwrap();
+        let bus = MockOneWire::new();
+        bus.add_probe(rom("28-0000075b0b1c"));
+        bus.add_probe(rom("28-0000075b0b2d"));
+        bus.set_temperature(rom("28-0000075b0b1c"), 4.5);
+        bus.corrupt_crc(rom("28-0000075b0b1c"));
+        //never converting leaves the power on value
+        assert_eq!(
+            read(&mut sensor, &bus),
+            vec![
+                Err(SensorError::Checksum),
+                Err(SensorError::Rejected("85°C, the DS18B20's power-on value".to_string())),
+            ]
+        );
+        bus.set_temperature(rom("28-0000075b0b2d"), 22.0);
+        assert_eq!(read(&mut sensor, &bus), vec![Err(SensorError::Checksum), Ok(22.0)]);
+        //an empty bus fails each probe, not the read
+        let results = read(&mut sensor, &MockOneWire::new());
+        assert!(results.iter().all(|r| matches!(r, Err(SensorError::Failed(_)))));
+    }
+}
@@ -0,0 +1,256 @@
+extern crate easer;
+
+use std::time::Instant;
+use chrono::prelude::*;
+use chrono::Duration;
+use easer::functions::*;
+
+/// A single read of the wall clock, with what the tubes should show and whether the
+/// system clock was stepped since the previous read.
+#[derive(Debug, Copy, Clone)]
+pub struct ClockReading {
+    pub actual: DateTime<Local>,
+    pub displayed: DateTime<Local>,
+    pub jump: Option<TimeJump>,
+    pub slewing: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TimeJump {
+    pub detected_at: DateTime<Local>,
+    //how far the wall clock moved beyond the elapsed monotonic time, positive is forward
+    pub offset: Duration,
+}
+
+impl TimeJump {
+    // Overlays are placed at a second within a minute, so they are moved by whole minutes
+    // to keep them at the same second in the corrected time.
+    pub fn whole_minutes(&self) -> Duration {
+        let secs = self.offset.num_seconds();
+        let rounded = if secs >= 0 { (secs + 30) / 60 } else { (secs - 30) / 60 };
+        Duration::minutes(rounded)
+    }
+}
+
+#[derive(Debug)]
+struct Slew {
+    started: Instant,
+    //offset added to the actual time when the slew started, decays to zero
+    offset: Duration,
+}
+
+/// Tracks the wall clock against the monotonic clock so that steps of the system time
+/// (e.g. NTP correcting a Pi that booted without network) are detected and logged.
+/// When a slew duration is given, the displayed time rolls over to the corrected time
+/// instead of jumping. Steps of a day or more (e.g. from the epoch at boot) always snap.
+#[derive(Debug)]
+pub struct TimeKeeper {
+    last_instant: Instant,
+    last_wall: DateTime<Local>,
+    jump_threshold: Duration,
+    slew_duration: Option<Duration>,
+    slew: Option<Slew>,
+}
+
+impl TimeKeeper {
+    pub fn new(jump_threshold: Duration, slew_duration: Option<Duration>) -> TimeKeeper {
+        TimeKeeper {
+            last_instant: Instant::now(),
+            last_wall: Local::now(),
+            jump_threshold,
+            slew_duration,
+            slew: None,
+        }
+    }
+
+    pub fn now(&mut self) -> ClockReading {
+        self.now_at(Instant::now(), Local::now())
+    }
+
+    /// Reads the clock given the monotonic and wall times read together
+    pub fn now_at(&mut self, instant: Instant, actual: DateTime<Local>) -> ClockReading {
+        let elapsed = Duration::from_std(instant - self.last_instant).unwrap_or_else(|_| Duration::zero());
+        let offset = actual - (self.last_wall + elapsed);
+        self.last_instant = instant;
+        self.last_wall = actual;
+
+        let mut jump = None;
+        if offset > self.jump_threshold || -offset > self.jump_threshold {
+            println!(
+                "Time jump of {}ms detected, clock is now {}",
+                offset.num_milliseconds(),
+                actual
+            );
+            if self.slew_duration.is_some() && offset.num_days().abs() < 1 {
+                //keep rolling from whatever is on the tubes right now if a slew was in progress
+                let shown_offset = self.slew_offset(instant);
+                self.slew = Some(Slew {
+                    started: instant,
+                    offset: shown_offset - offset,
+                });
+            } else {
+                self.slew = None;
+            }
+            jump = Some(TimeJump {
+                detected_at: actual,
+                offset,
+            });
+        }
+
+        let slew_offset = self.slew_offset(instant);
+        ClockReading {
+            actual,
+            displayed: actual + slew_offset,
+            jump,
+            slewing: self.slew.is_some(),
+        }
+    }
+
+    fn slew_offset(&mut self, instant: Instant) -> Duration {
+        let (slew, slew_duration) = match (&self.slew, self.slew_duration) {
+            (Some(s), Some(d)) => (s, d),
+            _ => return Duration::zero(),
+        };
+        let t = (instant - slew.started).as_micros() as f32;
+        let d = slew_duration.num_microseconds().unwrap_or(0) as f32;
+        if t >= d {
+            self.slew = None;
+            return Duration::zero();
+        }
+        let p = Cubic::ease_in_out(t, 0f32, 1f32, d);
+        let remaining_us = slew.offset.num_microseconds().unwrap_or(0) as f32 * (1f32 - p);
+        Duration::microseconds(remaining_us as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration as StdDuration;
+
+    fn keeper(slew_duration: Option<Duration>) -> (TimeKeeper, Instant, DateTime<Local>) {
+        let (instant, wall) = (Instant::now(), Local.ymd(2021, 6, 7).and_hms(12, 34, 0));
+        let keeper = TimeKeeper {
+            last_instant: instant,
+            last_wall: wall,
+            jump_threshold: Duration::seconds(1),
+            slew_duration,
+            slew: None,
+        };
+        (keeper, instant, wall)
+    }
+
+    fn after(instant: Instant, millis: u64) -> Instant {
+        instant + StdDuration::from_millis(millis)
+    }
+
+    #[test]
+    fn detects_a_forward_jump() {
+        let (mut keeper, instant, wall) = keeper(None);
+        let reading = keeper.now_at(after(instant, 1000), wall + Duration::seconds(1));
+        assert!(reading.jump.is_none());
+        //NTP sets the clock an hour on
+        let actual = wall + Duration::hours(1) + Duration::seconds(2);
+        let reading = keeper.now_at(after(instant, 2000), actual);
+        let jump = reading.jump.unwrap();
+        assert_eq!(jump.offset, Duration::hours(1));
+        assert_eq!(jump.detected_at, actual);
+        assert_eq!(jump.whole_minutes(), Duration::minutes(60));
+        //without a slew the tubes snap to it
+        assert_eq!(reading.displayed, actual);
+        assert!(!reading.slewing);
+        //and carry on from there
+        let reading = keeper.now_at(after(instant, 3000), actual + Duration::seconds(1));
+        assert!(reading.jump.is_none());
+    }
+
+    #[test]
+    fn detects_a_backward_jump() {
+        let (mut keeper, instant, wall) = keeper(None);
+        let actual = wall - Duration::seconds(89);
+        let jump = keeper.now_at(after(instant, 1000), actual).jump.unwrap();
+        assert_eq!(jump.offset, Duration::seconds(-90));
+        assert_eq!(jump.whole_minutes(), Duration::minutes(-2));
+    }
+
+    #[test]
+    fn leaves_drift_under_the_threshold_alone() {
+        let (mut keeper, instant, wall) = keeper(Some(Duration::seconds(10)));
+        //half a second fast over a second, then as much slow
+        let actual = wall + Duration::milliseconds(1500);
+        let reading = keeper.now_at(after(instant, 1000), actual);
+        assert!(reading.jump.is_none());
+        assert!(!reading.slewing);
+        assert_eq!(reading.displayed, actual);
+        let actual = wall + Duration::seconds(2);
+        let reading = keeper.now_at(after(instant, 2000), actual);
+        assert!(reading.jump.is_none());
+        assert_eq!(reading.displayed, actual);
+    }
+
+    #[test]
+    fn slews_to_a_jump() {
+        let (mut keeper, instant, wall) = keeper(Some(Duration::seconds(10)));
+        let actual = wall + Duration::seconds(6);
+        let reading = keeper.now_at(after(instant, 1000), actual);
+        assert_eq!(reading.jump.unwrap().offset, Duration::seconds(5));
+        //the tubes carry on from where they were
+        assert_eq!(reading.displayed, wall + Duration::seconds(1));
+        assert!(reading.slewing);
+        //half way through the slew, half way there
+        let actual = actual + Duration::seconds(5);
+        let reading = keeper.now_at(after(instant, 6000), actual);
+        assert_eq!(reading.displayed, actual - Duration::milliseconds(2500));
+        assert!(reading.slewing);
+        let actual = actual + Duration::seconds(5);
+        let reading = keeper.now_at(after(instant, 11000), actual);
+        assert_eq!(reading.displayed, actual);
+        assert!(!reading.slewing);
+    }
+
+    #[test]
+    fn slews_on_from_a_slew() {
+        //the same clock without the second step
+        let (mut stepped, instant, wall) = keeper(Some(Duration::seconds(10)));
+        let (mut steady, _, _) = keeper(Some(Duration::seconds(10)));
+        for keeper in [&mut stepped, &mut steady] {
+            //from the same start
+            keeper.last_instant = instant;
+            keeper.now_at(after(instant, 1000), wall + Duration::seconds(6));
+            keeper.now_at(after(instant, 6000), wall + Duration::seconds(11));
+        }
+        //another step of 2 seconds keeps rolling from what would have been shown
+        let reading = stepped.now_at(after(instant, 7000), wall + Duration::seconds(14));
+        assert_eq!(reading.jump.unwrap().offset, Duration::seconds(2));
+        assert!(reading.slewing);
+        let expected = steady.now_at(after(instant, 7000), wall + Duration::seconds(12));
+        assert_eq!(reading.displayed, expected.displayed);
+        assert!(reading.displayed < wall + Duration::seconds(12));
+    }
+
+    #[test]
+    fn snaps_steps_of_a_day() {
+        let (mut keeper, instant, wall) = keeper(Some(Duration::seconds(10)));
+        let actual = wall + Duration::days(1);
+        let reading = keeper.now_at(after(instant, 0), actual);
+        assert!(reading.jump.is_some());
+        assert_eq!(reading.displayed, actual);
+        assert!(!reading.slewing);
+    }
+
+    #[test]
+    fn rounds_jumps_to_whole_minutes() {
+        let minutes = |seconds| {
+            TimeJump {
+                detected_at: Local::now(),
+                offset: Duration::seconds(seconds),
+            }
+            .whole_minutes()
+            .num_minutes()
+        };
+        let cases = [(0, 0), (29, 0), (30, 1), (89, 1), (90, 2), (-29, 0), (-30, -1), (-89, -1), (-90, -2), (86400, 1440)];
+        for (seconds, expected) in cases {
+            assert_eq!(minutes(seconds), expected, "{}s", seconds);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::error::Error;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use chrono::prelude::*;
+use chrono::Duration;
+
+/// What the last successful time source check found. Shared with the drivers through an
+/// `Arc<RwLock<SyncStatus>>` so they can show whether the time on the tubes can be trusted.
+#[derive(Debug, Clone, Default)]
+pub struct SyncStatus {
+    pub source: Option<String>,
+    //how far the system clock is behind the source, positive means it is slow
+    pub offset: Option<Duration>,
+    pub delay: Option<Duration>,
+    pub stratum: Option<u8>,
+    //monotonic so a step of the system clock doesn't change the age
+    pub last_sync: Option<Instant>,
+    //set up by the configured sources, with none of them nothing is ever synced
+    pub tolerance: Option<Duration>,
+    pub max_age: Option<Duration>,
+    //how far the RTC was off the system clock when last compared, positive means it is fast
+    pub rtc_drift: Option<Duration>,
+    //the system time at the last pulse-per-second edge, which marks a true second boundary
+    pub pps_edge: Option<DateTime<Utc>>,
+    pub last_pps: Option<Instant>,
+}
+
+impl SyncStatus {
+    pub fn new(tolerance: Duration, max_age: Duration) -> SyncStatus {
+        SyncStatus {
+            tolerance: Some(tolerance),
+            max_age: Some(max_age),
+            ..Default::default()
+        }
+    }
+
+    /// Whether any time source was configured, without one the time can't be confirmed
+    pub fn has_sources(&self) -> bool {
+        self.tolerance.is_some()
+    }
+
+    pub fn age(&self) -> Option<Duration> {
+        self.last_sync
+            .map(|l| Duration::from_std(l.elapsed()).unwrap_or_else(|_| Duration::max_value()))
+    }
+
+    /// Synced recently enough, and the system clock was within tolerance of the source
+    pub fn is_synced(&self) -> bool {
+        match (self.offset, self.age(), self.tolerance, self.max_age) {
+            (Some(offset), Some(age), Some(tolerance), Some(max_age)) => {
+                age <= max_age && offset <= tolerance && -offset <= tolerance
+            }
+            _ => false,
+        }
+    }
+
+    pub fn record_pps(&mut self, system_time: DateTime<Utc>) {
+        self.pps_edge = Some(system_time);
+        self.last_pps = Some(Instant::now());
+    }
+
+    /// The system time at the PPS edge of the current second, if pulses are still coming
+    pub fn recent_pps_edge(&self) -> Option<DateTime<Utc>> {
+        match self.last_pps {
+            Some(l) if l.elapsed() < std::time::Duration::from_millis(1100) => self.pps_edge,
+            _ => None,
+        }
+    }
+
+    /// What to add to the system time to line its second boundaries up with the PPS edges,
+    /// at most half a second either way and zero once the pulses stop
+    pub fn pps_correction(&self) -> Duration {
+        match self.last_pps {
+            Some(l) if l.elapsed() < std::time::Duration::from_secs(3) => {
+                let phase = self.pps_edge.map_or(0, |e| e.timestamp_subsec_micros() as i64);
+                if phase > 500_000 {
+                    Duration::microseconds(1_000_000 - phase)
+                } else {
+                    Duration::microseconds(-phase)
+                }
+            }
+            _ => Duration::zero(),
+        }
+    }
+
+    pub fn record(&mut self, source: String, offset: Duration, delay: Duration, stratum: Option<u8>) {
+        self.source = Some(source);
+        self.offset = Some(offset);
+        self.delay = Some(delay);
+        self.stratum = stratum;
+        self.last_sync = Some(Instant::now());
+    }
+}
+
+/// Whether the time on the tubes has been confirmed by a time source. It latches, so a source
+/// going quiet later doesn't bring back the unsynced display, and without any sources
+/// configured there is nothing to wait for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimeConfirmation {
+    confirmed: bool,
+}
+
+impl TimeConfirmation {
+    pub fn new(status: &SyncStatus) -> TimeConfirmation {
+        TimeConfirmation {
+            confirmed: !status.has_sources(),
+        }
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    /// The source that confirmed the time, on the check that first finds it synced
+    pub fn update(&mut self, status: &SyncStatus) -> Option<String> {
+        if self.confirmed || !status.is_synced() {
+            return None;
+        }
+        self.confirmed = true;
+        Some(status.source.clone().unwrap_or_default())
+    }
+}
+
+/// Moves the system clock by `offset`, needs root (or CAP_SYS_TIME)
+pub fn step_system_clock(offset: Duration) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let target = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as i64
+        + offset.num_microseconds().ok_or("offset too large")?;
+    let ts = libc::timespec {
+        tv_sec: (target / 1_000_000) as libc::time_t,
+        tv_nsec: ((target % 1_000_000) * 1000) as libc::c_long,
+    };
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &ts) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> SyncStatus {
+        SyncStatus::new(Duration::milliseconds(100), Duration::minutes(30))
+    }
+
+    #[test]
+    fn is_synced_within_tolerance() {
+        let mut sync = status();
+        assert!(sync.has_sources());
+        assert!(!sync.is_synced());
+        sync.record("ntp".to_string(), Duration::milliseconds(-100), Duration::milliseconds(20), Some(2));
+        assert!(sync.is_synced());
+        sync.record("ntp".to_string(), Duration::milliseconds(101), Duration::milliseconds(20), Some(2));
+        assert!(!sync.is_synced());
+        sync.record("ntp".to_string(), Duration::zero(), Duration::milliseconds(20), Some(2));
+        sync.last_sync = Some(Instant::now() - std::time::Duration::from_secs(31 * 60));
+        assert!(!sync.is_synced());
+        assert!(!SyncStatus::default().has_sources());
+    }
+
+    #[test]
+    fn latches_the_first_confirmation() {
+        let mut sync = status();
+        let mut confirmation = TimeConfirmation::new(&sync);
+        assert!(!confirmation.is_confirmed());
+        //out of tolerance isn't confirmed
+        sync.record("gps".to_string(), Duration::seconds(3), Duration::zero(), None);
+        assert_eq!(confirmation.update(&sync), None);
+        assert!(!confirmation.is_confirmed());
+        sync.record("gps".to_string(), Duration::milliseconds(5), Duration::zero(), None);
+        assert_eq!(confirmation.update(&sync), Some("gps".to_string()));
+        assert!(confirmation.is_confirmed());
+        //only said once, and losing the source doesn't undo it
+        assert_eq!(confirmation.update(&sync), None);
+        sync.record("gps".to_string(), Duration::seconds(3), Duration::zero(), None);
+        assert_eq!(confirmation.update(&sync), None);
+        assert!(confirmation.is_confirmed());
+    }
+
+    #[test]
+    fn confirmed_without_sources() {
+        let mut confirmation = TimeConfirmation::new(&SyncStatus::default());
+        assert!(confirmation.is_confirmed());
+        assert_eq!(confirmation.update(&SyncStatus::default()), None);
+        assert!(confirmation.is_confirmed());
+    }
+}
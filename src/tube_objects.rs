@@ -41,7 +41,7 @@ impl Tube for NumericTube {
             '7' => NumericBitsIndex::_7,
             '8' => NumericBitsIndex::_8,
             '9' => NumericBitsIndex::_9,
-            ' ' => NumericBitsIndex::BLANK,
+            ' ' => NumericBitsIndex::Blank,
             _ => return Err(DisplayMessageError::UnexpectedCharForTubeType),
         };
         tube.set_cathode(bit_index);
@@ -60,7 +60,7 @@ impl NumericTube {
     pub fn set_cathode(&mut self, bit_index: NumericBitsIndex) {
         self.bits.clear();
         match bit_index {
-            NumericBitsIndex::BLANK => (),
+            NumericBitsIndex::Blank => (),
             _ => self.bits.set(bit_index as usize, true),
         }
     }
@@ -77,7 +77,7 @@ pub enum NumericBitsIndex {
     _7 = 2,
     _8 = 1,
     _9 = 0,
-    BLANK = 10,
+    Blank = 10,
 }
 
 pub struct IN19ATube {
@@ -164,10 +164,10 @@ impl Tube for Separator {
             bits: BitArray::<u8, U2>::from_elem(false)
         };
         let bit_index = match c {
-            ' ' => SeparatorBitsIndex::BLANK,
-            ':' => SeparatorBitsIndex::BOTH,
-            '.' => SeparatorBitsIndex::BOTTOM,
-            '\'' => SeparatorBitsIndex::TOP,
+            ' ' => SeparatorBitsIndex::Blank,
+            ':' => SeparatorBitsIndex::Both,
+            '.' => SeparatorBitsIndex::Bottom,
+            '\'' => SeparatorBitsIndex::Top,
             _ => return Err(DisplayMessageError::UnexpectedCharForTubeType),
         };
         tube.set_indicators(bit_index);
@@ -187,16 +187,16 @@ impl Separator {
     fn set_indicators(&mut self, bit_index: SeparatorBitsIndex) {
         self.bits.clear();
         match bit_index {
-            SeparatorBitsIndex::BLANK => (),
-            SeparatorBitsIndex::BOTH => self.bits.negate(),
+            SeparatorBitsIndex::Blank => (),
+            SeparatorBitsIndex::Both => self.bits.negate(),
             _ => self.bits.set(bit_index as usize, true),
         }
     }
 }
 
 pub enum SeparatorBitsIndex {
-    TOP = 0,
-    BOTTOM = 1,
-    BOTH,
-    BLANK,
+    Top = 0,
+    Bottom = 1,
+    Both,
+    Blank,
 }
@@ -0,0 +1,240 @@
+use std::ops::Range;
+use serde::Deserialize;
+
+use crate::clock_objects::{ClockType, SlotKind};
+use crate::sensors::Unit;
+
+//the SI prefixes the IN-19A has, by power of ten. It has a P too, but that's for pressure.
+const PREFIXES: [(i32, char); 5] = [(-9, 'η'), (-6, 'μ'), (-3, 'ₘ'), (3, 'κ'), (6, 'Μ')];
+
+/// Nixies have no minus sign
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NegativeStyle {
+    //the top dot of the separator left of the number
+    #[default]
+    TopDot,
+    //leave the time showing instead
+    Hide,
+}
+
+/// Lays a number out over some of a board's slots, right aligned with the decimal point
+/// on a separator. The digits either side of the point skip the separators in between.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NumberFormat {
+    //decimal places, fewer are shown when the number wouldn't fit otherwise
+    pub precision: usize,
+    pub negative: NegativeStyle,
+}
+
+impl NumberFormat {
+    /// Writes the number into `slots` within `field`, or None if it can't be shown there
+    pub fn place(&self, layout: &[SlotKind], slots: &mut [char], field: Range<usize>, value: f32) -> Option<()> {
+        let placed = (0..=self.precision)
+            .rev()
+            .find_map(|precision| self.place_at(layout, field.clone(), value, precision))?;
+        for (slot, c) in placed {
+            slots[slot] = c;
+        }
+        Some(())
+    }
+
+    //the decimal point goes on the last separator of the field
+    fn place_at(&self, layout: &[SlotKind], field: Range<usize>, value: f32, precision: usize) -> Option<Vec<(usize, char)>> {
+        let text = format!("{:.*}", precision, value.abs());
+        let (whole, fraction) = text.split_once('.').unwrap_or((&text, ""));
+        let numeric = |i: &usize| layout[*i] == SlotKind::Numeric;
+        let mut placed = vec![];
+        let mut whole_end = field.end;
+        if precision > 0 {
+            let point = field.clone().rev().find(|i| layout[*i] == SlotKind::Separator)?;
+            let fraction_slots: Vec<usize> = (point + 1..field.end).filter(numeric).collect();
+            if fraction.len() > fraction_slots.len() {
+                return None;
+            }
+            placed.push((point, '.'));
+            placed.extend(fraction_slots.into_iter().zip(fraction.chars()));
+            whole_end = point;
+        }
+        let whole_slots: Vec<usize> = (field.start..whole_end).rev().filter(numeric).collect();
+        if whole.len() > whole_slots.len() {
+            return None;
+        }
+        placed.extend(whole_slots.iter().copied().zip(whole.chars().rev()));
+        //a reading that rounds to 0 isn't negative
+        if value < 0f32 && text.chars().any(|c| c.is_ascii_digit() && c != '0') {
+            match self.negative {
+                NegativeStyle::Hide => return None,
+                NegativeStyle::TopDot => {
+                    let leftmost = whole_slots[whole.len() - 1];
+                    let sign = (field.start..leftmost).rev().find(|i| layout[*i] == SlotKind::Separator)?;
+                    placed.push((sign, '\''));
+                }
+            }
+        }
+        Some(placed)
+    }
+
+    /// The slot string for a value in a unit. The IN-19A, on boards that have one, shows the
+    /// unit if it has a glyph (℃, %, P for hPa) or else the SI prefix the value is scaled to, so 0.0042A
+    /// shows as `4.2 ₘ` and 1.5MPa as `1.5 Μ`. Concentrations like ppm are left unscaled.
+    pub fn render(&self, value: f32, unit: Unit, clock_type: ClockType) -> Option<String> {
+        let layout = clock_type.slot_layout();
+        let mut slots = vec![' '; layout.len()];
+        let mut end = layout.len();
+        let mut scaled = value;
+        if layout.last() == Some(&SlotKind::IN19A) {
+            end -= 1;
+            match unit.glyph() {
+                Some(glyph) => slots[end] = glyph,
+                None if !unit.si_prefixed() => (),
+                None => {
+                    if let Some((power, prefix)) = self.prefix_for(value) {
+                        scaled = value / 10f32.powi(power);
+                        slots[end] = prefix;
+                    }
+                }
+            }
+        }
+        self.place(layout, &mut slots, 0..end, scaled)?;
+        Some(slots.into_iter().collect())
+    }
+
+    //the prefix that leaves 1 to 999 in front of it once rounded, as near as the tube has
+    fn prefix_for(&self, value: f32) -> Option<(i32, char)> {
+        if value == 0f32 || !value.is_finite() {
+            return None;
+        }
+        let mut power = (value.abs().log10().floor() as i32).div_euclid(3) * 3;
+        //999.96 rounds up to 1000.0, which should be 1.0κ
+        let places = 10f32.powi(self.precision as i32);
+        if (value.abs() / 10f32.powi(power) * places).round() / places >= 1000f32 {
+            power += 3;
+        }
+        if power == 0 {
+            return None;
+        }
+        let (lowest, highest) = (PREFIXES[0], PREFIXES[PREFIXES.len() - 1]);
+        PREFIXES
+            .iter()
+            .copied()
+            .find(|(p, _)| *p == power)
+            .or(if power < lowest.0 { Some(lowest) } else { None })
+            .or(if power > highest.0 { Some(highest) } else { None })
+            .or_else(|| PREFIXES.iter().copied().rev().find(|(p, _)| *p < power))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(precision: usize) -> NumberFormat {
+        NumberFormat {
+            precision,
+            negative: NegativeStyle::TopDot,
+        }
+    }
+
+    fn render(value: f32, unit: Unit, clock_type: ClockType) -> Option<String> {
+        format(1).render(value, unit, clock_type)
+    }
+
+    #[test]
+    fn scales_to_a_prefix() {
+        let render = |value, unit| render(value, unit, ClockType::NCS3148C);
+        assert_eq!(render(0.0042, Unit::Ampere).as_deref(), Some("       4.2 ₘ"));
+        assert_eq!(render(1.5e6, Unit::Pascal).as_deref(), Some("       1.5 Μ"));
+        assert_eq!(render(0.00042, Unit::Volt).as_deref(), Some("    4 20.0 μ"));
+        assert_eq!(render(4.2e-9, Unit::Ampere).as_deref(), Some("       4.2 η"));
+        assert_eq!(render(2500f32, Unit::Watt).as_deref(), Some("       2.5 κ"));
+        //no prefix between 1 and 999
+        assert_eq!(render(230f32, Unit::Volt).as_deref(), Some("    2 30.0  "));
+        assert_eq!(render(0f32, Unit::Volt).as_deref(), Some("       0.0  "));
+    }
+
+    #[test]
+    fn picks_the_prefix_at_the_boundaries() {
+        let prefix = |value, precision| format(precision).prefix_for(value);
+        assert_eq!(prefix(999.94, 1), None);
+        //rounds up to 1000.0
+        assert_eq!(prefix(999.96, 1), Some((3, 'κ')));
+        assert_eq!(prefix(999.96, 2), None);
+        assert_eq!(prefix(1000f32, 1), Some((3, 'κ')));
+        assert_eq!(prefix(1f32, 1), None);
+        assert_eq!(prefix(0.999, 1), Some((-3, 'ₘ')));
+        assert_eq!(prefix(0.001, 1), Some((-3, 'ₘ')));
+        assert_eq!(prefix(-0.001, 1), Some((-3, 'ₘ')));
+        assert_eq!(prefix(0.00099, 1), Some((-6, 'μ')));
+        //there is nothing past Μ or below η, so the number grows or shrinks instead
+        assert_eq!(prefix(2e9, 1), Some((6, 'Μ')));
+        assert_eq!(prefix(2e-12, 1), Some((-9, 'η')));
+        assert_eq!(prefix(0f32, 1), None);
+        assert_eq!(prefix(f32::NAN, 1), None);
+        assert_eq!(prefix(f32::INFINITY, 1), None);
+        assert_eq!(
+            render(999.96, Unit::Watt, ClockType::NCS3148C).as_deref(),
+            Some("       1.0 κ")
+        );
+        assert_eq!(
+            render(2e9, Unit::Watt, ClockType::NCS3148C).as_deref(),
+            Some("   20 00.0 Μ")
+        );
+    }
+
+    #[test]
+    fn shows_negatives() {
+        let hide = NumberFormat {
+            precision: 1,
+            negative: NegativeStyle::Hide,
+        };
+        assert_eq!(render(-4.2, Unit::Celsius, ClockType::NCS3186).as_deref(), Some("  ' 4.2 "));
+        assert_eq!(render(-4.2, Unit::Celsius, ClockType::NCS3148C).as_deref(), Some("     ' 4.2 ℃"));
+        assert_eq!(hide.render(-4.2, Unit::Celsius, ClockType::NCS3186), None);
+        //rounds to 0, so isn't negative
+        assert_eq!(render(-0.04, Unit::Celsius, ClockType::NCS3186).as_deref(), Some("    0.0 "));
+        assert_eq!(hide.render(-0.04, Unit::Celsius, ClockType::NCS3186).as_deref(), Some("    0.0 "));
+        //no separator left of the hundreds for the sign, so the decimal goes
+        assert_eq!(render(-123.4, Unit::Celsius, ClockType::NCS3186).as_deref(), Some("  ' 1 23"));
+    }
+
+    #[test]
+    fn drops_decimals_to_fit() {
+        let render = |value, precision| format(precision).render(value, Unit::Celsius, ClockType::NCS3186);
+        assert_eq!(render(21.456, 2).as_deref(), Some("   21.46"));
+        assert_eq!(render(21.456, 1).as_deref(), Some("   21.5 "));
+        assert_eq!(render(21.456, 0).as_deref(), Some("      21"));
+        assert_eq!(render(3.999, 2).as_deref(), Some("    4.00"));
+        assert_eq!(render(12345.6, 2).as_deref(), Some(" 1 23 46"));
+    }
+
+    #[test]
+    fn none_when_it_does_not_fit() {
+        assert_eq!(render(1234567f32, Unit::Celsius, ClockType::NCS3186), None);
+        assert_eq!(render(123456789f32, Unit::Celsius, ClockType::NCS3148C), None);
+        assert_eq!(render(-1234567f32, Unit::Celsius, ClockType::NCS3148C), None);
+        //unscaled, as a concentration
+        assert_eq!(render(123456789f32, Unit::PartsPerMillion, ClockType::NCS3148C), None);
+        //a field with no separator for the point, and no room for the whole number
+        let layout = ClockType::NCS3186.slot_layout();
+        let mut slots = vec![' '; layout.len()];
+        assert_eq!(format(1).place(layout, &mut slots, 0..2, 123f32), None);
+        assert_eq!(slots, vec![' '; layout.len()]);
+        assert_eq!(format(1).place(layout, &mut slots, 0..2, 12.3), Some(()));
+        assert_eq!(slots.iter().collect::<String>(), "12      ");
+    }
+
+    #[test]
+    fn in19a_slot_on_each_board() {
+        //the unit's own glyph
+        assert_eq!(render(56.07, Unit::Percent, ClockType::NCS3148C).as_deref(), Some("      56.1 %"));
+        assert_eq!(render(1006.5, Unit::Hectopascal, ClockType::NCS3148C).as_deref(), Some("   10 06.5 P"));
+        assert_eq!(render(0.12, Unit::MicrosievertsPerHour, ClockType::NCS3148C).as_deref(), Some("       0.1 μ"));
+        //left blank when neither fits
+        assert_eq!(render(1234f32, Unit::PartsPerMillion, ClockType::NCS3148C).as_deref(), Some("   12 34.0  "));
+        //the NCS3186 has no IN-19A, so no glyph and no scaling
+        assert_eq!(render(56.07, Unit::Percent, ClockType::NCS3186).as_deref(), Some("   56.1 "));
+        assert_eq!(render(2500f32, Unit::Watt, ClockType::NCS3186).as_deref(), Some("25 00.0 "));
+        assert_eq!(render(0.0042, Unit::Ampere, ClockType::NCS3186).as_deref(), Some("    0.0 "));
+    }
+}
@@ -0,0 +1,206 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::errors::{SensorError, SensorResult, W1Error, W1Result};
+use crate::sensors::{Sensor, Unit};
+use crate::temperature_sensor::{check_power_on, Ds18b20Config, TemperatureConfig};
+
+//the DS18B20's 1-Wire family code, the start of its directory names
+const DS18B20_PREFIX: &str = "28-";
+
+/// Reads a DS18B20 through the kernel's w1-gpio driver (`dtoverlay=w1-gpio,gpiopin=5`)
+/// rather than bit-banging the bus from here, which fights the display loop for the CPU.
+/// Each `<devices>/28-*/w1_slave` holds the scratchpad, the kernel's CRC check and the
+/// temperature in m°C:
+///
+/// ```text
+/// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+/// 72 01 4b 46 7f ff 0e 10 57 t=23125
+/// ```
+///
+/// Configured probes are read from the directories named by their ROM IDs.
+#[derive(Debug, Clone)]
+pub struct W1TemperatureSensor {
+    devices: PathBuf,
+    probes: Vec<Ds18b20Config>,
+    measurements: Vec<(String, Unit)>,
+}
+
+impl W1TemperatureSensor {
+    pub fn new(config: &TemperatureConfig) -> Result<W1TemperatureSensor, Box<dyn Error>> {
+        config.validate()?;
+        let sensor = W1TemperatureSensor {
+            devices: PathBuf::from(&config.w1_devices),
+            probes: config.sensors.clone(),
+            measurements: config.measurements(),
+        };
+        for probe in &sensor.probes {
+            //the kernel keeps the probe's resolution, writing it needs root
+            let path = sensor.devices.join(&probe.rom).join("resolution");
+            if let Err(e) = fs::write(&path, probe.resolution.to_string()) {
+                println!("Couldn't set {} to {} bits: {}", probe.name, probe.resolution, e);
+            }
+        }
+        Ok(sensor)
+    }
+
+    /// The DS18B20 directories, sorted so the same one comes first every time
+    pub fn device_dirs(&self) -> W1Result<Vec<PathBuf>> {
+        let no_devices = || W1Error::NoDevices(self.devices.display().to_string());
+        let mut dirs: Vec<PathBuf> = fs::read_dir(&self.devices)
+            .map_err(|_| no_devices())?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(DS18B20_PREFIX))
+            .map(|entry| entry.path())
+            .collect();
+        dirs.sort();
+        if dirs.is_empty() {
+            return Err(no_devices());
+        }
+        Ok(dirs)
+    }
+
+    pub fn read_device(dir: &Path) -> W1Result<f32> {
+        let contents = fs::read_to_string(dir.join("w1_slave")).map_err(|e| W1Error::Read(e.to_string()))?;
+        W1TemperatureSensor::parse_w1_slave(&contents)
+    }
+
+    /// The temperature in °C from the contents of a `w1_slave` file
+    pub fn parse_w1_slave(contents: &str) -> W1Result<f32> {
+        let mut lines = contents.lines();
+        let crc_line = lines.next().ok_or(W1Error::BadFormat)?;
+        if !crc_line.contains("crc=") {
+            return Err(W1Error::BadFormat);
+        }
+        if !crc_line.trim_end().ends_with("YES") {
+            return Err(W1Error::CrcMismatch);
+        }
+        let millidegrees: i32 = lines
+            .next()
+            .and_then(|l| l.split("t=").nth(1))
+            .and_then(|t| t.trim().parse().ok())
+            .ok_or(W1Error::BadFormat)?;
+        Ok(millidegrees as f32 / 1000f32)
+    }
+}
+
+impl Sensor for W1TemperatureSensor {
+    fn name(&self) -> String {
+        "Temperature (w1)".to_string()
+    }
+
+    fn measurements(&self) -> Vec<(String, Unit)> {
+        self.measurements.clone()
+    }
+
+    fn read(&mut self) -> Result<Vec<SensorResult<f32>>, Box<dyn Error>> {
+        if self.probes.is_empty() {
+            let dirs = self.device_dirs()?;
+            let temperature = W1TemperatureSensor::read_device(&dirs[0]).map_err(SensorError::from);
+            return Ok(vec![temperature.and_then(check_power_on)]);
+        }
+        Ok(self
+            .probes
+            .iter()
+            .map(|p| {
+                W1TemperatureSensor::read_device(&self.devices.join(&p.rom))
+                    .map_err(SensorError::from)
+                    .and_then(check_power_on)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDOOR: &str = "28-0000075b0b1c";
+    const OUTDOOR: &str = "28-0000075c1d2e";
+
+    //a devices directory as w1-gpio lays it out, with the bus master alongside the probes
+    fn fake_sysfs(probes: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("w1_bus_master1")).unwrap();
+        for (rom, w1_slave) in probes {
+            fs::create_dir(dir.path().join(rom)).unwrap();
+            fs::write(dir.path().join(rom).join("w1_slave"), w1_slave).unwrap();
+        }
+        dir
+    }
+
+    fn w1_slave(crc_ok: bool, millidegrees: i32) -> String {
+        let crc = if crc_ok { "YES" } else { "NO" };
+        format!(
+            "72 01 4b 46 7f ff 0e 10 57 : crc=57 {}\n72 01 4b 46 7f ff 0e 10 57 t={}\n",
+            crc, millidegrees
+        )
+    }
+
+    fn sensor(dir: &tempfile::TempDir, probes: &[(&str, &str)]) -> W1TemperatureSensor {
+        let config = TemperatureConfig {
+            backend: crate::temperature_sensor::TemperatureBackend::W1Sysfs,
+            w1_devices: dir.path().display().to_string(),
+            sensors: probes
+                .iter()
+                .map(|(rom, name)| Ds18b20Config {
+                    rom: rom.to_string(),
+                    name: name.to_string(),
+                    resolution: 12,
+                })
+                .collect(),
+        };
+        W1TemperatureSensor::new(&config).unwrap()
+    }
+
+    #[test]
+    fn parses_w1_slave() {
+        assert_eq!(W1TemperatureSensor::parse_w1_slave(&w1_slave(true, 23125)), Ok(23.125));
+        assert_eq!(W1TemperatureSensor::parse_w1_slave(&w1_slave(true, -1250)), Ok(-1.25));
+        assert_eq!(W1TemperatureSensor::parse_w1_slave(&w1_slave(false, 23125)), Err(W1Error::CrcMismatch));
+        assert_eq!(W1TemperatureSensor::parse_w1_slave(""), Err(W1Error::BadFormat));
+        assert_eq!(
+            W1TemperatureSensor::parse_w1_slave("72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n"),
+            Err(W1Error::BadFormat)
+        );
+    }
+
+    #[test]
+    fn reads_the_first_probe() {
+        let dir = fake_sysfs(&[(OUTDOOR, &w1_slave(true, -1250)), (INDOOR, &w1_slave(true, 21500))]);
+        let mut w1 = sensor(&dir, &[]);
+        assert_eq!(w1.device_dirs().unwrap().len(), 2);
+        assert_eq!(w1.read().unwrap(), vec![Ok(21.5)]);
+    }
+
+    #[test]
+    fn reads_configured_probes() {
+        let dir = fake_sysfs(&[
+            (INDOOR, &w1_slave(false, 21500)),
+            (OUTDOOR, &w1_slave(true, 85000)),
+            ("28-0000075d2f3a", &w1_slave(true, -1250)),
+        ]);
+        let mut w1 = sensor(
+            &dir,
+            &[(INDOOR, "indoor"), (OUTDOOR, "outdoor"), ("28-0000075d2f3a", "shed"), ("28-00000000dead", "pond")],
+        );
+        let results = w1.read().unwrap();
+        assert_eq!(results[0], Err(SensorError::Checksum));
+        //85°C is what a DS18B20 reads before its first conversion
+        assert!(matches!(results[1], Err(SensorError::Rejected(_))));
+        assert_eq!(results[2], Ok(-1.25));
+        assert!(matches!(results[3], Err(SensorError::Failed(_))));
+    }
+
+    #[test]
+    fn no_devices() {
+        let dir = fake_sysfs(&[]);
+        let mut w1 = sensor(&dir, &[]);
+        assert!(matches!(w1.device_dirs(), Err(W1Error::NoDevices(_))));
+        assert!(w1.read().is_err());
+        let mut gone = sensor(&dir, &[]);
+        gone.devices = dir.path().join("missing");
+        assert!(matches!(gone.device_dirs(), Err(W1Error::NoDevices(_))));
+    }
+}
@@ -0,0 +1,189 @@
+use std::error::Error;
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use crate::clock_objects::{ClockType, SlotKind};
+use crate::display_template::DisplayTemplate;
+use crate::rgb_driver::LedColor;
+
+/// The `[world_clock]` section of the config
+#[derive(Debug, Clone, Deserialize)]
+pub struct WorldClockConfig {
+    #[serde(default = "WorldClockConfig::default_seconds_per_zone")]
+    pub seconds_per_zone: u32,
+    pub zones: Vec<WorldZone>,
+}
+
+impl WorldClockConfig {
+    fn default_seconds_per_zone() -> u32 {
+        10
+    }
+}
+
+/// An IANA time zone, and how to tell it apart from the others while it's shown
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WorldZone {
+    pub zone: Tz,
+    //shown on the IN-19A tube, so only boards with one can use it
+    pub symbol: Option<char>,
+    pub led: Option<LedColor>,
+}
+
+/// Rotates the time through a list of zones, each shown for the same number of seconds.
+/// The rotation is counted from the epoch so every clock shows the same zone at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorldClock {
+    zones: Vec<WorldZone>,
+    period: Duration,
+}
+
+impl WorldClock {
+    pub fn from_config(config: &WorldClockConfig, clock_type: ClockType) -> Result<WorldClock, Box<dyn Error>> {
+        if config.zones.is_empty() || config.seconds_per_zone == 0 {
+            return Err("World clock needs zones and a non-zero seconds_per_zone".into());
+        }
+        let has_in19a = clock_type.slot_layout().contains(&SlotKind::IN19A);
+        for z in &config.zones {
+            if let Some(symbol) = z.symbol {
+                if !has_in19a {
+                    return Err(format!("{:?} has no IN-19A tube for the {} symbol, use led instead", clock_type, z.zone).into());
+                }
+                if !SlotKind::IN19A.accepts(symbol) {
+                    return Err(format!("IN-19A can't show {:?} for {}", symbol, z.zone).into());
+                }
+            }
+        }
+        Ok(WorldClock {
+            zones: config.zones.clone(),
+            period: Duration::seconds(config.seconds_per_zone as i64),
+        })
+    }
+
+    fn zone_at(&self, t: &DateTime<Local>) -> &WorldZone {
+        let idx = t.timestamp().div_euclid(self.period.num_seconds()) as usize % self.zones.len();
+        &self.zones[idx]
+    }
+
+    pub fn render(
+        &self,
+        displayed: &DateTime<Local>,
+        separators_on: bool,
+        template: &DisplayTemplate,
+        clock_type: ClockType,
+    ) -> String {
+        let zone = self.zone_at(displayed);
+        let slots = template.render(&displayed.with_timezone(&zone.zone), separators_on);
+        match zone.symbol {
+            Some(symbol) => slots
+                .chars()
+                .zip(clock_type.slot_layout())
+                .map(|(c, kind)| if *kind == SlotKind::IN19A { symbol } else { c })
+                .collect(),
+            None => slots,
+        }
+    }
+
+    pub fn led_at(&self, displayed: &DateTime<Local>) -> Option<LedColor> {
+        self.zone_at(displayed).led
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::America::Denver;
+    use chrono_tz::Asia::Tokyo;
+    use chrono_tz::Europe::Berlin;
+
+    fn config(source: &str) -> WorldClockConfig {
+        toml::from_str(source).unwrap()
+    }
+
+    fn three_zones() -> WorldClockConfig {
+        config(
+            r#"
+            zones = [
+              { zone = "America/Denver", symbol = "%", led = "blue" },
+              { zone = "Europe/Berlin", symbol = "κ", led = "green" },
+              { zone = "Asia/Tokyo", led = "red" },
+            ]
+            "#,
+        )
+    }
+
+    //12:00 UTC, at the start of Denver's turn
+    fn at(seconds: i64) -> DateTime<Local> {
+        Utc.ymd(2021, 6, 7).and_hms(12, 0, 0).with_timezone(&Local) + Duration::seconds(seconds)
+    }
+
+    #[test]
+    fn needs_zones_and_a_period() {
+        let empty = config("zones = []");
+        assert!(WorldClock::from_config(&empty, ClockType::NCS3148C).is_err());
+        let still = WorldClockConfig {
+            seconds_per_zone: 0,
+            ..three_zones()
+        };
+        assert!(WorldClock::from_config(&still, ClockType::NCS3148C).is_err());
+        assert_eq!(three_zones().seconds_per_zone, 10);
+        assert!(WorldClock::from_config(&three_zones(), ClockType::NCS3148C).is_ok());
+        //a numeral isn't on the IN-19A
+        let numeral = config(r#"zones = [{ zone = "Asia/Tokyo", symbol = "5" }]"#);
+        assert!(WorldClock::from_config(&numeral, ClockType::NCS3148C).is_err());
+    }
+
+    #[test]
+    fn rotates_through_the_zones() {
+        let clock = WorldClock::from_config(&three_zones(), ClockType::NCS3148C).unwrap();
+        let zones: Vec<Tz> = [0, 9, 10, 19, 20, 29, 30]
+            .iter()
+            .map(|s| clock.zone_at(&at(*s)).zone)
+            .collect();
+        assert_eq!(zones, vec![Denver, Denver, Berlin, Berlin, Tokyo, Tokyo, Denver]);
+        let leds: Vec<Option<LedColor>> = [0, 10, 20].iter().map(|s| clock.led_at(&at(*s))).collect();
+        assert_eq!(leds, vec![Some(LedColor::Blue), Some(LedColor::Green), Some(LedColor::Red)]);
+        let slower = WorldClockConfig {
+            seconds_per_zone: 60,
+            ..three_zones()
+        };
+        let clock = WorldClock::from_config(&slower, ClockType::NCS3148C).unwrap();
+        //the minute 12:00 is the first of a three minute rotation
+        assert_eq!(clock.zone_at(&at(59)).zone, Denver);
+        assert_eq!(clock.zone_at(&at(60)).zone, Berlin);
+    }
+
+    #[test]
+    fn shows_the_symbol_on_the_in19a() {
+        let clock = WorldClock::from_config(&three_zones(), ClockType::NCS3148C).unwrap();
+        let template = DisplayTemplate::parse("%H%:%M%:%S%.%C%p", ClockType::NCS3148C).unwrap();
+        let render = |s| clock.render(&at(s), true, &template, ClockType::NCS3148C);
+        assert_eq!(render(0), "06:00:00.00%");
+        assert_eq!(render(15), "14:00:15.00κ");
+        //without a symbol the template's own IN-19A slot is left
+        assert_eq!(render(25), "21:00:25.00P");
+        assert_eq!(clock.render(&at(25), false, &template, ClockType::NCS3148C), "21 00 25 00P");
+    }
+
+    #[test]
+    fn falls_back_to_the_led_without_an_in19a() {
+        let e = WorldClock::from_config(&three_zones(), ClockType::NCS3186).unwrap_err();
+        assert_eq!(e.to_string(), "NCS3186 has no IN-19A tube for the America/Denver symbol, use led instead");
+        let leds = config(
+            r#"
+            seconds_per_zone = 5
+            zones = [
+              { zone = "America/Denver", led = "blue" },
+              { zone = "Europe/Berlin" },
+            ]
+            "#,
+        );
+        let clock = WorldClock::from_config(&leds, ClockType::NCS3186).unwrap();
+        let template = DisplayTemplate::parse("%H%:%M%:%S", ClockType::NCS3186).unwrap();
+        assert_eq!(clock.render(&at(0), true, &template, ClockType::NCS3186), "06:00:00");
+        assert_eq!(clock.led_at(&at(0)), Some(LedColor::Blue));
+        assert_eq!(clock.render(&at(5), true, &template, ClockType::NCS3186), "14:00:05");
+        assert_eq!(clock.led_at(&at(5)), None);
+    }
+}
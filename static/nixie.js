@@ -0,0 +1,76 @@
+// Draws the JSON frames of a display stream as nixie tubes in `container`, reconnecting
+// when the stream drops. `onStatus` gets a line of text to show.
+function NixieView(container, streamPath, onStatus) {
+  const cathodes = {
+    numeric: ["1", "2", "3", "4", "5", "6", "7", "8", "9", "0"],
+    separator: ["top", "bottom"],
+    in19a: ["℃", "μ", "η", "κ", "ₘ", "P", "Μ", "%"],
+  };
+  let layout = "";
+
+  function build(frame) {
+    container.innerHTML = "";
+    for (const tube of frame.tubes) {
+      const el = document.createElement("div");
+      el.className = "tube " + tube.kind;
+      for (const c of cathodes[tube.kind]) {
+        const cathode = document.createElement("div");
+        cathode.className = tube.kind === "separator" ? "dot " + c : "cathode";
+        cathode.dataset.cathode = c;
+        if (tube.kind !== "separator") cathode.textContent = c;
+        el.appendChild(cathode);
+      }
+      container.appendChild(el);
+    }
+    layout = frame.tubes.map(t => t.kind).join();
+  }
+
+  function show(frame) {
+    if (frame.tubes.map(t => t.kind).join() !== layout) build(frame);
+    frame.tubes.forEach((tube, i) => {
+      const lit = Object.fromEntries(tube.lit.map(l => [l.cathode, l.brightness]));
+      for (const el of container.children[i].children) {
+        const brightness = lit[el.dataset.cathode];
+        el.classList.toggle("lit", brightness !== undefined);
+        el.style.opacity = brightness !== undefined ? Math.max(brightness, 0.05) : "";
+      }
+    });
+    onStatus(frame.clock_type);
+  }
+
+  function connect() {
+    const scheme = location.protocol === "https:" ? "wss://" : "ws://";
+    const socket = new WebSocket(scheme + location.host + streamPath);
+    socket.onmessage = event => show(JSON.parse(event.data));
+    socket.onclose = () => {
+      onStatus("Disconnected, retrying…");
+      setTimeout(connect, 2000);
+    };
+  }
+  connect();
+}
+
+const NIXIE_CSS = `
+  .clock { display: flex; gap: 0.4em; justify-content: center; align-items: flex-end; flex-wrap: wrap; }
+  .tube {
+    position: relative; width: 2.8em; height: 4.4em; border-radius: 1.4em 1.4em 0.4em 0.4em;
+    background: radial-gradient(ellipse at 50% 40%, #2a2420, #141210 75%);
+    border: 1px solid #333; font-size: 32px;
+  }
+  .tube.separator { width: 0.9em; }
+  .tube.in19a { width: 2.2em; }
+  .cathode {
+    position: absolute; inset: 0; display: flex; align-items: center; justify-content: center;
+    font-size: 2.6em; color: #3a302a; opacity: 0.25;
+  }
+  .in19a .cathode { font-size: 1.8em; }
+  .cathode.lit { color: #ff9a3c; text-shadow: 0 0 0.15em #ff6a00, 0 0 0.4em #ff4a00; }
+  .dot {
+    position: absolute; left: 50%; width: 0.3em; height: 0.3em; margin-left: -0.15em;
+    border-radius: 50%; background: #3a302a; opacity: 0.4;
+  }
+  .dot.top { top: 35%; }
+  .dot.bottom { top: 60%; }
+  .dot.lit { background: #ff9a3c; box-shadow: 0 0 0.3em #ff6a00; }
+`;
+document.head.insertAdjacentHTML("beforeend", "<style>" + NIXIE_CSS + "</style>");